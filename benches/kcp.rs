@@ -0,0 +1,167 @@
+//! Throughput and per operation cost of `Kcp`
+//!
+//! `cargo bench` measures every case with criterion. `cargo test --benches` runs every case once
+//! as a smoke test.
+
+extern crate bytes;
+extern crate criterion;
+extern crate kcp;
+
+use std::hint::black_box;
+use std::time::{Duration, Instant};
+
+use bytes::{BufMut, BytesMut};
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+use kcp::{CollectingKcp, Kcp};
+
+const WND: u16 = 1024;
+
+/// Runs `iteration` as many times as criterion asks, each returns the time of the part measured
+fn measure<F>(iters: u64, mut iteration: F) -> Duration
+where
+    F: FnMut() -> Duration,
+{
+    (0..iters).map(|_| iteration()).sum()
+}
+
+fn new_kcp() -> CollectingKcp {
+    let mut kcp = Kcp::new_collecting(1);
+    kcp.set_nodelay(true, 10, 2, true);
+    kcp.set_wndsize(WND, WND);
+    kcp.update(0).unwrap();
+    kcp
+}
+
+/// Message of `len` bytes, the same for every run
+fn message(len: usize) -> Vec<u8> {
+    (0..len).map(|i| (i % 251) as u8).collect()
+}
+
+/// Datagrams of a sender with a message of `len` bytes, fragmented by the MSS
+fn fragments(len: usize) -> Vec<BytesMut> {
+    let mut sender = new_kcp();
+    sender.send(&message(len)).unwrap();
+    sender.flush().unwrap();
+    sender.take_output()
+}
+
+/// `count` datagrams of one PUSH segment with `len` bytes each, sn from 0
+///
+/// Encoded by hand, a sender would be limited by the window of a remote it hasn't heard from.
+fn pushes(count: usize, len: usize) -> Vec<BytesMut> {
+    let payload = message(len);
+    (0..count as u32)
+        .map(|sn| {
+            let mut datagram = BytesMut::with_capacity(kcp::KCP_OVERHEAD + len);
+            datagram.put_u32_le(1); // conv
+            datagram.put_u8(81); // cmd: push
+            datagram.put_u8(0); // frg
+            datagram.put_u16_le(WND);
+            datagram.put_u32_le(0); // ts
+            datagram.put_u32_le(sn);
+            datagram.put_u32_le(0); // una
+            datagram.put_u32_le(len as u32);
+            datagram.put_slice(&payload);
+            datagram
+        })
+        .collect()
+}
+
+/// `send` and `flush` of a message, acknowledged by a receiver outside of the measurement
+fn bench_send_flush(c: &mut Criterion, len: usize) {
+    let mut sender = new_kcp();
+    let mut receiver = new_kcp();
+    let message = message(len);
+    let mut current = 0;
+
+    let mut group = c.benchmark_group("send_flush");
+    group.throughput(Throughput::Bytes(len as u64));
+    group.bench_function(len.to_string(), |b| {
+        b.iter_custom(|iters| {
+            measure(iters, || {
+                let started = Instant::now();
+                sender.send(black_box(&message)).unwrap();
+                sender.flush().unwrap();
+                let elapsed = started.elapsed();
+
+                for datagram in sender.take_output() {
+                    receiver.input(&datagram).unwrap();
+                }
+                receiver.recv_all();
+                receiver.flush().unwrap();
+                for datagram in receiver.take_output() {
+                    sender.input(&datagram).unwrap();
+                }
+                current += 10;
+                sender.update(current).unwrap();
+                receiver.update(current).unwrap();
+                elapsed
+            })
+        })
+    });
+    group.finish();
+}
+
+/// `input` of a burst filling the receive window, one segment each
+fn bench_input(c: &mut Criterion, reverse: bool) {
+    let mut datagrams = pushes(WND as usize, 100);
+    if reverse {
+        datagrams.reverse();
+    }
+    let bytes = datagrams.iter().map(|d| d.len()).sum::<usize>();
+    let name = if reverse { "reverse" } else { "in_order" };
+
+    let mut group = c.benchmark_group("input");
+    group.throughput(Throughput::Bytes(bytes as u64));
+    group.bench_function(name, |b| {
+        b.iter_custom(|iters| {
+            measure(iters, || {
+                let mut receiver = new_kcp();
+                let started = Instant::now();
+                for datagram in &datagrams {
+                    receiver.input(black_box(datagram)).unwrap();
+                }
+                let elapsed = started.elapsed();
+                assert_eq!(receiver.ready_message_count(), WND as usize);
+                elapsed
+            })
+        })
+    });
+    group.finish();
+}
+
+/// `recv` of a message reassembled from its fragments
+fn bench_recv(c: &mut Criterion, len: usize) {
+    let datagrams = fragments(len);
+    let mut buf = vec![0u8; len];
+
+    let mut group = c.benchmark_group("recv");
+    group.throughput(Throughput::Bytes(len as u64));
+    group.bench_function(len.to_string(), |b| {
+        b.iter_custom(|iters| {
+            measure(iters, || {
+                let mut receiver = new_kcp();
+                for datagram in &datagrams {
+                    receiver.input(datagram).unwrap();
+                }
+                let started = Instant::now();
+                let n = receiver.recv(black_box(&mut buf)).unwrap();
+                let elapsed = started.elapsed();
+                assert_eq!(n, len);
+                elapsed
+            })
+        })
+    });
+    group.finish();
+}
+
+fn all(c: &mut Criterion) {
+    bench_send_flush(c, 1024);
+    bench_send_flush(c, 64 * 1024);
+    bench_input(c, false);
+    bench_input(c, true);
+    bench_recv(c, 64 * 1024);
+}
+
+criterion_group!(benches, all);
+criterion_main!(benches);
@@ -1,13 +1,28 @@
+#[cfg(feature = "std")]
 use std::error::Error as StdError;
-use std::io::{self, ErrorKind};
+
+use crate::io;
+#[cfg(feature = "std")]
+use crate::io::ErrorKind;
 
 /// KCP protocol errors
 #[derive(Debug, thiserror::Error)]
+#[non_exhaustive]
 pub enum Error {
     #[error("conv inconsistent, expected {0}, found {1}")]
     ConvInconsistent(u32, u32),
+    #[error("conv mismatch at offset {offset}, expected {expected}, found {found}")]
+    ConvMismatch {
+        expected: u32,
+        found: u32,
+        offset: usize,
+    },
     #[error("invalid mtu {0}")]
     InvalidMtu(usize),
+    #[error("invalid reserved size {0}")]
+    InvalidReserved(usize),
+    #[error("invalid config: {0}")]
+    InvalidConfig(&'static str),
     #[error("invalid segment size {0}")]
     InvalidSegmentSize(usize),
     #[error("invalid segment data size, expected {0}, found {1}")]
@@ -24,14 +39,37 @@ pub enum Error {
     RecvQueueEmpty,
     #[error("expecting fragment")]
     ExpectingFragment,
+    #[error("corrupt fragment chain")]
+    CorruptFragmentChain,
     #[error("command {0} is not supported")]
     UnsupportedCmd(u8),
     #[error("user's send buffer is too big")]
     UserBufTooBig,
+    #[error("send queue is full")]
+    SendQueueFull,
+    #[error("send side is shut down")]
+    SendShutdown,
+    #[error("segment decryption failed")]
+    DecryptFailed,
+    #[error("message decompression failed")]
+    DecompressFailed,
     #[error("user's recv buffer is too small")]
     UserBufTooSmall,
+    #[error("no clock, create with Kcp::with_clock")]
+    NoClock,
+    #[error("not a connection request")]
+    NotSyn,
+    #[error("segment checksum mismatch")]
+    ChecksumMismatch,
+    #[error("segment of another session")]
+    SessionMismatch,
+    #[error("{0} segments in flight exceed the mtu")]
+    InflightExceedsMtu(usize),
+    #[error("fec packet type {0:#x} is not supported")]
+    UnsupportedFecType(u16),
 }
 
+#[cfg(feature = "std")]
 fn make_io_error<T>(kind: ErrorKind, msg: T) -> io::Error
 where
     T: Into<Box<dyn StdError + Send + Sync>>,
@@ -39,20 +77,35 @@ where
     io::Error::new(kind, msg)
 }
 
+#[cfg(feature = "std")]
 impl From<Error> for io::Error {
     fn from(err: Error) -> io::Error {
         let kind = match err {
             Error::ConvInconsistent(..) => ErrorKind::Other,
+            Error::ConvMismatch { .. } => ErrorKind::Other,
             Error::InvalidMtu(..) => ErrorKind::Other,
+            Error::InvalidReserved(..) => ErrorKind::Other,
+            Error::InvalidConfig(..) => ErrorKind::InvalidInput,
             Error::InvalidSegmentSize(..) => ErrorKind::Other,
             Error::InvalidSegmentDataSize(..) => ErrorKind::Other,
             Error::IoError(err) => return err,
             Error::NeedUpdate => ErrorKind::Other,
             Error::RecvQueueEmpty => ErrorKind::WouldBlock,
             Error::ExpectingFragment => ErrorKind::WouldBlock,
+            Error::CorruptFragmentChain => ErrorKind::InvalidData,
             Error::UnsupportedCmd(..) => ErrorKind::Other,
             Error::UserBufTooBig => ErrorKind::Other,
+            Error::SendQueueFull => ErrorKind::WouldBlock,
+            Error::SendShutdown => ErrorKind::BrokenPipe,
+            Error::DecryptFailed => ErrorKind::InvalidData,
+            Error::DecompressFailed => ErrorKind::InvalidData,
             Error::UserBufTooSmall => ErrorKind::Other,
+            Error::NoClock => ErrorKind::Other,
+            Error::NotSyn => ErrorKind::Other,
+            Error::ChecksumMismatch => ErrorKind::InvalidData,
+            Error::SessionMismatch => ErrorKind::InvalidData,
+            Error::InflightExceedsMtu(..) => ErrorKind::Other,
+            Error::UnsupportedFecType(..) => ErrorKind::InvalidData,
         };
 
         make_io_error(kind, err)
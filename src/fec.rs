@@ -0,0 +1,461 @@
+//! Forward error correction under KCP
+//!
+//! Outgoing datagrams are grouped by `data_shards`, and `parity_shards` Reed-Solomon parity
+//! packets are sent after every complete group, and after the rest of a group when `Kcp` is done
+//! flushing. `FecDecoder` recovers up to `parity_shards` lost datagrams of a group before they
+//! are passed to `Kcp::input`.
+
+use alloc::collections::BTreeMap;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::mem;
+
+use bytes::{Buf, BufMut, BytesMut};
+
+use crate::error::Error;
+use crate::io::{self, Write};
+use crate::kcp::Kcp;
+use crate::KcpResult;
+
+/// Bytes added in front of every datagram, leave them in `Kcp::set_mtu`
+pub const FEC_OVERHEAD: usize = 8;
+
+const FEC_HEADER: usize = 6;
+const FEC_TYPE_DATA: u16 = 0xf1;
+const FEC_TYPE_PARITY: u16 = 0xf2;
+/// Parity of a group cut short by a flush, with the number of its data shards in the high byte
+const FEC_TYPE_PARITY_PARTIAL: u16 = 0xf3;
+
+/// Default time to wait for the rest of a group, in millisec
+const FEC_TIMEOUT_DEF: u32 = 1000;
+
+// exp and log tables of GF(2^8), with polynomial 0x11d
+const fn gf_tables() -> ([u8; 512], [u8; 256]) {
+    let mut exp = [0u8; 512];
+    let mut log = [0u8; 256];
+
+    let mut x: u16 = 1;
+    let mut i = 0;
+    while i < 255 {
+        exp[i] = x as u8;
+        exp[i + 255] = x as u8;
+        log[x as usize] = i as u8;
+        x <<= 1;
+        if x & 0x100 != 0 {
+            x ^= 0x11d;
+        }
+        i += 1;
+    }
+
+    (exp, log)
+}
+
+static GF_TABLES: ([u8; 512], [u8; 256]) = gf_tables();
+
+#[inline]
+fn gf_mul(a: u8, b: u8) -> u8 {
+    if a == 0 || b == 0 {
+        return 0;
+    }
+    let (exp, log) = &GF_TABLES;
+    exp[log[a as usize] as usize + log[b as usize] as usize]
+}
+
+#[inline]
+fn gf_inv(a: u8) -> u8 {
+    let (exp, log) = &GF_TABLES;
+    exp[255 - log[a as usize] as usize]
+}
+
+// `dst += c * src`, `dst` is at least as long as `src`
+fn gf_mul_add(dst: &mut [u8], c: u8, src: &[u8]) {
+    if c == 0 {
+        return;
+    }
+    for (d, &s) in dst.iter_mut().zip(src) {
+        *d ^= gf_mul(c, s);
+    }
+}
+
+// Coefficients of shard `index` on the data shards. Data shards are sent as they are, parity
+// shards use a Cauchy matrix, so that any `data_shards` of them can recover the others.
+fn coding_row(index: usize, data_shards: usize) -> Vec<u8> {
+    if index < data_shards {
+        let mut row = vec![0u8; data_shards];
+        row[index] = 1;
+        row
+    } else {
+        (0..data_shards)
+            .map(|j| gf_inv(index as u8 ^ j as u8))
+            .collect()
+    }
+}
+
+// Invert a square matrix with Gauss-Jordan elimination, `None` if it is singular
+fn invert(mut m: Vec<Vec<u8>>) -> Option<Vec<Vec<u8>>> {
+    let n = m.len();
+    let mut inv: Vec<Vec<u8>> = (0..n).map(|i| coding_row(i, n)).collect();
+
+    for col in 0..n {
+        let pivot = (col..n).find(|&r| m[r][col] != 0)?;
+        m.swap(col, pivot);
+        inv.swap(col, pivot);
+
+        let c = gf_inv(m[col][col]);
+        for j in 0..n {
+            m[col][j] = gf_mul(m[col][j], c);
+            inv[col][j] = gf_mul(inv[col][j], c);
+        }
+
+        for r in 0..n {
+            let f = m[r][col];
+            if r == col || f == 0 {
+                continue;
+            }
+            for j in 0..n {
+                m[r][j] ^= gf_mul(f, m[col][j]);
+                inv[r][j] ^= gf_mul(f, inv[col][j]);
+            }
+        }
+    }
+
+    Some(inv)
+}
+
+// Sequence numbers wrap at a multiple of the group size, so groups never straddle the wrap
+#[inline]
+fn seqid_wrap(shards: usize) -> u32 {
+    (u32::MAX / shards as u32) * shards as u32
+}
+
+/// Output adding FEC, groups every `data_shards` datagrams and sends `parity_shards` parity
+/// packets after them
+///
+/// `flush`, called by `Kcp` after the datagrams of each of its flushes, ends the current group
+/// early and sends its parity, so the tail of a burst is protected as well. The decoder drops
+/// groups it can't complete after a timeout.
+#[derive(Debug)]
+pub struct FecEncoder<W> {
+    output: W,
+    data_shards: usize,
+    parity_shards: usize,
+    /// Coefficients of the parity shards
+    matrix: Vec<Vec<u8>>,
+    /// Sequence number of the next packet
+    next: u32,
+    /// Data shards of the current group
+    shards: Vec<BytesMut>,
+}
+
+impl<W> FecEncoder<W> {
+    /// Creates an encoder writing into `output`
+    ///
+    /// Panics if `data_shards` is 0 or there are more than 255 shards in a group.
+    pub fn new(output: W, data_shards: usize, parity_shards: usize) -> FecEncoder<W> {
+        assert!(data_shards > 0, "data_shards must be positive");
+        assert!(
+            data_shards + parity_shards <= 255,
+            "at most 255 shards in a group"
+        );
+
+        FecEncoder {
+            output,
+            data_shards,
+            parity_shards,
+            matrix: (data_shards..data_shards + parity_shards)
+                .map(|i| coding_row(i, data_shards))
+                .collect(),
+            next: 0,
+            shards: Vec::with_capacity(data_shards),
+        }
+    }
+
+    /// Get the underlying output
+    #[inline]
+    pub fn get_ref(&self) -> &W {
+        &self.output
+    }
+
+    /// Get the underlying output for configuration
+    #[inline]
+    pub fn get_mut(&mut self) -> &mut W {
+        &mut self.output
+    }
+
+    #[inline]
+    fn advance(&mut self) {
+        self.next = (self.next + 1) % seqid_wrap(self.data_shards + self.parity_shards);
+    }
+}
+
+impl<W: Write> FecEncoder<W> {
+    fn emit(&mut self, kind: u16, shard: &[u8]) -> io::Result<()> {
+        let mut packet = BytesMut::with_capacity(FEC_HEADER + shard.len());
+        packet.put_u32_le(self.next);
+        packet.put_u16_le(kind);
+        packet.extend_from_slice(shard);
+        self.output.write_all(&packet)
+    }
+
+    // Send the parity shards of the current group, the data shards missing from a partial one
+    // are skipped and taken as empty
+    fn emit_parity(&mut self) -> io::Result<()> {
+        let shards = mem::take(&mut self.shards);
+        let size = shards.iter().map(|s| s.len()).max().unwrap_or(0);
+
+        let kind = if shards.len() < self.data_shards {
+            for _ in shards.len()..self.data_shards {
+                self.advance();
+            }
+            FEC_TYPE_PARITY_PARTIAL | (shards.len() as u16) << 8
+        } else {
+            FEC_TYPE_PARITY
+        };
+
+        for i in 0..self.parity_shards {
+            let mut parity = vec![0u8; size];
+            for (j, shard) in shards.iter().enumerate() {
+                gf_mul_add(&mut parity, self.matrix[i][j], shard);
+            }
+
+            let result = self.emit(kind, &parity);
+            self.advance();
+            result?;
+        }
+
+        Ok(())
+    }
+}
+
+impl<W: Write> Write for FecEncoder<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if buf.len() > u16::MAX as usize {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "datagram is too large for FEC",
+            ));
+        }
+
+        let mut shard = BytesMut::with_capacity(2 + buf.len());
+        shard.put_u16_le(buf.len() as u16);
+        shard.extend_from_slice(buf);
+
+        // Not counted in the group if it is not sent, it will be written again
+        self.emit(FEC_TYPE_DATA, &shard)?;
+        self.advance();
+
+        // The datagram is sent already, a lost parity only weakens the recovery
+        self.shards.push(shard);
+        if self.shards.len() == self.data_shards {
+            if let Err(err) = self.emit_parity() {
+                debug!("fec parity not sent: {}", err);
+            }
+        }
+
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        if !self.shards.is_empty() {
+            if let Err(err) = self.emit_parity() {
+                debug!("fec parity not sent: {}", err);
+            }
+        }
+        self.output.flush()
+    }
+}
+
+#[derive(Debug)]
+struct FecGroup {
+    /// Time when the first shard is received
+    ts: u32,
+    shards: Vec<Option<BytesMut>>,
+    received: usize,
+    /// All data shards are delivered, later shards are ignored
+    done: bool,
+}
+
+/// Recovers lost datagrams sent through `FecEncoder`
+#[derive(Debug)]
+pub struct FecDecoder {
+    data_shards: usize,
+    parity_shards: usize,
+    timeout: u32,
+    groups: BTreeMap<u32, FecGroup>,
+}
+
+impl FecDecoder {
+    /// Creates a decoder, `data_shards` and `parity_shards` must be the same as the encoder's
+    ///
+    /// Panics if `data_shards` is 0 or there are more than 255 shards in a group.
+    pub fn new(data_shards: usize, parity_shards: usize) -> FecDecoder {
+        assert!(data_shards > 0, "data_shards must be positive");
+        assert!(
+            data_shards + parity_shards <= 255,
+            "at most 255 shards in a group"
+        );
+
+        FecDecoder {
+            data_shards,
+            parity_shards,
+            timeout: FEC_TIMEOUT_DEF,
+            groups: BTreeMap::new(),
+        }
+    }
+
+    /// Set time in millisec to wait for the rest of a group before dropping it, 1000 by default
+    #[inline]
+    pub fn set_timeout(&mut self, timeout: u32) {
+        self.timeout = timeout;
+    }
+
+    /// Get time in millisec to wait for the rest of a group
+    #[inline]
+    pub fn timeout(&self) -> u32 {
+        self.timeout
+    }
+
+    /// Groups waiting for more shards or the timeout
+    #[inline]
+    pub fn pending_groups(&self) -> usize {
+        self.groups.len()
+    }
+
+    /// Decode a packet received at `current` (millisec), returns datagrams for `Kcp::input`
+    ///
+    /// A data packet is returned immediately, together with the datagrams it helps to recover.
+    pub fn decode(&mut self, packet: &[u8], current: u32) -> KcpResult<Vec<BytesMut>> {
+        let timeout = self.timeout;
+        self.groups
+            .retain(|_, group| current.wrapping_sub(group.ts) < timeout);
+
+        if packet.len() < FEC_HEADER {
+            debug!("fec packet size={} too small", packet.len());
+            return Err(Error::InvalidSegmentSize(packet.len()));
+        }
+
+        let mut buf = packet;
+        let seqid = buf.get_u32_le();
+        let kind = buf.get_u16_le();
+        let shard = buf;
+
+        let mut datagrams = Vec::new();
+        let mut data_count = self.data_shards;
+        match kind {
+            FEC_TYPE_DATA => {
+                let size = shard_size(shard).ok_or(Error::InvalidSegmentSize(packet.len()))?;
+                datagrams.push(BytesMut::from(&shard[2..2 + size]));
+            }
+            FEC_TYPE_PARITY => {}
+            _ if kind & 0xff == FEC_TYPE_PARITY_PARTIAL => {
+                data_count = (kind >> 8) as usize;
+                if data_count == 0 || data_count >= self.data_shards {
+                    debug!("fec partial group of {} data shards", data_count);
+                    return Err(Error::InvalidSegmentDataSize(self.data_shards, data_count));
+                }
+            }
+            _ => {
+                debug!("fec packet type={:#x} unrecognized", kind);
+                return Err(Error::UnsupportedFecType(kind));
+            }
+        }
+
+        let shards = self.data_shards + self.parity_shards;
+        let index = (seqid % shards as u32) as usize;
+        let group = self.groups.entry(seqid - index as u32).or_insert(FecGroup {
+            ts: current,
+            shards: vec![None; shards],
+            received: 0,
+            done: false,
+        });
+
+        if group.done || group.shards[index].is_some() {
+            return Ok(datagrams);
+        }
+        group.shards[index] = Some(BytesMut::from(shard));
+        group.received += 1;
+
+        // The data shards a partial group never had are empty
+        for skipped in group.shards[data_count..self.data_shards].iter_mut() {
+            if skipped.is_none() {
+                *skipped = Some(BytesMut::new());
+                group.received += 1;
+            }
+        }
+
+        let data_shards = self.data_shards;
+        if group.shards[..data_shards].iter().all(Option::is_some) {
+            group.done = true;
+        } else if group.received >= data_shards {
+            group.done = true;
+            datagrams.extend(recover(&group.shards, data_shards));
+        }
+
+        if group.done {
+            group.shards.clear();
+        }
+
+        Ok(datagrams)
+    }
+}
+
+// Length of the datagram in a data shard, `None` if the shard is malformed
+fn shard_size(shard: &[u8]) -> Option<usize> {
+    if shard.len() < 2 {
+        return None;
+    }
+    let size = (&shard[..2]).get_u16_le() as usize;
+    (2 + size <= shard.len()).then_some(size)
+}
+
+// Rebuild the missing data shards from any `data_shards` received, returns their datagrams
+fn recover(shards: &[Option<BytesMut>], data_shards: usize) -> Vec<BytesMut> {
+    let received: Vec<(usize, &BytesMut)> = shards
+        .iter()
+        .enumerate()
+        .filter_map(|(i, s)| s.as_ref().map(|s| (i, s)))
+        .take(data_shards)
+        .collect();
+
+    let matrix = received
+        .iter()
+        .map(|&(i, _)| coding_row(i, data_shards))
+        .collect();
+    let inv = match invert(matrix) {
+        Some(inv) => inv,
+        None => return Vec::new(),
+    };
+
+    let size = received.iter().map(|(_, s)| s.len()).max().unwrap_or(0);
+
+    let mut datagrams = Vec::new();
+    for (j, _) in shards[..data_shards]
+        .iter()
+        .enumerate()
+        .filter(|(_, s)| s.is_none())
+    {
+        let mut shard = vec![0u8; size];
+        for (k, (_, s)) in received.iter().enumerate() {
+            gf_mul_add(&mut shard, inv[j][k], s);
+        }
+
+        match shard_size(&shard) {
+            Some(len) => datagrams.push(BytesMut::from(&shard[2..2 + len])),
+            None => debug!("fec recovered shard is malformed"),
+        }
+    }
+
+    datagrams
+}
+
+impl<Output: Write> Kcp<FecEncoder<Output>> {
+    /// Creates a KCP control object sending through `FecEncoder`, see `FecDecoder` for the remote
+    ///
+    /// The MTU is reduced by `FEC_OVERHEAD`, leave it as well if you change it with `set_mtu`.
+    pub fn with_fec(conv: u32, output: Output, data_shards: usize, parity_shards: usize) -> Self {
+        let mut kcp = Kcp::new(conv, FecEncoder::new(output, data_shards, parity_shards));
+        let mtu = kcp.mtu() - FEC_OVERHEAD;
+        kcp.set_mtu(mtu).expect("default mtu is valid");
+        kcp
+    }
+}
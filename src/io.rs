@@ -0,0 +1,113 @@
+//! Output of `Kcp`
+//!
+//! `std::io` with the `std` feature, otherwise a minimal replacement of its `Write` for `no_std`.
+
+#[cfg(feature = "std")]
+pub use std::io::{Error, ErrorKind, Result, Write};
+
+#[cfg(not(feature = "std"))]
+pub use self::core_io::{Error, ErrorKind, Result, Write};
+
+#[cfg(not(feature = "std"))]
+mod core_io {
+    use alloc::boxed::Box;
+    use core::fmt;
+
+    /// Kind of an output error
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub enum ErrorKind {
+        /// Invalid data to write
+        InvalidInput,
+        /// Nothing could be written
+        WriteZero,
+        /// Not able to write now, try again later
+        WouldBlock,
+        /// Anything else
+        Other,
+    }
+
+    /// Error of an output
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct Error {
+        kind: ErrorKind,
+        msg: &'static str,
+    }
+
+    impl Error {
+        /// Creates an error with a description
+        pub fn new(kind: ErrorKind, msg: &'static str) -> Error {
+            Error { kind, msg }
+        }
+
+        /// Creates an error of `ErrorKind::Other`
+        pub fn other(msg: &'static str) -> Error {
+            Error::new(ErrorKind::Other, msg)
+        }
+
+        /// Get the kind of the error
+        #[inline]
+        pub fn kind(&self) -> ErrorKind {
+            self.kind
+        }
+    }
+
+    impl fmt::Display for Error {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            f.write_str(self.msg)
+        }
+    }
+
+    impl core::error::Error for Error {}
+
+    /// Result of an output
+    pub type Result<T> = core::result::Result<T, Error>;
+
+    /// Where `Kcp` writes datagrams into, `std::io::Write` without `std`
+    pub trait Write {
+        /// Write a datagram, returns how many bytes are written
+        fn write(&mut self, buf: &[u8]) -> Result<usize>;
+
+        /// Flush anything buffered
+        fn flush(&mut self) -> Result<()>;
+
+        /// Write the whole `buf`
+        fn write_all(&mut self, mut buf: &[u8]) -> Result<()> {
+            while !buf.is_empty() {
+                match self.write(buf)? {
+                    0 => {
+                        return Err(Error::new(
+                            ErrorKind::WriteZero,
+                            "failed to write whole buffer",
+                        ))
+                    }
+                    n => buf = &buf[n..],
+                }
+            }
+            Ok(())
+        }
+    }
+
+    impl<W: Write + ?Sized> Write for &mut W {
+        #[inline]
+        fn write(&mut self, buf: &[u8]) -> Result<usize> {
+            (**self).write(buf)
+        }
+
+        #[inline]
+        fn flush(&mut self) -> Result<()> {
+            (**self).flush()
+        }
+    }
+
+    impl<W: Write + ?Sized> Write for Box<W> {
+        #[inline]
+        fn write(&mut self, buf: &[u8]) -> Result<usize> {
+            (**self).write(buf)
+        }
+
+        #[inline]
+        fn flush(&mut self) -> Result<()> {
+            (**self).flush()
+        }
+    }
+}
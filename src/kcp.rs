@@ -1,69 +1,471 @@
 //! KCP
 
-use std::cmp;
-use std::cmp::Ordering;
-use std::collections::VecDeque;
-use std::fmt::{self, Debug};
-use std::io::{self, Cursor, Read, Write};
+use alloc::boxed::Box;
+use alloc::collections::VecDeque;
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::cmp;
+use core::cmp::Ordering;
+use core::fmt::{self, Debug};
+use core::mem;
+use core::ops::Range;
 #[cfg(feature = "tokio")]
-use std::pin::Pin;
+use core::pin::Pin;
+use core::sync::atomic::{self, AtomicU32};
 #[cfg(feature = "tokio")]
-use std::task::{Context, Poll};
+use core::task::{Context, Poll};
 
-use bytes::{Buf, BufMut, BytesMut};
+use bytes::{Buf, BufMut, Bytes, BytesMut};
 #[cfg(feature = "tokio")]
 use tokio::io::{AsyncWrite, AsyncWriteExt};
 
 use crate::error::Error;
+use crate::io::{self, Write};
 use crate::KcpResult;
 
 const KCP_RTO_NDL: u32 = 30; // no delay min rto
-const KCP_RTO_MIN: u32 = 100; // normal min rto
-const KCP_RTO_DEF: u32 = 200;
-const KCP_RTO_MAX: u32 = 60000;
+/// Default minimum RTO in millisec, see `Kcp::set_rx_minrto`
+pub const KCP_RTO_MIN: u32 = 100;
+/// Default RTO in millisec until RTT is measured, see `Kcp::set_initial_rto`
+pub const KCP_RTO_DEF: u32 = 200;
+/// Maximum RTO in millisec
+pub const KCP_RTO_MAX: u32 = 60000;
+const KCP_RTO_RFC: u32 = 1000; // RFC 6298 initial and min rto
 
 const KCP_CMD_PUSH: u8 = 81; // cmd: push data
 const KCP_CMD_ACK: u8 = 82; // cmd: ack
 const KCP_CMD_WASK: u8 = 83; // cmd: window probe (ask)
 const KCP_CMD_WINS: u8 = 84; // cmd: window size (tell)
+#[cfg(feature = "sack")]
+const KCP_CMD_SACK: u8 = 85; // cmd: ranges of acknowledged sn
+#[cfg(feature = "fin")]
+const KCP_CMD_FIN: u8 = 86; // cmd: end of data
+#[cfg(feature = "wscale")]
+const KCP_CMD_WSCALE: u8 = 87; // cmd: window scale negotiation
+#[cfg(feature = "partial")]
+const KCP_CMD_FORWARD: u8 = 88; // cmd: skip to sn, forgotten segments before it
+#[cfg(feature = "handshake")]
+const KCP_CMD_SYN: u8 = 89; // cmd: connection request
+#[cfg(feature = "handshake")]
+const KCP_CMD_SYNACK: u8 = 90; // cmd: connection accepted
 
 const KCP_ASK_SEND: u32 = 1; // need to send IKCP_CMD_WASK
 const KCP_ASK_TELL: u32 = 2; // need to send IKCP_CMD_WINS
 
-const KCP_WND_SND: u16 = 32;
-const KCP_WND_RCV: u16 = 128; // must >= max fragment size
-
-const KCP_MTU_DEF: usize = 1400;
+/// Default send window
+pub const KCP_WND_SND: u16 = 32;
+/// Default receive window, also the minimum one, must >= max fragment size
+pub const KCP_WND_RCV: u16 = 128;
+const KCP_FRG_MAX: usize = 256; // frg is u8
+#[cfg(feature = "wscale")]
+const KCP_WSCALE_MAX: u8 = 14;
+#[cfg(feature = "wscale")]
+const KCP_WSCALE_SCALED: u32 = 1; // wscale: the wnd of the sender is scaled from this datagram on
+#[cfg(feature = "wscale")]
+const KCP_WSCALE_DECODED: u32 = 2; // wscale: the sender scales the wnd of the receiver
+#[cfg(feature = "wscale")]
+const KCP_WSCALE_DONE: u32 = 4; // wscale: both ways are settled for the sender
+
+/// Default MTU, see `Kcp::set_mtu`
+pub const KCP_MTU_DEF: usize = 1400;
 // const KCP_ACK_FAST: u32 = 3;
 
-const KCP_INTERVAL: u32 = 100;
+/// Default flush interval in millisec, see `Kcp::set_interval`
+pub const KCP_INTERVAL: u32 = 100;
+const KCP_IDLE_INTERVAL: u32 = 1000; // max flush interval when idle
+const KCP_RATE_WINDOW: u32 = 1000; // window of `Kcp::delivery_rate`, one second
+const KCP_FLUSH_CLAMP: u32 = 10000; // clock jumps resetting the flush timer
 /// KCP Header size
 pub const KCP_OVERHEAD: usize = 24;
+/// KCP Header size without `conv`, see `Kcp::new_compact`
+#[cfg(feature = "compact")]
+pub const KCP_COMPACT_OVERHEAD: usize = KCP_OVERHEAD - 4;
+const KCP_CHECKSUM_LEN: usize = 4; // CRC32 after the payload, see `Kcp::set_checksum`
+const KCP_TOKEN_LEN: usize = 4; // session token after the payload, see `Kcp::set_session_token`
 const KCP_DEADLINK: u32 = 20;
 
-const KCP_THRESH_INIT: u16 = 2;
-const KCP_THRESH_MIN: u16 = 2;
+const KCP_THRESH_INIT: u32 = 2;
+const KCP_THRESH_MIN: u32 = 2;
 
 const KCP_PROBE_INIT: u32 = 7000; // 7 secs to probe window size
 const KCP_PROBE_LIMIT: u32 = 120000; // up to 120 secs to probe window
 const KCP_FASTACK_LIMIT: u32 = 5; // max times to trigger fastack
+const KCP_SN_WRAP_MARGIN: u32 = 1 << 20; // sequence numbers left before wrap
+
+/// Header of a segment, decoded by `parse_header`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SegmentHeader {
+    /// Conversation id
+    pub conv: u32,
+    /// Command, PUSH, ACK, WASK or WINS
+    pub cmd: u8,
+    /// Fragments left in the message after this one
+    pub frg: u8,
+    /// Free receive window of the sender
+    pub wnd: u16,
+    /// Timestamp
+    pub ts: u32,
+    /// Sequence number
+    pub sn: u32,
+    /// Next sequence number the sender expects to receive
+    pub una: u32,
+    /// Payload length
+    pub len: u32,
+}
+
+const CRC32_TABLE: [u32; 256] = crc32_table();
+
+// Table of the IEEE CRC32, reflected polynomial 0xEDB88320
+const fn crc32_table() -> [u32; 256] {
+    let mut table = [0u32; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xEDB8_8320
+            } else {
+                crc >> 1
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+}
+
+// IEEE CRC32 of `buf`, as zlib
+fn crc32(buf: &[u8]) -> u32 {
+    !buf.iter().fold(!0u32, |crc, &b| {
+        CRC32_TABLE[((crc ^ b as u32) & 0xff) as usize] ^ (crc >> 8)
+    })
+}
+
+// Read a header field in the byte order of the wire format
+#[inline]
+fn get_u16<B: Buf>(buf: &mut B, big_endian: bool) -> u16 {
+    if big_endian {
+        buf.get_u16()
+    } else {
+        buf.get_u16_le()
+    }
+}
+
+#[inline]
+fn get_u32<B: Buf>(buf: &mut B, big_endian: bool) -> u32 {
+    if big_endian {
+        buf.get_u32()
+    } else {
+        buf.get_u32_le()
+    }
+}
+
+// Write a header field in the byte order of the wire format
+#[inline]
+fn put_u16<B: BufMut>(buf: &mut B, n: u16, big_endian: bool) {
+    if big_endian {
+        buf.put_u16(n)
+    } else {
+        buf.put_u16_le(n)
+    }
+}
+
+#[inline]
+fn put_u32<B: BufMut>(buf: &mut B, n: u32, big_endian: bool) {
+    if big_endian {
+        buf.put_u32(n)
+    } else {
+        buf.put_u32_le(n)
+    }
+}
+
+// Check the CRC32 after the segment at the start of `buf`, and after `token_len` bytes of the
+// session token if any, false if it is truncated
+fn checksum_ok(buf: &[u8], header_len: usize, token_len: usize, big_endian: bool) -> bool {
+    if buf.len() < header_len {
+        return false;
+    }
+
+    let len = get_u32(&mut &buf[header_len - 4..], big_endian) as usize;
+    let end = header_len.saturating_add(len).saturating_add(token_len);
+    if buf.len() < end.saturating_add(KCP_CHECKSUM_LEN) {
+        return false;
+    }
+    crc32(&buf[..end]) == (&buf[end..]).get_u32_le()
+}
+
+// A window of the public API, which is `u16` unless scaled by `wscale`
+#[inline]
+fn wnd_u16(wnd: u32) -> u16 {
+    cmp::min(wnd, u16::MAX as u32) as u16
+}
+
+// Check `buf` holds a whole segment header
+#[inline]
+fn check_header(buf: &[u8]) -> KcpResult<()> {
+    if buf.len() < KCP_OVERHEAD {
+        return Err(Error::InvalidSegmentSize(buf.len()));
+    }
+    Ok(())
+}
+
+/// Read `conv` from raw buffer
+///
+/// Panics if `buf` is shorter than a segment header.
+#[deprecated(note = "panics on a short buffer, use `try_get_conv` instead")]
+pub fn get_conv(buf: &[u8]) -> u32 {
+    try_get_conv(buf).expect("buffer shorter than a segment header")
+}
 
 /// Read `conv` from raw buffer
-pub fn get_conv(mut buf: &[u8]) -> u32 {
-    assert!(buf.len() >= KCP_OVERHEAD as usize);
-    buf.get_u32_le()
+///
+/// It doesn't panic on a malformed datagram. A server sharing a socket among conversations
+/// routes each datagram to the `Kcp` of its `conv` with it before `input`.
+pub fn try_get_conv(mut buf: &[u8]) -> KcpResult<u32> {
+    check_header(buf)?;
+    Ok(buf.get_u32_le())
+}
+
+/// Check if raw buffer starts with a connection request of `Kcp::connect`
+///
+/// A server creates a `Kcp` for an unknown `conv` only for such a datagram, and passes it to
+/// `Kcp::accept`, any other one is a stray packet. Compact headers of `Kcp::new_compact` have no
+/// `conv` to route by, pass them to `accept` of the `Kcp` they are meant for.
+#[cfg(feature = "handshake")]
+pub fn is_syn(buf: &[u8]) -> bool {
+    get_cmd(buf).is_ok_and(|cmd| cmd == KCP_CMD_SYN)
+}
+
+/// An unpredictable initial sequence number for `Kcp::set_isn`
+///
+/// Taken from the randomly seeded hasher of `std` and the clock. It is not meant to be a
+/// cryptographic random number, use a CSPRNG for that.
+#[cfg(all(feature = "isn", feature = "std"))]
+pub fn random_isn() -> u32 {
+    use std::collections::hash_map::RandomState;
+    use std::hash::{BuildHasher, Hasher};
+    use std::time::SystemTime;
+
+    let mut hasher = RandomState::new().build_hasher();
+    if let Ok(elapsed) = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH) {
+        hasher.write_u128(elapsed.as_nanos());
+    }
+    hasher.finish() as u32
+}
+
+/// Set `conv` to raw buffer
+///
+/// Panics if `buf` is shorter than a segment header.
+#[deprecated(note = "panics on a short buffer, use `try_set_conv` instead")]
+pub fn set_conv(buf: &mut [u8], conv: u32) {
+    try_set_conv(buf, conv).expect("buffer shorter than a segment header")
 }
 
 /// Set `conv` to raw buffer
-pub fn set_conv(mut buf: &mut [u8], conv: u32) {
-    assert!(buf.len() >= KCP_OVERHEAD as usize);
-    buf.put_u32_le(conv)
+pub fn try_set_conv(mut buf: &mut [u8], conv: u32) -> KcpResult<()> {
+    check_header(buf)?;
+    buf.put_u32_le(conv);
+    Ok(())
+}
+
+/// Get `cmd` from raw buffer
+pub fn get_cmd(buf: &[u8]) -> KcpResult<u8> {
+    check_header(buf)?;
+    Ok(buf[4])
+}
+
+/// Get `frg` from raw buffer
+pub fn get_frg(buf: &[u8]) -> KcpResult<u8> {
+    check_header(buf)?;
+    Ok(buf[5])
+}
+
+/// Get `wnd` from raw buffer
+pub fn get_wnd(buf: &[u8]) -> KcpResult<u16> {
+    check_header(buf)?;
+    Ok((&buf[6..]).get_u16_le())
+}
+
+/// Get `ts` from raw buffer
+pub fn get_ts(buf: &[u8]) -> KcpResult<u32> {
+    check_header(buf)?;
+    Ok((&buf[8..]).get_u32_le())
 }
 
 /// Get `sn` from raw buffer
+///
+/// Panics if `buf` is shorter than a segment header.
+#[deprecated(note = "panics on a short buffer, use `try_get_sn` instead")]
 pub fn get_sn(buf: &[u8]) -> u32 {
-    assert!(buf.len() >= KCP_OVERHEAD as usize);
-    (&buf[12..]).get_u32_le()
+    try_get_sn(buf).expect("buffer shorter than a segment header")
+}
+
+/// Get `sn` from raw buffer
+pub fn try_get_sn(buf: &[u8]) -> KcpResult<u32> {
+    check_header(buf)?;
+    Ok((&buf[12..]).get_u32_le())
+}
+
+/// Get `una` from raw buffer
+pub fn get_una(buf: &[u8]) -> KcpResult<u32> {
+    check_header(buf)?;
+    Ok((&buf[16..]).get_u32_le())
+}
+
+/// Decode the header of the first segment in raw buffer, without checking the payload
+pub fn parse_header(buf: &[u8]) -> KcpResult<SegmentHeader> {
+    decode_header(buf, false)
+}
+
+/// Byte order of the multi-byte header fields, see `Kcp::set_endianness`
+#[cfg(feature = "endian")]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Endian {
+    /// Little-endian, as the reference C implementation
+    #[default]
+    Little,
+    /// Big-endian, network byte order
+    Big,
+}
+
+#[cfg(feature = "endian")]
+impl Endian {
+    #[inline]
+    fn is_big(self) -> bool {
+        self == Endian::Big
+    }
+}
+
+/// Read `conv` from raw buffer in the byte order `endian`, see `try_get_conv`
+#[cfg(feature = "endian")]
+pub fn get_conv_with(mut buf: &[u8], endian: Endian) -> KcpResult<u32> {
+    check_header(buf)?;
+    Ok(get_u32(&mut buf, endian.is_big()))
+}
+
+/// Set `conv` to raw buffer in the byte order `endian`, see `try_set_conv`
+#[cfg(feature = "endian")]
+pub fn try_set_conv_with(mut buf: &mut [u8], conv: u32, endian: Endian) -> KcpResult<()> {
+    check_header(buf)?;
+    put_u32(&mut buf, conv, endian.is_big());
+    Ok(())
+}
+
+/// Get `sn` from raw buffer in the byte order `endian`, see `try_get_sn`
+#[cfg(feature = "endian")]
+pub fn get_sn_with(buf: &[u8], endian: Endian) -> KcpResult<u32> {
+    check_header(buf)?;
+    Ok(get_u32(&mut &buf[12..], endian.is_big()))
+}
+
+/// Decode the header of the first segment in raw buffer in the byte order `endian`, see
+/// `parse_header`
+#[cfg(feature = "endian")]
+pub fn parse_header_with(buf: &[u8], endian: Endian) -> KcpResult<SegmentHeader> {
+    decode_header(buf, endian.is_big())
+}
+
+fn decode_header(mut buf: &[u8], big_endian: bool) -> KcpResult<SegmentHeader> {
+    check_header(buf)?;
+    Ok(SegmentHeader {
+        conv: get_u32(&mut buf, big_endian),
+        cmd: buf.get_u8(),
+        frg: buf.get_u8(),
+        wnd: get_u16(&mut buf, big_endian),
+        ts: get_u32(&mut buf, big_endian),
+        sn: get_u32(&mut buf, big_endian),
+        una: get_u32(&mut buf, big_endian),
+        len: get_u32(&mut buf, big_endian),
+    })
+}
+
+/// Split raw buffer into the segments of each `conv`, for routing them to their `Kcp`
+///
+/// Yields `conv` and the bytes of consecutive segments of it, each of which could be passed to
+/// the `input` of that `Kcp` as is. A `Kcp` never mixes convs in a datagram, but one batching
+/// segments of several conversations is supported. Every header and payload length is checked,
+/// a malformed segment yields an error and ends the iteration, the segments before it are still
+/// yielded.
+///
+/// Only the standard little-endian header without a trailer is supported, datagrams of a `Kcp`
+/// with any of these couldn't be split:
+///
+/// - `Kcp::set_crypt`, route them with `try_get_conv`
+/// - `Kcp::new_compact`, the header has no `conv`
+/// - `Kcp::set_endianness` with big-endian, route them with `get_conv_with`
+/// - `Kcp::set_checksum` and `Kcp::set_session_token`, the trailer after each payload isn't
+///   counted in the segment length
+pub fn demux(buf: &[u8]) -> Demux<'_> {
+    Demux { buf }
+}
+
+/// Iterator of `demux`
+#[derive(Debug, Clone)]
+pub struct Demux<'a> {
+    buf: &'a [u8],
+}
+
+impl<'a> Iterator for Demux<'a> {
+    type Item = KcpResult<(u32, &'a [u8])>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.buf.is_empty() {
+            return None;
+        }
+
+        let conv = match try_get_conv(self.buf) {
+            Ok(conv) => conv,
+            Err(err) => {
+                self.buf = &[];
+                return Some(Err(err));
+            }
+        };
+
+        let mut end = 0;
+        while end < self.buf.len() {
+            let header = match parse_header(&self.buf[end..]) {
+                Ok(header) => header,
+                Err(err) if end == 0 => {
+                    self.buf = &[];
+                    return Some(Err(err));
+                }
+                Err(..) => break,
+            };
+            if header.conv != conv {
+                break;
+            }
+
+            let rest = self.buf.len() - end - KCP_OVERHEAD;
+            if rest < header.len as usize {
+                if end == 0 {
+                    self.buf = &[];
+                    return Some(Err(Error::InvalidSegmentDataSize(
+                        header.len as usize,
+                        rest,
+                    )));
+                }
+                break;
+            }
+            end += KCP_OVERHEAD + header.len as usize;
+        }
+
+        let (segments, rest) = self.buf.split_at(end);
+        self.buf = rest;
+        Some(Ok((conv, segments)))
+    }
+}
+
+// PUSH, or FIN sent reliably as one without data
+#[inline]
+fn is_push(cmd: u8) -> bool {
+    #[cfg(feature = "fin")]
+    if cmd == KCP_CMD_FIN {
+        return true;
+    }
+    cmd == KCP_CMD_PUSH
 }
 
 #[inline]
@@ -71,12 +473,96 @@ fn bound(lower: u32, v: u32, upper: u32) -> u32 {
     cmp::min(cmp::max(lower, v), upper)
 }
 
+// Difference of two timestamps, wrapping around as `serial_diff`
 #[inline]
 fn timediff(later: u32, earlier: u32) -> i32 {
-    later as i32 - earlier as i32
+    later.wrapping_sub(earlier) as i32
+}
+
+/// Difference `a - b` of two sequence numbers in serial number arithmetic (RFC 1982)
+///
+/// Positive if `a` comes after `b`, taking the wraparound of `u32` into account. It is correct
+/// as long as they are less than 2^31 apart.
+#[inline]
+pub fn serial_diff(a: u32, b: u32) -> i32 {
+    a.wrapping_sub(b) as i32
+}
+
+/// Check if the sequence number `a` comes before `b`, see `serial_diff`
+#[inline]
+pub fn serial_lt(a: u32, b: u32) -> bool {
+    serial_diff(a, b) < 0
+}
+
+/// Check if the sequence number `a` comes before `b` or equals it, see `serial_diff`
+#[inline]
+pub fn serial_le(a: u32, b: u32) -> bool {
+    serial_diff(a, b) <= 0
+}
+
+// Data split into segments by `Kcp::enqueue`
+trait SendBuf {
+    fn len(&self) -> usize;
+    #[inline]
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+    // Take the first `n` bytes as the payload of a new segment
+    fn take(&mut self, n: usize) -> BytesMut;
+    // Append the first `n` bytes to the payload of a queued segment
+    fn append_to(&mut self, n: usize, dst: &mut BytesMut);
+    // The bytes not taken yet
+    fn as_slice(&self) -> &[u8];
+}
+
+impl SendBuf for &[u8] {
+    #[inline]
+    fn len(&self) -> usize {
+        <[u8]>::len(self)
+    }
+
+    fn take(&mut self, n: usize) -> BytesMut {
+        let (lf, rt) = self.split_at(n);
+        *self = rt;
+        lf.into()
+    }
+
+    fn append_to(&mut self, n: usize, dst: &mut BytesMut) {
+        let (lf, rt) = self.split_at(n);
+        dst.extend_from_slice(lf);
+        *self = rt;
+    }
+
+    #[inline]
+    fn as_slice(&self) -> &[u8] {
+        self
+    }
+}
+
+impl SendBuf for BytesMut {
+    #[inline]
+    fn len(&self) -> usize {
+        BytesMut::len(self)
+    }
+
+    #[inline]
+    fn take(&mut self, n: usize) -> BytesMut {
+        self.split_to(n)
+    }
+
+    fn append_to(&mut self, n: usize, dst: &mut BytesMut) {
+        dst.extend_from_slice(&self[..n]);
+        self.advance(n);
+    }
+
+    #[inline]
+    fn as_slice(&self) -> &[u8] {
+        self
+    }
 }
 
 #[derive(Default, Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 struct KcpSegment {
     conv: u32,
     cmd: u8,
@@ -89,6 +575,11 @@ struct KcpSegment {
     rto: u32,
     fastack: u32,
     xmit: u32,
+    /// Dropped if not sent before this time
+    deadline: Option<u32>,
+    /// Known to be lost by `Kcp::retransmit`, resent as a fast resend
+    #[cfg_attr(feature = "serde", serde(default))]
+    nack: bool,
     data: BytesMut,
 }
 
@@ -106,1441 +597,6697 @@ impl KcpSegment {
             rto: 0,
             fastack: 0,
             xmit: 0,
+            deadline: None,
+            nack: false,
             data,
         }
     }
 
-    fn encode(&self, buf: &mut BytesMut) {
-        if buf.remaining_mut() < self.encoded_len() {
-            panic!(
-                "REMAIN {} encoded {} {:?}",
-                buf.remaining_mut(),
-                self.encoded_len(),
-                self
-            );
+    // Encode into `buf`, without `conv` if `compact`, followed by the session `token` if any,
+    // then its CRC32 if `checksum`. Header fields are big-endian if `big_endian`, the trailers are
+    // always little-endian.
+    fn encode(
+        &self,
+        buf: &mut BytesMut,
+        compact: bool,
+        checksum: bool,
+        big_endian: bool,
+        token: Option<u32>,
+    ) {
+        // Grown for a segment larger than the MTU, resent after `set_mtu` lowered it
+        buf.reserve(self.encoded_len(compact, checksum, token.is_some()));
+
+        let start = buf.len();
+        if !compact {
+            put_u32(buf, self.conv, big_endian);
         }
-
-        buf.put_u32_le(self.conv);
         buf.put_u8(self.cmd);
         buf.put_u8(self.frg);
-        buf.put_u16_le(self.wnd);
-        buf.put_u32_le(self.ts);
-        buf.put_u32_le(self.sn);
-        buf.put_u32_le(self.una);
-        buf.put_u32_le(self.data.len() as u32);
+        put_u16(buf, self.wnd, big_endian);
+        put_u32(buf, self.ts, big_endian);
+        put_u32(buf, self.sn, big_endian);
+        put_u32(buf, self.una, big_endian);
+        put_u32(buf, self.data.len() as u32, big_endian);
         buf.put_slice(&self.data);
+        if let Some(token) = token {
+            buf.put_u32_le(token);
+        }
+        if checksum {
+            let crc = crc32(&buf[start..]);
+            buf.put_u32_le(crc);
+        }
     }
 
-    fn encoded_len(&self) -> usize {
-        KCP_OVERHEAD as usize + self.data.len()
+    fn encoded_len(&self, compact: bool, checksum: bool, token: bool) -> usize {
+        // Without the 4 bytes of `conv` if compact
+        let header = if compact {
+            KCP_OVERHEAD - 4
+        } else {
+            KCP_OVERHEAD
+        };
+        let token = if token { KCP_TOKEN_LEN } else { 0 };
+        let checksum = if checksum { KCP_CHECKSUM_LEN } else { 0 };
+        header + self.data.len() + token + checksum
     }
-}
-
-#[derive(Default)]
-struct KcpOutput<O>(O);
 
-impl<O: Write> Write for KcpOutput<O> {
-    #[inline]
-    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
-        trace!("[RO] {} bytes", data.len());
-        self.0.write(data)
+    // How it should be resent, a segment of `Kcp::retransmit` is resent at once, as a fast resend
+    fn detect_loss(&self, detector: &dyn LossDetector, conn: &ConnectionState) -> Retransmit {
+        if self.nack {
+            Retransmit::Fast
+        } else {
+            detector.detect(&self.state(), conn)
+        }
     }
 
-    #[inline]
-    fn flush(&mut self) -> io::Result<()> {
-        self.0.flush()
+    fn state(&self) -> SegmentState {
+        SegmentState {
+            sn: self.sn,
+            xmit: self.xmit,
+            ts: self.ts,
+            resendts: self.resendts,
+            rto: self.rto,
+            fastack: self.fastack,
+        }
     }
 }
 
-#[cfg(feature = "tokio")]
-impl<O: AsyncWrite + Unpin> AsyncWrite for KcpOutput<O> {
-    #[inline(always)]
-    fn poll_write(
-        mut self: Pin<&mut Self>,
-        cx: &mut Context<'_>,
-        buf: &[u8],
-    ) -> Poll<io::Result<usize>> {
-        Pin::new(&mut self.0).poll_write(cx, buf)
-    }
-    #[inline(always)]
-    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
-        Pin::new(&mut self.0).poll_flush(cx)
-    }
-    #[inline(always)]
-    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
-        Pin::new(&mut self.0).poll_shutdown(cx)
-    }
-    #[inline(always)]
-    fn poll_write_vectored(
-        mut self: Pin<&mut Self>,
-        cx: &mut Context<'_>,
-        bufs: &[io::IoSlice<'_>],
-    ) -> Poll<io::Result<usize>> {
-        Pin::new(&mut self.0).poll_write_vectored(cx, bufs)
-    }
-    #[inline(always)]
-    fn is_write_vectored(&self) -> bool {
-        self.0.is_write_vectored()
-    }
+/// State of a segment waiting for ACK, as seen by a `LossDetector` or `Kcp::inflight_segments`
+#[derive(Debug, Clone, Copy)]
+pub struct SegmentState {
+    /// Sequence number
+    pub sn: u32,
+    /// Times it has been transmitted
+    pub xmit: u32,
+    /// Time of the last transmission
+    pub ts: u32,
+    /// Time when it times out
+    pub resendts: u32,
+    /// Current retransmission timeout
+    pub rto: u32,
+    /// Times it has been skipped by ACKs of later segments
+    pub fastack: u32,
 }
 
-/// KCP control
-#[derive(Default)]
-pub struct Kcp<Output> {
-    /// Conversation ID
+/// State of the connection, as seen by a `LossDetector`
+#[derive(Debug, Clone, Copy)]
+pub struct ConnectionState {
+    /// Current time
+    pub current: u32,
+    /// Smoothed RTT
+    pub srtt: u32,
+    /// Retransmission timeout
+    pub rto: u32,
+    /// Fast resend trigger count, 0 means disabled
+    pub fastresend: u32,
+    /// Maximum transmissions by fast resend, 0 means unlimited
+    pub fastlimit: u32,
+    /// Whether nodelay mode is enabled
+    pub nodelay: bool,
+}
+
+/// Decision of a `LossDetector`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Retransmit {
+    /// Still in flight
+    No,
+    /// Timed out, its RTO backs off and the window collapses
+    Timeout,
+    /// Lost, detected by later ACKs, the window halves
+    Fast,
+}
+
+/// Learned RTT estimation, in clock ticks
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RttState {
+    /// Smoothed RTT, 0 if not measured yet
+    pub srtt: u32,
+    /// RTT variation
+    pub rttval: u32,
+    /// Retransmission timeout
+    pub rto: u32,
+    /// Minimal RTT, 0 if not measured yet
+    pub min_rtt: u32,
+}
+
+/// A raw RTT sample taken from an ACK, in clock ticks, see `Kcp::set_ack_sample_handler`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AckSample {
+    /// Sequence number acknowledged
+    pub sn: u32,
+    /// Round trip time, `recv_ts - send_ts`
+    pub rtt: u32,
+    /// Clock when the segment was sent, echoed by the remote
+    pub send_ts: u32,
+    /// Clock when the ACK was input
+    pub recv_ts: u32,
+}
+
+/// A segment retransmitted as lost by `flush`, see `Kcp::set_loss_handler`
+///
+/// `cwnd` and `ssthresh` are the ones after the congestion control has reacted to the losses
+/// of the flush, `ssthresh` is the one of `ClassicCc`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LossEvent {
+    /// Not acknowledged within its RTO
+    Timeout {
+        /// Sequence number retransmitted
+        sn: u32,
+        /// Congestion window in packets
+        cwnd: u16,
+        /// Slow start threshold in packets
+        ssthresh: u16,
+    },
+    /// Skipped by later ACKs, resent before its RTO
+    FastResend {
+        /// Sequence number retransmitted
+        sn: u32,
+        /// Congestion window in packets
+        cwnd: u16,
+        /// Slow start threshold in packets
+        ssthresh: u16,
+    },
+}
+
+/// State of a connection to be restored later, maybe in another process, see `Kcp::snapshot`
+///
+/// Serializable with the `serde` feature.
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct KcpSnapshot {
     conv: u32,
-    /// Maximum Transmission Unit
     mtu: usize,
-    /// Maximum Segment Size
-    mss: usize,
-    /// Connection state
+    stream: bool,
+    #[cfg_attr(feature = "serde", serde(default))]
+    compact: bool,
     state: i32,
 
-    /// First unacknowledged packet
     snd_una: u32,
-    /// Next packet
     snd_nxt: u32,
-    /// Next packet to be received
     rcv_nxt: u32,
 
-    /// Congestion window threshold
-    ssthresh: u16,
-
-    /// ACK receive variable RTT
+    ssthresh: u32,
     rx_rttval: u32,
-    /// ACK receive static RTT
     rx_srtt: u32,
-    /// Resend time (calculated by ACK delay time)
     rx_rto: u32,
-    /// Minimal resend timeout
     rx_minrto: u32,
+    rx_minrtt: u32,
 
-    /// Send window
-    snd_wnd: u16,
-    /// Receive window
-    rcv_wnd: u16,
-    /// Remote receive window
-    rmt_wnd: u16,
-    /// Congestion window
-    cwnd: u16,
-    /// Check window
-    /// - IKCP_ASK_TELL, telling window size to remote
-    /// - IKCP_ASK_SEND, ask remote for window size
+    snd_wnd: u32,
+    rcv_wnd: u32,
+    rmt_wnd: u32,
+    cwnd: u32,
+    incr: usize,
     probe: u32,
 
-    /// Last update time
     current: u32,
-    /// Flush interval
+    tick: u32,
     interval: u32,
-    /// Next flush interval
-    ts_flush: u32,
-    xmit: u32,
-
-    /// Enable nodelay
-    nodelay: bool,
-    /// Updated has been called or not
-    updated: bool,
-
-    /// Next check window timestamp
     ts_probe: u32,
-    /// Check window wait time
     probe_wait: u32,
 
-    /// Maximum resend time
+    nodelay: bool,
+    fastresend: u32,
+    fastlimit: u32,
+    nocwnd: bool,
     dead_link: u32,
-    /// Maximum payload size
-    incr: usize,
+    xmit: u32,
 
     snd_queue: VecDeque<KcpSegment>,
+    snd_frg: usize,
     rcv_queue: VecDeque<KcpSegment>,
     snd_buf: VecDeque<KcpSegment>,
     rcv_buf: VecDeque<KcpSegment>,
-
-    /// Pending ACK
     acklist: VecDeque<(u32, u32)>,
-    buf: BytesMut,
+}
 
-    /// ACK number to trigger fast resend
-    fastresend: u32,
-    fastlimit: u32,
-    /// Disable congestion control
-    nocwnd: bool,
-    /// Enable stream mode
+/// State of the connection, see `Kcp::state`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum LinkState {
+    /// Data flows
+    #[default]
+    Active,
+    /// Resend times exceeded, the remote never answered or stopped keeping alive
+    DeadLink,
+    /// Nothing received for the idle timeout, see `Kcp::set_idle_timeout`
+    TimedOut,
+    /// `Kcp::connect` is waiting for the remote to accept, data is held back
+    #[cfg(feature = "handshake")]
+    Connecting,
+}
+
+/// Result of `Kcp::try_flush`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FlushOutcome {
+    /// Everything has been written
+    Complete,
+    /// The output would block, `pending_bytes` of datagrams are kept for the next `try_flush`
+    WouldBlock {
+        /// Size of the datagrams not written yet
+        pending_bytes: usize,
+    },
+}
+
+/// What the last `flush` has written
+///
+/// Counted from zero by every flush, `flush_ack` and `flush_recv_side` included.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct FlushStats {
+    /// Bytes written into output, including datagrams failed to be written before
+    pub bytes_written: usize,
+    /// PUSH segments sent, including retransmissions
+    pub segments_sent: usize,
+    /// ACK segments sent
+    pub acks_sent: usize,
+    /// PUSH segments retransmitted
+    pub retransmits: usize,
+}
+
+/// Counters since the creation of `Kcp` or the last `reset_stats`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Stats {
+    /// PUSH segments sent, including retransmissions
+    pub segments_sent: u64,
+    /// PUSH segments retransmitted because of RTO timeout
+    pub timeout_retransmits: u64,
+    /// PUSH segments retransmitted because of fast resend
+    pub fast_retransmits: u64,
+    /// ACK segments received
+    pub acks_received: u64,
+    /// PUSH segments received, including duplicated ones
+    pub pushes_received: u64,
+    /// PUSH segments received again while waiting in the receive buffer
+    pub duplicate_pushes_received: u64,
+    /// PUSH segments received again after being moved to the receive queue, before `rcv_nxt`
+    ///
+    /// Many of them with little loss means the remote retransmits too early, a higher
+    /// `rx_minrto` or fast resend of it helps. They could also be replayed.
+    pub stale_pushes_received: u64,
+    /// Current smoothed RTT, millisec (clock ticks, see `set_tick_resolution`)
+    pub srtt: u32,
+}
+
+/// Counters telling reordering from loss on the path, since the creation or the last `reset_stats`
+///
+/// Many `dup_acks_received` with few `reordered_on_recv` on the remote points to loss, where a
+/// lower fast resend helps. If segments are mostly reordered, fast resend should be higher or
+/// off, and the windows large enough to hold them.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PathMetrics {
+    /// Inputs acknowledging a segment sent after an unacknowledged one, counting towards fast resend
+    pub dup_acks_received: u64,
+    /// PUSH segments filling a hole, received after a segment of a higher sn
+    pub out_of_order_segments: u64,
+    /// Out of order PUSH segments which are not retransmissions, but reordered on the path
+    pub reordered_on_recv: u64,
+}
+
+/// Algorithm estimating the retransmission timeout from RTT samples
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum RtoEstimator {
+    /// ikcp, `RTO = SRTT + max(interval, 4 * RTTVAR)`
+    #[default]
+    Ikcp,
+    /// RFC 6298, `RTO = SRTT + max(G, 4 * RTTVAR)` where G is the clock granularity
+    Rfc6298,
+}
+
+/// Decides whether a segment sent before has to be retransmitted
+///
+/// Called for every unacknowledged segment in `flush`, and when checking if there is anything to flush.
+pub trait LossDetector {
+    /// Decide what to do with `segment`
+    fn detect(&self, segment: &SegmentState, conn: &ConnectionState) -> Retransmit;
+}
+
+/// Encryption of datagrams, installed with `Kcp::set_crypt`
+///
+/// The conv in the first 4 bytes of a datagram is left in the clear for demultiplexing (see
+/// `try_get_conv`), only the rest of the datagram is passed here.
+pub trait SegmentCrypt {
+    /// Encrypt `buf` in place, it may grow for a nonce or an authentication tag
+    fn encrypt(&self, buf: &mut BytesMut);
+    /// Decrypt `buf` in place, returns the length of the plaintext at the beginning of `buf`
+    ///
+    /// Return `Error::DecryptFailed` if `buf` is not authentic.
+    fn decrypt(&self, buf: &mut [u8]) -> KcpResult<usize>;
+}
+
+/// Compression of messages, installed with `Kcp::set_payload_codec`
+///
+/// A message is compressed as a whole by `send`, before it is split into segments, and
+/// decompressed by `recv` once all its fragments are received.
+pub trait PayloadCodec {
+    /// Compress the message `data`
+    fn compress(&self, data: &[u8]) -> BytesMut;
+    /// Decompress the message `data`
+    ///
+    /// Return `Error::DecompressFailed` if `data` is malformed.
+    fn decompress(&self, data: &[u8]) -> KcpResult<BytesMut>;
+}
+
+/// Source of the time for `Kcp::update_now` and `Kcp::check_now`
+///
+/// A monotonic millisecond clock (or ticks, see `Kcp::set_tick_resolution`) wrapping around
+/// `u32`, as passed to `Kcp::update`.
+pub trait Clock {
+    /// The current time
+    fn now(&self) -> u32;
+}
+
+/// A `Clock` advanced by hand, for deterministic tests
+///
+/// Clones share the same time, keep one to advance the clock given to `Kcp::with_clock`.
+#[derive(Debug, Default, Clone)]
+pub struct ManualClock(Arc<AtomicU32>);
+
+impl ManualClock {
+    /// Create a clock starting at `now`
+    pub fn new(now: u32) -> ManualClock {
+        ManualClock(Arc::new(AtomicU32::new(now)))
+    }
+
+    /// Set the time to `now`
+    #[inline]
+    pub fn set(&self, now: u32) {
+        self.0.store(now, atomic::Ordering::Relaxed);
+    }
+
+    /// Move the time forward by `ms`, wrapping around
+    #[inline]
+    pub fn advance(&self, ms: u32) {
+        self.set(self.now().wrapping_add(ms));
+    }
+}
+
+impl Clock for ManualClock {
+    #[inline]
+    fn now(&self) -> u32 {
+        self.0.load(atomic::Ordering::Relaxed)
+    }
+}
+
+/// The loss detection of KCP, by RTO timeout and fast resend
+#[derive(Debug, Default, Clone, Copy)]
+pub struct DefaultLossDetector;
+
+impl LossDetector for DefaultLossDetector {
+    fn detect(&self, segment: &SegmentState, conn: &ConnectionState) -> Retransmit {
+        if timediff(conn.current, segment.resendts) >= 0 {
+            Retransmit::Timeout
+        } else if conn.fastresend > 0
+            && segment.fastack >= conn.fastresend
+            && (segment.xmit <= conn.fastlimit || conn.fastlimit == 0)
+        {
+            Retransmit::Fast
+        } else {
+            Retransmit::No
+        }
+    }
+}
+
+/// Congestion control of `Kcp`, selected with `Kcp::set_congestion`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum CongestionControl {
+    /// KCP, the window halves on fast resend and collapses to 1 on RTO timeout
+    #[default]
+    Classic,
+    /// The window halves on any loss, without collapsing on RTO timeout
+    LossBased,
+    /// Only limited by the send and remote windows, same as `nocwnd` of `set_nodelay`
+    None,
+}
+
+/// What a `CcAlgo` knows about the connection
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CongestionState {
+    /// Current time
+    pub current: u32,
+    /// Smoothed RTT
+    pub srtt: u32,
+    /// Maximum payload size of a segment
+    pub mss: usize,
+    /// Remote receive window
+    pub rmt_wnd: u16,
+    /// Remote receive window, not capped at `u16::MAX` once scaled
+    #[cfg(feature = "wscale")]
+    pub rmt_wnd_scaled: u32,
+    /// Segments sent and not acknowledged yet
+    pub inflight: u32,
+    /// Send window of the current flush, the smallest of `cwnd`, send and remote windows
+    pub window: u16,
+    /// Send window of the current flush, not capped at `u16::MAX` once scaled
+    #[cfg(feature = "wscale")]
+    pub window_scaled: u32,
+    /// Fast resend trigger count, 0 means disabled
+    pub fastresend: u32,
+}
+
+impl CongestionState {
+    // The remote window, scaled if supported
+    #[cfg(feature = "wscale")]
+    #[inline]
+    fn rmt_wnd_full(&self) -> u32 {
+        self.rmt_wnd_scaled
+    }
+
+    #[cfg(not(feature = "wscale"))]
+    #[inline]
+    fn rmt_wnd_full(&self) -> u32 {
+        self.rmt_wnd as u32
+    }
+
+    // The send window, scaled if supported
+    #[cfg(feature = "wscale")]
+    #[inline]
+    fn window_full(&self) -> u32 {
+        self.window_scaled
+    }
+
+    #[cfg(not(feature = "wscale"))]
+    #[inline]
+    fn window_full(&self) -> u32 {
+        self.window as u32
+    }
+}
+
+/// Congestion control algorithm deciding the congestion window
+///
+/// Installed with `Kcp::set_congestion_algo`, `ClassicCc` is used by default.
+pub trait CcAlgo {
+    /// Called in `input` when `acked` more segments are acknowledged in order
+    fn on_ack(&mut self, acked: u32, state: &CongestionState);
+    /// Called in `flush` after segments are retransmitted for `loss`
+    fn on_loss(&mut self, loss: Retransmit, state: &CongestionState);
+    /// Congestion window in packets
+    fn cwnd(&self) -> u16;
+    /// Congestion window in packets, above `u16::MAX` with a scaled window
+    #[cfg(feature = "wscale")]
+    fn cwnd_scaled(&self) -> u32 {
+        self.cwnd() as u32
+    }
+}
+
+/// The congestion control of KCP, slow start and congestion avoidance by `ssthresh`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ClassicCc {
+    cwnd: u32,
+    ssthresh: u32,
+    incr: usize,
+    loss_based: bool,
+    // Percent of the congestion avoidance increment, see `Kcp::set_cwnd_increase_factor`
+    increase: usize,
+}
+
+impl Default for ClassicCc {
+    fn default() -> ClassicCc {
+        ClassicCc {
+            cwnd: 0,
+            ssthresh: KCP_THRESH_INIT,
+            incr: 0,
+            loss_based: false,
+            increase: 100,
+        }
+    }
+}
+
+impl ClassicCc {
+    /// Create the algorithm of `CongestionControl::Classic`
+    pub fn new() -> ClassicCc {
+        ClassicCc::default()
+    }
+
+    /// Create the algorithm of `CongestionControl::LossBased`
+    pub fn loss_based() -> ClassicCc {
+        ClassicCc {
+            loss_based: true,
+            ..ClassicCc::default()
+        }
+    }
+
+    /// Slow start threshold in packets
+    #[inline]
+    pub fn ssthresh(&self) -> u16 {
+        wnd_u16(self.ssthresh)
+    }
+
+    /// Congestion window size in bytes, grows with every ACK
+    #[inline]
+    pub fn incr(&self) -> usize {
+        self.incr
+    }
+
+    // At least one segment could be sent
+    fn ensure_cwnd(&mut self, mss: usize) {
+        if self.cwnd < 1 {
+            self.cwnd = 1;
+            self.incr = mss;
+        }
+    }
+}
+
+impl CcAlgo for ClassicCc {
+    fn on_ack(&mut self, _acked: u32, state: &CongestionState) {
+        let rmt_wnd = state.rmt_wnd_full();
+        if self.cwnd >= rmt_wnd {
+            return;
+        }
+
+        let mss = state.mss;
+        if self.cwnd < self.ssthresh {
+            self.cwnd += 1;
+            self.incr += mss;
+        } else {
+            if self.incr < mss {
+                self.incr = mss;
+            }
+            self.incr += ((mss * mss) / self.incr + (mss / 16)) * self.increase / 100;
+            if (self.cwnd as usize + 1) * mss <= self.incr {
+                // self.cwnd += 1;
+                self.cwnd = ((self.incr + mss - 1) / if mss > 0 { mss } else { 1 }) as u32;
+            }
+        }
+        if self.cwnd > rmt_wnd {
+            self.cwnd = rmt_wnd;
+            self.incr = rmt_wnd as usize * mss;
+        }
+    }
+
+    fn on_loss(&mut self, loss: Retransmit, state: &CongestionState) {
+        match loss {
+            Retransmit::No => return,
+            Retransmit::Fast => {
+                self.ssthresh = cmp::max(state.inflight / 2, KCP_THRESH_MIN);
+                self.cwnd = self.ssthresh;
+                if !self.loss_based {
+                    self.cwnd += state.fastresend;
+                }
+            }
+            Retransmit::Timeout => {
+                self.ssthresh = cmp::max(state.window_full() / 2, KCP_THRESH_MIN);
+                self.cwnd = if self.loss_based { self.ssthresh } else { 1 };
+            }
+        }
+        self.incr = self.cwnd as usize * state.mss;
+    }
+
+    #[inline]
+    fn cwnd(&self) -> u16 {
+        wnd_u16(self.cwnd)
+    }
+
+    #[cfg(feature = "wscale")]
+    #[inline]
+    fn cwnd_scaled(&self) -> u32 {
+        self.cwnd
+    }
+}
+
+// Highest ACK received in one input, for the fast-ack scan
+#[derive(Default)]
+struct MaxAck {
+    flag: bool,
+    sn: u32,
+    ts: u32,
+}
+
+impl MaxAck {
+    // The highest sn, which has to be sent later too unless `conserve`
+    fn update(&mut self, sn: u32, ts: u32, conserve: bool) {
+        if !self.flag {
+            self.flag = true;
+            self.sn = sn;
+            self.ts = ts;
+        } else if serial_lt(self.sn, sn) && (conserve || timediff(ts, self.ts) > 0) {
+            self.sn = sn;
+            self.ts = ts;
+        }
+    }
+}
+
+#[derive(Default)]
+struct KcpOutput<O>(O);
+
+impl<O: Write> Write for KcpOutput<O> {
+    #[inline]
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        trace!("[RO] {} bytes", data.len());
+        self.0.write(data)
+    }
+
+    #[cfg(feature = "std")]
+    #[inline]
+    fn write_vectored(&mut self, bufs: &[std::io::IoSlice<'_>]) -> io::Result<usize> {
+        trace!("[RO] {} buffers", bufs.len());
+        self.0.write_vectored(bufs)
+    }
+
+    #[inline]
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.flush()
+    }
+}
+
+#[cfg(feature = "tokio")]
+impl<O: AsyncWrite + Unpin> AsyncWrite for KcpOutput<O> {
+    #[inline(always)]
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.0).poll_write(cx, buf)
+    }
+    #[inline(always)]
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.0).poll_flush(cx)
+    }
+    #[inline(always)]
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.0).poll_shutdown(cx)
+    }
+    #[inline(always)]
+    fn poll_write_vectored(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[std::io::IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.0).poll_write_vectored(cx, bufs)
+    }
+    #[inline(always)]
+    fn is_write_vectored(&self) -> bool {
+        self.0.is_write_vectored()
+    }
+}
+
+/// Options of `Kcp::configure`, the arguments of `Kcp::set_nodelay` by name
+///
+/// The default is the configuration of `Kcp::new`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct NoDelayConfig {
+    /// Nodelay mode, which lowers the minimum RTO
+    pub nodelay: bool,
+    /// Internal update timer interval in millisec, clamped within 10 and 5000
+    pub interval: u32,
+    /// Duplicated ACKs triggering a fast resend, 0 disables it
+    pub resend: u32,
+    /// Disable the congestion window, only limited by the send and remote windows
+    pub no_congestion: bool,
+}
+
+impl Default for NoDelayConfig {
+    fn default() -> NoDelayConfig {
+        NoDelayConfig {
+            nodelay: false,
+            interval: KCP_INTERVAL,
+            resend: 0,
+            no_congestion: false,
+        }
+    }
+}
+
+impl NoDelayConfig {
+    /// The fastest configuration, `set_nodelay(true, 20, 2, true)`
+    pub fn fast() -> NoDelayConfig {
+        NoDelayConfig {
+            nodelay: true,
+            interval: 20,
+            resend: 2,
+            no_congestion: true,
+        }
+    }
+}
+
+/// Configuration of a `Kcp`, validated as a whole by `build`
+///
+/// Options not set keep the defaults of `Kcp::new`. Unlike the setters, invalid values are
+/// errors instead of being clamped or ignored, so a configuration is applied as written or not
+/// at all. The setters of `Kcp` are still there for changing it at runtime.
+#[derive(Debug, Clone, Default)]
+pub struct KcpBuilder {
     stream: bool,
+    tick_resolution: Option<u32>,
+    mtu: Option<usize>,
+    reserved: Option<usize>,
+    nodelay: Option<bool>,
+    interval: Option<u32>,
+    fast_resend: Option<u32>,
+    congestion: Option<CongestionControl>,
+    snd_wnd: Option<u16>,
+    rcv_wnd: Option<u16>,
+    rx_minrto: Option<u32>,
+    initial_rto: Option<u32>,
+    maximum_resend_times: Option<u32>,
+    max_fragments: Option<usize>,
+    snd_queue_limit: Option<usize>,
+}
+
+impl KcpBuilder {
+    /// Creates a builder with the defaults of `Kcp::new`
+    pub fn new() -> KcpBuilder {
+        KcpBuilder::default()
+    }
+
+    /// Build a stream `Kcp`, see `Kcp::new_stream`
+    pub fn stream(mut self, stream: bool) -> Self {
+        self.stream = stream;
+        self
+    }
+
+    /// Clock ticks per millisec, see `Kcp::set_tick_resolution`
+    pub fn tick_resolution(mut self, ticks_per_ms: u32) -> Self {
+        self.tick_resolution = Some(ticks_per_ms);
+        self
+    }
+
+    /// MTU, see `Kcp::set_mtu`
+    pub fn mtu(mut self, mtu: usize) -> Self {
+        self.mtu = Some(mtu);
+        self
+    }
+
+    /// Bytes of every datagram left for the layers below, see `Kcp::set_reserved`
+    pub fn reserved(mut self, reserved: usize) -> Self {
+        self.reserved = Some(reserved);
+        self
+    }
+
+    /// Nodelay mode, which lowers the minimum RTO, see `Kcp::set_nodelay`
+    pub fn nodelay(mut self, nodelay: bool) -> Self {
+        self.nodelay = Some(nodelay);
+        self
+    }
+
+    /// Flush interval in millisec, within 10 and 5000
+    pub fn interval(mut self, interval: u32) -> Self {
+        self.interval = Some(interval);
+        self
+    }
+
+    /// Duplicated ACKs triggering a fast resend, 0 disables it
+    pub fn fast_resend(mut self, resend: u32) -> Self {
+        self.fast_resend = Some(resend);
+        self
+    }
+
+    /// Congestion control, see `Kcp::set_congestion`
+    pub fn congestion(mut self, cc: CongestionControl) -> Self {
+        self.congestion = Some(cc);
+        self
+    }
+
+    /// Send and receive windows in segments, neither could be 0
+    ///
+    /// The receive window is taken as is, without the minimum of `Kcp::set_wndsize`.
+    pub fn wndsize(mut self, snd_wnd: u16, rcv_wnd: u16) -> Self {
+        self.snd_wnd = Some(snd_wnd);
+        self.rcv_wnd = Some(rcv_wnd);
+        self
+    }
+
+    /// Minimum RTO in millisec, see `Kcp::set_rx_minrto`
+    pub fn rx_minrto(mut self, rto: u32) -> Self {
+        self.rx_minrto = Some(rto);
+        self
+    }
+
+    /// RTO in millisec until RTT is measured, not lower than the minimum RTO
+    pub fn initial_rto(mut self, rto: u32) -> Self {
+        self.initial_rto = Some(rto);
+        self
+    }
+
+    /// Resend times before the connection is dead, see `Kcp::set_maximum_resend_times`
+    pub fn maximum_resend_times(mut self, times: u32) -> Self {
+        self.maximum_resend_times = Some(times);
+        self
+    }
+
+    /// Maximum fragments of a message, not more than the receive window
+    pub fn max_fragments(mut self, max: usize) -> Self {
+        self.max_fragments = Some(max);
+        self
+    }
+
+    /// Maximum segments in the send queue, see `Kcp::set_snd_queue_limit`
+    pub fn snd_queue_limit(mut self, limit: usize) -> Self {
+        self.snd_queue_limit = Some(limit);
+        self
+    }
+
+    /// Creates a `Kcp` of `conv` writing into `output`
+    ///
+    /// Returns `Error::InvalidMtu` or `Error::InvalidReserved` for a MTU which doesn't fit a
+    /// segment, and `Error::InvalidConfig` for any other invalid option.
+    pub fn build<Output>(&self, conv: u32, output: Output) -> KcpResult<Kcp<Output>> {
+        self.validate()?;
+
+        let mut kcp = Kcp::construct(conv, output, self.stream);
+        if let Some(ticks_per_ms) = self.tick_resolution {
+            kcp.set_tick_resolution(ticks_per_ms);
+        }
+        if let Some(mtu) = self.mtu {
+            kcp.set_mtu(mtu)?;
+        }
+        if let Some(reserved) = self.reserved {
+            kcp.set_reserved(reserved)?;
+        }
+        if let Some(nodelay) = self.nodelay {
+            let interval = kcp.interval / kcp.tick;
+            kcp.set_nodelay(nodelay, interval as i32, -1, kcp.nocwnd);
+        }
+        if let Some(interval) = self.interval {
+            kcp.set_interval(interval);
+        }
+        if let Some(resend) = self.fast_resend {
+            kcp.set_fast_resend(resend);
+        }
+        if let Some(cc) = self.congestion {
+            kcp.set_congestion(cc);
+        }
+        if let Some(snd_wnd) = self.snd_wnd {
+            kcp.snd_wnd = snd_wnd as u32;
+        }
+        if let Some(rcv_wnd) = self.rcv_wnd {
+            kcp.resize_rcv_wnd(rcv_wnd);
+        }
+        if let Some(rto) = self.rx_minrto {
+            kcp.set_rx_minrto(rto);
+        }
+        if let Some(rto) = self.initial_rto {
+            kcp.set_initial_rto(rto);
+        }
+        if let Some(times) = self.maximum_resend_times {
+            kcp.set_maximum_resend_times(times);
+        }
+        if let Some(max) = self.max_fragments {
+            kcp.set_max_fragments(max);
+        }
+        if let Some(limit) = self.snd_queue_limit {
+            kcp.set_snd_queue_limit(limit);
+        }
+        Ok(kcp)
+    }
+
+    // Check the options which setters would clamp or ignore, and the ones depending on others
+    fn validate(&self) -> KcpResult<()> {
+        if self.tick_resolution == Some(0) {
+            return Err(Error::InvalidConfig("tick resolution is 0"));
+        }
+        if self.interval.is_some_and(|i| !(10..=5000).contains(&i)) {
+            return Err(Error::InvalidConfig(
+                "interval is not within 10 and 5000 ms",
+            ));
+        }
+        if self.snd_wnd == Some(0) || self.rcv_wnd == Some(0) {
+            return Err(Error::InvalidConfig("window is 0"));
+        }
+
+        let minrto = match (self.rx_minrto, self.nodelay) {
+            (Some(rto), _) => rto,
+            (None, Some(true)) => KCP_RTO_NDL,
+            (None, _) => KCP_RTO_MIN,
+        };
+        if self.initial_rto.is_some_and(|rto| rto < minrto) {
+            return Err(Error::InvalidConfig(
+                "initial RTO is lower than the minimum RTO",
+            ));
+        }
+
+        let rcv_wnd = self.rcv_wnd.unwrap_or(KCP_WND_RCV);
+        if self.max_fragments.is_some_and(|max| max > rcv_wnd as usize) {
+            return Err(Error::InvalidConfig(
+                "max fragments exceed the receive window",
+            ));
+        }
+        Ok(())
+    }
+}
+
+type PacketHook = Box<dyn FnMut(&[u8], u32) + Send>;
+
+/// KCP control
+///
+/// `update` has to be called once before anything is sent, it sets the baseline of the clock.
+/// `send` queues messages and `input` takes datagrams at any time, but nothing is written to
+/// the output, not even the ACKs, and `flush` fails with `Error::NeedUpdate` until then. Keep
+/// calling `update` afterwards, or `check` when to.
+#[derive(Default)]
+pub struct Kcp<Output> {
+    /// Conversation ID
+    conv: u32,
+    /// Maximum Transmission Unit
+    mtu: usize,
+    /// Bytes of each datagram left for the layers below
+    reserved: usize,
+    /// MTU being probed, 0 means none
+    mtu_probe: usize,
+    /// Time to give up the MTU probe, `None` until it is sent
+    mtu_probe_deadline: Option<u32>,
+    /// Maximum Segment Size
+    mss: usize,
+    /// Connection state, `LinkState::Active`, `LinkState::DeadLink` or `LinkState::TimedOut`
+    state: LinkState,
+
+    /// First unacknowledged packet
+    snd_una: u32,
+    /// Next packet
+    snd_nxt: u32,
+    /// Next packet to be received
+    rcv_nxt: u32,
+
+    /// ACK receive variable RTT
+    rx_rttval: u32,
+    /// ACK receive static RTT
+    rx_srtt: u32,
+    /// Resend time (calculated by ACK delay time)
+    rx_rto: u32,
+    /// Minimal resend timeout
+    rx_minrto: u32,
+    /// Resend timeout after a fast resend, 0 means the RTO of the segment
+    fastack_rto: u32,
+    /// Multiplier of the RTO of a segment timed out, 0 means the classic backoff
+    rto_backoff: f32,
+    /// Fraction of `rcv_wnd` in `rcv_queue` from which a `recv` tells the window
+    window_tell_threshold: f32,
+    /// Minimal RTT measured, 0 if not measured yet
+    rx_minrtt: u32,
+    /// Algorithm updating `rx_rto`
+    rto_estimator: RtoEstimator,
+
+    /// Send window
+    snd_wnd: u32,
+    /// Receive window
+    rcv_wnd: u32,
+    /// Advertised receive window instead of the free space in `rcv_queue`
+    wnd_override: Option<u32>,
+    /// Remote receive window
+    rmt_wnd: u32,
+    /// Largest window advertised by the remote, 0 until it is heard from
+    rmt_wnd_max: u32,
+    /// Congestion control, `cwnd`, `ssthresh` and `incr`
+    classic: ClassicCc,
+    /// Custom congestion control replacing `classic`
+    cc_algo: Option<Box<dyn CcAlgo + Send>>,
+    /// Receive window of the last flush, `None` before the first one
+    wnd_advertised: Option<u32>,
+    /// Check window
+    /// - IKCP_ASK_TELL, telling window size to remote
+    /// - IKCP_ASK_SEND, ask remote for window size
+    probe: u32,
+
+    /// Last update time
+    current: u32,
+    /// Clock ticks in one millisec
+    tick: u32,
+    /// Clock jumps resetting the flush timer in millisec, `None` never resets
+    flush_clamp: Option<u32>,
+    /// Flush interval
+    interval: u32,
+    /// Next flush interval
+    ts_flush: u32,
+    xmit: u32,
+
+    /// Enable nodelay
+    nodelay: bool,
+    /// Updated has been called or not
+    updated: bool,
+    /// Warned about `send` or `input` before the first update
+    update_warned: bool,
+    /// Restored from a snapshot, timers are on its clock until the first update
+    restored: bool,
+
+    /// Next check window timestamp
+    ts_probe: u32,
+    /// Check window wait time
+    probe_wait: u32,
+
+    /// Maximum resend time
+    dead_link: u32,
+
+    snd_queue: VecDeque<KcpSegment>,
+    /// Segments in `snd_queue` left from a message partially moved to `snd_buf`
+    snd_frg: usize,
+    rcv_queue: VecDeque<KcpSegment>,
+    snd_buf: VecDeque<KcpSegment>,
+    rcv_buf: VecDeque<KcpSegment>,
+
+    /// Pending ACK
+    acklist: VecDeque<(u32, u32)>,
+    /// Datagram being encoded
+    buf: BytesMut,
+    /// Encoded datagrams waiting to be written into output
+    output_queue: VecDeque<BytesMut>,
+    /// Incomplete segment received by `input_framed`
+    framed: BytesMut,
+
+    /// ACK number to trigger fast resend
+    fastresend: u32,
+    /// Duplicate ACKs to trigger fast resend, 0 means same as `fastresend`
+    dupack: u32,
+    /// Count every ACK skipping a segment, not only the ones sent after it
+    fastack_conserve: bool,
+    /// Raise `dupack` with `reorder`
+    adaptive_dupack: bool,
+    /// Maximum reordering degree observed
+    reorder: u32,
+    /// Next of the highest `sn` received
+    rcv_max: u32,
+    /// Timestamp of the highest `sn` received
+    ts_rcv_max: u32,
+    fastlimit: u32,
+    /// Disable congestion control
+    nocwnd: bool,
+    /// Enable stream mode
+    stream: bool,
+    /// Leave `conv` out of segment headers
+    compact: bool,
+    /// Append a CRC32 to every segment
+    checksum: bool,
+    /// Append this token to every segment, and reject segments without it
+    session_token: Option<u32>,
+    /// Encode header fields big-endian
+    big_endian: bool,
+
+    /// Get conv from the next input call
+    input_conv: bool,
+    /// Abort `input` at a segment of another conv, otherwise it is skipped
+    strict_conv: bool,
+    /// Abort with `Error::ConvInconsistent` instead of `Error::ConvMismatch`
+    legacy_conv_error: bool,
+
+    /// `close` was called, nothing could be appended in stream mode
+    closed: bool,
+    /// `shutdown_send` was called, `send` is refused
+    send_shutdown: bool,
+    /// A FIN from the remote has been received in order
+    peer_closed: bool,
+
+    /// Shift of the advertised receive window, `None` if scaling is disabled
+    wscale: Option<u8>,
+    /// Shift of the remote, `Some` once it has announced the support, so ours is scaled
+    wscale_peer: Option<u8>,
+    /// The remote scales its advertised receive window by `wscale_peer`
+    wscale_decode: bool,
+    /// The remote scales ours
+    wscale_acked: bool,
+    /// The remote is still negotiating, answer it in the next flush
+    wscale_reply: bool,
+
+    /// Send ACKs as ranges of sn once the remote understands them
+    sack: bool,
+    /// The remote sent ranges of ACKs
+    sack_peer: bool,
+
+    /// Forget sent segments past their deadline instead of resending them
+    partial: bool,
+    /// The remote has to skip to this sn, until its `una` reaches it
+    forward_sn: Option<u32>,
+    /// Time to send the skip again
+    ts_forward: u32,
+    /// Ranges of sn forgotten, not delivered whatever `snd_una` says
+    forgotten: VecDeque<Range<u32>>,
+
+    /// Waiting for the remote to accept the connection
+    connecting: bool,
+    /// Time to send the connection request again
+    ts_syn: u32,
+    /// Connection requests sent
+    syn_xmit: u32,
+    /// The remote requested a connection, accept it in the next flush
+    synack: bool,
+    /// Sequence numbers start at an ISN exchanged by the handshake
+    isn: bool,
+    /// The ISN of the remote has been learned from its request or answer
+    isn_known: bool,
+
+    /// Encode ACKs in `input` instead of the next flush
+    ack_nodelay: bool,
+    /// Maximum time ACK-only flush could be deferred
+    ack_coalesce: u32,
+    /// Time when the first pending ACK was queued
+    ts_ack: u32,
+    /// Minimum interval between flushes of ACKs, 0 means disabled
+    ack_delay: u32,
+    /// Time when ACKs were flushed last
+    ts_ack_flush: u32,
+
+    /// Maximum segments sent for the first time in one flush
+    max_burst: usize,
+    /// Segments sent for the first time in the last flush
+    last_burst: usize,
+
+    /// Maximum segments in `snd_queue`
+    snd_queue_limit: usize,
+    /// Maximum segments in `rcv_buf`
+    rcv_buf_limit: usize,
+    /// Maximum bytes buffered in all the queues for `send`
+    total_buffer_limit: usize,
+    /// Maximum fragments of a message, 0 means less than `rcv_wnd`
+    max_fragments: usize,
+
+    /// Flush less frequently while there is nothing to do
+    idle: bool,
+    /// Maximum flush interval in millisec when idle
+    idle_interval: u32,
+    /// Current flush interval when idle
+    idle_wait: u32,
+
+    /// Interval to send a keepalive while nothing else is sent, 0 means disabled
+    keepalive: u32,
+    /// Keepalive intervals without input before the connection is dead, 0 means never
+    keepalive_max_missed: u32,
+    /// Time without input before the connection times out, 0 means never
+    idle_timeout: u32,
+    /// Time when a datagram was last encoded
+    ts_last_send: u32,
+    /// Time when a datagram was last input
+    ts_last_recv: u32,
+
+    /// Maximum data sent in bytes per second, 0 means unlimited
+    rate_limit: u32,
+    /// Bytes could be sent now, negative if the last segment overdrew it
+    rate_tokens: i64,
+    /// Time when `rate_tokens` was refilled
+    ts_rate: u32,
+    /// Bytes acknowledged at each time within the last `KCP_RATE_WINDOW`
+    delivered: VecDeque<(u32, usize)>,
+
+    /// Output of the last flush
+    flush_stats: FlushStats,
+    /// Cumulative counters
+    stats: Stats,
+    /// Cumulative reordering and loss counters
+    path_metrics: PathMetrics,
+
+    /// Bytes appended to a queued segment in stream mode
+    stream_coalesced_bytes: u64,
+    /// Segments created in stream mode
+    stream_new_segments: u64,
+
+    /// Times `update` was called with a `current` earlier than the last one
+    clock_went_backwards: u64,
+
+    /// Custom loss detection, `DefaultLossDetector` if not set
+    loss_detector: Option<Box<dyn LossDetector + Send>>,
+
+    /// Encryption of datagrams
+    crypt: Option<Box<dyn SegmentCrypt + Send>>,
+    /// Compression of messages
+    payload_codec: Option<Box<dyn PayloadCodec + Send>>,
+    /// Time source of `update_now`, kept by `reset`
+    clock: Option<Box<dyn Clock + Send>>,
+
+    /// Called with `conv` when the connection becomes dead
+    dead_link_handler: Option<Box<dyn FnMut(u32) + Send>>,
+    /// Called with every datagram and `current` before it is written
+    packet_hook: Option<PacketHook>,
+    /// Write queued datagrams with `write_vectored`
+    vectored: bool,
+    /// Called with the RTT sample of every ACK
+    ack_sample_handler: Option<Box<dyn FnMut(AckSample) + Send>>,
+    /// Called with every segment retransmitted as lost
+    loss_handler: Option<Box<dyn FnMut(LossEvent) + Send>>,
+
+    output: KcpOutput<Output>,
+}
+
+impl<Output> Debug for Kcp<Output> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Kcp")
+            .field("conv", &self.conv)
+            .field("mtu", &self.mtu)
+            .field("reserved", &self.reserved)
+            .field("mtu_probe", &self.mtu_probe)
+            .field("mtu_probe_deadline", &self.mtu_probe_deadline)
+            .field("mss", &self.mss)
+            .field("state", &self.state)
+            .field("snd_una", &self.snd_una)
+            .field("snd_nxt", &self.snd_nxt)
+            .field("rcv_nxt", &self.rcv_nxt)
+            .field("rx_rttval", &self.rx_rttval)
+            .field("rx_srtt", &self.rx_srtt)
+            .field("rx_rto", &self.rx_rto)
+            .field("rx_minrto", &self.rx_minrto)
+            .field("fastack_rto", &self.fastack_rto)
+            .field("rto_backoff", &self.rto_backoff)
+            .field("window_tell_threshold", &self.window_tell_threshold)
+            .field("rx_minrtt", &self.rx_minrtt)
+            .field("rto_estimator", &self.rto_estimator)
+            .field("snd_wnd", &self.snd_wnd)
+            .field("rcv_wnd", &self.rcv_wnd)
+            .field("rmt_wnd", &self.rmt_wnd)
+            .field("rmt_wnd_max", &self.rmt_wnd_max)
+            .field("wnd_override", &self.wnd_override)
+            .field("classic", &self.classic)
+            .field("cc_algo", &self.cc_algo.is_some())
+            .field("wnd_advertised", &self.wnd_advertised)
+            .field("probe", &self.probe)
+            .field("current", &self.current)
+            .field("tick", &self.tick)
+            .field("flush_clamp", &self.flush_clamp)
+            .field("interval", &self.interval)
+            .field("ts_flush", &self.ts_flush)
+            .field("xmit", &self.xmit)
+            .field("nodelay", &self.nodelay)
+            .field("updated", &self.updated)
+            .field("update_warned", &self.update_warned)
+            .field("restored", &self.restored)
+            .field("ts_probe", &self.ts_probe)
+            .field("probe_wait", &self.probe_wait)
+            .field("dead_link", &self.dead_link)
+            .field("snd_queue.len", &self.snd_queue.len())
+            .field("rcv_queue.len", &self.rcv_queue.len())
+            .field("snd_buf.len", &self.snd_buf.len())
+            .field("rcv_buf.len", &self.rcv_buf.len())
+            .field("acklist.len", &self.acklist.len())
+            .field("buf.len", &self.buf.len())
+            .field("output_queue.len", &self.output_queue.len())
+            .field("fastresend", &self.fastresend)
+            .field("dupack", &self.dupack)
+            .field("fastack_conserve", &self.fastack_conserve)
+            .field("adaptive_dupack", &self.adaptive_dupack)
+            .field("reorder", &self.reorder)
+            .field("fastlimit", &self.fastlimit)
+            .field("nocwnd", &self.nocwnd)
+            .field("stream", &self.stream)
+            .field("compact", &self.compact)
+            .field("checksum", &self.checksum)
+            .field("session_token", &self.session_token.is_some())
+            .field("big_endian", &self.big_endian)
+            .field("input_conv", &self.input_conv)
+            .field("strict_conv", &self.strict_conv)
+            .field("legacy_conv_error", &self.legacy_conv_error)
+            .field("closed", &self.closed)
+            .field("send_shutdown", &self.send_shutdown)
+            .field("peer_closed", &self.peer_closed)
+            .field("wscale", &self.wscale)
+            .field("wscale_peer", &self.wscale_peer)
+            .field("wscale_decode", &self.wscale_decode)
+            .field("wscale_acked", &self.wscale_acked)
+            .field("wscale_reply", &self.wscale_reply)
+            .field("sack", &self.sack)
+            .field("sack_peer", &self.sack_peer)
+            .field("partial", &self.partial)
+            .field("forward_sn", &self.forward_sn)
+            .field("ts_forward", &self.ts_forward)
+            .field("forgotten", &self.forgotten)
+            .field("connecting", &self.connecting)
+            .field("ts_syn", &self.ts_syn)
+            .field("syn_xmit", &self.syn_xmit)
+            .field("synack", &self.synack)
+            .field("isn", &self.isn)
+            .field("isn_known", &self.isn_known)
+            .field("ack_nodelay", &self.ack_nodelay)
+            .field("ack_coalesce", &self.ack_coalesce)
+            .field("ts_ack", &self.ts_ack)
+            .field("ack_delay", &self.ack_delay)
+            .field("ts_ack_flush", &self.ts_ack_flush)
+            .field("max_burst", &self.max_burst)
+            .field("last_burst", &self.last_burst)
+            .field("snd_queue_limit", &self.snd_queue_limit)
+            .field("rcv_buf_limit", &self.rcv_buf_limit)
+            .field("total_buffer_limit", &self.total_buffer_limit)
+            .field("max_fragments", &self.max_fragments)
+            .field("idle", &self.idle)
+            .field("idle_interval", &self.idle_interval)
+            .field("keepalive", &self.keepalive)
+            .field("keepalive_max_missed", &self.keepalive_max_missed)
+            .field("idle_timeout", &self.idle_timeout)
+            .field("ts_last_send", &self.ts_last_send)
+            .field("ts_last_recv", &self.ts_last_recv)
+            .field("rate_limit", &self.rate_limit)
+            .field("rate_tokens", &self.rate_tokens)
+            .field("delivered.len", &self.delivered.len())
+            .field("stats", &self.stats)
+            .field("path_metrics", &self.path_metrics)
+            .field("stream_coalesced_bytes", &self.stream_coalesced_bytes)
+            .field("stream_new_segments", &self.stream_new_segments)
+            .field("clock_went_backwards", &self.clock_went_backwards)
+            .field("loss_detector", &self.loss_detector.is_some())
+            .field("crypt", &self.crypt.is_some())
+            .field("payload_codec", &self.payload_codec.is_some())
+            .field("clock", &self.clock.is_some())
+            .field("dead_link_handler", &self.dead_link_handler.is_some())
+            .field("packet_hook", &self.packet_hook.is_some())
+            .field("vectored", &self.vectored)
+            .field("ack_sample_handler", &self.ack_sample_handler.is_some())
+            .field("loss_handler", &self.loss_handler.is_some())
+            .finish()
+    }
+}
+
+/// The state of a `Kcp` worth a log line, see `Kcp::summary`
+///
+/// Its `Debug` prints the connection state, the windows, the sequence numbers, the queue lengths,
+/// the RTT estimation and the pending ACKs, never any payload.
+pub struct KcpSummary<'a, Output> {
+    kcp: &'a Kcp<Output>,
+}
+
+impl<Output> Debug for KcpSummary<'_, Output> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let kcp = self.kcp;
+        f.debug_struct("Kcp")
+            .field("conv", &kcp.conv)
+            .field("state", &kcp.state())
+            .field("snd_wnd", &kcp.snd_wnd)
+            .field("rcv_wnd", &kcp.rcv_wnd)
+            .field("rmt_wnd", &kcp.rmt_wnd)
+            .field("cwnd", &kcp.cwnd())
+            .field("snd_una", &kcp.snd_una)
+            .field("snd_nxt", &kcp.snd_nxt)
+            .field("rcv_nxt", &kcp.rcv_nxt)
+            .field("snd_queue", &kcp.snd_queue.len())
+            .field("snd_buf", &kcp.snd_buf.len())
+            .field("rcv_queue", &kcp.rcv_queue.len())
+            .field("rcv_buf", &kcp.rcv_buf.len())
+            .field("srtt", &kcp.rx_srtt)
+            .field("rttval", &kcp.rx_rttval)
+            .field("rto", &kcp.rx_rto)
+            .field("acks", &kcp.acklist.len())
+            .finish_non_exhaustive()
+    }
+}
+
+/// `Kcp` writing into a boxed output, to keep connections with different outputs together
+pub type BoxedKcp = Kcp<Box<dyn Write + Send>>;
+
+/// `Kcp` writing into a boxed output which is not `Send`
+pub type LocalBoxedKcp = Kcp<Box<dyn Write>>;
+
+impl<W: Write + ?Sized> Kcp<Box<W>> {
+    /// Creates a KCP control object with a boxed `output`, see `BoxedKcp` and `LocalBoxedKcp`
+    pub fn new_boxed(conv: u32, output: Box<W>) -> Self {
+        Kcp::construct(conv, output, false)
+    }
+}
+
+/// Output keeping every datagram written, to be sent by the caller, see `Kcp::new_collecting`
+#[derive(Debug, Default)]
+pub struct Collector(Vec<BytesMut>);
+
+impl Collector {
+    /// Take all the datagrams written so far
+    #[inline]
+    pub fn take(&mut self) -> Vec<BytesMut> {
+        mem::take(&mut self.0)
+    }
+}
+
+impl Write for Collector {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.push(BytesMut::from(buf));
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// `Kcp` handing back the datagrams to send with `take_output`
+pub type CollectingKcp = Kcp<Collector>;
+
+impl Kcp<Collector> {
+    /// Creates a KCP control object keeping the datagrams to send, see `take_output`
+    ///
+    /// For async sockets, the datagrams could be sent with `send_to` after every `update` or
+    /// `flush`, without a synchronous `Write`.
+    pub fn new_collecting(conv: u32) -> Self {
+        Kcp::construct(conv, Collector::default(), false)
+    }
+
+    /// Take the datagrams written since the last call, in order
+    #[inline]
+    pub fn take_output(&mut self) -> Vec<BytesMut> {
+        self.output.0.take()
+    }
+}
+
+impl<Output> Kcp<Output> {
+    /// Creates a KCP control object, `conv` must be equal in both endpoints in one connection.
+    /// `output` is the callback object for writing.
+    ///
+    /// `conv` represents conversation.
+    pub fn new(conv: u32, output: Output) -> Self {
+        Kcp::construct(conv, output, false)
+    }
+
+    /// Creates a KCP control object in stream mode, `conv` must be equal in both endpoints in one connection.
+    /// `output` is the callback object for writing.
+    ///
+    /// `conv` represents conversation.
+    pub fn new_stream(conv: u32, output: Output) -> Self {
+        Kcp::construct(conv, output, true)
+    }
+
+    /// Creates a KCP control object leaving `conv` out of segment headers, which take
+    /// `KCP_COMPACT_OVERHEAD` bytes instead of `KCP_OVERHEAD`
+    ///
+    /// For point to point links where every byte counts, every segment received is taken as one
+    /// of `conv`. `try_get_conv` and the other header functions don't apply to its datagrams.
+    ///
+    /// It changes the wire format, the remote must be created with `new_compact` as well. The
+    /// reference C implementation, or a `Kcp` created otherwise, can't talk to it.
+    #[cfg(feature = "compact")]
+    pub fn new_compact(conv: u32, output: Output) -> Self {
+        let mut kcp = Kcp::construct(conv, output, false);
+        kcp.compact = true;
+        kcp.mss = kcp.mtu - kcp.overhead();
+        kcp
+    }
+
+    /// Creates a KCP control object taking the time from `clock`, so `update_now` and `check_now`
+    /// could be called without passing the current time
+    ///
+    /// `update` and `check` are still available, passing the time of the same clock.
+    pub fn with_clock<C>(conv: u32, output: Output, clock: C) -> Self
+    where
+        C: Clock + Send + 'static,
+    {
+        let mut kcp = Kcp::construct(conv, output, false);
+        kcp.clock = Some(Box::new(clock));
+        kcp
+    }
+
+    fn construct(conv: u32, output: Output, stream: bool) -> Self {
+        Kcp {
+            conv,
+            snd_una: 0,
+            snd_nxt: 0,
+            rcv_nxt: 0,
+            ts_probe: 0,
+            probe_wait: 0,
+            snd_wnd: KCP_WND_SND as u32,
+            rcv_wnd: KCP_WND_RCV as u32,
+            rmt_wnd: KCP_WND_RCV as u32,
+            rmt_wnd_max: 0,
+            wnd_override: None,
+            classic: ClassicCc::default(),
+            cc_algo: None,
+            wnd_advertised: None,
+            probe: 0,
+            mtu: KCP_MTU_DEF,
+            reserved: 0,
+            mtu_probe: 0,
+            mtu_probe_deadline: None,
+            mss: KCP_MTU_DEF - KCP_OVERHEAD,
+            stream,
+            compact: false,
+            checksum: false,
+            session_token: None,
+            big_endian: false,
+
+            buf: BytesMut::with_capacity((KCP_MTU_DEF + KCP_OVERHEAD) * 3),
+            output_queue: VecDeque::new(),
+            framed: BytesMut::new(),
+
+            snd_queue: VecDeque::new(),
+            snd_frg: 0,
+            rcv_queue: VecDeque::new(),
+            snd_buf: VecDeque::new(),
+            rcv_buf: VecDeque::new(),
+
+            state: LinkState::Active,
+
+            acklist: VecDeque::new(),
+
+            rx_srtt: 0,
+            rx_rttval: 0,
+            rx_rto: KCP_RTO_DEF,
+            rx_minrto: KCP_RTO_MIN,
+            fastack_rto: 0,
+            rto_backoff: 0.0,
+            window_tell_threshold: 1.0,
+            rx_minrtt: 0,
+            rto_estimator: RtoEstimator::Ikcp,
+
+            current: 0,
+            tick: 1,
+            flush_clamp: Some(KCP_FLUSH_CLAMP),
+            interval: KCP_INTERVAL,
+            ts_flush: KCP_INTERVAL,
+            nodelay: false,
+            updated: false,
+            update_warned: false,
+            restored: false,
+            fastresend: 0,
+            dupack: 0,
+            fastack_conserve: cfg!(feature = "fastack-conserve"),
+            adaptive_dupack: false,
+            reorder: 0,
+            rcv_max: 0,
+            ts_rcv_max: 0,
+            fastlimit: KCP_FASTACK_LIMIT,
+            nocwnd: false,
+            xmit: 0,
+            dead_link: KCP_DEADLINK,
+
+            input_conv: false,
+            strict_conv: true,
+            legacy_conv_error: false,
+            closed: false,
+            send_shutdown: false,
+            peer_closed: false,
+            wscale: None,
+            wscale_peer: None,
+            wscale_decode: false,
+            wscale_acked: false,
+            wscale_reply: false,
+            sack: false,
+            sack_peer: false,
+            partial: false,
+            forward_sn: None,
+            ts_forward: 0,
+            forgotten: VecDeque::new(),
+            connecting: false,
+            ts_syn: 0,
+            syn_xmit: 0,
+            synack: false,
+            isn: false,
+            isn_known: false,
+            ack_nodelay: false,
+            ack_coalesce: 0,
+            ts_ack: 0,
+            ack_delay: 0,
+            ts_ack_flush: 0,
+            max_burst: 0,
+            last_burst: 0,
+            snd_queue_limit: 0,
+            rcv_buf_limit: 0,
+            total_buffer_limit: 0,
+            max_fragments: 0,
+            idle: false,
+            idle_interval: KCP_IDLE_INTERVAL,
+            idle_wait: 0,
+            keepalive: 0,
+            keepalive_max_missed: 0,
+            idle_timeout: 0,
+            ts_last_send: 0,
+            ts_last_recv: 0,
+            rate_limit: 0,
+            rate_tokens: 0,
+            ts_rate: 0,
+            delivered: VecDeque::new(),
+            flush_stats: FlushStats::default(),
+            stats: Stats::default(),
+            path_metrics: PathMetrics::default(),
+            stream_coalesced_bytes: 0,
+            stream_new_segments: 0,
+            clock_went_backwards: 0,
+            loss_detector: None,
+            crypt: None,
+            payload_codec: None,
+            clock: None,
+            dead_link_handler: None,
+            packet_hook: None,
+            vectored: false,
+            ack_sample_handler: None,
+            loss_handler: None,
+            output: KcpOutput(output),
+        }
+    }
+
+    /// Reset to a new conversation `conv`, as if it was created again with the same `output`
+    ///
+    /// All queued data and settings are dropped, except the stream mode, the compact headers of
+    /// `new_compact` and the clock. Allocated buffers are kept to be reused.
+    pub fn reset(&mut self, conv: u32) {
+        let mut fresh = Kcp::construct(conv, (), self.stream);
+        if self.compact {
+            fresh.compact = true;
+            fresh.mss = fresh.mtu - fresh.overhead();
+        }
+        fresh.clock = self.clock.take();
+
+        // Every field is listed, so none could be missed, buffers are cleared to be reused
+        macro_rules! take_fields {
+            ($($field:ident),*; $($buffer:ident),*) => {{
+                let Kcp { $($field,)* $($buffer: _,)* output: _ } = fresh;
+                $(self.$field = $field;)*
+                $(self.$buffer.clear();)*
+            }};
+        }
+        take_fields!(
+            conv, snd_una, snd_nxt, rcv_nxt, ts_probe, probe_wait, snd_wnd, rcv_wnd, rmt_wnd,
+            rmt_wnd_max, wnd_override, classic, cc_algo, wnd_advertised, probe, mtu, reserved,
+            mtu_probe, mtu_probe_deadline, mss, stream, compact, checksum, session_token,
+            big_endian, snd_frg, state, rx_srtt, rx_rttval, rx_rto, rx_minrto, fastack_rto,
+            rto_backoff, window_tell_threshold, rx_minrtt, rto_estimator, current, tick,
+            flush_clamp, interval, ts_flush, nodelay, updated, update_warned, restored, fastresend,
+            dupack, fastack_conserve, adaptive_dupack, reorder, rcv_max, ts_rcv_max, fastlimit,
+            nocwnd, xmit, dead_link, input_conv, strict_conv, legacy_conv_error, closed,
+            send_shutdown, peer_closed, wscale, wscale_peer, wscale_decode, wscale_acked,
+            wscale_reply, sack, sack_peer, partial, forward_sn, ts_forward, connecting, ts_syn,
+            syn_xmit, synack, isn, isn_known, ack_nodelay, ack_coalesce, ts_ack, ack_delay,
+            ts_ack_flush, max_burst, last_burst, snd_queue_limit, rcv_buf_limit,
+            total_buffer_limit, max_fragments, idle, idle_interval, idle_wait, keepalive,
+            keepalive_max_missed, idle_timeout, ts_last_send, ts_last_recv, rate_limit,
+            rate_tokens, ts_rate, flush_stats, stats, path_metrics, stream_coalesced_bytes,
+            stream_new_segments, clock_went_backwards, loss_detector, crypt, payload_codec, clock,
+            dead_link_handler, packet_hook, vectored, ack_sample_handler, loss_handler;
+            buf, output_queue, framed, snd_queue, rcv_queue, snd_buf, rcv_buf, acklist, delivered,
+            forgotten
+        );
+    }
+
+    /// Save the state of the connection, to be restored with `restore`
+    ///
+    /// Settings beyond the ikcp ones (loss detector, encryption, rate limit, ...) are not
+    /// included, set them again after `restore`. Datagrams encoded but not written yet are not
+    /// included either, they are sent again as the segments time out.
+    pub fn snapshot(&self) -> KcpSnapshot {
+        KcpSnapshot {
+            conv: self.conv,
+            mtu: self.mtu,
+            stream: self.stream,
+            compact: self.compact,
+            state: if self.is_dead_link() { -1 } else { 0 },
+            snd_una: self.snd_una,
+            snd_nxt: self.snd_nxt,
+            rcv_nxt: self.rcv_nxt,
+            ssthresh: self.classic.ssthresh,
+            rx_rttval: self.rx_rttval,
+            rx_srtt: self.rx_srtt,
+            rx_rto: self.rx_rto,
+            rx_minrto: self.rx_minrto,
+            rx_minrtt: self.rx_minrtt,
+            snd_wnd: self.snd_wnd,
+            rcv_wnd: self.rcv_wnd,
+            rmt_wnd: self.rmt_wnd,
+            cwnd: self.classic.cwnd,
+            incr: self.classic.incr,
+            probe: self.probe,
+            current: self.current,
+            tick: self.tick,
+            interval: self.interval,
+            ts_probe: self.ts_probe,
+            probe_wait: self.probe_wait,
+            nodelay: self.nodelay,
+            fastresend: self.fastresend,
+            fastlimit: self.fastlimit,
+            nocwnd: self.nocwnd,
+            dead_link: self.dead_link,
+            xmit: self.xmit,
+            snd_queue: self.snd_queue.clone(),
+            snd_frg: self.snd_frg,
+            rcv_queue: self.rcv_queue.clone(),
+            snd_buf: self.snd_buf.clone(),
+            rcv_buf: self.rcv_buf.clone(),
+            acklist: self.acklist.clone(),
+        }
+    }
+
+    /// Creates a KCP control object from a `snapshot`, writing into `output`
+    ///
+    /// Timers are moved onto the clock of the first `update` call afterwards, so the clock
+    /// doesn't need to be the same as the one where the snapshot is taken.
+    pub fn restore(snapshot: KcpSnapshot, output: Output) -> Self {
+        let mut kcp = Kcp::construct(snapshot.conv, output, snapshot.stream);
+
+        kcp.compact = snapshot.compact;
+        kcp.mtu = snapshot.mtu;
+        kcp.mss = snapshot.mtu.saturating_sub(kcp.overhead());
+        kcp.state = if snapshot.state != 0 {
+            LinkState::DeadLink
+        } else {
+            LinkState::Active
+        };
+        kcp.snd_una = snapshot.snd_una;
+        kcp.snd_nxt = snapshot.snd_nxt;
+        kcp.rcv_nxt = snapshot.rcv_nxt;
+        kcp.rcv_max = snapshot.rcv_nxt;
+        kcp.rx_rttval = snapshot.rx_rttval;
+        kcp.rx_srtt = snapshot.rx_srtt;
+        kcp.rx_rto = snapshot.rx_rto;
+        kcp.rx_minrto = snapshot.rx_minrto;
+        kcp.rx_minrtt = snapshot.rx_minrtt;
+        kcp.snd_wnd = snapshot.snd_wnd;
+        kcp.rcv_wnd = snapshot.rcv_wnd;
+        kcp.rmt_wnd = snapshot.rmt_wnd;
+        kcp.classic.cwnd = snapshot.cwnd;
+        kcp.classic.ssthresh = snapshot.ssthresh;
+        kcp.classic.incr = snapshot.incr;
+        kcp.probe = snapshot.probe;
+        kcp.current = snapshot.current;
+        kcp.tick = snapshot.tick;
+        kcp.interval = snapshot.interval;
+        kcp.ts_probe = snapshot.ts_probe;
+        kcp.probe_wait = snapshot.probe_wait;
+        kcp.nodelay = snapshot.nodelay;
+        kcp.fastresend = snapshot.fastresend;
+        kcp.fastlimit = snapshot.fastlimit;
+        kcp.nocwnd = snapshot.nocwnd;
+        kcp.dead_link = snapshot.dead_link;
+        kcp.xmit = snapshot.xmit;
+        kcp.snd_queue = snapshot.snd_queue;
+        kcp.snd_frg = snapshot.snd_frg;
+        kcp.rcv_queue = snapshot.rcv_queue;
+        kcp.snd_buf = snapshot.snd_buf;
+        kcp.rcv_buf = snapshot.rcv_buf;
+        kcp.acklist = snapshot.acklist;
+        kcp.restored = true;
+
+        kcp
+    }
+
+    // Move timers restored from a snapshot onto the clock of the first update
+    fn rebase_timers(&mut self, previous: u32) {
+        if !mem::take(&mut self.restored) {
+            return;
+        }
+
+        let delta = self.current.wrapping_sub(previous);
+
+        for segment in &mut self.snd_buf {
+            segment.ts = segment.ts.wrapping_add(delta);
+            segment.resendts = segment.resendts.wrapping_add(delta);
+        }
+        for segment in &mut self.snd_queue {
+            if let Some(ref mut deadline) = segment.deadline {
+                *deadline = deadline.wrapping_add(delta);
+            }
+        }
+        self.ts_probe = self.ts_probe.wrapping_add(delta);
+    }
+
+    // move available data from rcv_buf -> rcv_queue
+    pub fn move_buf(&mut self) {
+        let rcv_nxt = self.rcv_nxt;
+
+        while !self.rcv_buf.is_empty() {
+            let nrcv_que = self.rcv_queue.len();
+            {
+                let seg = self.rcv_buf.front().unwrap();
+                if seg.sn == self.rcv_nxt && nrcv_que < self.rcv_wnd as usize {
+                    self.rcv_nxt = self.rcv_nxt.wrapping_add(1);
+                } else {
+                    break;
+                }
+            }
+
+            let seg = self.rcv_buf.pop_front().unwrap();
+            #[cfg(feature = "fin")]
+            if seg.cmd == KCP_CMD_FIN {
+                debug!("conv={} closed by the remote, sn={}", self.conv, seg.sn);
+                self.peer_closed = true;
+                continue;
+            }
+            self.rcv_queue.push_back(seg);
+        }
+
+        if serial_diff(self.rcv_nxt, rcv_nxt) > 1 {
+            self.prune_acks(rcv_nxt);
+        }
+    }
+
+    // A gap has been filled, drop pending ACKs from `from` to `rcv_nxt`, which are covered by `una`
+    // of any segment. Keep the latest one to be sent for the RTT sample.
+    fn prune_acks(&mut self, from: u32) {
+        let latest = match self.acklist.pop_back() {
+            Some(latest) => latest,
+            None => return,
+        };
+
+        let rcv_nxt = self.rcv_nxt;
+        self.acklist
+            .retain(|&(sn, _)| serial_lt(sn, from) || serial_le(rcv_nxt, sn));
+        self.acklist.push_back(latest);
+    }
+
+    /// Receive data from buffer
+    ///
+    /// An empty message, which some remotes send as a keepalive, returns `Ok(0)` and is consumed
+    /// like any other, the next call goes on with the message after it. `Error::RecvQueueEmpty`
+    /// is returned when there is nothing to receive.
+    pub fn recv(&mut self, buf: &mut [u8]) -> KcpResult<usize> {
+        if self.rcv_queue.is_empty() {
+            return Err(Error::RecvQueueEmpty);
+        }
+
+        let peeksize = self.checked_peeksize()?;
+
+        if self.payload_codec.is_some() {
+            return self.recv_decoded(buf, peeksize);
+        }
+
+        if peeksize > buf.len() {
+            debug!("recv peeksize={} bufsize={} too small", peeksize, buf.len());
+            return Err(Error::UserBufTooSmall);
+        }
+
+        let recover = self.window_tell_due();
+
+        // Merge fragment
+        let mut len = 0;
+        while let Some(seg) = self.rcv_queue.pop_front() {
+            buf[len..len + seg.data.len()].copy_from_slice(&seg.data);
+            len += seg.data.len();
+
+            trace!("recv sn={}", seg.sn);
+
+            if seg.frg == 0 {
+                break;
+            }
+        }
+        assert_eq!(len, peeksize);
+
+        self.recovered(recover);
+
+        Ok(len)
+    }
+
+    /// Receive data from buffer, `Ok(None)` if no whole message is ready yet
+    ///
+    /// Works as `recv`, but nothing to receive, an empty queue or a message still waiting for
+    /// fragments, isn't an error. Any error returned is a real one, like `UserBufTooSmall`.
+    pub fn try_recv(&mut self, buf: &mut [u8]) -> KcpResult<Option<usize>> {
+        if !self.recv_ready() {
+            return Ok(None);
+        }
+        self.recv(buf).map(Some)
+    }
+
+    // `recv` of a message of `set_payload_codec`, left in the queue if it doesn't fit in `buf`
+    fn recv_decoded(&mut self, buf: &mut [u8], peeksize: usize) -> KcpResult<usize> {
+        let mut message = BytesMut::with_capacity(peeksize);
+        for seg in &self.rcv_queue {
+            message.extend_from_slice(&seg.data);
+            if seg.frg == 0 {
+                break;
+            }
+        }
+
+        let message = match self.decode_message(message) {
+            Ok(message) => message,
+            Err(err) => {
+                self.take_message()?;
+                return Err(err);
+            }
+        };
+        if message.len() > buf.len() {
+            debug!(
+                "recv size={} bufsize={} too small",
+                message.len(),
+                buf.len()
+            );
+            return Err(Error::UserBufTooSmall);
+        }
+
+        self.take_message()?;
+        buf[..message.len()].copy_from_slice(&message);
+        Ok(message.len())
+    }
+
+    // Decompress a whole message with `set_payload_codec`
+    fn decode_message(&self, message: BytesMut) -> KcpResult<BytesMut> {
+        match self.payload_codec {
+            Some(ref codec) => codec.decompress(&message),
+            None => Ok(message),
+        }
+    }
+
+    /// Receive data from buffer, without copying if the message has only one fragment
+    pub fn recv_bytes(&mut self) -> KcpResult<BytesMut> {
+        let message = self.take_message()?;
+        self.decode_message(message)
+    }
+
+    // Take the next message from `rcv_queue` as received
+    fn take_message(&mut self) -> KcpResult<BytesMut> {
+        if self.rcv_queue.is_empty() {
+            return Err(Error::RecvQueueEmpty);
+        }
+
+        let peeksize = self.checked_peeksize()?;
+
+        let recover = self.window_tell_due();
+
+        let first = self.rcv_queue.pop_front().unwrap();
+        trace!("recv sn={}", first.sn);
+
+        let mut buf = first.data;
+        if first.frg > 0 {
+            // Merge fragment
+            buf.reserve(peeksize - buf.len());
+            while let Some(seg) = self.rcv_queue.pop_front() {
+                buf.extend_from_slice(&seg.data);
+
+                trace!("recv sn={}", seg.sn);
+
+                if seg.frg == 0 {
+                    break;
+                }
+            }
+        }
+        assert_eq!(buf.len(), peeksize);
+
+        self.recovered(recover);
+
+        Ok(buf)
+    }
+
+    /// Receive data into `out`, which is cleared and grown to fit the message
+    ///
+    /// Never returns `Error::UserBufTooSmall`, and `out` is left untouched on any error. The
+    /// capacity of `out` is kept, so reusing it saves allocating for every message.
+    pub fn recv_into_vec(&mut self, out: &mut Vec<u8>) -> KcpResult<usize> {
+        if self.rcv_queue.is_empty() {
+            return Err(Error::RecvQueueEmpty);
+        }
+
+        if self.payload_codec.is_some() {
+            let message = self.recv_bytes()?;
+            out.clear();
+            out.extend_from_slice(&message);
+            return Ok(message.len());
+        }
+
+        let peeksize = self.checked_peeksize()?;
+        out.clear();
+        out.resize(peeksize, 0);
+        self.recv(out)
+    }
+
+    /// Receive all the complete messages in buffer, stops at the first incomplete one
+    pub fn recv_all(&mut self) -> Vec<BytesMut> {
+        let mut messages = Vec::new();
+        self.recv_batch(&mut messages, 0);
+        messages
+    }
+
+    /// Receive at most `max` complete messages into `out`, 0 means unlimited, returns how many
+    ///
+    /// Stops at the first incomplete one like `recv_all`. The messages are appended, `out` isn't
+    /// cleared. Reading a bounded batch per wakeup keeps a flooding remote from starving the
+    /// other work of an event loop, and the receive buffer is moved once for the whole batch.
+    /// A message failing to decompress with `set_payload_codec` is dropped.
+    pub fn recv_batch(&mut self, out: &mut Vec<BytesMut>, max: usize) -> usize {
+        let max = if max == 0 { usize::MAX } else { max };
+        let recover = self.window_tell_due();
+
+        let received = out.len();
+        let mut count = 0;
+        while count < max {
+            let fragments = match self.peek_fragments() {
+                Ok(fragments) => fragments,
+                Err(Error::CorruptFragmentChain) => {
+                    self.drop_corrupt_chain();
+                    continue;
+                }
+                Err(_) => break,
+            };
+
+            let first = self.rcv_queue.pop_front().unwrap();
+            trace!("recv sn={}", first.sn);
+
+            let mut buf = first.data;
+            for _ in 1..fragments {
+                let seg = self.rcv_queue.pop_front().unwrap();
+                buf.extend_from_slice(&seg.data);
+                trace!("recv sn={}", seg.sn);
+            }
+            match self.decode_message(buf) {
+                Ok(buf) => out.push(buf),
+                Err(err) => warn!("recv message dropped: {}", err),
+            }
+            count += 1;
+        }
+
+        if count > 0 {
+            self.recovered(recover);
+        }
+
+        out.len() - received
+    }
+
+    // Check if reading from `rcv_queue` now should tell the window to the remote
+    fn window_tell_due(&self) -> bool {
+        let threshold = (self.rcv_wnd as f32 * self.window_tell_threshold) as usize;
+        self.rcv_queue.len() >= cmp::max(threshold, 1)
+    }
+
+    // A message has been received from rcv_queue
+    fn recovered(&mut self, recover: bool) {
+        self.move_buf();
+
+        // fast recover
+        if self.rcv_queue.len() < self.rcv_wnd as usize && recover {
+            // ready to send back IKCP_CMD_WINS in ikcp_flush
+            // tell remote my window size
+            self.probe |= KCP_ASK_TELL;
+        }
+    }
+
+    /// Copy the next message into `buf` without consuming it
+    pub fn peek(&self, buf: &mut [u8]) -> KcpResult<usize> {
+        if self.rcv_queue.is_empty() {
+            return Err(Error::RecvQueueEmpty);
+        }
+
+        let peeksize = self.peeksize()?;
+
+        if peeksize > buf.len() {
+            debug!("peek peeksize={} bufsize={} too small", peeksize, buf.len());
+            return Err(Error::UserBufTooSmall);
+        }
+
+        // Merge fragment
+        let mut len = 0;
+        for seg in &self.rcv_queue {
+            buf[len..len + seg.data.len()].copy_from_slice(&seg.data);
+            len += seg.data.len();
+
+            if seg.frg == 0 {
+                break;
+            }
+        }
+        assert_eq!(len, peeksize);
+
+        Ok(len)
+    }
+
+    /// Check buffer size without actually consuming it
+    ///
+    /// 0 for an empty message, see `recv`.
+    pub fn peeksize(&self) -> KcpResult<usize> {
+        let count = self.peek_fragments()?;
+        Ok(self
+            .rcv_queue
+            .iter()
+            .take(count)
+            .map(|s| s.data.len())
+            .sum())
+    }
+
+    /// Check how many segments the next message spans without actually consuming it
+    ///
+    /// `Error::CorruptFragmentChain` is returned if the `frg` of the segments doesn't count down
+    /// to 0 along the message, which only a broken remote sends. `recv` drops them.
+    pub fn peek_fragments(&self) -> KcpResult<usize> {
+        if self.chain_break().is_some() {
+            return Err(Error::CorruptFragmentChain);
+        }
+
+        match self.rcv_queue.front() {
+            Some(segment) => {
+                let count = segment.frg as usize + 1;
+                if self.rcv_queue.len() < count {
+                    return Err(Error::ExpectingFragment);
+                }
+                Ok(count)
+            }
+            None => Err(Error::RecvQueueEmpty),
+        }
+    }
+
+    /// Check if a whole message is ready for `recv`, when `peeksize` succeeds
+    #[inline]
+    pub fn recv_ready(&self) -> bool {
+        self.peek_fragments().is_ok()
+    }
+
+    // Index of the first segment of the next message in `rcv_queue` whose `frg` is out of the
+    // count down from the first one
+    fn chain_break(&self) -> Option<usize> {
+        let count = self.rcv_queue.front()?.frg as usize + 1;
+        self.rcv_queue
+            .iter()
+            .take(count)
+            .enumerate()
+            .position(|(i, seg)| seg.frg as usize + i + 1 != count)
+    }
+
+    // `peeksize`, dropping a corrupt fragment chain so that the next message could be received
+    fn checked_peeksize(&mut self) -> KcpResult<usize> {
+        let result = self.peeksize();
+        if let Err(Error::CorruptFragmentChain) = result {
+            self.drop_corrupt_chain();
+        }
+        result
+    }
+
+    // Drop the segments before the one breaking the fragment chain of the next message
+    fn drop_corrupt_chain(&mut self) {
+        if let Some(n) = self.chain_break() {
+            warn!(
+                "conv={} dropped a corrupt fragment chain of {} segments",
+                self.conv, n
+            );
+            self.rcv_queue.drain(..n);
+            self.move_buf();
+        }
+    }
+
+    /// Count the whole messages ready for `recv`
+    ///
+    /// In stream mode every segment counts as one.
+    pub fn ready_message_count(&self) -> usize {
+        self.rcv_queue.iter().filter(|seg| seg.frg == 0).count()
+    }
+
+    /// Send bytes into buffer
+    ///
+    /// Sending an empty buffer does nothing and returns `Ok(0)`.
+    pub fn send(&mut self, buf: &[u8]) -> KcpResult<usize> {
+        self.enqueue(buf, true)
+    }
+
+    /// Send bytes into buffer, starting a new segment even in stream mode
+    ///
+    /// Later `send` calls in stream mode could still be appended to it.
+    pub fn send_no_coalesce(&mut self, buf: &[u8]) -> KcpResult<usize> {
+        self.enqueue(buf, false)
+    }
+
+    /// Send `data` into buffer, moving it into the segments instead of copying
+    ///
+    /// The segments share the buffer of `data`, split by the MSS without allocating, if it is
+    /// not shared with any other `Bytes`, otherwise it is copied once. Bytes appended to the
+    /// last queued segment in stream mode are copied. Works as `send` otherwise.
+    pub fn send_bytes(&mut self, data: Bytes) -> KcpResult<usize> {
+        self.enqueue(BytesMut::from(data), true)
+    }
+
+    fn enqueue<B: SendBuf>(&mut self, buf: B, coalesce: bool) -> KcpResult<usize> {
+        if self.send_shutdown {
+            return Err(Error::SendShutdown);
+        }
+
+        match self.payload_codec {
+            Some(ref codec) if !buf.is_empty() => {
+                let compressed = codec.compress(buf.as_slice());
+                self.enqueue_segments(compressed, coalesce)?;
+                Ok(buf.len())
+            }
+            _ => self.enqueue_segments(buf, coalesce),
+        }
+    }
+
+    // Split `buf` into segments queued for sending
+    fn enqueue_segments<B: SendBuf>(&mut self, mut buf: B, coalesce: bool) -> KcpResult<usize> {
+        let mut sent_size = 0;
+
+        assert!(self.mss > 0);
+
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        self.warn_not_updated("send");
+
+        // bytes appended to previous segment in streaming mode (if possible)
+        let extend = match self.snd_queue.back() {
+            Some(old) if self.stream && coalesce && !self.closed && old.data.len() < self.mss => {
+                cmp::min(buf.len(), self.mss - old.data.len())
+            }
+            _ => 0,
+        };
+
+        let rest = buf.len() - extend;
+        let count = rest.div_ceil(self.mss);
+
+        if count > self.max_fragments() {
+            debug!("send bufsize={} mss={} too large", buf.len(), self.mss);
+            return Err(Error::UserBufTooBig);
+        }
+
+        // Check before touching the queue, nothing is enqueued if it is full
+        if self.snd_queue_limit > 0 && self.snd_queue.len() + count > self.snd_queue_limit {
+            debug!(
+                "send queue full, queued={} need={} limit={}",
+                self.snd_queue.len(),
+                count,
+                self.snd_queue_limit
+            );
+            return Err(Error::SendQueueFull);
+        }
+        if self.total_buffer_limit > 0 {
+            let buffered = self.buffered_bytes() + buf.len() + count * self.overhead();
+            if buffered > self.total_buffer_limit {
+                debug!(
+                    "send buffer full, buffered={} limit={}",
+                    buffered, self.total_buffer_limit
+                );
+                return Err(Error::SendQueueFull);
+            }
+        }
+
+        if extend > 0 {
+            let old = self.snd_queue.back_mut().unwrap();
+
+            trace!(
+                "send stream mss={} last length={} extend={}",
+                self.mss,
+                old.data.len(),
+                extend
+            );
+
+            buf.append_to(extend, &mut old.data);
+
+            old.frg = 0;
+            old.deadline = None;
+            sent_size += extend;
+            self.stream_coalesced_bytes += extend as u64;
+        }
+
+        for i in 0..count {
+            let size = cmp::min(self.mss, buf.len());
+            let mut new_segment = KcpSegment::new_with_data(buf.take(size));
+
+            new_segment.frg = if self.stream {
+                0
+            } else {
+                (count - i - 1) as u8
+            };
+
+            self.snd_queue.push_back(new_segment);
+            sent_size += size;
+            if self.stream {
+                self.stream_new_segments += 1;
+            }
+        }
+
+        self.wake_idle();
+        Ok(sent_size)
+    }
+
+    /// Send bytes into buffer, drops them if they are not sent before `deadline`
+    ///
+    /// The message is dropped in `flush` as a whole if none of its segments has been sent yet.
+    /// Once sent, it is delivered reliably as any other message, unless it is forgotten with
+    /// `set_partial_reliability`, which drops it from the send buffer and tells the remote to
+    /// skip it. Writes have no boundaries in stream mode, `Error::InvalidConfig` is returned then.
+    pub fn send_with_deadline(&mut self, buf: &[u8], deadline: u32) -> KcpResult<usize> {
+        if self.stream {
+            return Err(Error::InvalidConfig("deadline in stream mode"));
+        }
+
+        let queued = self.snd_queue.len();
+        let n = self.send(buf)?;
+
+        for seg in self.snd_queue.iter_mut().skip(queued) {
+            seg.deadline = Some(deadline);
+        }
+
+        Ok(n)
+    }
+
+    /// Stop sending, `send` returns `Error::SendShutdown` afterwards
+    ///
+    /// Only new data is refused: the data queued before is still sent by `update` and
+    /// retransmitted until acknowledged, and receiving goes on as usual, like a request whose
+    /// response is still awaited. The remote is not told, see `close` for that. Calling it again
+    /// does nothing, `reset` allows sending again.
+    pub fn shutdown_send(&mut self) {
+        self.send_shutdown = true;
+    }
+
+    /// Check if `shutdown_send` has been called
+    #[inline]
+    pub fn is_send_shutdown(&self) -> bool {
+        self.send_shutdown
+    }
+
+    /// Close the sending side, the remote sees `is_peer_closed` after all the data sent before
+    ///
+    /// A FIN is queued after the data and retransmitted like it. Nothing should be sent
+    /// afterwards, calling it again does nothing.
+    ///
+    /// It extends the protocol with a new command, the remote must also be built with the `fin`
+    /// feature. The reference C implementation never acknowledges it, so the FIN is retransmitted
+    /// until the link is considered dead.
+    #[cfg(feature = "fin")]
+    pub fn close(&mut self) {
+        if self.closed {
+            return;
+        }
+
+        let mut fin = KcpSegment::new_with_data(BytesMut::new());
+        fin.cmd = KCP_CMD_FIN;
+        self.snd_queue.push_back(fin);
+        self.closed = true;
+    }
+
+    /// Check if `close` has been called
+    #[cfg(feature = "fin")]
+    #[inline]
+    pub fn is_closed(&self) -> bool {
+        self.closed
+    }
+
+    /// Check if the remote has closed, after all the data it sent has been received
+    ///
+    /// The data may still be waiting in buffer to be read by `recv`.
+    #[cfg(feature = "fin")]
+    #[inline]
+    pub fn is_peer_closed(&self) -> bool {
+        self.peer_closed
+    }
+
+    /// Connect to the remote, which has to accept before any data is sent
+    ///
+    /// A connection request with `conv` is sent by `flush`, again every RTO until the remote
+    /// answers, the data sent meanwhile is queued. The link is dead if it doesn't answer after
+    /// `set_maximum_resend_times` requests. Call it before the first `update`.
+    ///
+    /// It extends the protocol with new commands, the remote must also be built with the
+    /// `handshake` feature and call `accept`. The reference C implementation rejects the request,
+    /// so the connection is never established. Without `connect`, nothing is sent, and a remote
+    /// without the feature works as always.
+    #[cfg(feature = "handshake")]
+    pub fn connect(&mut self) {
+        self.connecting = true;
+        self.ts_syn = self.current;
+        self.syn_xmit = 0;
+    }
+
+    /// Accept the connection request `syn` received from the remote, see `is_syn`
+    ///
+    /// Takes the `conv` of the request and inputs it, the next `flush` answers it. Returns
+    /// `Error::NotSyn` for any other datagram, which is not input. A repeated request is answered
+    /// again by `input`. The header is read in the byte order of `set_endianness`, a compact one
+    /// of `new_compact` has no `conv` and keeps the one given.
+    #[cfg(feature = "handshake")]
+    pub fn accept(&mut self, syn: &[u8]) -> KcpResult<usize> {
+        match self.syn_conv(syn) {
+            Some(conv) => self.conv = conv,
+            None => return Err(Error::NotSyn),
+        }
+        self.input(syn)
+    }
+
+    // The `conv` of a connection request in `buf`, decoded in the layout and byte order of this
+    // `Kcp`
+    #[cfg(feature = "handshake")]
+    fn syn_conv(&self, mut buf: &[u8]) -> Option<u32> {
+        if buf.len() < self.wire_header_len() {
+            return None;
+        }
+        let conv = if self.compact {
+            self.conv
+        } else {
+            get_u32(&mut buf, self.big_endian)
+        };
+        (buf.get_u8() == KCP_CMD_SYN).then_some(conv)
+    }
+
+    /// Start sending at the initial sequence number `isn`, exchanged with the remote by the
+    /// handshake
+    ///
+    /// Call it before `connect` or `accept`. The connection request and its answer carry the ISN
+    /// of each side, the receiving sequence number is taken from the one of the remote, so an
+    /// off-path attacker guessing `conv` still has to guess where the sequence numbers are. Use
+    /// an unpredictable value, such as `random_isn`. Segments of the remote before its ISN is
+    /// known are dropped.
+    ///
+    /// It changes the handshake, the remote must also be built with the `isn` feature and set
+    /// its own ISN. A remote without it starts receiving at 0 and drops everything sent.
+    #[cfg(feature = "isn")]
+    pub fn set_isn(&mut self, isn: u32) {
+        self.set_initial_sn(isn, self.rcv_nxt);
+        self.isn = true;
+        self.isn_known = false;
+    }
+
+    /// State of the connection
+    ///
+    /// Active from the start without `connect`, until the link is dead.
+    #[inline]
+    pub fn state(&self) -> LinkState {
+        #[cfg(feature = "handshake")]
+        if self.connecting && !self.is_dead_link() {
+            return LinkState::Connecting;
+        }
+        self.state
+    }
+
+    /// Discard the data in buffer which hasn't been sent yet, returns the size discarded
+    ///
+    /// Segments already sent are still delivered, along with the rest of the message they belong to.
+    pub fn cancel_unsent(&mut self) -> usize {
+        let kept = cmp::min(self.snd_frg, self.snd_queue.len());
+        let size = self.snd_queue.drain(kept..).map(|seg| seg.data.len()).sum();
+        if size > 0 {
+            debug!("cancel_unsent discarded {} bytes", size);
+        }
+        size
+    }
+
+    /// Discard the messages in buffer which haven't been sent yet and match `predicate`, returns
+    /// how many are discarded
+    ///
+    /// `predicate` is called with every whole message not sent yet, in order, like a game state
+    /// update superseded by a newer one. A message of which some fragments are already sent is
+    /// kept. Unsent messages have no sequence numbers yet, so the rest is sent as if they never
+    /// were. In stream mode it sees segments instead, which may hold the data of several `send`
+    /// calls, or a part of one.
+    pub fn cancel_unsent_if<F>(&mut self, mut predicate: F) -> usize
+    where
+        F: FnMut(&[u8]) -> bool,
+    {
+        let kept = cmp::min(self.snd_frg, self.snd_queue.len());
+        let mut unsent = self.snd_queue.split_off(kept);
+
+        let mut cancelled = 0;
+        let mut message = Vec::new();
+        while let Some(seg) = unsent.pop_front() {
+            let last = seg.frg == 0 || unsent.is_empty();
+            message.push(seg);
+            if !last {
+                continue;
+            }
+
+            let cancel = match message.as_slice() {
+                #[cfg(feature = "fin")]
+                [seg] if seg.cmd == KCP_CMD_FIN => false,
+                [seg] => predicate(&seg.data),
+                fragments => {
+                    let data: Vec<u8> = fragments
+                        .iter()
+                        .flat_map(|seg| seg.data.iter().copied())
+                        .collect();
+                    predicate(&data)
+                }
+            };
+            if cancel {
+                cancelled += 1;
+                message.clear();
+            } else {
+                self.snd_queue.extend(message.drain(..));
+            }
+        }
+
+        if cancelled > 0 {
+            debug!("cancel_unsent_if discarded {} messages", cancelled);
+        }
+        cancelled
+    }
+
+    /// Send bytes into buffer, returns the range of sequence numbers carrying them
+    ///
+    /// Check the delivery of this message later with `is_acked`. In stream mode, the range may
+    /// include a segment shared with the previous message.
+    ///
+    /// Sequence numbers are assigned by `flush` in the order of the send queue, so the range is
+    /// known before, from the segments queued ahead. It no longer holds if segments ahead of it
+    /// leave the queue or are fragmented again before it is sent:
+    ///
+    /// - `cancel_unsent` and `cancel_unsent_if` discarding messages
+    /// - messages of `send_with_deadline` dropped past their deadline, or forgotten by
+    ///   `set_partial_reliability` with the rest of them still queued
+    /// - a smaller MSS, from `set_mtu`, `set_reserved`, `set_checksum`, `set_session_token` or
+    ///   `on_mtu_exceeded`
+    ///
+    /// The range of a message dropped from the queue is reused by the messages behind it. Track
+    /// messages sent after these changes only.
+    pub fn send_tracked(&mut self, buf: &[u8]) -> KcpResult<Range<u32>> {
+        let queued = self.snd_queue.len() as u32;
+        let appended = self.stream
+            && !buf.is_empty()
+            && self
+                .snd_queue
+                .back()
+                .is_some_and(|seg| seg.data.len() < self.mss);
+
+        self.send(buf)?;
+
+        let start = self
+            .snd_nxt
+            .wrapping_add(queued)
+            .wrapping_sub(appended as u32);
+        let end = self.snd_nxt.wrapping_add(self.snd_queue.len() as u32);
+        Ok(start..end)
+    }
+
+    /// Check if all the segments in `sn` have been acknowledged by the peer
+    ///
+    /// False if any of them was forgotten by `set_partial_reliability`, the remote skipped it.
+    #[inline]
+    pub fn is_acked(&self, sn: &Range<u32>) -> bool {
+        serial_le(sn.end, self.snd_una)
+            && !self
+                .forgotten
+                .iter()
+                .any(|range| serial_lt(range.start, sn.end) && serial_lt(sn.start, range.end))
+    }
+
+    /// Resend the segment `sn` by the next `flush`, known to be lost from outside of KCP
+    ///
+    /// Like an explicit NACK from a side channel. It is resent as a fast resend, without waiting
+    /// for duplicate ACKs: the RTO doesn't back off, the window shrinks as on fast resend rather
+    /// than collapsing as on timeout, and it is counted in `fast_retransmits`. `xmit` is bumped
+    /// and counted by `set_maximum_resend_times` like any resend. Returns false if it has been
+    /// acknowledged, or never sent yet.
+    ///
+    /// A `LossDetector` of `set_loss_detector` is not asked about it.
+    pub fn retransmit(&mut self, sn: u32) -> KcpResult<bool> {
+        if !self.updated {
+            return Err(Error::NeedUpdate);
+        }
+
+        match self
+            .snd_buf
+            .iter_mut()
+            .find(|seg| seg.sn == sn && seg.xmit > 0)
+        {
+            Some(segment) => {
+                segment.nack = true;
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    // Take the window advertised by the remote, remembering the largest one
+    fn update_rmt_wnd(&mut self, wnd: u16) {
+        self.rmt_wnd = self.decode_wnd(wnd);
+        self.rmt_wnd_max = cmp::max(self.rmt_wnd_max, self.rmt_wnd);
+    }
+
+    fn update_ack(&mut self, rtt: u32) {
+        // Beyond any RTO, likely a forged `ts` which would overflow the estimation
+        let rtt = cmp::min(rtt, self.ticks(KCP_RTO_MAX));
+        if self.rx_minrtt == 0 || rtt < self.rx_minrtt {
+            self.rx_minrtt = cmp::max(rtt, 1);
+        }
+
+        if self.rx_srtt == 0 {
+            self.rx_srtt = rtt;
+            self.rx_rttval = rtt / 2;
+        } else {
+            let delta = rtt.abs_diff(self.rx_srtt);
+            self.rx_rttval = (3 * self.rx_rttval + delta) / 4;
+            self.rx_srtt = (7 * self.rx_srtt + rtt) / 8;
+            if self.rx_srtt < 1 {
+                self.rx_srtt = 1;
+            }
+        }
+        let granularity = match self.rto_estimator {
+            RtoEstimator::Ikcp => self.interval,
+            RtoEstimator::Rfc6298 => 1,
+        };
+        let rto = self.rx_srtt + cmp::max(granularity, 4 * self.rx_rttval);
+        self.rx_rto = bound(self.rx_minrto, rto, self.ticks(KCP_RTO_MAX));
+    }
+
+    #[inline]
+    fn shrink_buf(&mut self) {
+        self.snd_una = match self.snd_buf.front() {
+            Some(seg) => seg.sn,
+            None => self.snd_nxt,
+        };
+    }
+
+    fn parse_ack(&mut self, sn: u32) {
+        if serial_lt(sn, self.snd_una) || serial_le(self.snd_nxt, sn) {
+            return;
+        }
+
+        let mut i = 0;
+        while i < self.snd_buf.len() {
+            match serial_diff(sn, self.snd_buf[i].sn).cmp(&0) {
+                Ordering::Equal => {
+                    if let Some(seg) = self.snd_buf.remove(i) {
+                        self.record_delivered(seg.data.len());
+                    }
+                    break;
+                }
+                Ordering::Less => break,
+                _ => i += 1,
+            }
+        }
+    }
+
+    // Acknowledge the `[start, end)` ranges of sn in `ranges`, `ts` is of the latest one
+    #[cfg(feature = "sack")]
+    fn parse_sack(&mut self, mut ranges: &[u8], ts: u32, max_ack: &mut MaxAck) {
+        self.sack_peer = true;
+
+        let mut acked = false;
+        while ranges.len() >= 8 {
+            let start = ranges.get_u32_le();
+            let end = ranges.get_u32_le();
+            trace!("input sack: [{}, {}) ts={}", start, end, ts);
+
+            // Only the sn in flight, clamped as offsets from `snd_una` so a bogus range couldn't
+            // wrap around
+            let flight = serial_diff(self.snd_nxt, self.snd_una);
+            let from = serial_diff(start, self.snd_una).clamp(0, flight);
+            let to = serial_diff(end, self.snd_una).clamp(0, flight);
+            if to <= from {
+                continue;
+            }
+            let start = self.snd_una.wrapping_add(from as u32);
+            let end = self.snd_una.wrapping_add(to as u32);
+
+            let mut sn = start;
+            while sn != end {
+                self.parse_ack(sn);
+                sn = sn.wrapping_add(1);
+            }
+            self.stats.acks_received += end.wrapping_sub(start) as u64;
+            max_ack.update(end.wrapping_sub(1), ts, self.fastack_conserve);
+            acked = true;
+        }
+
+        if acked {
+            let rtt = timediff(self.current, ts);
+            if rtt >= 0 {
+                self.update_ack(rtt as u32);
+            }
+            self.shrink_buf();
+        }
+    }
+
+    fn parse_una(&mut self, una: u32) {
+        while let Some(seg) = self.snd_buf.front() {
+            if serial_lt(seg.sn, una) {
+                let len = seg.data.len();
+                self.snd_buf.pop_front();
+                self.record_delivered(len);
+            } else {
+                break;
+            }
+        }
+    }
+
+    // Count `bytes` acknowledged now for `delivery_rate`, dropping the samples out of the window
+    fn record_delivered(&mut self, bytes: usize) {
+        let window = self.ticks(KCP_RATE_WINDOW) as i32;
+        while let Some(&(ts, _)) = self.delivered.front() {
+            if timediff(self.current, ts) < window {
+                break;
+            }
+            self.delivered.pop_front();
+        }
+
+        match self.delivered.back_mut() {
+            Some((ts, sum)) if *ts == self.current => *sum += bytes,
+            _ => self.delivered.push_back((self.current, bytes)),
+        }
+    }
+
+    fn parse_fastack(&mut self, sn: u32, ts: u32) {
+        if serial_lt(sn, self.snd_una) || serial_le(self.snd_nxt, sn) {
+            return;
+        }
+
+        let mut dup = false;
+        for seg in &mut self.snd_buf {
+            if serial_lt(sn, seg.sn) {
+                break;
+            } else if sn != seg.sn && (self.fastack_conserve || timediff(ts, seg.ts) >= 0) {
+                seg.fastack += 1;
+                dup = true;
+            }
+        }
+        if dup {
+            self.path_metrics.dup_acks_received += 1;
+        }
+    }
+
+    #[inline]
+    fn ack_push(&mut self, sn: u32, ts: u32) {
+        if self.acklist.is_empty() {
+            self.ts_ack = self.current;
+        }
+        self.acklist.push_back((sn, ts));
+    }
+
+    fn parse_data(&mut self, new_segment: KcpSegment) {
+        let sn = new_segment.sn;
+
+        if serial_le(self.rcv_nxt.wrapping_add(self.rcv_wnd), sn) || serial_lt(sn, self.rcv_nxt) {
+            return;
+        }
+
+        match self.rcv_buf_search(sn) {
+            Ok(_) => self.stats.duplicate_pushes_received += 1,
+            Err(new_index) => {
+                if new_index != self.rcv_buf.len() {
+                    self.path_metrics.out_of_order_segments += 1;
+                }
+                self.detect_reordering(sn, new_segment.ts);
+                self.rcv_buf.insert(new_index, new_segment);
+            }
+        }
+
+        // move available data from rcv_buf -> rcv_queue
+        self.move_buf();
+    }
+
+    /// Acknowledge every segment before `una` as an ACK from the remote would, for tests
+    ///
+    /// The segments are dropped from the send buffer, the RTT and the congestion window are left
+    /// alone.
+    #[cfg(feature = "testing")]
+    pub fn force_ack_upto(&mut self, una: u32) {
+        self.parse_una(una);
+        self.shrink_buf();
+    }
+
+    /// Start sending at `snd_nxt` and receiving at `rcv_nxt`, for tests
+    ///
+    /// Both endpoints must agree, call it before any data has been sent or received. It reaches
+    /// sequence numbers near the wrap around without sending that many segments.
+    #[cfg(feature = "testing")]
+    pub fn force_initial_sn(&mut self, snd_nxt: u32, rcv_nxt: u32) {
+        self.set_initial_sn(snd_nxt, rcv_nxt);
+    }
+
+    /// Place a PUSH segment of `header` and `data` as if received, for tests
+    ///
+    /// It goes through the receive buffer like one from `input`, but no ACK is sent for it.
+    /// `header.len` is ignored, and the window and `una` of the remote are not taken.
+    #[cfg(feature = "testing")]
+    pub fn inject_segment(&mut self, header: &SegmentHeader, data: &[u8]) {
+        let mut segment = KcpSegment::new_with_data(BytesMut::from(data));
+        segment.conv = header.conv;
+        segment.cmd = header.cmd;
+        segment.frg = header.frg;
+        segment.wnd = header.wnd;
+        segment.ts = header.ts;
+        segment.sn = header.sn;
+        segment.una = header.una;
+        self.parse_data(segment);
+    }
+
+    // Index of `sn` in `rcv_buf`, or where to insert it, sorted within the receive window
+    #[inline]
+    fn rcv_buf_search(&self, sn: u32) -> Result<usize, usize> {
+        self.rcv_buf
+            .binary_search_by(|segment| serial_diff(segment.sn, sn).cmp(&0))
+    }
+
+    // Check if a new segment `sn` ahead of `rcv_nxt` would exceed `rcv_buf_limit`
+    fn rcv_buf_full(&self, sn: u32) -> bool {
+        self.rcv_buf_limit > 0
+            && serial_lt(self.rcv_nxt, sn)
+            && self.rcv_buf.len() >= self.rcv_buf_limit
+            && self.rcv_buf_search(sn).is_err()
+    }
+
+    fn detect_reordering(&mut self, sn: u32, ts: u32) {
+        let next = sn.wrapping_add(1);
+        if serial_lt(self.rcv_max, next) {
+            self.rcv_max = next;
+            self.ts_rcv_max = ts;
+        } else if timediff(ts, self.ts_rcv_max) <= 0 {
+            // Filling a hole, and not a retransmission sent after the latest segment
+            let degree = self.rcv_max.wrapping_sub(next);
+            self.path_metrics.reordered_on_recv += 1;
+            if degree > self.reorder {
+                trace!("reordering degree {} detected, sn={}", degree, sn);
+                self.reorder = degree;
+            }
+        }
+    }
+
+    /// Get `conv` from the next `input` call
+    #[inline]
+    pub fn input_conv(&mut self) {
+        self.input_conv = true;
+    }
+
+    /// Set whether `input` aborts at a segment of another conv, default is true
+    ///
+    /// With `ConvMismatch` returned, nothing from that segment on is input. Its `offset` is where
+    /// the segment starts, the bytes before it have been input, so a demultiplexer could pass
+    /// the rest on to the `Kcp` of the `found` conv, unless `set_crypt` covers the whole
+    /// datagram. Otherwise these segments are skipped, so a stray one doesn't lose the valid
+    /// segments batched after it.
+    #[inline]
+    pub fn set_strict_conv(&mut self, strict: bool) {
+        self.strict_conv = strict;
+    }
+
+    /// Check if `input` aborts at a segment of another conv
+    #[inline]
+    pub fn strict_conv(&self) -> bool {
+        self.strict_conv
+    }
+
+    /// Set whether `input` aborts with `ConvInconsistent` instead of `ConvMismatch`, default is
+    /// false
+    ///
+    /// For the users matching the error of older versions, it doesn't tell the offset.
+    #[inline]
+    pub fn set_legacy_conv_error(&mut self, enabled: bool) {
+        self.legacy_conv_error = enabled;
+    }
+
+    /// Check if `input` aborts with `ConvInconsistent` instead of `ConvMismatch`
+    #[inline]
+    pub fn legacy_conv_error(&self) -> bool {
+        self.legacy_conv_error
+    }
+
+    /// Check if Kcp is waiting for the next input
+    #[inline]
+    pub fn waiting_conv(&self) -> bool {
+        self.input_conv
+    }
+
+    /// Set `conv` value
+    #[inline]
+    pub fn set_conv(&mut self, conv: u32) {
+        self.conv = conv;
+    }
+
+    /// Get `conv`
+    #[inline]
+    pub fn conv(&self) -> u32 {
+        self.conv
+    }
+
+    /// Get the output
+    #[inline]
+    pub fn output_ref(&self) -> &Output {
+        &self.output.0
+    }
+
+    /// Get the output for configuration
+    #[inline]
+    pub fn output_mut(&mut self) -> &mut Output {
+        &mut self.output.0
+    }
+
+    /// Call this when you received bytes from a stream connection
+    ///
+    /// Bytes are buffered until a complete segment is available, so segments could be split
+    /// arbitrarily by the transport. Returns the size of the segments processed in this call.
+    ///
+    /// A payload length above the MTU fails with `Error::InvalidSegmentDataSize` after the
+    /// segments before it are processed, and drops the bytes buffered, as the stream couldn't be
+    /// resynchronized. So no more than a segment of the MTU is ever buffered.
+    pub fn input_framed(&mut self, buf: &[u8]) -> KcpResult<usize> {
+        self.framed.extend_from_slice(buf);
+
+        let mut end = 0;
+        let mut oversized = None;
+        while self.framed.len() - end >= self.overhead() {
+            let mut header = &self.framed[end + self.wire_header_len() - 4..];
+            let len = get_u32(&mut header, self.big_endian) as usize;
+            if len > self.mtu {
+                oversized = Some(len);
+                break;
+            }
+            if self.framed.len() - end - self.overhead() < len {
+                break;
+            }
+            end += self.overhead() + len;
+        }
+
+        let processed = if end > 0 {
+            let segments = self.framed.split_to(end);
+            self.input(&segments)?
+        } else {
+            0
+        };
+
+        if let Some(len) = oversized {
+            debug!("framed payload length={} above mtu={}", len, self.mtu);
+            self.framed.clear();
+            return Err(Error::InvalidSegmentDataSize(self.mtu, len));
+        }
+        Ok(processed)
+    }
+
+    /// Call this when you received a packet from raw connection
+    pub fn input(&mut self, buf: &[u8]) -> KcpResult<usize> {
+        self.input_segments(buf, false)
+    }
+
+    /// Call this when you received a packet from raw connection, ignores a malformed tail
+    ///
+    /// Segments before the first malformed one are processed, returns the bytes they take. It
+    /// fails as `input` only if the first segment is malformed.
+    pub fn input_lenient(&mut self, buf: &[u8]) -> KcpResult<usize> {
+        self.input_segments(buf, true)
+    }
+
+    /// Call this when you received a packet from raw connection, then receive all the complete
+    /// messages into `out`, returns how many
+    ///
+    /// Same as `input` followed by `recv_batch` without a limit. The ACKs of the packet are only
+    /// queued, `update` or `flush` still has to send them. On an error of `input` nothing is
+    /// received, the messages completed by the segments before the bad one are left for `recv`.
+    pub fn input_and_recv(&mut self, buf: &[u8], out: &mut Vec<BytesMut>) -> KcpResult<usize> {
+        self.input(buf)?;
+        Ok(self.recv_batch(out, 0))
+    }
+
+    /// Call this when you received a packet from raw connection, held in any `Buf`
+    ///
+    /// Works as `input`, for a packet in a chain of `Bytes` or a ring buffer, which doesn't have
+    /// to be copied into a contiguous slice first. Headers are read across the chunks, payloads
+    /// copied into the segments as usual. With `set_crypt` or a checksum, a packet of several
+    /// chunks is still gathered first.
+    pub fn input_buf<B: Buf>(&mut self, buf: B) -> KcpResult<usize> {
+        self.input_segments(buf, false)
+    }
+
+    /// Call this when you received several packets from raw connection, like a `recvmmsg` batch
+    ///
+    /// Works as `input` on every packet, but updates the congestion window only once. A malformed
+    /// packet is skipped without affecting the others, returns how many packets are accepted.
+    pub fn input_many(&mut self, packets: &[&[u8]]) -> KcpResult<usize> {
+        let old_una = self.snd_una;
+        let mut max_ack = MaxAck::default();
+
+        let mut accepted = 0;
+        for packet in packets {
+            match self.decode_segments(*packet, false, &mut max_ack) {
+                Ok(..) => accepted += 1,
+                Err(err) => debug!("input_many skipped {} bytes: {}", packet.len(), err),
+            }
+        }
+
+        self.input_done(old_una, &max_ack);
+        if accepted > 0 {
+            self.wake_idle();
+        }
+        Ok(accepted)
+    }
+
+    // Process all segments in `buf`. Segments before an error are processed anyway, the error is
+    // ignored in `lenient` mode if there is any.
+    fn input_segments<B: Buf>(&mut self, buf: B, lenient: bool) -> KcpResult<usize> {
+        let old_una = self.snd_una;
+        let mut max_ack = MaxAck::default();
+        let result = self.decode_segments(buf, lenient, &mut max_ack);
+        self.input_done(old_una, &max_ack);
+        if result.is_ok() {
+            self.wake_idle();
+        }
+        result
+    }
+
+    // Decode segments in `buf`, ACKs are collected into `max_ack` for `input_done`
+    fn decode_segments<B: Buf>(
+        &mut self,
+        mut buf: B,
+        lenient: bool,
+        max_ack: &mut MaxAck,
+    ) -> KcpResult<usize> {
+        let input_size = buf.remaining();
+
+        trace!("[RI] {} bytes", input_size);
+
+        if input_size < self.overhead() {
+            debug!(
+                "input bufsize={} too small, at least {}",
+                input_size,
+                self.overhead()
+            );
+            return Err(Error::InvalidSegmentSize(input_size));
+        }
+
+        if let Some(ref crypt) = self.crypt {
+            let mut data = BytesMut::with_capacity(input_size);
+            data.put(buf);
+            let clear = if self.compact { 0 } else { 4 };
+            let n = crypt.decrypt(&mut data[clear..])?;
+            data.truncate(clear + n);
+            return self.decode_plain(&data[..], input_size, lenient, max_ack);
+        }
+
+        // Checksums and session tokens are verified over contiguous segments
+        if (self.checksum || self.session_token.is_some()) && buf.chunk().len() < input_size {
+            let data = buf.copy_to_bytes(input_size);
+            return self.decode_plain(&data[..], input_size, lenient, max_ack);
+        }
+
+        self.decode_plain(buf, input_size, lenient, max_ack)
+    }
+
+    // Decode segments in `buf` after decryption, contiguous if checksums or session tokens are
+    // enabled
+    fn decode_plain<B: Buf>(
+        &mut self,
+        mut buf: B,
+        input_size: usize,
+        lenient: bool,
+        max_ack: &mut MaxAck,
+    ) -> KcpResult<usize> {
+        let mut error = None;
+        let mut consumed = 0;
+
+        let total = buf.remaining();
+        let token_len = self.token_len();
+        let trailer_len = token_len + self.checksum_len();
+        while buf.remaining() >= self.overhead() {
+            consumed = total - buf.remaining();
+            let checksum_ok = !self.checksum
+                || checksum_ok(
+                    buf.chunk(),
+                    self.wire_header_len(),
+                    token_len,
+                    self.big_endian,
+                );
+
+            let conv = if self.compact {
+                self.conv
+            } else {
+                get_u32(&mut buf, self.big_endian)
+            };
+            let stray = !self.input_conv && conv != self.conv;
+            if stray && self.strict_conv {
+                debug!("input conv={} expected conv={} not match", conv, self.conv);
+                error = Some(if self.legacy_conv_error {
+                    Error::ConvInconsistent(self.conv, conv)
+                } else {
+                    Error::ConvMismatch {
+                        expected: self.conv,
+                        found: conv,
+                        offset: consumed,
+                    }
+                });
+                break;
+            }
+
+            let cmd = buf.get_u8();
+            let frg = buf.get_u8();
+            let wnd = get_u16(&mut buf, self.big_endian);
+            let ts = get_u32(&mut buf, self.big_endian);
+            let sn = get_u32(&mut buf, self.big_endian);
+            let una = get_u32(&mut buf, self.big_endian);
+            let len = get_u32(&mut buf, self.big_endian) as usize;
+
+            if buf.remaining() < len + trailer_len {
+                debug!(
+                    "input bufsize={} payload length={} remaining={} not match",
+                    input_size,
+                    len,
+                    buf.remaining()
+                );
+                error = Some(Error::InvalidSegmentDataSize(len, buf.remaining()));
+                break;
+            }
+
+            if !checksum_ok {
+                debug!("input conv={} sn={} checksum mismatch", conv, sn);
+                error = Some(Error::ChecksumMismatch);
+                break;
+            }
+
+            if let Some(token) = self.session_token {
+                if (&buf.chunk()[len..]).get_u32_le() != token {
+                    debug!("input conv={} sn={} session token mismatch", conv, sn);
+                    error = Some(Error::SessionMismatch);
+                    break;
+                }
+            }
+
+            if stray {
+                debug!("input conv={} expected conv={} skipped", conv, self.conv);
+                buf.advance(len + trailer_len);
+                continue;
+            }
+
+            match cmd {
+                KCP_CMD_PUSH | KCP_CMD_ACK | KCP_CMD_WASK | KCP_CMD_WINS => {}
+                #[cfg(feature = "sack")]
+                KCP_CMD_SACK => {}
+                #[cfg(feature = "fin")]
+                KCP_CMD_FIN => {}
+                #[cfg(feature = "wscale")]
+                KCP_CMD_WSCALE => {}
+                #[cfg(feature = "partial")]
+                KCP_CMD_FORWARD => {}
+                #[cfg(feature = "handshake")]
+                KCP_CMD_SYN | KCP_CMD_SYNACK => {}
+                _ => {
+                    debug!("input cmd={} unrecognized", cmd);
+                    error = Some(Error::UnsupportedCmd(cmd));
+                    break;
+                }
+            }
+
+            // This allows getting conv from this call, which allows us to allocate
+            // conv from the server side. Taken only from a complete segment, even if it
+            // equals the current one, so a truncated one keeps waiting.
+            if self.input_conv {
+                debug!("input conv={} updated, original conv={}", conv, self.conv);
+                self.conv = conv;
+                self.input_conv = false;
+            }
+
+            #[cfg(feature = "isn")]
+            let una = match self.accept_isn(cmd, sn, una) {
+                Some(una) => una,
+                None => {
+                    debug!(
+                        "input conv={} sn={} before the remote ISN, skipped",
+                        conv, sn
+                    );
+                    buf.advance(len + trailer_len);
+                    continue;
+                }
+            };
+
+            self.ts_last_recv = self.current;
+            self.update_rmt_wnd(wnd);
+
+            self.parse_una(una);
+            self.shrink_buf();
+            #[cfg(feature = "partial")]
+            if self
+                .forward_sn
+                .is_some_and(|forward| serial_le(forward, una))
+            {
+                self.forward_sn = None;
+            }
+            // Anything else but a request of the remote proves it has accepted
+            #[cfg(feature = "handshake")]
+            if self.connecting && cmd != KCP_CMD_SYN {
+                debug!("conv={} connection established", self.conv);
+                self.connecting = false;
+            }
+
+            let mut has_read_data = false;
+
+            match cmd {
+                KCP_CMD_ACK => {
+                    self.stats.acks_received += 1;
+                    let rtt = timediff(self.current, ts);
+                    if rtt >= 0 {
+                        if let Some(ref mut handler) = self.ack_sample_handler {
+                            handler(AckSample {
+                                sn,
+                                rtt: rtt as u32,
+                                send_ts: ts,
+                                recv_ts: self.current,
+                            });
+                        }
+                        self.update_ack(rtt as u32);
+                    }
+                    self.parse_ack(sn);
+                    self.shrink_buf();
+                    max_ack.update(sn, ts, self.fastack_conserve);
+
+                    trace!(
+                        "input ack: sn={} rtt={} rto={}",
+                        sn,
+                        timediff(self.current, ts),
+                        self.rx_rto
+                    );
+                }
+                _ if is_push(cmd) => {
+                    trace!("input psh: cmd={} sn={} ts={}", cmd, sn, ts);
+                    self.stats.pushes_received += 1;
+
+                    if self.rcv_buf_full(sn) {
+                        trace!("input psh: sn={} dropped, rcv_buf is full", sn);
+                    } else if serial_lt(sn, self.rcv_nxt.wrapping_add(self.rcv_wnd)) {
+                        self.ack_push(sn, ts);
+                        if serial_le(self.rcv_nxt, sn) {
+                            let mut sbuf = BytesMut::with_capacity(len);
+                            sbuf.put((&mut buf).take(len));
+                            has_read_data = true;
+
+                            let mut segment = KcpSegment::new_with_data(sbuf);
+
+                            segment.conv = conv;
+                            segment.cmd = cmd;
+                            segment.frg = frg;
+                            segment.wnd = wnd;
+                            segment.ts = ts;
+                            segment.sn = sn;
+                            segment.una = una;
+
+                            self.parse_data(segment);
+                        } else {
+                            self.stats.stale_pushes_received += 1;
+                        }
+                    }
+                }
+                KCP_CMD_WASK => {
+                    // ready to send back IKCP_CMD_WINS in ikcp_flush
+                    // tell remote my window size
+                    trace!("input probe");
+                    self.probe |= KCP_ASK_TELL;
+                }
+                KCP_CMD_WINS => {
+                    trace!("input wins: {}", wnd);
+                    self.mtu_probe_answered();
+                }
+                #[cfg(feature = "sack")]
+                KCP_CMD_SACK => {
+                    let chunk = buf.chunk();
+                    if chunk.len() >= len {
+                        self.parse_sack(&chunk[..len], ts, max_ack);
+                    } else {
+                        let ranges = buf.copy_to_bytes(len);
+                        has_read_data = true;
+                        self.parse_sack(&ranges, ts, max_ack);
+                    }
+                }
+                #[cfg(feature = "wscale")]
+                KCP_CMD_WSCALE => {
+                    self.parse_wscale(frg, sn);
+                    self.update_rmt_wnd(wnd);
+                }
+                #[cfg(feature = "partial")]
+                KCP_CMD_FORWARD => {
+                    self.parse_forward(sn);
+                }
+                #[cfg(feature = "handshake")]
+                KCP_CMD_SYN => {
+                    trace!("input syn");
+                    self.synack = true;
+                }
+                #[cfg(feature = "handshake")]
+                KCP_CMD_SYNACK => {
+                    trace!("input synack");
+                }
+                _ => unreachable!(),
+            }
+
+            // Force skip unread data
+            if !has_read_data {
+                buf.advance(len);
+            }
+            buf.advance(trailer_len);
+        }
+
+        match error {
+            Some(err) if !lenient || consumed == 0 => Err(err),
+            Some(err) => {
+                debug!("input ignored {} bytes: {}", input_size - consumed, err);
+                Ok(consumed)
+            }
+            None => Ok(total - buf.remaining()),
+        }
+    }
+
+    // Fast-ack scan and congestion window update after segments are decoded
+    fn input_done(&mut self, old_una: u32, max_ack: &MaxAck) {
+        if max_ack.flag {
+            self.parse_fastack(max_ack.sn, max_ack.ts);
+        }
+
+        let acked = self.snd_una.wrapping_sub(old_una);
+        if (acked as i32) > 0 {
+            let state = self.congestion_state(0);
+            self.cc_algo_mut().on_ack(acked, &state);
+        }
+
+        if !self.acklist.is_empty() {
+            self.warn_not_updated("input");
+        }
+        if self.ack_nodelay && self.updated && !self.acklist.is_empty() {
+            self.encode_acks(false);
+        }
+    }
+
+    // Warn once that nothing is written until the first `update`, a common mistake
+    fn warn_not_updated(&mut self, op: &str) {
+        if !self.updated && !self.update_warned {
+            warn!(
+                "{} called before update, nothing is written until update is called",
+                op
+            );
+            self.update_warned = true;
+        }
+    }
+
+    // Advertised receive window on the wire, scaled once the remote knows the shift
+    fn encode_wnd(&self, wnd: u32) -> u16 {
+        let shift = match self.wscale_peer {
+            Some(_) => self.wscale.unwrap_or(0),
+            None => 0,
+        };
+        cmp::min(wnd >> shift, u16::MAX as u32) as u16
+    }
+
+    // Remote receive window from the wire
+    fn decode_wnd(&self, wnd: u16) -> u32 {
+        match self.wscale_peer {
+            Some(shift) if self.wscale_decode => (wnd as u32) << shift,
+            _ => wnd as u32,
+        }
+    }
+
+    fn wnd_unused(&self) -> u32 {
+        if let Some(wnd) = self.wnd_override {
+            return wnd;
+        }
+
+        if self.rcv_queue.len() < self.rcv_wnd as usize {
+            self.rcv_wnd - self.rcv_queue.len() as u32
+        } else {
+            0
+        }
+    }
+
+    // Check if the remote window should be probed, not necessary when idle without anything to send
+    fn should_probe(&self) -> bool {
+        let idle = self.idle && self.snd_queue.is_empty() && self.snd_buf.is_empty();
+        self.rmt_wnd == 0 && !idle
+    }
+
+    // Check if a window probe will be sent by flush at `current`
+    fn probe_due(&self, current: u32) -> bool {
+        self.should_probe() && self.probe_wait != 0 && timediff(current, self.ts_probe) >= 0
+    }
+
+    fn probe_wnd_size(&mut self) {
+        // probe window size (if remote window size equals zero)
+        if self.should_probe() {
+            if self.probe_wait == 0 {
+                self.probe_wait = self.ticks(KCP_PROBE_INIT);
+                self.ts_probe = self.current + self.probe_wait;
+            } else {
+                if timediff(self.current, self.ts_probe) >= 0 {
+                    if self.probe_wait < self.ticks(KCP_PROBE_INIT) {
+                        self.probe_wait = self.ticks(KCP_PROBE_INIT);
+                    }
+
+                    self.probe_wait += self.probe_wait / 2;
+
+                    if self.probe_wait > self.ticks(KCP_PROBE_LIMIT) {
+                        self.probe_wait = self.ticks(KCP_PROBE_LIMIT);
+                    }
+
+                    self.ts_probe = self.current + self.probe_wait;
+                    self.probe |= KCP_ASK_SEND;
+                }
+            }
+        } else {
+            self.ts_probe = 0;
+            self.probe_wait = 0;
+        }
+    }
+
+    /// Advance the clock to `current`, returns `true` if it is time to flush
+    fn update_clock(&mut self, current: u32) -> bool {
+        let previous = self.current;
+        if self.updated && timediff(current, self.current) < 0 {
+            // The clock went backwards, keep using the last known time until it catches up,
+            // otherwise all the resend timers will be postponed. Warned once, a jittery clock
+            // would flood the log, see `clock_went_backwards_count`.
+            if self.clock_went_backwards == 0 {
+                warn!(
+                    "update current={} went backwards, last current={}",
+                    current, self.current
+                );
+            } else {
+                debug!(
+                    "update current={} went backwards, last current={}",
+                    current, self.current
+                );
+            }
+            self.clock_went_backwards += 1;
+        } else {
+            self.current = current;
+        }
+
+        if !self.updated {
+            self.updated = true;
+            self.ts_flush = self.current;
+            self.ts_last_send = self.current;
+            self.ts_last_recv = self.current;
+            self.rebase_timers(previous);
+        }
+
+        let mut slap = timediff(self.current, self.ts_flush);
+
+        if self.out_of_flush_clamp(slap) {
+            self.ts_flush = self.current;
+            slap = 0;
+        }
+
+        if self.idle && !self.is_quiet() {
+            // Back to normal cadence while there is something to do
+            self.idle_wait = self.interval;
+            if timediff(self.ts_flush, self.current.wrapping_add(self.interval)) > 0 {
+                self.ts_flush = self.current;
+                slap = 0;
+            }
+        }
+
+        if slap >= 0 {
+            let interval = self.flush_interval();
+            self.ts_flush = self.ts_flush.wrapping_add(interval);
+            if timediff(self.current, self.ts_flush) >= 0 {
+                self.ts_flush = self.current.wrapping_add(interval);
+            }
+
+            if self.idle && self.is_quiet() {
+                self.idle_wait = cmp::min(self.idle_wait * 2, self.ticks(self.idle_interval));
+            }
+            return true;
+        }
+
+        false
+    }
+
+    // Check if there is nothing to send or acknowledge
+    fn is_quiet(&self) -> bool {
+        self.acklist.is_empty()
+            && self.snd_queue.is_empty()
+            && self.snd_buf.is_empty()
+            && self.probe == 0
+    }
+
+    // Shrink the idle interval back on `send` or `input`, flushing at the next `update` if the
+    // flush timer was stretched beyond it
+    fn wake_idle(&mut self) {
+        if !self.idle {
+            return;
+        }
+        self.idle_wait = self.interval;
+        if self.updated && timediff(self.ts_flush, self.current.wrapping_add(self.interval)) > 0 {
+            self.ts_flush = self.current;
+        }
+    }
+
+    // Interval to the next flush, stretched when idle
+    fn flush_interval(&self) -> u32 {
+        if self.idle && self.is_quiet() {
+            let interval = cmp::max(self.idle_wait, self.interval);
+            if self.keepalive > 0 {
+                // Keepalives are still sent in time
+                cmp::min(interval, cmp::max(self.keepalive, self.interval))
+            } else {
+                interval
+            }
+        } else {
+            self.interval
+        }
+    }
+
+    /// Check if `flush` at `current` would write anything: ACKs, window probes or data segments
+    ///
+    /// Datagrams failed to be written before are counted as well.
+    pub fn would_flush(&self, current: u32) -> bool {
+        if !self.updated {
+            return false;
+        }
+
+        if !self.output_queue.is_empty() || !self.buf.is_empty() {
+            return true;
+        }
+
+        if self.probe != 0 || self.probe_due(current) {
+            return true;
+        }
+
+        if !self.acklist.is_empty() && !self.defer_ack(current) {
+            return true;
+        }
+
+        #[cfg(feature = "partial")]
+        if self.forward_due(current) {
+            return true;
+        }
+
+        #[cfg(feature = "handshake")]
+        if self.handshake_due(current) {
+            return true;
+        }
+
+        self.has_data_to_send(current) || self.keepalive_due(current) || self.mtu_probe_pending()
+    }
+
+    /// Check if `flush` would write anything at the clock of the last `update`, see `would_flush`
+    #[inline]
+    pub fn has_pending_output(&self) -> bool {
+        self.would_flush(self.current)
+    }
+
+    /// Size of everything `flush` at `current` would write: ACKs, window probes and data segments
+    ///
+    /// Datagrams failed to be written before are counted as well.
+    pub fn pending_output_size(&self, current: u32) -> usize {
+        if !self.updated {
+            return 0;
+        }
+
+        let mut size = self.buf.len() + self.output_queue.iter().map(|d| d.len()).sum::<usize>();
+
+        if !self.defer_ack(current) {
+            size += self.acklist.len() * self.overhead();
+        }
+
+        let mut probe = self.probe;
+        if self.probe_due(current) {
+            probe |= KCP_ASK_SEND;
+        }
+        if (probe & KCP_ASK_SEND) != 0 {
+            size += self.overhead();
+        }
+        if (probe & KCP_ASK_TELL) != 0 {
+            size += self.overhead();
+        }
+        #[cfg(feature = "partial")]
+        if self.forward_due(current) {
+            size += self.overhead();
+        }
+        #[cfg(feature = "handshake")]
+        if self.handshake_due(current) {
+            size += self.overhead();
+        }
+
+        size += self.pending_data_size(current);
+        if self.mtu_probe_pending() {
+            size += self.mtu_probe - self.reserved;
+        } else if size == 0 && self.keepalive_due(current) {
+            size += self.overhead();
+        }
+        size
+    }
+
+    // Size of PUSH segments flush at `current` would send
+    fn pending_data_size(&self, current: u32) -> usize {
+        if self.rate_wait(current) > 0 {
+            return 0;
+        }
+
+        let mut tokens = match self.rate_limit {
+            0 => i64::MAX,
+            _ => self.rate_tokens_at(current).unwrap_or(self.rate_tokens),
+        };
+        let max_burst = match self.max_burst {
+            0 => usize::MAX,
+            n => n,
+        };
+
+        let conn = ConnectionState {
+            current,
+            ..self.connection_state()
+        };
+        let detector = self.loss_detector();
+
+        let mut size = 0;
+        let mut burst = 0;
+
+        let mut send = |len: usize| {
+            if tokens > 0 {
+                size += self.overhead() + len;
+                tokens -= (self.overhead() + len) as i64;
+            }
+        };
+
+        for seg in &self.snd_buf {
+            if seg.xmit == 0 {
+                if burst < max_burst {
+                    burst += 1;
+                    send(seg.data.len());
+                }
+            } else if seg.detect_loss(detector, &conn) != Retransmit::No {
+                send(seg.data.len());
+            }
+        }
+
+        // Segments moved from snd_queue
+        let window = self.snd_una.wrapping_add(self.send_window());
+        let room = cmp::max(serial_diff(window, self.snd_nxt), 0) as usize;
+        let mut committed = self.snd_frg;
+        let queued = self.snd_queue.iter().filter(|seg| {
+            if committed > 0 {
+                committed -= 1;
+                return true;
+            }
+            seg.deadline
+                .is_none_or(|deadline| timediff(current, deadline) < 0)
+        });
+        for seg in queued.take(cmp::min(room, max_burst)) {
+            if burst >= max_burst {
+                break;
+            }
+            burst += 1;
+            send(seg.data.len());
+        }
+
+        size
+    }
+
+    /// Determine when you should call `update`.
+    /// Return when you should invoke `update` in millisec (clock ticks, see `set_tick_resolution`),
+    /// if there is no `input`/`send` calling.
+    /// You can call `update` in that time without calling it repeatly.
+    pub fn check(&self, current: u32) -> u32 {
+        timediff(self.next_flush_time(current), current) as u32
+    }
+
+    /// Determine the timestamp at which you should call `update`, `current + check(current)`
+    ///
+    /// Timestamps are `u32` counters wrapping around, the returned one may be numerically
+    /// smaller than `current` after a wrap. Compare them with the wrapping difference, as in
+    /// `(deadline.wrapping_sub(now) as i32) <= 0`, it is never earlier than `current`.
+    pub fn next_flush_time(&self, current: u32) -> u32 {
+        if !self.updated || (self.ack_nodelay && !self.output_queue.is_empty()) {
+            return current;
+        }
+
+        let mut deadline = self.flush_tick(current);
+        if deadline == current {
+            return current;
+        }
+
+        let rate_wait = self.rate_wait(current) as i32;
+        for seg in &self.snd_buf {
+            let due = if seg.nack {
+                0
+            } else {
+                timediff(seg.resendts, current)
+            };
+            // Nothing could be resent before the rate limit allows
+            let diff = cmp::max(due, rate_wait);
+            if diff <= 0 {
+                return current;
+            }
+            if diff < timediff(deadline, current) {
+                deadline = current.wrapping_add(diff as u32);
+            }
+        }
+
+        deadline
+    }
+
+    // Time of the next flush by the interval, `current` if it is due
+    fn flush_tick(&self, current: u32) -> u32 {
+        let mut ts_flush = self.ts_flush;
+
+        if self.out_of_flush_clamp(timediff(current, ts_flush)) {
+            ts_flush = current;
+        }
+
+        if self.idle
+            && !self.is_quiet()
+            && timediff(ts_flush, current.wrapping_add(self.interval)) > 0
+        {
+            ts_flush = current;
+        }
+
+        if timediff(current, ts_flush) >= 0 {
+            return current;
+        }
+
+        // Never later than the flush interval
+        let deadline = current.wrapping_add(self.flush_interval());
+        if timediff(ts_flush, deadline) < 0 {
+            ts_flush
+        } else {
+            deadline
+        }
+    }
+
+    /// Time until the next flush by the interval, in millisec (clock ticks)
+    ///
+    /// It is the part of `check` of the flush timer alone, 0 if it is due or `update` has not
+    /// been called yet.
+    pub fn time_until_flush(&self, current: u32) -> u32 {
+        if !self.updated {
+            return 0;
+        }
+        timediff(self.flush_tick(current), current) as u32
+    }
+
+    /// Time until the earliest retransmission timer expires, in millisec (clock ticks), `None` if
+    /// nothing is waiting for an ACK
+    ///
+    /// It is the part of `check` of the segments sent alone, 0 if one is overdue. The rate limit
+    /// of `set_rate_limit` may hold the retransmission back further.
+    pub fn time_until_retransmit(&self, current: u32) -> Option<u32> {
+        self.snd_buf
+            .iter()
+            .map(|seg| {
+                if seg.nack {
+                    0
+                } else {
+                    cmp::max(timediff(seg.resendts, current), 0) as u32
+                }
+            })
+            .min()
+    }
+
+    /// Change MTU size, default is 1400
+    ///
+    /// MTU = Maximum Transmission Unit
+    ///
+    /// Messages waiting in the send queue are fragmented again to fit a smaller MTU. Segments
+    /// already sent, and the rest of a message partially sent, are left alone and may exceed it.
+    /// Returns `Error::UserBufTooBig` and keeps the MTU if a queued message needs too many fragments.
+    /// It must leave room for the bytes of `set_reserved`.
+    pub fn set_mtu(&mut self, mtu: usize) -> KcpResult<()> {
+        if mtu < 50 || mtu <= self.overhead() + self.reserved {
+            debug!("set_mtu mtu={} invalid", mtu);
+            return Err(Error::InvalidMtu(mtu));
+        }
+
+        self.resize_mss(mtu, self.reserved)
+    }
+
+    /// Leave `reserved` bytes of every datagram for the layers below, like encryption or FEC
+    ///
+    /// The datagrams written are at most `mtu - reserved` bytes, and the MSS is reduced by it,
+    /// so a layer adding its own header still fits the MTU of the path. Queued messages are
+    /// fragmented again like `set_mtu`. Returns `Error::InvalidReserved` if no data fits.
+    pub fn set_reserved(&mut self, reserved: usize) -> KcpResult<()> {
+        if reserved >= self.mtu - self.overhead() {
+            debug!(
+                "set_reserved reserved={} mtu={} invalid",
+                reserved, self.mtu
+            );
+            return Err(Error::InvalidReserved(reserved));
+        }
+
+        self.resize_mss(self.mtu, reserved)
+    }
+
+    /// Get bytes of every datagram left for the layers below
+    #[inline]
+    pub fn reserved(&self) -> usize {
+        self.reserved
+    }
+
+    /// Lower the MTU after the path rejected a datagram, e.g. ICMP "fragmentation needed"
+    ///
+    /// `suggested` not lower than the current MTU is ignored as stale. Queued messages are
+    /// fragmented again like `set_mtu`, and a MTU probe is given up.
+    ///
+    /// Segments in flight already have their sequence numbers, splitting them would take new ones
+    /// which the remote couldn't reassemble the messages with, so they keep their size. If any of
+    /// them exceeds the new MTU, `Error::InflightExceedsMtu` is returned with how many, the MTU
+    /// is lowered all the same. Their resends are likely dropped by the path as well, the
+    /// connection may have to be given up.
+    pub fn on_mtu_exceeded(&mut self, suggested: usize) -> KcpResult<()> {
+        if suggested >= self.mtu {
+            return Ok(());
+        }
+
+        self.set_mtu(suggested)?;
+        self.mtu_probe = 0;
+        self.mtu_probe_deadline = None;
+
+        let limit = self.mtu - self.reserved - self.overhead();
+        let oversized = self
+            .snd_buf
+            .iter()
+            .filter(|seg| seg.xmit > 0 && seg.data.len() > limit)
+            .count();
+        if oversized > 0 {
+            debug!("{} segments in flight exceed mtu={}", oversized, self.mtu);
+            return Err(Error::InflightExceedsMtu(oversized));
+        }
+        Ok(())
+    }
+
+    /// Probe whether the path carries datagrams of `mtu` bytes, and raise the MTU to it if so
+    ///
+    /// A window probe (`WASK`) padded to `mtu` is sent alone in a datagram by the next `flush`,
+    /// which any KCP answers with its window. The MTU is raised when the answer arrives within
+    /// the RTO, otherwise it is kept. Answers to window probes can't be told apart, so the probe
+    /// is given up if another one is sent before. Returns `Error::InvalidMtu` if `mtu` is not
+    /// larger than the current one.
+    pub fn probe_mtu(&mut self, mtu: usize) -> KcpResult<()> {
+        if mtu <= self.mtu {
+            debug!("probe_mtu mtu={} not larger than {}", mtu, self.mtu);
+            return Err(Error::InvalidMtu(mtu));
+        }
+
+        self.mtu_probe = mtu;
+        self.mtu_probe_deadline = None;
+        Ok(())
+    }
+
+    /// Check if a MTU probe is waiting to be sent or answered
+    #[inline]
+    pub fn is_probing_mtu(&self) -> bool {
+        self.mtu_probe > 0
+    }
+
+    // A MTU probe is waiting to be sent
+    #[inline]
+    fn mtu_probe_pending(&self) -> bool {
+        self.mtu_probe > 0 && self.mtu_probe_deadline.is_none()
+    }
+
+    // The remote answered a window probe
+    fn mtu_probe_answered(&mut self) {
+        if self.mtu_probe_deadline.take().is_none() {
+            return;
+        }
+
+        let mtu = mem::take(&mut self.mtu_probe);
+        if mtu > self.mtu {
+            debug!("conv={} mtu probe {} answered", self.conv, mtu);
+            // Never fails with a larger MSS
+            let _ = self.resize_mss(mtu, self.reserved);
+        }
+    }
+
+    // Send the MTU probe alone in a datagram, or give it up if it is not answered in time
+    fn encode_mtu_probe(&mut self, wnd: u16) {
+        if self.mtu_probe == 0 {
+            return;
+        }
+
+        match self.mtu_probe_deadline {
+            Some(deadline) => {
+                if timediff(self.current, deadline) >= 0 {
+                    debug!("conv={} mtu probe {} lost", self.conv, self.mtu_probe);
+                    self.mtu_probe = 0;
+                    self.mtu_probe_deadline = None;
+                }
+            }
+            None => {
+                let probe = KcpSegment {
+                    conv: self.conv,
+                    cmd: KCP_CMD_WASK,
+                    wnd,
+                    ts: self.current,
+                    una: self.rcv_nxt,
+                    data: BytesMut::zeroed(self.mtu_probe - self.reserved - self.overhead()),
+                    ..Default::default()
+                };
+                self.finish_datagram();
+                probe.encode(
+                    &mut self.buf,
+                    self.compact,
+                    self.checksum,
+                    self.big_endian,
+                    self.session_token,
+                );
+                self.finish_datagram();
+                self.mtu_probe_deadline = Some(self.current.wrapping_add(self.rx_rto));
+            }
+        }
+    }
+
+    // Apply the MSS of `mtu` and `reserved`, if all queued messages could still be sent
+    fn resize_mss(&mut self, mtu: usize, reserved: usize) -> KcpResult<()> {
+        let mss = mtu - self.overhead() - reserved;
+        if !self.stream && mss < self.mss {
+            let kept = cmp::min(self.snd_frg, self.snd_queue.len());
+            let mut size = 0;
+            for seg in self.snd_queue.iter().skip(kept) {
+                size += seg.data.len();
+                if seg.frg == 0 {
+                    if size.div_ceil(mss) > self.max_fragments() {
+                        debug!("mss={} queued message={} too large", mss, size);
+                        return Err(Error::UserBufTooBig);
+                    }
+                    size = 0;
+                }
+            }
+        }
+
+        self.mtu = mtu;
+        self.reserved = reserved;
+        self.mss = mss;
+        self.refragment_queue();
+
+        let target_size = (mtu + KCP_OVERHEAD) * 3;
+        if target_size > self.buf.capacity() {
+            self.buf.reserve(target_size - self.buf.capacity());
+        }
+
+        Ok(())
+    }
+
+    // Split queued messages with segments larger than `mss`, except the one partially sent
+    fn refragment_queue(&mut self) {
+        let mss = self.mss;
+        let kept = cmp::min(self.snd_frg, self.snd_queue.len());
+        if self
+            .snd_queue
+            .iter()
+            .skip(kept)
+            .all(|seg| seg.data.len() <= mss)
+        {
+            return;
+        }
+
+        let mut rest = self.snd_queue.split_off(kept);
+        while let Some(seg) = rest.pop_front() {
+            let mut message = vec![seg];
+            while !self.stream && message[message.len() - 1].frg > 0 {
+                match rest.pop_front() {
+                    Some(seg) => message.push(seg),
+                    None => break,
+                }
+            }
+
+            if message.iter().all(|seg| seg.data.len() <= mss) {
+                self.snd_queue.extend(message);
+                continue;
+            }
+
+            let deadline = message[0].deadline;
+            let mut data = BytesMut::new();
+            for seg in &message {
+                data.extend_from_slice(&seg.data);
+            }
+
+            let count = data.len().div_ceil(mss);
+            for i in 0..count {
+                let size = cmp::min(mss, data.len());
+                let mut segment = KcpSegment::new_with_data(data.split_to(size));
+                segment.frg = if self.stream {
+                    0
+                } else {
+                    (count - i - 1) as u8
+                };
+                segment.deadline = deadline;
+                self.snd_queue.push_back(segment);
+            }
+        }
+    }
+
+    /// Bytes held in the send queue, the send buffer, the receive queue and the receive buffer,
+    /// headers included
+    ///
+    /// The footprint of the session to account against a budget shared by many of them. The
+    /// memory for output datagrams is `output_buf_capacity`.
+    pub fn buffered_bytes(&self) -> usize {
+        let overhead = self.overhead();
+        self.snd_queue
+            .iter()
+            .chain(&self.snd_buf)
+            .chain(&self.rcv_queue)
+            .chain(&self.rcv_buf)
+            .map(|seg| overhead + seg.data.len())
+            .sum()
+    }
+
+    /// Memory held for encoding output datagrams, including the ones not written yet
+    pub fn output_buf_capacity(&self) -> usize {
+        self.buf.capacity()
+            + self
+                .output_queue
+                .iter()
+                .map(|d| d.capacity())
+                .sum::<usize>()
+    }
+
+    /// Release the memory held for encoding output datagrams after a burst
+    ///
+    /// Datagrams not written yet are kept.
+    pub fn shrink_output_buf(&mut self) {
+        let target_size = cmp::max((self.mtu + KCP_OVERHEAD) * 3, self.buf.len());
+        if self.buf.capacity() > target_size {
+            let mut buf = BytesMut::with_capacity(target_size);
+            buf.extend_from_slice(&self.buf);
+            self.buf = buf;
+        }
+        self.output_queue.shrink_to_fit();
+    }
+
+    /// Reserve room for `snd_segments` more segments to send and `rcv_segments` to receive
+    ///
+    /// For a known workload, so the first burst doesn't allocate while growing the queues, and
+    /// the buffer encoding datagrams is sized for the MTU. It is advisory, the queues still grow
+    /// as needed beyond it, and the payload of every segment is allocated anyway.
+    pub fn reserve(&mut self, snd_segments: usize, rcv_segments: usize) {
+        self.snd_queue.reserve(snd_segments);
+        self.snd_buf.reserve(snd_segments);
+        self.rcv_queue.reserve(rcv_segments);
+        self.rcv_buf.reserve(rcv_segments);
+        self.acklist.reserve(rcv_segments);
+
+        let target_size = (self.mtu + KCP_OVERHEAD) * 3;
+        if self.buf.capacity() < target_size {
+            self.buf.reserve(target_size - self.buf.len());
+        }
+    }
+
+    /// Get MTU
+    #[inline]
+    pub fn mtu(&self) -> usize {
+        self.mtu
+    }
+
+    /// Set check interval
+    pub fn set_interval(&mut self, interval: u32) {
+        self.interval = self.ticks(interval.clamp(10, 5000));
+    }
+
+    /// Set nodelay
+    ///
+    /// fastest config: nodelay(true, 20, 2, true)
+    ///
+    /// `nodelay`: default is disable (false)
+    /// `interval`: internal update timer interval in millisec, default is 100ms
+    /// `resend`: 0:disable fast resend(default), 1:enable fast resend
+    /// `nc`: `false`: normal congestion control(default), `true`: disable congestion control
+    ///
+    /// A negative `resend` keeps the current one. See `configure` for the same with named
+    /// options.
+    pub fn set_nodelay(&mut self, nodelay: bool, interval: i32, resend: i32, nc: bool) {
+        let resend = if resend >= 0 {
+            resend as u32
+        } else {
+            self.fastresend
+        };
+        self.configure(NoDelayConfig {
+            nodelay,
+            interval: interval.max(0) as u32,
+            resend,
+            no_congestion: nc,
+        });
+    }
+
+    /// Set nodelay mode, interval, fast resend and congestion window at once
+    ///
+    /// `NoDelayConfig::fast()` is the fastest configuration, `NoDelayConfig::default()` the one
+    /// of `Kcp::new`.
+    pub fn configure(&mut self, cfg: NoDelayConfig) {
+        self.nodelay = cfg.nodelay;
+        self.rx_minrto = if cfg.nodelay {
+            self.ticks(KCP_RTO_NDL)
+        } else {
+            self.ticks(KCP_RTO_MIN)
+        };
+        self.interval = self.ticks(cfg.interval.clamp(10, 5000));
+        self.fastresend = cfg.resend;
+        self.set_nocwnd(cfg.no_congestion);
+    }
+
+    /// Disable the congestion window, same as `nc` of `set_nodelay`
+    ///
+    /// The congestion state of `ClassicCc` keeps changing with losses while disabled, so it is
+    /// started over when enabled again: slow start from 1 segment up to the send window, instead
+    /// of whatever the last loss left. A custom algorithm of `set_congestion_algo` is left alone.
+    pub fn set_nocwnd(&mut self, nocwnd: bool) {
+        if self.nocwnd && !nocwnd {
+            self.restart_congestion();
+        }
+        self.nocwnd = nocwnd;
+    }
+
+    // Start the congestion state of `ClassicCc` over, in slow start up to the send window
+    fn restart_congestion(&mut self) {
+        self.classic = ClassicCc {
+            ssthresh: cmp::max(cmp::min(self.snd_wnd, self.rmt_wnd), KCP_THRESH_INIT),
+            loss_based: self.classic.loss_based,
+            increase: self.classic.increase,
+            ..ClassicCc::default()
+        };
+        self.classic.ensure_cwnd(self.mss);
+    }
+
+    /// Set `wndsize`
+    /// set maximum window size: `sndwnd=32`, `rcvwnd=32` by default
+    ///
+    /// `rcvwnd` is at least 128, see `resize_rcv_wnd` for a smaller one.
+    pub fn set_wndsize(&mut self, sndwnd: u16, rcvwnd: u16) {
+        self.set_wnd(sndwnd as u32, rcvwnd as u32);
+    }
+
+    /// Set maximum window size above `u16::MAX`, advertised to the remote by `set_window_scale`
+    ///
+    /// The same as `set_wndsize` otherwise.
+    #[cfg(feature = "wscale")]
+    pub fn set_wndsize_scaled(&mut self, sndwnd: u32, rcvwnd: u32) {
+        self.set_wnd(sndwnd, rcvwnd);
+    }
+
+    fn set_wnd(&mut self, sndwnd: u32, rcvwnd: u32) {
+        if sndwnd > 0 {
+            self.snd_wnd = sndwnd;
+        }
+
+        if rcvwnd > 0 {
+            self.rcv_wnd = cmp::max(rcvwnd, KCP_WND_RCV as u32);
+        }
+    }
+
+    /// Change the receive window to `rcv_wnd` segments, without the minimum of `set_wndsize`
+    ///
+    /// Safe to call while receiving. Segments already received are kept when shrinking, and
+    /// moved to the receive queue when growing. The remote is told about a reopened window
+    /// by the next `flush`. A message with more fragments than `rcv_wnd` can't be received.
+    pub fn resize_rcv_wnd(&mut self, rcv_wnd: u16) {
+        self.resize_rcv(rcv_wnd as u32);
+    }
+
+    /// Change the receive window to `rcv_wnd` segments, above `u16::MAX` with `set_window_scale`
+    ///
+    /// The same as `resize_rcv_wnd` otherwise.
+    #[cfg(feature = "wscale")]
+    pub fn resize_rcv_wnd_scaled(&mut self, rcv_wnd: u32) {
+        self.resize_rcv(rcv_wnd);
+    }
+
+    fn resize_rcv(&mut self, rcv_wnd: u32) {
+        if rcv_wnd == 0 {
+            return;
+        }
+
+        let was_full = self.window_tell_due();
+        self.rcv_wnd = rcv_wnd;
+
+        self.recovered(was_full);
+    }
+
+    /// `snd_wnd` Send window
+    #[inline]
+    pub fn snd_wnd(&self) -> u16 {
+        wnd_u16(self.snd_wnd)
+    }
+
+    /// `snd_wnd` Send window, not capped at `u16::MAX`
+    #[cfg(feature = "wscale")]
+    #[inline]
+    pub fn snd_wnd_scaled(&self) -> u32 {
+        self.snd_wnd
+    }
+
+    /// `rcv_wnd` Receive window
+    #[inline]
+    pub fn rcv_wnd(&self) -> u16 {
+        wnd_u16(self.rcv_wnd)
+    }
+
+    /// `rcv_wnd` Receive window, not capped at `u16::MAX`
+    #[cfg(feature = "wscale")]
+    #[inline]
+    pub fn rcv_wnd_scaled(&self) -> u32 {
+        self.rcv_wnd
+    }
+
+    /// Get `waitsnd`, how many packet is waiting to be sent
+    #[inline]
+    pub fn wait_snd(&self) -> usize {
+        self.snd_buf.len() + self.snd_queue.len()
+    }
+
+    /// Segments in flight, sent and waiting for ACKs
+    ///
+    /// `wait_snd` is the sum of it and `snd_queue_len`.
+    #[inline]
+    pub fn snd_buf_len(&self) -> usize {
+        self.snd_buf.len()
+    }
+
+    /// Payload bytes in flight, sent and waiting for ACKs, headers excluded
+    ///
+    /// With `rx_srtt` it gives the delivery rate and the bandwidth-delay product a rate based
+    /// controller needs. Messages not sent yet are in `bytes_queued`.
+    pub fn bytes_in_flight(&self) -> usize {
+        self.snd_buf.iter().map(|seg| seg.data.len()).sum()
+    }
+
+    /// Payload bytes in the send queue, not yet moved into the send window, headers excluded
+    pub fn bytes_queued(&self) -> usize {
+        self.snd_queue.iter().map(|seg| seg.data.len()).sum()
+    }
+
+    /// Segments received out of order, waiting for the missing ones before them
+    #[inline]
+    pub fn rcv_buf_len(&self) -> usize {
+        self.rcv_buf.len()
+    }
+
+    /// Segments received in order, waiting to be read by `recv`
+    #[inline]
+    pub fn rcv_queue_len(&self) -> usize {
+        self.rcv_queue.len()
+    }
+
+    /// ACKs waiting to be sent
+    #[inline]
+    pub fn pending_acks(&self) -> usize {
+        self.acklist.len()
+    }
+
+    /// Segments sent and waiting for ACK, in ascending `sn`
+    ///
+    /// Includes segments moved into the send window but not transmitted yet (`xmit` is 0).
+    pub fn inflight_segments(&self) -> impl Iterator<Item = SegmentState> + '_ {
+        self.snd_buf.iter().map(KcpSegment::state)
+    }
+
+    /// Current retransmission timeout of the in-flight segment `sn`, including backoff
+    ///
+    /// Returns `None` if `sn` is not waiting for ACK.
+    pub fn current_rto_for(&self, sn: u32) -> Option<u32> {
+        self.snd_buf
+            .iter()
+            .find(|seg| seg.sn == sn)
+            .map(|seg| seg.rto)
+    }
+
+    /// Advertise `wnd` as the receive window instead of the free space, `None` to restore
+    ///
+    /// Useful to throttle the remote, or to test the zero window probing.
+    #[inline]
+    pub fn set_advertised_wnd_override(&mut self, wnd: Option<u16>) {
+        self.wnd_override = wnd.map(u32::from);
+    }
+
+    /// Ask the remote for its window size in the next `flush`, instead of waiting for the
+    /// probe schedule of a closed remote window, which starts at 7 seconds
+    ///
+    /// Useful when the remote is known to have reopened its window by other means. Every call
+    /// costs a segment, and the remote answers with another one.
+    pub fn probe_now(&mut self) {
+        self.probe |= KCP_ASK_SEND;
+        self.ts_probe = 0;
+        self.probe_wait = 0;
+    }
+
+    /// Move the segments received in order into the receive queue, and tell the remote the
+    /// window in the next `flush` if it has grown since the last one, returns if it is told
+    ///
+    /// Reading with `recv` does it only once the queue drops from the threshold of
+    /// `set_window_tell_threshold`, and the remote learns a window reopened by smaller reads
+    /// from the next segment. Call it after reading messages one by one, or after changing
+    /// `set_advertised_wnd_override`, to let the remote know at once.
+    pub fn notify_window_update(&mut self) -> bool {
+        self.move_buf();
+
+        let reopened = self
+            .wnd_advertised
+            .is_some_and(|wnd| self.wnd_unused() > wnd);
+        if reopened {
+            self.probe |= KCP_ASK_TELL;
+        }
+        reopened
+    }
+
+    /// Tell the remote the window size in the next `flush`, without being asked
+    ///
+    /// Useful when the application has just read a lot and the window reopened, the remote
+    /// otherwise learns it from the next segment, or probes a closed window only every 7 seconds
+    /// at first. The window is the one at the time the segment is encoded.
+    #[inline]
+    pub fn tell_window(&mut self) {
+        self.probe |= KCP_ASK_TELL;
+    }
+
+    /// Check if a window probe or answer is waiting for the next `flush`
+    #[inline]
+    pub fn is_probing(&self) -> bool {
+        self.probe != 0
+    }
+
+    /// Get `rmt_wnd`, remote window size
+    #[inline]
+    pub fn rmt_wnd(&self) -> u16 {
+        wnd_u16(self.rmt_wnd)
+    }
+
+    /// Get `rmt_wnd`, remote window size, not capped at `u16::MAX` once scaled
+    #[cfg(feature = "wscale")]
+    #[inline]
+    pub fn rmt_wnd_scaled(&self) -> u32 {
+        self.rmt_wnd
+    }
+
+    /// Get `cwnd`, congestion window size in packets
+    #[inline]
+    pub fn cwnd(&self) -> u16 {
+        self.cc_algo().cwnd()
+    }
+
+    /// Get `cwnd`, congestion window size in packets, not capped at `u16::MAX` once scaled
+    #[cfg(feature = "wscale")]
+    #[inline]
+    pub fn cwnd_scaled(&self) -> u32 {
+        self.cc_cwnd()
+    }
+
+    /// Send window in packets which `flush` applies now
+    ///
+    /// The minimum of `snd_wnd` and `rmt_wnd`, and of `cwnd` unless the congestion window is
+    /// disabled, 0 while `connect` is waiting for the remote. At most that many segments are in
+    /// flight, compare it with `snd_buf_len` for how many more could be sent.
+    #[inline]
+    pub fn effective_send_window(&self) -> u16 {
+        wnd_u16(self.send_window())
+    }
+
+    /// Get `ssthresh`, slow start threshold in packets of `ClassicCc`
+    #[inline]
+    pub fn ssthresh(&self) -> u16 {
+        self.classic.ssthresh()
+    }
+
+    /// Get `incr`, congestion window size in bytes of `ClassicCc`, grows with every ACK
+    #[inline]
+    pub fn incr(&self) -> usize {
+        self.classic.incr
+    }
+
+    /// Get `rx_srtt`, smoothed RTT in millisec (clock ticks, see `set_tick_resolution`)
+    ///
+    /// 0 until the first RTT sample.
+    #[inline]
+    pub fn rx_srtt(&self) -> u32 {
+        self.rx_srtt
+    }
+
+    /// Get `rx_rttval`, RTT variation in millisec (clock ticks, see `set_tick_resolution`)
+    #[inline]
+    pub fn rx_rttval(&self) -> u32 {
+        self.rx_rttval
+    }
+
+    /// Get `rx_rto`, retransmission timeout in millisec (clock ticks, see `set_tick_resolution`)
+    #[inline]
+    pub fn rx_rto(&self) -> u32 {
+        self.rx_rto
+    }
+
+    /// Get the RTT estimation, to be carried to a new connection with the same peer
+    pub fn rtt_estimator_state(&self) -> RttState {
+        RttState {
+            srtt: self.rx_srtt,
+            rttval: self.rx_rttval,
+            rto: self.rx_rto,
+            min_rtt: self.rx_minrtt,
+        }
+    }
+
+    /// Start from a RTT estimation learned before, instead of the default RTO
+    ///
+    /// `rto` is bounded by `rx_minrto` and the maximum RTO.
+    pub fn apply_rtt_estimator_state(&mut self, state: RttState) {
+        self.rx_srtt = state.srtt;
+        self.rx_rttval = state.rttval;
+        self.rx_rto = bound(self.rx_minrto, state.rto, self.ticks(KCP_RTO_MAX));
+        self.rx_minrtt = state.min_rtt;
+    }
+
+    /// Set the algorithm estimating RTO, default is `RtoEstimator::Ikcp`
+    ///
+    /// `RtoEstimator::Rfc6298` also sets `rx_minrto`, and RTO if not measured yet, to 1 second
+    /// as the RFC recommends. Call `set_rx_minrto` afterwards for a lower bound.
+    pub fn set_rto_estimator(&mut self, estimator: RtoEstimator) {
+        self.rto_estimator = estimator;
+        if estimator == RtoEstimator::Rfc6298 {
+            self.rx_minrto = self.ticks(KCP_RTO_RFC);
+            if self.rx_srtt == 0 {
+                self.rx_rto = self.ticks(KCP_RTO_RFC);
+            }
+        }
+    }
+
+    /// Get the algorithm estimating RTO
+    #[inline]
+    pub fn rto_estimator(&self) -> RtoEstimator {
+        self.rto_estimator
+    }
+
+    /// Set RTO in millisec used until RTT is measured, default is 200ms
+    ///
+    /// A link with a longer RTT resends every segment before its first ACK could arrive. It is
+    /// bounded by `rx_minrto` and the maximum RTO, and ignored once RTT is measured.
+    pub fn set_initial_rto(&mut self, rto: u32) {
+        if self.rx_srtt == 0 {
+            self.rx_rto = bound(self.rx_minrto, self.ticks(rto), self.ticks(KCP_RTO_MAX));
+        }
+    }
+
+    /// Set the multiplier of the RTO of a segment timed out, 0 means the classic backoff (default)
+    ///
+    /// Classically the RTO doubles, or grows by half in nodelay mode. `factor` is bounded within
+    /// 1.0, no backoff at all, and 4.0.
+    pub fn set_rto_backoff(&mut self, factor: f32) {
+        self.rto_backoff = if factor > 0.0 {
+            factor.clamp(1.0, 4.0)
+        } else {
+            0.0
+        };
+    }
+
+    /// Get the multiplier of the RTO of a segment timed out, 0 means the classic backoff
+    #[inline]
+    pub fn rto_backoff(&self) -> f32 {
+        self.rto_backoff
+    }
+
+    /// Set the fraction of the receive window filled from which reading tells the window to the
+    /// remote, 1.0 means only a full window (default)
+    ///
+    /// Classically a `recv` from a full receive queue sends a window answer (`WINS`) by the next
+    /// `flush`, as the remote has stopped sending. If it is lost the remote waits for its window
+    /// probe, 7 seconds at first. With a lower fraction every `recv` from a queue filled beyond it
+    /// tells the window as well, before the remote is starved, and a lost answer is made up by the
+    /// next read. `fraction` is bounded within 0.0, exclusive, and 1.0.
+    pub fn set_window_tell_threshold(&mut self, fraction: f32) {
+        self.window_tell_threshold = if fraction > 0.0 {
+            fraction.min(1.0)
+        } else {
+            1.0
+        };
+    }
+
+    /// Get the fraction of the receive window filled from which reading tells the window
+    #[inline]
+    pub fn window_tell_threshold(&self) -> f32 {
+        self.window_tell_threshold
+    }
+
+    /// Set `rx_minrto` in millisec
+    ///
+    /// Any value down to 1 is taken, but `set_nodelay` sets it again, so call it afterwards.
+    /// With `RtoEstimator::Ikcp` the RTO is still at least the smoothed RTT plus the interval,
+    /// its clock granularity, `RtoEstimator::Rfc6298` follows the RTT alone. Resending takes
+    /// a `flush`, which `update` does every interval, for a faster one call `flush` more often.
+    #[inline]
+    pub fn set_rx_minrto(&mut self, rto: u32) {
+        self.rx_minrto = self.ticks(rto);
+    }
+
+    /// Set the resend timeout in millisec after a fast resend, 0 means the RTO of the segment (default)
+    ///
+    /// A fast resend lost again is resent after it instead of the RTO, which is bounded by
+    /// `rx_minrto`. It doesn't back off.
+    #[inline]
+    pub fn set_fastack_rto(&mut self, rto: u32) {
+        self.fastack_rto = self.ticks(rto);
+    }
+
+    /// Get the resend timeout after a fast resend in clock ticks, 0 if not set
+    #[inline]
+    pub fn fastack_rto(&self) -> u32 {
+        self.fastack_rto
+    }
+
+    /// Set clock resolution, how many ticks of `current` in one millisec, default is 1
+    ///
+    /// For example, 1000 makes `update`, `check` and segment timestamps work in microseconds,
+    /// for a finer RTT measurement on fast links. Other settings are still in millisec.
+    ///
+    /// Timestamps in segments are only echoed back by the remote, so it is not required for
+    /// both endpoints to agree on the resolution. Call it before the first `update`.
+    /// Microseconds in `u32` wraps in about 71 minutes, which is handled like the millisec clock.
+    pub fn set_tick_resolution(&mut self, ticks_per_ms: u32) {
+        let ticks_per_ms = cmp::max(ticks_per_ms, 1);
+        if self.updated {
+            warn!("set_tick_resolution called after update, timers are inconsistent");
+        }
+
+        let rescale = |v: u32| (v as u64 * ticks_per_ms as u64 / self.tick as u64) as u32;
+        self.interval = rescale(self.interval);
+        self.ts_flush = rescale(self.ts_flush);
+        self.rx_rto = rescale(self.rx_rto);
+        self.rx_minrto = rescale(self.rx_minrto);
+        self.fastack_rto = rescale(self.fastack_rto);
+        self.rx_srtt = rescale(self.rx_srtt);
+        self.rx_rttval = rescale(self.rx_rttval);
+        self.rx_minrtt = rescale(self.rx_minrtt);
+        self.idle_wait = rescale(self.idle_wait);
+        self.keepalive = rescale(self.keepalive);
+        self.tick = ticks_per_ms;
+    }
+
+    /// Get clock resolution, how many ticks of `current` in one millisec
+    #[inline]
+    pub fn tick_resolution(&self) -> u32 {
+        self.tick
+    }
+
+    /// Set how far in millisec `current` may jump from the flush timer before it is reset, default is 10000
+    ///
+    /// `None` disables the reset, `update` and `check` trust `current` verbatim, which makes
+    /// synthetic clocks in simulations behave exactly as supplied.
+    #[inline]
+    pub fn set_flush_clamp(&mut self, window: Option<u32>) {
+        self.flush_clamp = window;
+    }
+
+    /// Get the window resetting the flush timer, `None` if disabled
+    #[inline]
+    pub fn flush_clamp(&self) -> Option<u32> {
+        self.flush_clamp
+    }
+
+    // Check if the clock jumped `slap` away from the flush timer
+    fn out_of_flush_clamp(&self, slap: i32) -> bool {
+        match self.flush_clamp {
+            Some(window) => {
+                let clamp = cmp::min(self.ticks(window), i32::MAX as u32) as i32;
+                !(-clamp..clamp).contains(&slap)
+            }
+            None => false,
+        }
+    }
+
+    // Convert millisec to clock ticks
+    #[inline]
+    fn ticks(&self, ms: u32) -> u32 {
+        ms.saturating_mul(self.tick)
+    }
+
+    /// Set `fastresend`
+    #[inline]
+    pub fn set_fast_resend(&mut self, fr: u32) {
+        self.fastresend = fr;
+    }
+
+    /// Set maximum transmissions of a segment by fast resend, 0 means unlimited, 5 by default
+    ///
+    /// A segment sent that many times is only resent by its RTO, however many ACKs skip it.
+    #[inline]
+    pub fn set_fastack_limit(&mut self, limit: u32) {
+        self.fastlimit = limit;
+    }
+
+    /// Get maximum transmissions of a segment by fast resend
+    #[inline]
+    pub fn fastack_limit(&self) -> u32 {
+        self.fastlimit
+    }
 
-    /// Get conv from the next input call
-    input_conv: bool,
+    /// Set duplicate ACKs to trigger fast resend, 0 means same as `fastresend` (default)
+    ///
+    /// `fastresend` still enables fast resend and sizes `cwnd` after the recovery, so the
+    /// trigger sensitivity could be tuned separately.
+    #[inline]
+    pub fn set_dupack_threshold(&mut self, n: u32) {
+        self.dupack = n;
+    }
 
-    output: KcpOutput<Output>,
-}
+    /// Set whether every ACK skipping a segment counts towards its fast resend
+    ///
+    /// By default an ACK only counts for the segments sent before the acknowledged one, and of
+    /// several ACKs in an `input` only the one sent latest is taken, so ACKs of old transmissions
+    /// don't trigger another resend of a segment just retransmitted. The conserve mode counts
+    /// them all, resending sooner on lossy paths. Defaults to the `fastack-conserve` feature.
+    #[inline]
+    pub fn set_fastack_conserve(&mut self, conserve: bool) {
+        self.fastack_conserve = conserve;
+    }
 
-impl<Output> Debug for Kcp<Output> {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        f.debug_struct("Kcp")
-            .field("conv", &self.conv)
-            .field("mtu", &self.mtu)
-            .field("mss", &self.mss)
-            .field("state", &self.state)
-            .field("snd_una", &self.snd_una)
-            .field("snd_nxt", &self.snd_nxt)
-            .field("rcv_nxt", &self.rcv_nxt)
-            .field("ssthresh", &self.ssthresh)
-            .field("rx_rttval", &self.rx_rttval)
-            .field("rx_srtt", &self.rx_srtt)
-            .field("rx_rto", &self.rx_rto)
-            .field("rx_minrto", &self.rx_minrto)
-            .field("snd_wnd", &self.snd_wnd)
-            .field("rcv_wnd", &self.rcv_wnd)
-            .field("rmt_wnd", &self.rmt_wnd)
-            .field("cwnd", &self.cwnd)
-            .field("probe", &self.probe)
-            .field("current", &self.current)
-            .field("interval", &self.interval)
-            .field("ts_flush", &self.ts_flush)
-            .field("xmit", &self.xmit)
-            .field("nodelay", &self.nodelay)
-            .field("updated", &self.updated)
-            .field("ts_probe", &self.ts_probe)
-            .field("probe_wait", &self.probe_wait)
-            .field("dead_link", &self.dead_link)
-            .field("incr", &self.incr)
-            .field("snd_queue.len", &self.snd_queue.len())
-            .field("rcv_queue.len", &self.rcv_queue.len())
-            .field("snd_buf.len", &self.snd_buf.len())
-            .field("rcv_buf.len", &self.rcv_buf.len())
-            .field("acklist.len", &self.acklist.len())
-            .field("buf.len", &self.buf.len())
-            .field("fastresend", &self.fastresend)
-            .field("fastlimit", &self.fastlimit)
-            .field("nocwnd", &self.nocwnd)
-            .field("stream", &self.stream)
-            .field("input_conv", &self.input_conv)
-            .finish()
+    /// Check if every ACK skipping a segment counts towards its fast resend
+    #[inline]
+    pub fn fastack_conserve(&self) -> bool {
+        self.fastack_conserve
     }
-}
 
-impl<Output> Kcp<Output> {
-    /// Creates a KCP control object, `conv` must be equal in both endpoints in one connection.
-    /// `output` is the callback object for writing.
+    /// Duplicate ACKs to trigger fast resend, 0 if fast resend is disabled
     ///
-    /// `conv` represents conversation.
-    pub fn new(conv: u32, output: Output) -> Self {
-        Kcp::construct(conv, output, false)
+    /// Raised above the reordering degree if `set_adaptive_dupack` is enabled.
+    #[inline]
+    pub fn dupack_threshold(&self) -> u32 {
+        let threshold = if self.fastresend == 0 || self.dupack == 0 {
+            self.fastresend
+        } else {
+            self.dupack
+        };
+
+        if threshold > 0 && self.adaptive_dupack {
+            cmp::max(threshold, self.reorder + 1)
+        } else {
+            threshold
+        }
     }
 
-    /// Creates a KCP control object in stream mode, `conv` must be equal in both endpoints in one connection.
-    /// `output` is the callback object for writing.
+    /// Raise the duplicate ACK threshold with the reordering observed, disabled by default
     ///
-    /// `conv` represents conversation.
-    pub fn new_stream(conv: u32, output: Output) -> Self {
-        Kcp::construct(conv, output, true)
+    /// Avoids spurious fast resends on paths reordering segments rather than losing them.
+    #[inline]
+    pub fn set_adaptive_dupack(&mut self, enabled: bool) {
+        self.adaptive_dupack = enabled;
     }
 
-    fn construct(conv: u32, output: Output, stream: bool) -> Self {
-        Kcp {
-            conv,
-            snd_una: 0,
-            snd_nxt: 0,
-            rcv_nxt: 0,
-            ts_probe: 0,
-            probe_wait: 0,
-            snd_wnd: KCP_WND_SND,
-            rcv_wnd: KCP_WND_RCV,
-            rmt_wnd: KCP_WND_RCV,
-            cwnd: 0,
-            incr: 0,
-            probe: 0,
-            mtu: KCP_MTU_DEF,
-            mss: KCP_MTU_DEF - KCP_OVERHEAD,
-            stream,
+    /// Maximum reordering observed in received segments
+    ///
+    /// How many later segments had been received before a segment sent earlier than them.
+    #[inline]
+    pub fn reordering_degree(&self) -> u32 {
+        self.reorder
+    }
 
-            buf: BytesMut::with_capacity((KCP_MTU_DEF + KCP_OVERHEAD) as usize * 3),
+    /// KCP header size, same as `KCP_OVERHEAD`
+    pub const OVERHEAD: usize = KCP_OVERHEAD;
 
-            snd_queue: VecDeque::new(),
-            rcv_queue: VecDeque::new(),
-            snd_buf: VecDeque::new(),
-            rcv_buf: VecDeque::new(),
+    /// KCP header size
+    #[inline]
+    pub fn header_len() -> usize {
+        KCP_OVERHEAD
+    }
 
-            state: 0,
+    /// Payload size of a segment with `mtu` for the standard header of `KCP_OVERHEAD` bytes
+    ///
+    /// It matches `mss` after `set_mtu` only without a trailer and with the standard header, `mss`
+    /// differs with `new_compact`, `set_checksum` or `set_session_token`. Subtract the bytes of
+    /// `set_reserved` from `mtu` if any. It is 0 for a `mtu` too small for the header, which
+    /// `set_mtu` rejects.
+    #[inline]
+    pub fn mss_for_mtu(mtu: usize) -> usize {
+        mtu.saturating_sub(KCP_OVERHEAD)
+    }
 
-            acklist: VecDeque::new(),
+    /// Largest message `send` accepts with `mtu` and the receive window `rcv_wnd`
+    ///
+    /// For the default maximum fragments, see `max_fragments`. Fragments are sized by
+    /// `mss_for_mtu`, so it assumes the standard header and no trailer as well.
+    #[inline]
+    pub fn max_message_size(mtu: usize, rcv_wnd: u16) -> usize {
+        let fragments = cmp::max(rcv_wnd as usize, 2) - 1;
+        Self::mss_for_mtu(mtu) * cmp::min(fragments, KCP_FRG_MAX)
+    }
 
-            rx_srtt: 0,
-            rx_rttval: 0,
-            rx_rto: KCP_RTO_DEF,
-            rx_minrto: KCP_RTO_MIN,
+    /// A compact view for logging, `{:?}` of it fits on one line
+    ///
+    /// `Debug` of `Kcp` itself prints every setting and timer, this only the ones telling how the
+    /// connection is doing.
+    pub fn summary(&self) -> KcpSummary<'_, Output> {
+        KcpSummary { kcp: self }
+    }
 
-            current: 0,
-            interval: KCP_INTERVAL,
-            ts_flush: KCP_INTERVAL,
-            nodelay: false,
-            updated: false,
-            ssthresh: KCP_THRESH_INIT,
-            fastresend: 0,
-            fastlimit: KCP_FASTACK_LIMIT,
-            nocwnd: false,
-            xmit: 0,
-            dead_link: KCP_DEADLINK,
+    /// Counters since the creation or the last `reset_stats`
+    pub fn stats(&self) -> Stats {
+        Stats {
+            srtt: self.rx_srtt,
+            ..self.stats
+        }
+    }
 
-            input_conv: false,
-            output: KcpOutput(output),
+    /// Reset the counters of `stats` and `path_metrics`
+    #[inline]
+    pub fn reset_stats(&mut self) {
+        self.stats = Stats::default();
+        self.path_metrics = PathMetrics::default();
+    }
+
+    /// Reordering and loss counters since the creation or the last `reset_stats`
+    #[inline]
+    pub fn path_metrics(&self) -> PathMetrics {
+        self.path_metrics
+    }
+
+    /// Bytes appended to a queued segment by `send` in stream mode
+    #[inline]
+    pub fn stream_coalesced_bytes(&self) -> u64 {
+        self.stream_coalesced_bytes
+    }
+
+    /// Segments created by `send` in stream mode
+    #[inline]
+    pub fn stream_new_segments(&self) -> u64 {
+        self.stream_new_segments
+    }
+
+    /// Enabled stream or not
+    #[inline]
+    pub fn is_stream(&self) -> bool {
+        self.stream
+    }
+
+    /// Check if segment headers leave `conv` out, see `new_compact`
+    #[cfg(feature = "compact")]
+    #[inline]
+    pub fn is_compact(&self) -> bool {
+        self.compact
+    }
+
+    // Size of a segment header on the wire
+    #[inline]
+    fn wire_header_len(&self) -> usize {
+        if self.compact {
+            KCP_OVERHEAD - 4
+        } else {
+            KCP_OVERHEAD
         }
     }
 
-    // move available data from rcv_buf -> rcv_queue
-    pub fn move_buf(&mut self) {
-        while !self.rcv_buf.is_empty() {
-            let nrcv_que = self.rcv_queue.len();
-            {
-                let seg = self.rcv_buf.front().unwrap();
-                if seg.sn == self.rcv_nxt && nrcv_que < self.rcv_wnd as usize {
-                    self.rcv_nxt += 1;
-                } else {
-                    break;
-                }
-            }
+    // Bytes of a segment on the wire besides its payload, the header, the session token and the
+    // checksum
+    #[inline]
+    fn overhead(&self) -> usize {
+        self.wire_header_len() + self.token_len() + self.checksum_len()
+    }
 
-            let seg = self.rcv_buf.pop_front().unwrap();
-            self.rcv_queue.push_back(seg);
+    // Size of the session token after the payload of every segment
+    #[inline]
+    fn token_len(&self) -> usize {
+        if self.session_token.is_some() {
+            KCP_TOKEN_LEN
+        } else {
+            0
         }
     }
 
-    /// Receive data from buffer
-    pub fn recv(&mut self, buf: &mut [u8]) -> KcpResult<usize> {
-        if self.rcv_queue.is_empty() {
-            return Err(Error::RecvQueueEmpty);
+    // Size of the checksum after the payload of every segment
+    #[inline]
+    fn checksum_len(&self) -> usize {
+        if self.checksum {
+            KCP_CHECKSUM_LEN
+        } else {
+            0
         }
+    }
 
-        let peeksize = self.peeksize()?;
+    /// Maximum Segment Size
+    #[inline]
+    pub fn mss(&self) -> usize {
+        self.mss
+    }
 
-        if peeksize > buf.len() {
-            debug!("recv peeksize={} bufsize={} too small", peeksize, buf.len());
-            return Err(Error::UserBufTooSmall);
+    /// Set maximum resend times
+    #[inline]
+    pub fn set_maximum_resend_times(&mut self, dead_link: u32) {
+        self.dead_link = dead_link;
+    }
+
+    // Set the first sequence numbers to send and to receive, before any data is sent or received
+    #[cfg(any(feature = "isn", feature = "testing"))]
+    fn set_initial_sn(&mut self, snd_nxt: u32, rcv_nxt: u32) {
+        if !self.snd_buf.is_empty() || !self.rcv_buf.is_empty() || !self.rcv_queue.is_empty() {
+            warn!("set_initial_sn called with data in flight");
         }
 
-        let recover = self.rcv_queue.len() >= self.rcv_wnd as usize;
+        self.snd_una = snd_nxt;
+        self.snd_nxt = snd_nxt;
+        self.rcv_nxt = rcv_nxt;
+        self.rcv_max = rcv_nxt;
+    }
 
-        // Merge fragment
-        let mut cur = Cursor::new(buf);
-        while let Some(seg) = self.rcv_queue.pop_front() {
-            Write::write_all(&mut cur, &seg.data)?;
+    /// Get `snd_una`, the first sequence number not acknowledged by the remote yet
+    ///
+    /// Every segment before it has been received, `snd_una - 1` is the highest one acknowledged
+    /// in order. It is `snd_nxt` when nothing is in flight. Sequence numbers wrap around
+    /// `u32::MAX`, compare them with `serial_lt` and `serial_diff`, not `<` and `-`.
+    #[inline]
+    pub fn snd_una(&self) -> u32 {
+        self.snd_una
+    }
 
-            trace!("recv sn={}", seg.sn);
+    /// Get `snd_nxt`, the sequence number of the next segment sent
+    ///
+    /// It wraps around `u32::MAX` as `snd_una`.
+    #[inline]
+    pub fn snd_nxt(&self) -> u32 {
+        self.snd_nxt
+    }
 
-            if seg.frg == 0 {
-                break;
+    /// Get `rcv_nxt`, the next sequence number to receive in order
+    ///
+    /// Every segment before it has been moved to the receive queue. It wraps around `u32::MAX`
+    /// as `snd_una`.
+    #[inline]
+    pub fn rcv_nxt(&self) -> u32 {
+        self.rcv_nxt
+    }
+
+    /// Check if sending or receiving sequence number is about to wrap around `u32::MAX`
+    ///
+    /// Wrapping is handled, but a cautious application could reconnect with a fresh `conv` before.
+    #[inline]
+    pub fn sequence_near_wrap(&self) -> bool {
+        self.snd_nxt > u32::MAX - KCP_SN_WRAP_MARGIN || self.rcv_nxt > u32::MAX - KCP_SN_WRAP_MARGIN
+    }
+
+    /// Check if `update` has been called, nothing is written to the output before
+    #[inline]
+    pub fn is_updated(&self) -> bool {
+        self.updated
+    }
+
+    /// Check if KCP connection is dead (resend times excceeded, or timed out without input)
+    #[inline]
+    pub fn is_dead_link(&self) -> bool {
+        matches!(self.state, LinkState::DeadLink | LinkState::TimedOut)
+    }
+
+    /// Set a handler called with `conv` by `flush` when the connection becomes dead
+    ///
+    /// It is called once, until the state is cleared by `clear_dead_link`.
+    pub fn set_dead_link_handler<F>(&mut self, handler: F)
+    where
+        F: FnMut(u32) + Send + 'static,
+    {
+        self.dead_link_handler = Some(Box::new(handler));
+    }
+
+    /// Set a hook called with every datagram and the current clock right before it is written
+    ///
+    /// The datagram is what the output receives, encrypted if `set_crypt` is used. It is called
+    /// again for a datagram whose write is retried after a failure. Useful for metrics and
+    /// pacing decisions, as it sees the clock of `Kcp` which a plain output doesn't.
+    pub fn set_packet_hook<F>(&mut self, hook: F)
+    where
+        F: FnMut(&[u8], u32) + Send + 'static,
+    {
+        self.packet_hook = Some(Box::new(hook));
+    }
+
+    /// Set whether datagrams are written with `Write::write_vectored`, default is false
+    ///
+    /// All datagrams ready in a flush are passed in one call, one `IoSlice` each, instead of one
+    /// `write_all` per datagram. Only enable it for an output which sends every slice as a
+    /// datagram of its own, like a `sendmmsg` batch, a socket would join them into one. The
+    /// default `write_vectored` writes a single slice, which works as the per datagram writes.
+    #[cfg(feature = "std")]
+    #[inline]
+    pub fn set_vectored(&mut self, vectored: bool) {
+        self.vectored = vectored;
+    }
+
+    /// Check if datagrams are written with `Write::write_vectored`
+    #[cfg(feature = "std")]
+    #[inline]
+    pub fn vectored(&self) -> bool {
+        self.vectored
+    }
+
+    /// Set a handler called with the RTT sample of every ACK input
+    ///
+    /// Samples are raw, seen before they are smoothed into the RTT estimation, for measuring
+    /// jitter. ACKs echoing a `ts` later than the current clock are not sampled.
+    pub fn set_ack_sample_handler<F>(&mut self, handler: F)
+    where
+        F: FnMut(AckSample) + Send + 'static,
+    {
+        self.ack_sample_handler = Some(Box::new(handler));
+    }
+
+    /// Set a handler called by `flush` with every segment retransmitted as lost
+    ///
+    /// Timeouts and fast resends are told apart, the first transmission of a segment is never
+    /// reported. It is called at the end of the flush, after the congestion window has been
+    /// adjusted, for reacting to losses as they happen, like lowering the quality of a video.
+    pub fn set_loss_handler<F>(&mut self, handler: F)
+    where
+        F: FnMut(LossEvent) + Send + 'static,
+    {
+        self.loss_handler = Some(Box::new(handler));
+    }
+
+    /// Clear the dead link state, for recovering the connection
+    ///
+    /// Segments which have exceeded the resend times still do, the connection is dead again by
+    /// the next retransmission of them.
+    #[inline]
+    pub fn clear_dead_link(&mut self) {
+        self.state = LinkState::Active;
+    }
+
+    /// Clear the dead link state and give the segments in flight a fresh resend budget
+    ///
+    /// For a connection which survived a total outage longer than `set_maximum_resend_times`
+    /// allows, once the path is known to be back. The data is kept, the segments in flight count
+    /// as sent once, with the current RTO, and are resent by the next `flush`. A request of
+    /// `connect` starts over as well. If the remote is really gone, it only delays the dead link,
+    /// check `is_dead_link` to decide.
+    pub fn revive(&mut self) {
+        self.state = LinkState::Active;
+        for segment in &mut self.snd_buf {
+            if segment.xmit > 0 {
+                segment.xmit = 1;
+                segment.rto = self.rx_rto;
+                segment.resendts = self.current;
             }
         }
-        assert_eq!(cur.position() as usize, peeksize);
+        self.syn_xmit = 0;
+    }
 
-        self.move_buf();
+    /// How many times `update` was called with a `current` earlier than the previous one
+    ///
+    /// `current` must be monotonic, these calls are clamped to the last known time.
+    #[inline]
+    pub fn clock_went_backwards_count(&self) -> u64 {
+        self.clock_went_backwards
+    }
 
-        // fast recover
-        if self.rcv_queue.len() < self.rcv_wnd as usize && recover {
-            // ready to send back IKCP_CMD_WINS in ikcp_flush
-            // tell remote my window size
-            self.probe |= KCP_ASK_TELL;
+    /// Mark the connection as idle, or resume it
+    ///
+    /// While idle and there is nothing to send or acknowledge, the flush interval doubles up
+    /// to `set_idle_interval` and the remote window isn't probed. Any traffic restores the
+    /// normal interval until it is quiet again. Resuming flushes at the next `update`.
+    pub fn set_idle(&mut self, idle: bool) {
+        if idle && !self.idle {
+            self.idle_wait = self.interval;
+        } else if !idle && self.idle && self.updated {
+            self.ts_flush = self.current;
         }
+        self.idle = idle;
+    }
 
-        Ok(cur.position() as usize)
+    /// Check if the connection is marked as idle
+    #[inline]
+    pub fn is_idle(&self) -> bool {
+        self.idle
+    }
+
+    /// Set maximum flush interval in millisec when idle, default is 1000ms
+    #[inline]
+    pub fn set_idle_interval(&mut self, interval: u32) {
+        self.idle_interval = interval.clamp(10, 5000);
     }
 
-    /// Check buffer size without actually consuming it
-    pub fn peeksize(&self) -> KcpResult<usize> {
-        match self.rcv_queue.front() {
-            Some(segment) => {
-                if segment.frg == 0 {
-                    return Ok(segment.data.len());
-                }
+    /// Flush every `min_ms` while busy, backing off up to `max_ms` while there is nothing to send
+    /// or acknowledge
+    ///
+    /// Same as `set_interval(min_ms)`, `set_idle_interval(max_ms)` and `set_idle(true)`: `check`
+    /// reports the stretched interval, and `send` or `input` shrink it back to `min_ms` at once.
+    pub fn set_adaptive_interval(&mut self, min_ms: u32, max_ms: u32) {
+        self.set_interval(min_ms);
+        self.set_idle_interval(cmp::max(min_ms, max_ms));
+        self.set_idle(true);
+        self.idle_wait = self.interval;
+    }
 
-                if self.rcv_queue.len() < (segment.frg + 1) as usize {
-                    return Err(Error::ExpectingFragment);
-                }
+    /// Send keepalives every `interval` millisec while nothing else is sent, 0 disables it (default)
+    ///
+    /// A keepalive is a window probe (`WASK`) which any KCP answers with its window (`WINS`), so
+    /// it works with a remote that doesn't enable it. Like window probes, it is neither counted
+    /// as data in the stats nor seen by congestion control. The connection is dead after
+    /// `max_missed` intervals without any input, 0 never marks it dead.
+    pub fn set_keepalive(&mut self, interval: u32, max_missed: u32) {
+        self.keepalive = self.ticks(interval);
+        self.keepalive_max_missed = max_missed;
+    }
 
-                let mut len = 0;
+    /// Get keepalive interval in ticks, 0 means disabled
+    #[inline]
+    pub fn keepalive(&self) -> u32 {
+        self.keepalive
+    }
 
-                for segment in &self.rcv_queue {
-                    len += segment.data.len();
-                    if segment.frg == 0 {
-                        break;
-                    }
-                }
+    /// Get the clock when a datagram was last input
+    #[inline]
+    pub fn last_recv_time(&self) -> u32 {
+        self.ts_last_recv
+    }
 
-                Ok(len)
-            }
-            None => Err(Error::RecvQueueEmpty),
-        }
+    /// Time out the connection after `timeout` millisec without any input, 0 disables it
+    /// (default)
+    ///
+    /// Checked by `flush`, whether there is anything to send or not, so it also covers a remote
+    /// which vanished while nothing is in flight and no resend could exceed
+    /// `set_maximum_resend_times`. The state becomes `LinkState::TimedOut`, `is_dead_link` is
+    /// true, and the handler of `set_dead_link_handler` is called. Unlike `set_keepalive`,
+    /// nothing is sent to make the remote answer. Not related to `set_idle`.
+    #[inline]
+    pub fn set_idle_timeout(&mut self, timeout: u32) {
+        self.idle_timeout = self.ticks(timeout);
     }
 
-    /// Send bytes into buffer
-    pub fn send(&mut self, mut buf: &[u8]) -> KcpResult<usize> {
-        let mut sent_size = 0;
+    /// Get the idle timeout in ticks, 0 means disabled
+    #[inline]
+    pub fn idle_timeout(&self) -> u32 {
+        self.idle_timeout
+    }
 
-        assert!(self.mss > 0);
+    /// Time since a datagram was last input, at the clock `current`
+    #[inline]
+    pub fn idle_time(&self, current: u32) -> u32 {
+        cmp::max(timediff(current, self.ts_last_recv), 0) as u32
+    }
 
-        // append to previous segment in streaming mode (if possible)
-        if self.stream {
-            if let Some(old) = self.snd_queue.back_mut() {
-                let l = old.data.len();
-                if l < self.mss as usize {
-                    let capacity = self.mss as usize - l;
-                    let extend = cmp::min(buf.len(), capacity);
+    /// Set maximum data sent in bytes per second, 0 means unlimited (default)
+    ///
+    /// Data segments, including retransmissions, are deferred to later `flush` when the limit is
+    /// reached. Unlike the congestion window, it doesn't react to the network.
+    pub fn set_rate_limit(&mut self, bytes_per_sec: u32) {
+        self.rate_limit = bytes_per_sec;
+        self.rate_tokens = self.rate_capacity();
+        self.ts_rate = self.current;
+    }
 
-                    trace!(
-                        "send stream mss={} last length={} extend={}",
-                        self.mss,
-                        l,
-                        extend
-                    );
+    /// Get maximum data sent in bytes per second, 0 means unlimited
+    #[inline]
+    pub fn rate_limit(&self) -> u32 {
+        self.rate_limit
+    }
 
-                    let (lf, rt) = buf.split_at(extend);
-                    old.data.extend_from_slice(lf);
-                    buf = rt;
+    /// Payload bytes acknowledged by the remote per second, over the last second of the clock
+    ///
+    /// A segment counts once when it is acknowledged, however many times it was sent, at the
+    /// clock of the last `update`. Compared with the link or `rate_limit`, a lower rate while
+    /// `wait_snd` stays low means the application is not sending enough.
+    pub fn delivery_rate(&self) -> f64 {
+        // The window is one second, the sum is the rate
+        let window = self.ticks(KCP_RATE_WINDOW) as i32;
+        self.delivered
+            .iter()
+            .filter(|&&(ts, _)| timediff(self.current, ts) < window)
+            .map(|&(_, bytes)| bytes)
+            .sum::<usize>() as f64
+    }
 
-                    old.frg = 0;
-                    sent_size += extend;
-                }
-            }
+    /// Select the congestion control, default is `CongestionControl::Classic`
+    ///
+    /// Replaces the one set with `set_congestion_algo`. `CongestionControl::None` is the same
+    /// as `nocwnd` of `set_nodelay`.
+    pub fn set_congestion(&mut self, cc: CongestionControl) {
+        self.cc_algo = None;
+        self.classic.loss_based = cc == CongestionControl::LossBased;
+        self.set_nocwnd(cc == CongestionControl::None);
+    }
 
-            if buf.is_empty() {
-                return Ok(sent_size);
-            }
+    /// Get the congestion control, a custom one set with `set_congestion_algo` isn't reported
+    pub fn congestion(&self) -> CongestionControl {
+        if self.nocwnd {
+            CongestionControl::None
+        } else if self.classic.loss_based {
+            CongestionControl::LossBased
+        } else {
+            CongestionControl::Classic
         }
+    }
 
-        let count = if buf.len() <= self.mss as usize {
-            1
+    /// Scale how fast `cwnd` grows in congestion avoidance, default is 1.0
+    ///
+    /// Beyond `ssthresh`, the window of `ClassicCc` grows by about one segment a round trip, which
+    /// takes long to fill a link with a large bandwidth-delay product. A factor of 2.0 doubles the
+    /// increment of every ACK, still backing off on losses unlike `nocwnd`. Slow start is not
+    /// changed. `factor` is bounded within 0.25 and 8.0. A custom algorithm of
+    /// `set_congestion_algo` is left alone.
+    pub fn set_cwnd_increase_factor(&mut self, factor: f32) {
+        let factor = if factor.is_nan() {
+            1.0
         } else {
-            (buf.len() + self.mss as usize - 1) / self.mss as usize
+            factor.clamp(0.25, 8.0)
         };
+        self.classic.increase = (factor * 100.0) as usize;
+    }
 
-        if count >= KCP_WND_RCV as usize {
-            debug!("send bufsize={} mss={} too large", buf.len(), self.mss);
-            return Err(Error::UserBufTooBig);
-        }
+    /// Get the factor of the growth of `cwnd` in congestion avoidance
+    #[inline]
+    pub fn cwnd_increase_factor(&self) -> f32 {
+        self.classic.increase as f32 / 100.0
+    }
 
-        let count = cmp::max(1, count);
+    /// Set a custom congestion control algorithm deciding `cwnd`
+    ///
+    /// It is not consulted if `nocwnd` is set, and not saved by `snapshot`.
+    pub fn set_congestion_algo<A>(&mut self, algo: A)
+    where
+        A: CcAlgo + Send + 'static,
+    {
+        self.nocwnd = false;
+        self.cc_algo = Some(Box::new(algo));
+    }
 
-        for i in 0..count {
-            let size = cmp::min(self.mss as usize, buf.len());
+    // The installed congestion control
+    fn cc_algo(&self) -> &dyn CcAlgo {
+        match self.cc_algo {
+            Some(ref algo) => algo.as_ref(),
+            None => &self.classic,
+        }
+    }
 
-            let (lf, rt) = buf.split_at(size);
+    fn cc_algo_mut(&mut self) -> &mut dyn CcAlgo {
+        match self.cc_algo {
+            Some(ref mut algo) => algo.as_mut(),
+            None => &mut self.classic,
+        }
+    }
 
-            let mut new_segment = KcpSegment::new_with_data(lf.into());
-            buf = rt;
+    // The congestion window of the algorithm, scaled if supported
+    #[cfg(feature = "wscale")]
+    #[inline]
+    fn cc_cwnd(&self) -> u32 {
+        self.cc_algo().cwnd_scaled()
+    }
 
-            new_segment.frg = if self.stream {
-                0
-            } else {
-                (count - i - 1) as u8
-            };
+    #[cfg(not(feature = "wscale"))]
+    #[inline]
+    fn cc_cwnd(&self) -> u32 {
+        self.cc_algo().cwnd() as u32
+    }
 
-            self.snd_queue.push_back(new_segment);
-            sent_size += size;
+    fn congestion_state(&self, window: u32) -> CongestionState {
+        CongestionState {
+            current: self.current,
+            srtt: self.rx_srtt,
+            mss: self.mss,
+            rmt_wnd: wnd_u16(self.rmt_wnd),
+            #[cfg(feature = "wscale")]
+            rmt_wnd_scaled: self.rmt_wnd,
+            inflight: self.snd_nxt.wrapping_sub(self.snd_una),
+            window: wnd_u16(window),
+            #[cfg(feature = "wscale")]
+            window_scaled: window,
+            fastresend: self.fastresend,
         }
-
-        Ok(sent_size)
     }
 
-    fn update_ack(&mut self, rtt: u32) {
-        if self.rx_srtt == 0 {
-            self.rx_srtt = rtt;
-            self.rx_rttval = rtt / 2;
-        } else {
-            let delta = if rtt > self.rx_srtt {
-                rtt - self.rx_srtt
-            } else {
-                self.rx_srtt - rtt
-            };
-            self.rx_rttval = (3 * self.rx_rttval + delta) / 4;
-            self.rx_srtt = (7 * self.rx_srtt + rtt) / 8;
-            if self.rx_srtt < 1 {
-                self.rx_srtt = 1;
-            }
-        }
-        let rto = self.rx_srtt + cmp::max(self.interval, 4 * self.rx_rttval);
-        self.rx_rto = bound(self.rx_minrto, rto, KCP_RTO_MAX);
+    /// Set a custom policy deciding when a segment is considered lost and retransmitted
+    ///
+    /// `DefaultLossDetector`, RTO timeout and fast resend, is used by default.
+    pub fn set_loss_detector<D>(&mut self, detector: D)
+    where
+        D: LossDetector + Send + 'static,
+    {
+        self.loss_detector = Some(Box::new(detector));
     }
 
-    #[inline]
-    fn shrink_buf(&mut self) {
-        self.snd_una = match self.snd_buf.front() {
-            Some(seg) => seg.sn,
-            None => self.snd_nxt,
-        };
+    /// Encrypt every datagram sent, and decrypt every datagram received with `crypt`
+    ///
+    /// Both endpoints must use the same. Leave the growth of `SegmentCrypt::encrypt` with
+    /// `set_reserved`. The segments of an encrypted datagram can't be told apart without it, so
+    /// it doesn't work with `input_framed` and `flush_into`.
+    pub fn set_crypt<C>(&mut self, crypt: C)
+    where
+        C: SegmentCrypt + Send + 'static,
+    {
+        self.crypt = Some(Box::new(crypt));
     }
 
-    fn parse_ack(&mut self, sn: u32) {
-        if timediff(sn, self.snd_una) < 0 || timediff(sn, self.snd_nxt) >= 0 {
-            return;
+    /// Compress every message sent, and decompress every message received with `codec`
+    ///
+    /// A message is compressed as a whole before it is split into segments, so more of it fits
+    /// in each, and the limit of `max_message_size` applies to the compressed size. `send`
+    /// still returns the size given. A message failing to decompress is dropped, `recv` returns
+    /// the error of `PayloadCodec::decompress`. `peek` and `peeksize` see compressed messages.
+    /// Messages have no boundaries in stream mode, `Error::InvalidConfig` is returned then.
+    ///
+    /// Both endpoints must use the same codec, but nothing changes on the wire, the compressed
+    /// bytes are carried as any message.
+    #[cfg(feature = "codec")]
+    pub fn set_payload_codec<C>(&mut self, codec: C) -> KcpResult<()>
+    where
+        C: PayloadCodec + Send + 'static,
+    {
+        if self.stream {
+            return Err(Error::InvalidConfig("payload codec in stream mode"));
         }
+        self.payload_codec = Some(Box::new(codec));
+        Ok(())
+    }
 
-        let mut i = 0 as usize;
-        while i < self.snd_buf.len() {
-            match sn.cmp(&self.snd_buf[i].sn) {
-                Ordering::Equal => {
-                    self.snd_buf.remove(i);
-                    break;
-                }
-                Ordering::Less => break,
-                _ => i = i + 1,
-            }
-        }
+    /// Take the time of `update_now` and `check_now` from `clock`, see `with_clock`
+    pub fn set_clock<C>(&mut self, clock: C)
+    where
+        C: Clock + Send + 'static,
+    {
+        self.clock = Some(Box::new(clock));
     }
 
-    fn parse_una(&mut self, una: u32) {
-        while let Some(seg) = self.snd_buf.front() {
-            if timediff(una, seg.sn) > 0 {
-                self.snd_buf.pop_front();
-            } else {
-                break;
-            }
+    // The time of the clock
+    fn now(&self) -> KcpResult<u32> {
+        match self.clock {
+            Some(ref clock) => Ok(clock.now()),
+            None => Err(Error::NoClock),
         }
     }
 
-    fn parse_fastack(&mut self, sn: u32, ts: u32) {
-        if timediff(sn, self.snd_una) < 0 || timediff(sn, self.snd_nxt) >= 0 {
-            return;
-        }
+    /// `check` with the time of the clock
+    ///
+    /// Return `Error::NoClock` without `with_clock` or `set_clock`.
+    pub fn check_now(&self) -> KcpResult<u32> {
+        Ok(self.check(self.now()?))
+    }
 
-        for seg in &mut self.snd_buf {
-            if timediff(sn, seg.sn) < 0 {
-                break;
-            } else if sn != seg.sn {
-                #[cfg(feature = "fastack-conserve")]
-                {
-                    seg.fastack += 1;
-                }
-                #[cfg(not(feature = "fastack-conserve"))]
-                if timediff(ts, seg.ts) >= 0 {
-                    seg.fastack += 1;
-                }
-            }
-        }
+    /// Set maximum segments sent for the first time in one `flush`, 0 means unlimited (default)
+    ///
+    /// The rest of the data is kept in queue and sent in the next `flush`. This avoids
+    /// dumping the whole window at once when it opens.
+    #[inline]
+    pub fn set_max_burst(&mut self, segments: usize) {
+        self.max_burst = segments;
     }
 
+    /// Get maximum segments sent for the first time in one `flush`
     #[inline]
-    fn ack_push(&mut self, sn: u32, ts: u32) {
-        self.acklist.push_back((sn, ts));
+    pub fn max_burst(&self) -> usize {
+        self.max_burst
     }
 
-    fn parse_data(&mut self, new_segment: KcpSegment) {
-        let sn = new_segment.sn;
+    /// Segments sent for the first time in the last `flush`
+    #[inline]
+    pub fn last_burst(&self) -> usize {
+        self.last_burst
+    }
 
-        if timediff(sn, self.rcv_nxt + self.rcv_wnd as u32) >= 0 || timediff(sn, self.rcv_nxt) < 0 {
-            return;
-        }
+    /// Set maximum segments waiting in the send queue, 0 means unlimited (default)
+    ///
+    /// `send` fails with `Error::SendQueueFull` if the message doesn't fit, nothing is enqueued.
+    #[inline]
+    pub fn set_snd_queue_limit(&mut self, max_segments: usize) {
+        self.snd_queue_limit = max_segments;
+    }
 
-        let mut repeat = false;
-        let mut new_index = self.rcv_buf.len();
+    /// Get maximum segments waiting in the send queue
+    #[inline]
+    pub fn snd_queue_limit(&self) -> usize {
+        self.snd_queue_limit
+    }
 
-        for segment in self.rcv_buf.iter().rev() {
-            if segment.sn == sn {
-                repeat = true;
-                break;
-            }
-            if timediff(sn, segment.sn) > 0 {
-                break;
-            }
-            new_index -= 1;
-        }
+    /// Set maximum segments buffered out of order, 0 means up to the receive window (default)
+    ///
+    /// Segments beyond it are dropped without an ACK, the remote retransmits them later. The next
+    /// expected segment is always accepted, so the buffer drains and the connection never stalls.
+    #[inline]
+    pub fn set_rcv_buf_limit(&mut self, max_segments: usize) {
+        self.rcv_buf_limit = max_segments;
+    }
 
-        if !repeat {
-            self.rcv_buf.insert(new_index, new_segment);
-        }
+    /// Get maximum segments buffered out of order
+    #[inline]
+    pub fn rcv_buf_limit(&self) -> usize {
+        self.rcv_buf_limit
+    }
 
-        // move available data from rcv_buf -> rcv_queue
-        self.move_buf();
+    /// Set maximum bytes of `buffered_bytes`, 0 means unlimited (default)
+    ///
+    /// `send` fails with `Error::SendQueueFull` if the message would exceed it, nothing is
+    /// enqueued. Segments received are never dropped for it, the receive window bounds them.
+    #[inline]
+    pub fn set_total_buffer_limit(&mut self, bytes: usize) {
+        self.total_buffer_limit = bytes;
     }
 
-    /// Get `conv` from the next `input` call
+    /// Get maximum bytes of `buffered_bytes`
     #[inline]
-    pub fn input_conv(&mut self) {
-        self.input_conv = true;
+    pub fn total_buffer_limit(&self) -> usize {
+        self.total_buffer_limit
     }
 
-    /// Check if Kcp is waiting for the next input
+    /// Set maximum fragments of a message, 0 means less than the receive window (default)
+    ///
+    /// `send` fails with `Error::UserBufTooBig` for a message needing more. A message can't be
+    /// received with more fragments than the receive window of the remote, it would never be
+    /// complete. The default assumes the remote has the same window, and once it has been heard
+    /// from, not more than the largest window it has advertised. It is never more than 256, the
+    /// fragment number is a byte.
     #[inline]
-    pub fn waiting_conv(&self) -> bool {
-        self.input_conv
+    pub fn set_max_fragments(&mut self, max_fragments: usize) {
+        self.max_fragments = max_fragments;
     }
 
-    /// Set `conv` value
+    /// Get maximum fragments of a message
     #[inline]
-    pub fn set_conv(&mut self, conv: u32) {
-        self.conv = conv;
+    pub fn max_fragments(&self) -> usize {
+        let max = if self.max_fragments > 0 {
+            self.max_fragments
+        } else if self.rmt_wnd_max > 0 {
+            cmp::max(cmp::min(self.rcv_wnd, self.rmt_wnd_max) as usize - 1, 1)
+        } else {
+            cmp::max(self.rcv_wnd as usize - 1, 1)
+        };
+        cmp::min(max, KCP_FRG_MAX)
     }
 
-    /// Get `conv`
+    /// Segments waiting in the send queue, not yet moved into the send window
     #[inline]
-    pub fn conv(&self) -> u32 {
-        self.conv
+    pub fn snd_queue_len(&self) -> usize {
+        self.snd_queue.len()
     }
 
-    /// Call this when you received a packet from raw connection
-    pub fn input(&mut self, buf: &[u8]) -> KcpResult<usize> {
-        let input_size = buf.len();
+    // Start a new datagram if `need` more bytes doesn't fit in the current one
+    #[inline]
+    fn reserve_datagram(&mut self, need: usize) {
+        if !self.buf.is_empty() && self.buf.len() + need > self.mtu - self.reserved {
+            let datagram = self.buf.split();
+            self.output_queue.push_back(datagram);
+        }
+    }
+
+    // Queue the current datagram for writing
+    #[inline]
+    fn finish_datagram(&mut self) {
+        if !self.buf.is_empty() {
+            let datagram = self.buf.split();
+            self.output_queue.push_back(datagram);
+            self.ts_last_send = self.current;
+        }
+    }
 
-        trace!("[RI] {} bytes", buf.len());
+    fn encode_ack(&mut self, segment: &mut KcpSegment) {
+        // flush acknowledges
+        // while let Some((sn, ts)) = self.acklist.pop_front() {
+        let acklist = mem::take(&mut self.acklist);
+        if !acklist.is_empty() {
+            self.ts_ack_flush = self.current;
+        }
+        self.flush_stats.acks_sent += acklist.len();
+
+        #[cfg(feature = "sack")]
+        let sack = self.sack && !acklist.is_empty();
+        #[cfg(feature = "sack")]
+        if sack && self.sack_peer {
+            self.encode_sack(&acklist, segment);
+            self.acklist = acklist;
+            self.acklist.clear();
+            return;
+        }
 
-        if buf.len() < KCP_OVERHEAD as usize {
-            debug!(
-                "input bufsize={} too small, at least {}",
-                buf.len(),
-                KCP_OVERHEAD
+        for &(sn, ts) in &acklist {
+            self.reserve_datagram(self.overhead());
+            segment.sn = sn;
+            segment.ts = ts;
+            segment.encode(
+                &mut self.buf,
+                self.compact,
+                self.checksum,
+                self.big_endian,
+                self.session_token,
             );
-            return Err(Error::InvalidSegmentSize(buf.len()));
         }
 
-        let mut flag = false;
-        let mut max_ack = 0;
-        let old_una = self.snd_una;
-        let mut latest_ts = 0;
-
-        let mut buf = Cursor::new(buf);
-        while buf.remaining() >= KCP_OVERHEAD as usize {
-            let conv = buf.get_u32_le();
-            if conv != self.conv {
-                // This allows getting conv from this call, which allows us to allocate
-                // conv from the server side.
-                if self.input_conv {
-                    debug!("input conv={} updated, original conv={}", conv, self.conv);
-                    self.conv = conv;
-                    self.input_conv = false;
-                } else {
-                    debug!("input conv={} expected conv={} not match", conv, self.conv);
-                    return Err(Error::ConvInconsistent(self.conv, conv));
+        // Announced after the usual ACKs, which are read by any remote
+        #[cfg(feature = "sack")]
+        if sack {
+            self.encode_sack(&acklist, segment);
+        }
+        self.acklist = acklist;
+        self.acklist.clear();
+    }
+
+    // Encode `acklist` as ranges of sn, or an empty SACK announcing the support before the
+    // remote is known to understand them
+    #[cfg(feature = "sack")]
+    fn encode_sack(&mut self, acklist: &VecDeque<(u32, u32)>, segment: &mut KcpSegment) {
+        let mut ranges: Vec<(u32, u32)> = Vec::new();
+        let mut ts = acklist[0].1;
+        if self.sack_peer {
+            let mut sns: Vec<u32> = acklist.iter().map(|&(sn, _)| sn).collect();
+            sns.sort_unstable_by(|&a, &b| serial_diff(a, b).cmp(&0));
+            for sn in sns {
+                match ranges.last_mut() {
+                    Some(range) if range.1 == sn => range.1 = sn.wrapping_add(1),
+                    Some(range) if serial_lt(sn, range.1) => {}
+                    _ => ranges.push((sn, sn.wrapping_add(1))),
                 }
             }
-
-            let cmd = buf.get_u8();
-            let frg = buf.get_u8();
-            let wnd = buf.get_u16_le();
-            let ts = buf.get_u32_le();
-            let sn = buf.get_u32_le();
-            let una = buf.get_u32_le();
-            let len = buf.get_u32_le() as usize;
-
-            if buf.remaining() < len as usize {
-                debug!(
-                    "input bufsize={} payload length={} remaining={} not match",
-                    input_size,
-                    len,
-                    buf.remaining()
-                );
-                return Err(Error::InvalidSegmentDataSize(len, buf.remaining()));
+            for &(_, t) in acklist {
+                if timediff(t, ts) > 0 {
+                    ts = t;
+                }
             }
+        }
 
-            match cmd {
-                KCP_CMD_PUSH | KCP_CMD_ACK | KCP_CMD_WASK | KCP_CMD_WINS => {}
-                _ => {
-                    debug!("input cmd={} unrecognized", cmd);
-                    return Err(Error::UnsupportedCmd(cmd));
-                }
+        segment.cmd = KCP_CMD_SACK;
+        segment.ts = ts;
+        segment.sn = 0;
+        let mut chunks = ranges.chunks(cmp::max(self.mss / 8, 1)).peekable();
+        loop {
+            for &(start, end) in chunks.next().unwrap_or(&[]) {
+                segment.data.put_u32_le(start);
+                segment.data.put_u32_le(end);
+            }
+            self.reserve_datagram(self.overhead() + segment.data.len());
+            segment.encode(
+                &mut self.buf,
+                self.compact,
+                self.checksum,
+                self.big_endian,
+                self.session_token,
+            );
+            segment.data.clear();
+            if chunks.peek().is_none() {
+                break;
             }
+        }
+        segment.cmd = KCP_CMD_ACK;
 
-            self.rmt_wnd = wnd;
+        // Nothing after an unknown command is read by the remote
+        if !self.sack_peer {
+            self.finish_datagram();
+        }
+    }
 
-            self.parse_una(una);
-            self.shrink_buf();
+    fn encode_probe_command(&mut self, cmd: u8, segment: &mut KcpSegment) {
+        if cmd == KCP_CMD_WASK && self.mtu_probe > 0 {
+            debug!("conv={} mtu probe {} given up", self.conv, self.mtu_probe);
+            self.mtu_probe = 0;
+            self.mtu_probe_deadline = None;
+        }
+        segment.cmd = cmd;
+        self.reserve_datagram(self.overhead());
+        segment.encode(
+            &mut self.buf,
+            self.compact,
+            self.checksum,
+            self.big_endian,
+            self.session_token,
+        );
+    }
 
-            let mut has_read_data = false;
+    // Nothing has been sent for a keepalive interval at `current`
+    fn keepalive_due(&self, current: u32) -> bool {
+        self.keepalive > 0 && timediff(current, self.ts_last_send) >= self.keepalive as i32
+    }
 
-            match cmd {
-                KCP_CMD_ACK => {
-                    let rtt = timediff(self.current, ts);
-                    if rtt >= 0 {
-                        self.update_ack(rtt as u32);
-                    }
-                    self.parse_ack(sn);
-                    self.shrink_buf();
+    // Ask for the remote window while nothing has been sent for an interval, its answer proves
+    // the remote is alive
+    fn encode_keepalive(&mut self, queued: usize, segment: &mut KcpSegment) {
+        // A MTU probe to be sent is a window probe as well
+        let sending =
+            !self.buf.is_empty() || self.output_queue.len() > queued || self.mtu_probe_pending();
+        if !sending && self.keepalive_due(self.current) {
+            trace!("conv={} keepalive", self.conv);
+            self.encode_probe_command(KCP_CMD_WASK, segment);
+        }
 
-                    if !flag {
-                        flag = true;
-                        max_ack = sn;
-                        latest_ts = ts;
-                    } else if timediff(sn, max_ack) > 0 {
-                        #[cfg(feature = "fastack-conserve")]
-                        {
-                            max_ack = sn;
-                            latest_ts = ts;
-                        }
-                        #[cfg(not(feature = "fastack-conserve"))]
-                        if timediff(ts, latest_ts) > 0 {
-                            max_ack = sn;
-                            latest_ts = ts;
-                        }
-                    }
+        let missed = timediff(self.current, self.ts_last_recv) as i64;
+        if self.keepalive_max_missed > 0
+            && missed >= self.keepalive as i64 * self.keepalive_max_missed as i64
+        {
+            self.state = LinkState::DeadLink;
+        }
+    }
 
-                    trace!(
-                        "input ack: sn={} rtt={} rto={}",
-                        sn,
-                        timediff(self.current, ts),
-                        self.rx_rto
-                    );
-                }
-                KCP_CMD_PUSH => {
-                    trace!("input psh: sn={} ts={}", sn, ts);
+    fn encode_probe_commands(&mut self, segment: &mut KcpSegment) {
+        // flush window probing commands
+        if (self.probe & KCP_ASK_SEND) != 0 {
+            self.encode_probe_command(KCP_CMD_WASK, segment);
+        }
 
-                    if timediff(sn, self.rcv_nxt + self.rcv_wnd as u32) < 0 {
-                        self.ack_push(sn, ts);
-                        if timediff(sn, self.rcv_nxt) >= 0 {
-                            let mut sbuf = BytesMut::with_capacity(len as usize);
-                            unsafe {
-                                sbuf.set_len(len as usize);
-                            }
-                            buf.read_exact(&mut sbuf).unwrap();
-                            has_read_data = true;
+        // flush window probing commands
+        if (self.probe & KCP_ASK_TELL) != 0 {
+            self.encode_probe_command(KCP_CMD_WINS, segment);
+        }
+        self.probe = 0;
+    }
 
-                            let mut segment = KcpSegment::new_with_data(sbuf);
+    // Forget the segments past their deadline at the front of `snd_buf`, with the rest of their
+    // messages, so the remote could be told to skip them. One behind a segment still to be
+    // delivered is resent until it comes to the front.
+    #[cfg(feature = "partial")]
+    fn forget_expired(&mut self) {
+        let current = self.current;
+        let mut forward = None;
 
-                            segment.conv = conv;
-                            segment.cmd = cmd;
-                            segment.frg = frg;
-                            segment.wnd = wnd;
-                            segment.ts = ts;
-                            segment.sn = sn;
-                            segment.una = una;
+        while let Some(seg) = self.snd_buf.front() {
+            if seg
+                .deadline
+                .is_none_or(|deadline| timediff(current, deadline) < 0)
+            {
+                break;
+            }
 
-                            self.parse_data(segment);
-                        }
-                    }
-                }
-                KCP_CMD_WASK => {
-                    // ready to send back IKCP_CMD_WINS in ikcp_flush
-                    // tell remote my window size
-                    trace!("input probe");
-                    self.probe |= KCP_ASK_TELL;
-                }
-                KCP_CMD_WINS => {
-                    // Do nothing
-                    trace!("input wins: {}", wnd);
-                }
-                _ => unreachable!(),
+            let start = seg.sn;
+            let mut end = seg.sn.wrapping_add(seg.frg as u32 + 1);
+            while self
+                .snd_buf
+                .front()
+                .is_some_and(|seg| serial_lt(seg.sn, end))
+            {
+                self.snd_buf.pop_front();
+            }
+            if serial_lt(self.snd_nxt, end) {
+                // The rest of the message is still queued
+                let rest = end.wrapping_sub(self.snd_nxt) as usize;
+                self.snd_queue.drain(..cmp::min(rest, self.snd_queue.len()));
+                self.snd_frg = 0;
+                end = self.snd_nxt;
             }
+            forward = Some(end);
 
-            // Force skip unread data
-            if !has_read_data {
-                let next_pos = buf.position() + len as u64;
-                buf.set_position(next_pos);
+            match self.forgotten.back_mut() {
+                Some(range) if range.end == start => range.end = end,
+                _ => self.forgotten.push_back(start..end),
             }
         }
 
-        if flag {
-            self.parse_fastack(max_ack, latest_ts);
+        // Too old to compare with sn
+        let snd_nxt = self.snd_nxt;
+        while self
+            .forgotten
+            .front()
+            .is_some_and(|range| snd_nxt.wrapping_sub(range.end) > 1 << 30)
+        {
+            self.forgotten.pop_front();
         }
 
-        if timediff(self.snd_una, old_una) > 0 && self.cwnd < self.rmt_wnd {
-            let mss = self.mss;
-            if self.cwnd < self.ssthresh {
-                self.cwnd += 1;
-                self.incr += mss;
-            } else {
-                if self.incr < mss {
-                    self.incr = mss;
-                }
-                self.incr += (mss * mss) / self.incr + (mss / 16);
-                if (self.cwnd as usize + 1) * mss <= self.incr {
-                    // self.cwnd += 1;
-                    self.cwnd = ((self.incr + mss - 1) / if mss > 0 { mss } else { 1 }) as u16;
-                }
-            }
-            if self.cwnd > self.rmt_wnd {
-                self.cwnd = self.rmt_wnd;
-                self.incr = self.rmt_wnd as usize * mss;
-            }
+        if let Some(sn) = forward {
+            debug!("conv={} forgot segments before sn={}", self.conv, sn);
+            self.forward_sn = Some(sn);
+            self.ts_forward = current;
+            self.shrink_buf();
         }
+    }
 
-        Ok(buf.position() as usize)
+    // The skip to `forward_sn` has to be sent at `current`
+    #[cfg(feature = "partial")]
+    fn forward_due(&self, current: u32) -> bool {
+        self.forward_sn.is_some() && timediff(current, self.ts_forward) >= 0
     }
 
-    fn wnd_unused(&self) -> u16 {
-        if self.rcv_queue.len() < self.rcv_wnd as usize {
-            self.rcv_wnd - self.rcv_queue.len() as u16
-        } else {
-            0
-        }
+    // Tell the remote to skip the forgotten segments, again every RTO until its `una` reaches them
+    #[cfg(feature = "partial")]
+    fn encode_forward(&mut self, segment: &mut KcpSegment) {
+        let sn = match self.forward_sn {
+            Some(sn) if self.forward_due(self.current) => sn,
+            _ => return,
+        };
+
+        self.ts_forward = self.current.wrapping_add(self.rx_rto);
+        segment.cmd = KCP_CMD_FORWARD;
+        segment.sn = sn;
+        segment.ts = self.current;
+        self.reserve_datagram(self.overhead());
+        segment.encode(
+            &mut self.buf,
+            self.compact,
+            self.checksum,
+            self.big_endian,
+            self.session_token,
+        );
     }
 
-    fn probe_wnd_size(&mut self) {
-        // probe window size (if remote window size equals zero)
-        if self.rmt_wnd == 0 {
-            if self.probe_wait == 0 {
-                self.probe_wait = KCP_PROBE_INIT;
-                self.ts_probe = self.current + self.probe_wait;
-            } else {
-                if timediff(self.current, self.ts_probe) >= 0 {
-                    if self.probe_wait < KCP_PROBE_INIT {
-                        self.probe_wait = KCP_PROBE_INIT;
-                    }
+    // Skip to `sn`, the segments before it are forgotten by the remote. Always answered with the
+    // window, whose `una` tells the remote it is done.
+    #[cfg(feature = "partial")]
+    fn parse_forward(&mut self, sn: u32) {
+        trace!("input forward: sn={}", sn);
+        self.probe |= KCP_ASK_TELL;
+        if serial_le(sn, self.rcv_nxt) || serial_lt(self.rcv_nxt.wrapping_add(self.rcv_wnd), sn) {
+            return;
+        }
 
-                    self.probe_wait += self.probe_wait / 2;
+        // The head of a message whose rest is forgotten
+        while self.rcv_queue.back().is_some_and(|seg| seg.frg != 0) {
+            self.rcv_queue.pop_back();
+        }
+        self.rcv_buf.retain(|seg| serial_le(sn, seg.sn));
+        self.rcv_nxt = sn;
+        self.move_buf();
+    }
 
-                    if self.probe_wait > KCP_PROBE_LIMIT {
-                        self.probe_wait = KCP_PROBE_LIMIT;
-                    }
+    // Learn the ISN of the remote from its request or answer, and drop anything else before it.
+    // Returns the `una` to take, the one of a request is ignored, the remote doesn't know our ISN
+    // yet.
+    #[cfg(feature = "isn")]
+    fn accept_isn(&mut self, cmd: u8, sn: u32, una: u32) -> Option<u32> {
+        if !self.isn {
+            return Some(una);
+        }
 
-                    self.ts_probe = self.current + self.probe_wait;
-                    self.probe |= KCP_ASK_SEND;
-                }
+        let handshake = cmd == KCP_CMD_SYN || cmd == KCP_CMD_SYNACK;
+        if !self.isn_known {
+            if !handshake {
+                return None;
             }
-        } else {
-            self.ts_probe = 0;
-            self.probe_wait = 0;
+            trace!("input isn={}", sn);
+            self.rcv_nxt = sn;
+            self.rcv_max = sn;
+            self.isn_known = true;
         }
-    }
 
-    /// Determine when you should call `update`.
-    /// Return when you should invoke `update` in millisec, if there is no `input`/`send` calling.
-    /// You can call `update` in that time without calling it repeatly.
-    pub fn check(&self, current: u32) -> u32 {
-        if !self.updated {
-            return 0;
+        if cmd == KCP_CMD_SYN {
+            Some(self.snd_una)
+        } else {
+            Some(una)
         }
+    }
 
-        let mut ts_flush = self.ts_flush;
-        let mut tm_packet = u32::max_value();
+    // A connection request or its answer has to be sent at `current`
+    #[cfg(feature = "handshake")]
+    fn handshake_due(&self, current: u32) -> bool {
+        self.synack || (self.connecting && timediff(current, self.ts_syn) >= 0)
+    }
 
-        if timediff(current, ts_flush) >= 10000 || timediff(current, ts_flush) < -10000 {
-            ts_flush = current;
+    // Answer the request of the remote, or request again every RTO until the remote answers.
+    // The link is dead after as many requests as resends of a segment.
+    #[cfg(feature = "handshake")]
+    fn encode_handshake(&mut self, segment: &mut KcpSegment) {
+        // Nothing is acknowledged before the remote knows the ISN, `snd_una` is still at it
+        segment.sn = if self.isn { self.snd_una } else { 0 };
+        segment.ts = self.current;
+
+        if self.synack {
+            self.synack = false;
+            segment.cmd = KCP_CMD_SYNACK;
+            self.reserve_datagram(self.overhead());
+            segment.encode(
+                &mut self.buf,
+                self.compact,
+                self.checksum,
+                self.big_endian,
+                self.session_token,
+            );
         }
 
-        if timediff(current, ts_flush) >= 0 {
-            return 0;
+        if !self.handshake_due(self.current) || self.is_dead_link() {
+            return;
+        }
+        if self.syn_xmit >= self.dead_link {
+            debug!("conv={} connection not accepted", self.conv);
+            self.state = LinkState::DeadLink;
+            return;
         }
 
-        let tm_flush = timediff(ts_flush, current) as u32;
-        for seg in &self.snd_buf {
-            let diff = timediff(seg.resendts, current);
-            if diff <= 0 {
-                return 0;
-            }
-            if (diff as u32) < tm_packet {
-                tm_packet = diff as u32;
-            }
+        self.syn_xmit += 1;
+        self.ts_syn = self.current.wrapping_add(self.rx_rto);
+        segment.cmd = KCP_CMD_SYN;
+        self.reserve_datagram(self.overhead());
+        segment.encode(
+            &mut self.buf,
+            self.compact,
+            self.checksum,
+            self.big_endian,
+            self.session_token,
+        );
+    }
+
+    // Record the window scale negotiation state of the remote
+    #[cfg(feature = "wscale")]
+    fn parse_wscale(&mut self, shift: u8, flags: u32) {
+        trace!("input wscale: shift={} flags={}", shift, flags);
+        if self.wscale.is_none() {
+            return;
         }
 
-        let mut minimal = cmp::min(tm_packet, tm_flush);
-        if minimal >= self.interval {
-            minimal = self.interval;
+        // Its shift is fixed once it scales
+        if !self.wscale_decode || self.wscale_peer.is_none() {
+            self.wscale_peer = Some(cmp::min(shift, KCP_WSCALE_MAX));
         }
+        self.wscale_decode = flags & KCP_WSCALE_SCALED != 0;
+        self.wscale_acked = flags & KCP_WSCALE_DECODED != 0;
+        self.wscale_reply = flags & KCP_WSCALE_DONE == 0;
+    }
 
-        minimal
+    #[cfg(feature = "wscale")]
+    fn should_send_wscale(&self) -> bool {
+        self.wscale.is_some()
+            && (self.wscale_peer.is_none() || !self.wscale_acked || self.wscale_reply)
     }
 
-    /// Change MTU size, default is 1400
-    ///
-    /// MTU = Maximum Transmission Unit
-    pub fn set_mtu(&mut self, mtu: usize) -> KcpResult<()> {
-        if mtu < 50 || mtu < KCP_OVERHEAD {
-            debug!("set_mtu mtu={} invalid", mtu);
-            return Err(Error::InvalidMtu(mtu));
+    // Encode the shift and the negotiation state, the last segment of the datagram
+    #[cfg(feature = "wscale")]
+    fn encode_wscale(&mut self, segment: &mut KcpSegment) {
+        let mut flags = 0;
+        if self.wscale_peer.is_some() {
+            flags |= KCP_WSCALE_SCALED;
+            if self.wscale_acked {
+                flags |= KCP_WSCALE_DONE;
+            }
+        }
+        if self.wscale_decode {
+            flags |= KCP_WSCALE_DECODED;
         }
 
-        self.mtu = mtu;
-        self.mss = self.mtu - KCP_OVERHEAD;
+        segment.cmd = KCP_CMD_WSCALE;
+        segment.frg = self.wscale.unwrap_or(0);
+        segment.sn = flags;
+        segment.ts = self.current;
+        self.reserve_datagram(self.overhead());
+        segment.encode(
+            &mut self.buf,
+            self.compact,
+            self.checksum,
+            self.big_endian,
+            self.session_token,
+        );
+        segment.frg = 0;
+        self.wscale_reply = false;
+    }
 
-        let target_size = ((mtu + KCP_OVERHEAD) * 3) as usize;
-        if target_size > self.buf.capacity() {
-            self.buf.reserve(target_size - self.buf.capacity());
+    /// Scale the advertised receive window by `shift` bits, up to 14, allowing windows beyond
+    /// 65535 segments
+    ///
+    /// The shift is announced to the remote along with the data and ACKs, and applied once the
+    /// remote has announced its support, until then the windows are capped at 65535. Set it
+    /// before sending anything, it can't be changed once applied. A shift of 0 allows the remote
+    /// to scale its window without scaling ours. Windows above 65535 are set and read with the
+    /// `_scaled` methods, `set_wndsize_scaled` and `rmt_wnd_scaled` for instance, the others cap
+    /// them at `u16::MAX`.
+    ///
+    /// It extends the protocol with a new command, enable it only if the remote is also built
+    /// with the `wscale` feature. The reference C implementation rejects the datagram from that
+    /// segment on, which is the last one of it, and never sends one back, so no window is scaled.
+    #[cfg(feature = "wscale")]
+    pub fn set_window_scale(&mut self, shift: u8) {
+        if self.wscale_peer.is_some() {
+            return;
         }
+        self.wscale = Some(cmp::min(shift, KCP_WSCALE_MAX));
+    }
 
-        Ok(())
+    /// Get the shift of the advertised receive window, `None` if scaling is disabled
+    #[cfg(feature = "wscale")]
+    #[inline]
+    pub fn window_scale(&self) -> Option<u8> {
+        self.wscale
     }
 
-    /// Get MTU
+    /// Check if the window scaling is settled both ways with the remote
+    #[cfg(feature = "wscale")]
     #[inline]
-    pub fn mtu(&self) -> usize {
-        self.mtu
+    pub fn window_scale_negotiated(&self) -> bool {
+        self.wscale_peer.is_some() && self.wscale_decode && self.wscale_acked
     }
 
-    /// Set check interval
-    pub fn set_interval(&mut self, mut interval: u32) {
-        if interval > 5000 {
-            interval = 5000;
-        } else if interval < 10 {
-            interval = 10;
-        }
-        self.interval = interval;
+    /// Enable selective acknowledgement, disabled by default
+    ///
+    /// ACKs are sent as ranges of sn once the remote has sent any, which saves a segment
+    /// for every ACK under loss. Until then every ACK datagram ends with an empty SACK segment
+    /// announcing the support, along with the usual ACKs.
+    ///
+    /// It extends the protocol with a new command, enable it only if the remote is also built
+    /// with the `sack` feature. The reference C implementation rejects the datagram from that
+    /// segment on, which is the last one of it, and never sends one back.
+    #[cfg(feature = "sack")]
+    #[inline]
+    pub fn set_sack(&mut self, enabled: bool) {
+        self.sack = enabled;
     }
 
-    /// Set nodelay
+    /// Append a CRC32 of the header and the payload to every segment, disabled by default
     ///
-    /// fastest config: nodelay(true, 20, 2, true)
+    /// A segment corrupted on the way, which the weak UDP checksum let through, fails `input`
+    /// with `Error::ChecksumMismatch` instead of being taken as valid, and is retransmitted like a
+    /// lost one. It costs 4 bytes of every segment, the MSS shrinks accordingly. Returns
+    /// `Error::UserBufTooBig` and keeps it disabled if a queued message needs too many fragments.
     ///
-    /// `nodelay`: default is disable (false)
-    /// `interval`: internal update timer interval in millisec, default is 100ms
-    /// `resend`: 0:disable fast resend(default), 1:enable fast resend
-    /// `nc`: `false`: normal congestion control(default), `true`: disable congestion control
-    pub fn set_nodelay(&mut self, nodelay: bool, interval: i32, resend: i32, nc: bool) {
-        if nodelay {
-            self.nodelay = true;
-            self.rx_minrto = KCP_RTO_NDL;
-        } else {
-            self.nodelay = false;
-            self.rx_minrto = KCP_RTO_MIN;
+    /// It changes the wire format, enable it only if the remote has enabled it as well. The
+    /// reference C implementation, or a `Kcp` without it, can't talk to it.
+    #[cfg(feature = "checksum")]
+    pub fn set_checksum(&mut self, enabled: bool) -> KcpResult<()> {
+        let old = mem::replace(&mut self.checksum, enabled);
+        let result = self.resize_mss(self.mtu, self.reserved);
+        if result.is_err() {
+            self.checksum = old;
         }
+        result
+    }
 
-        match interval {
-            interval if interval < 10 => self.interval = 10,
-            interval if interval > 5000 => self.interval = 5000,
-            _ => self.interval = interval as u32,
-        }
+    /// Check if every segment carries a CRC32
+    #[cfg(feature = "checksum")]
+    #[inline]
+    pub fn checksum(&self) -> bool {
+        self.checksum
+    }
 
-        if resend >= 0 {
-            self.fastresend = resend as u32;
+    /// Append `token` to every segment, and reject any segment without it, `None` disables it
+    /// (default)
+    ///
+    /// A server allocating convs from a small space may give the same one to two sessions by
+    /// accident, and a datagram misrouted between them would be taken by the wrong one. Derive
+    /// the token from a secret of each session, like a hash of the key exchanged when it was set
+    /// up, and a segment of another session fails `input` with `Error::SessionMismatch`. It is
+    /// not a MAC, it only tells sessions apart. It costs 4 bytes of every segment, the MSS shrinks
+    /// accordingly. Returns `Error::UserBufTooBig` and keeps the previous token if a queued
+    /// message needs too many fragments.
+    ///
+    /// It changes the wire format, both sides must set the same token. The token comes after the
+    /// payload, before the CRC32 of `set_checksum` which covers it. The reference C
+    /// implementation, or a `Kcp` without it, can't talk to it.
+    #[cfg(feature = "session")]
+    pub fn set_session_token(&mut self, token: Option<u32>) -> KcpResult<()> {
+        let old = mem::replace(&mut self.session_token, token);
+        let result = self.resize_mss(self.mtu, self.reserved);
+        if result.is_err() {
+            self.session_token = old;
         }
+        result
+    }
 
-        self.nocwnd = nc;
+    /// Get the session token appended to every segment
+    #[cfg(feature = "session")]
+    #[inline]
+    pub fn session_token(&self) -> Option<u32> {
+        self.session_token
     }
 
-    /// Set `wndsize`
-    /// set maximum window size: `sndwnd=32`, `rcvwnd=32` by default
-    pub fn set_wndsize(&mut self, sndwnd: u16, rcvwnd: u16) {
-        if sndwnd > 0 {
-            self.snd_wnd = sndwnd as u16;
-        }
+    /// Set the byte order of the multi-byte header fields, little-endian by default
+    ///
+    /// `conv`, `wnd`, `ts`, `sn`, `una` and `len` are encoded and decoded in it. Payloads are left
+    /// as they are, and so are the CRC32 of `set_checksum` and the ranges of SACK segments, which
+    /// stay little-endian. Route big-endian datagrams with `get_conv_with` instead of
+    /// `try_get_conv`.
+    ///
+    /// Big-endian breaks compatibility with the reference C implementation and every other
+    /// standard KCP peer, enable it only if the remote uses it as well.
+    #[cfg(feature = "endian")]
+    #[inline]
+    pub fn set_endianness(&mut self, endian: Endian) {
+        self.big_endian = endian.is_big();
+    }
 
-        if rcvwnd > 0 {
-            self.rcv_wnd = cmp::max(rcvwnd, KCP_WND_RCV) as u16;
+    /// Get the byte order of the multi-byte header fields
+    #[cfg(feature = "endian")]
+    #[inline]
+    pub fn endianness(&self) -> Endian {
+        if self.big_endian {
+            Endian::Big
+        } else {
+            Endian::Little
         }
     }
 
-    /// `snd_wnd` Send window
+    /// Forget messages of `send_with_deadline` past their deadline even once sent, disabled by default
+    ///
+    /// Such a message at the front of the send buffer is neither resent nor waited for anymore,
+    /// the remote is told to skip it and drops any part of it already received. One behind a
+    /// message still to be delivered is resent until it comes to the front, as the remote
+    /// delivers in order. Partial reliability for data going stale, like media frames.
+    ///
+    /// It extends the protocol with a new command, enable it only if the remote is also built
+    /// with the `partial` feature. The reference C implementation rejects the datagram from that
+    /// segment on and never skips, so the connection stalls.
+    #[cfg(feature = "partial")]
     #[inline]
-    pub fn snd_wnd(&self) -> u16 {
-        self.snd_wnd
+    pub fn set_partial_reliability(&mut self, enabled: bool) {
+        self.partial = enabled;
     }
 
-    /// `rcv_wnd` Receive window
+    /// Check if messages past their deadline are forgotten once sent
+    #[cfg(feature = "partial")]
     #[inline]
-    pub fn rcv_wnd(&self) -> u16 {
-        self.rcv_wnd
+    pub fn partial_reliability(&self) -> bool {
+        self.partial
     }
 
-    /// Get `waitsnd`, how many packet is waiting to be sent
+    /// Check if selective acknowledgement is enabled
+    #[cfg(feature = "sack")]
     #[inline]
-    pub fn wait_snd(&self) -> usize {
-        self.snd_buf.len() + self.snd_queue.len()
+    pub fn sack(&self) -> bool {
+        self.sack
     }
 
-    /// Get `rmt_wnd`, remote window size
+    /// Check if the remote has sent selective acknowledgements
+    #[cfg(feature = "sack")]
     #[inline]
-    pub fn rmt_wnd(&self) -> u16 {
-        self.rmt_wnd
+    pub fn sack_peer(&self) -> bool {
+        self.sack_peer
     }
 
-    /// Set `rx_minrto`
+    /// Acknowledge the data received by `input` right away, disabled by default
+    ///
+    /// The ACKs are encoded at the end of `input`, instead of waiting for the next flush, and
+    /// written by the next `update`, `flush_ack` or `flush`. `check` returns 0 while they are
+    /// waiting, `KcpStream` writes them at once. It lowers the RTT seen by the remote for
+    /// request/response traffic, at the cost of more ACK datagrams. The ACK coalesce window is
+    /// ignored.
     #[inline]
-    pub fn set_rx_minrto(&mut self, rto: u32) {
-        self.rx_minrto = rto;
+    pub fn set_ack_nodelay(&mut self, enabled: bool) {
+        self.ack_nodelay = enabled;
     }
 
-    /// Set `fastresend`
+    /// Check if the data received is acknowledged right away
     #[inline]
-    pub fn set_fast_resend(&mut self, fr: u32) {
-        self.fastresend = fr;
+    pub fn ack_nodelay(&self) -> bool {
+        self.ack_nodelay
     }
 
-    /// KCP header size
+    /// Set how long an ACK-only flush could be deferred in millisec, 0 means never (default)
+    ///
+    /// When there is nothing but ACKs to send, they are kept until more traffic comes or the
+    /// coalesce window passes, which saves radio wakeups on battery-powered receivers.
+    /// ACKs are never deferred longer than half of the RTO, so the sender won't resend.
     #[inline]
-    pub fn header_len() -> usize {
-        KCP_OVERHEAD as usize
+    pub fn set_ack_coalesce_window(&mut self, ms: u32) {
+        self.ack_coalesce = ms;
     }
 
-    /// Enabled stream or not
+    /// Get how long an ACK-only flush could be deferred in millisec
     #[inline]
-    pub fn is_stream(&self) -> bool {
-        self.stream
+    pub fn ack_coalesce_window(&self) -> u32 {
+        self.ack_coalesce
     }
 
-    /// Maximum Segment Size
+    /// Set the minimum interval between flushes of ACKs in millisec, 0 means disabled (default)
+    ///
+    /// Like the delayed ACK of TCP, data could be flushed every `interval` while ACKs are
+    /// batched every `ms`, the `una` of the data segments still acknowledges in between. It
+    /// saves datagrams on the reverse path. ACKs are sent at once when a window probe is
+    /// answered, and never delayed longer than half of the RTO, so the sender won't resend.
     #[inline]
-    pub fn mss(&self) -> usize {
-        self.mss
+    pub fn set_ack_delay(&mut self, ms: u32) {
+        self.ack_delay = ms;
     }
 
-    /// Set maximum resend times
+    /// Get the minimum interval between flushes of ACKs in millisec
     #[inline]
-    pub fn set_maximum_resend_times(&mut self, dead_link: u32) {
-        self.dead_link = dead_link;
+    pub fn ack_delay(&self) -> u32 {
+        self.ack_delay
     }
 
-    /// Check if KCP connection is dead (resend times excceeded)
-    #[inline]
-    pub fn is_dead_link(&self) -> bool {
-        self.state != 0
+    // Calculate window size for sending
+    fn send_window(&self) -> u32 {
+        if self.connecting {
+            return 0;
+        }
+
+        let cwnd = cmp::min(self.snd_wnd, self.rmt_wnd);
+        if !self.nocwnd {
+            cmp::min(self.cc_cwnd(), cwnd)
+        } else {
+            cwnd
+        }
     }
-}
 
-impl<Output: Write> Kcp<Output> {
-    fn _flush_ack(&mut self, segment: &mut KcpSegment) -> KcpResult<()> {
-        // flush acknowledges
-        // while let Some((sn, ts)) = self.acklist.pop_front() {
-        for &(sn, ts) in &self.acklist {
-            if self.buf.len() + KCP_OVERHEAD as usize > self.mtu as usize {
-                self.output.write_all(&self.buf)?;
-                self.buf.clear();
+    // Check if flush at `current` will send any PUSH segments
+    fn has_data_to_send(&self, current: u32) -> bool {
+        if self.rate_wait(current) > 0 {
+            return false;
+        }
+
+        if !self.snd_queue.is_empty()
+            && serial_lt(self.snd_nxt, self.snd_una.wrapping_add(self.send_window()))
+        {
+            return true;
+        }
+
+        let conn = ConnectionState {
+            current,
+            ..self.connection_state()
+        };
+        let detector = self.loss_detector();
+        self.snd_buf
+            .iter()
+            .any(|seg| seg.xmit == 0 || seg.detect_loss(detector, &conn) != Retransmit::No)
+    }
+
+    fn loss_detector(&self) -> &dyn LossDetector {
+        match self.loss_detector {
+            Some(ref detector) => detector.as_ref(),
+            None => &DefaultLossDetector,
+        }
+    }
+
+    fn connection_state(&self) -> ConnectionState {
+        ConnectionState {
+            current: self.current,
+            srtt: self.rx_srtt,
+            rto: self.rx_rto,
+            fastresend: self.dupack_threshold(),
+            fastlimit: self.fastlimit,
+            nodelay: self.nodelay,
+        }
+    }
+
+    // Drop queued messages past their deadline
+    fn drop_expired(&mut self) {
+        let current = self.current;
+        let before = self.snd_queue.len();
+        let mut committed = self.snd_frg;
+        self.snd_queue.retain(|seg| {
+            if committed > 0 {
+                // The rest of a message partially sent
+                committed -= 1;
+                return true;
             }
-            segment.sn = sn;
-            segment.ts = ts;
-            segment.encode(&mut self.buf);
+            match seg.deadline {
+                Some(deadline) => timediff(current, deadline) < 0,
+                None => true,
+            }
+        });
+
+        let dropped = before - self.snd_queue.len();
+        if dropped > 0 {
+            debug!("dropped {} segments past deadline", dropped);
         }
-        self.acklist.clear();
+    }
 
-        Ok(())
+    // Bytes the rate limit allows to be accumulated while idle
+    fn rate_capacity(&self) -> i64 {
+        cmp::max(self.rate_limit as i64 / 10, self.mtu as i64)
     }
 
-    fn _flush_probe_commands(&mut self, cmd: u8, segment: &mut KcpSegment) -> KcpResult<()> {
-        segment.cmd = cmd;
-        if self.buf.len() + KCP_OVERHEAD as usize > self.mtu as usize {
-            self.output.write_all(&self.buf)?;
-            self.buf.clear();
+    fn refill_rate_tokens(&mut self) {
+        if let Some(tokens) = self.rate_tokens_at(self.current) {
+            self.rate_tokens = tokens;
+            self.ts_rate = self.current;
         }
-        segment.encode(&mut self.buf);
-        Ok(())
     }
 
-    fn flush_probe_commands(&mut self, segment: &mut KcpSegment) -> KcpResult<()> {
-        // flush window probing commands
-        if (self.probe & KCP_ASK_SEND) != 0 {
-            self._flush_probe_commands(KCP_CMD_WASK, segment)?;
+    // Bytes the rate limit allows at `current`, `None` if nothing has been refilled since
+    fn rate_tokens_at(&self, current: u32) -> Option<i64> {
+        if self.rate_limit == 0 {
+            return None;
         }
 
-        // flush window probing commands
-        if (self.probe & KCP_ASK_TELL) != 0 {
-            self._flush_probe_commands(KCP_CMD_WINS, segment)?;
+        let elapsed = timediff(current, self.ts_rate);
+        if elapsed <= 0 {
+            return None;
+        }
+
+        let tokens = self.rate_limit as i64 * elapsed as i64 / (1000 * self.tick as i64);
+        if tokens > 0 {
+            Some(cmp::min(self.rate_tokens + tokens, self.rate_capacity()))
+        } else {
+            None
         }
-        self.probe = 0;
-        Ok(())
     }
 
-    /// Flush pending ACKs
-    pub fn flush_ack(&mut self) -> KcpResult<()> {
+    // Clock ticks from `current` until the rate limit allows sending again, 0 if it does now
+    fn rate_wait(&self, current: u32) -> u32 {
+        if self.rate_limit == 0 || self.rate_tokens > 0 {
+            return 0;
+        }
+
+        let need = (1 - self.rate_tokens) as u64 * 1000 * self.tick as u64;
+        let elapsed = need.div_ceil(self.rate_limit as u64);
+        let refilled = self
+            .ts_rate
+            .wrapping_add(elapsed.min(u32::MAX as u64) as u32);
+        cmp::max(timediff(refilled, current), 0) as u32
+    }
+
+    // Check if ACKs should be kept for the next flush
+    fn defer_ack(&self, current: u32) -> bool {
+        if self.acklist.is_empty() || self.probe != 0 {
+            return false;
+        }
+
+        if self.ack_delay > 0 {
+            let delay = cmp::min(self.ticks(self.ack_delay), self.rx_rto / 2);
+            if timediff(current, self.ts_ack_flush.wrapping_add(delay)) < 0 {
+                return true;
+            }
+        }
+
+        if self.ack_coalesce == 0 {
+            return false;
+        }
+
+        let window = cmp::min(self.ticks(self.ack_coalesce), self.rx_rto / 2);
+        timediff(current, self.ts_ack.wrapping_add(window)) < 0 && !self.has_data_to_send(current)
+    }
+
+    // Encode pending ACKs into datagrams
+    fn encode_flush_ack(&mut self, probes: bool) -> KcpResult<()> {
         if !self.updated {
             debug!("flush updated() must be called at least once");
             return Err(Error::NeedUpdate);
         }
 
-        let mut segment = KcpSegment {
-            conv: self.conv,
-            cmd: KCP_CMD_ACK,
-            wnd: self.wnd_unused(),
-            una: self.rcv_nxt,
-            ..Default::default()
-        };
+        self.flush_stats = FlushStats::default();
+        self.encode_acks(probes);
+        Ok(())
+    }
+
+    // Encode pending ACKs, and pending window probes if `probes`, into datagrams of their own
+    fn encode_acks(&mut self, probes: bool) {
+        let queued = self.output_queue.len();
+        self.wnd_advertised = Some(self.wnd_unused());
+        let mut segment = KcpSegment {
+            conv: self.conv,
+            cmd: KCP_CMD_ACK,
+            wnd: self.encode_wnd(self.wnd_unused()),
+            una: self.rcv_nxt,
+            ..Default::default()
+        };
+
+        self.encode_ack(&mut segment);
+        if probes {
+            self.encode_probe_commands(&mut segment);
+        }
+        self.finish_datagram();
+        self.encrypt_datagrams(queued);
+    }
+
+    // Encrypt the datagrams queued from `from`, the conv is left in the clear
+    fn encrypt_datagrams(&mut self, from: usize) {
+        let crypt = match self.crypt {
+            Some(ref crypt) => crypt,
+            None => return,
+        };
+
+        let clear = if self.compact { 0 } else { 4 };
+        for datagram in self.output_queue.iter_mut().skip(from) {
+            let mut body = datagram.split_off(clear);
+            crypt.encrypt(&mut body);
+            datagram.unsplit(body);
+        }
+    }
+
+    // Check if at least `min` ACKs are pending for `flush_ack_if`
+    #[inline]
+    fn acks_reached(&self, min: usize) -> bool {
+        !self.acklist.is_empty() && self.acklist.len() >= min
+    }
+
+    /// Flush pending data in buffer, appending the datagrams to `out` instead of writing them to
+    /// the output, returns the bytes appended
+    ///
+    /// Datagrams kept by a failed write of the output go first. The length of each one is pushed
+    /// to `lens`, so `out` is split into datagrams of at most `mtu` bytes, each one the payload of
+    /// a UDP packet. Segments are self-delimiting as well, so `out` could be written as is to a
+    /// stream read by `input_framed`. Returns `Error::InvalidConfig` with `set_crypt`, as
+    /// encrypted datagrams are not self-delimiting, use `new_collecting` and `take_output` then.
+    pub fn flush_into(&mut self, out: &mut BytesMut, lens: &mut Vec<usize>) -> KcpResult<usize> {
+        if self.crypt.is_some() {
+            return Err(Error::InvalidConfig("flush_into with crypt"));
+        }
+        self.encode_flush()?;
+
+        let mut n = 0;
+        while let Some(datagram) = self.output_queue.pop_front() {
+            if let Some(ref mut hook) = self.packet_hook {
+                hook(&datagram, self.current);
+            }
+            out.extend_from_slice(&datagram);
+            lens.push(datagram.len());
+            n += datagram.len();
+        }
+        self.flush_stats.bytes_written += n;
+        Ok(n)
+    }
 
-        self._flush_ack(&mut segment)
+    // Encode everything should be sent into datagrams
+    fn encode_flush(&mut self) -> KcpResult<()> {
+        self.encode_flush_limited(0)
     }
 
-    /// Flush pending data in buffer.
-    pub fn flush(&mut self) -> KcpResult<()> {
+    // Encode a flush sending at most `max_segments` PUSH segments, 0 means unlimited
+    fn encode_flush_limited(&mut self, max_segments: usize) -> KcpResult<()> {
         if !self.updated {
             debug!("flush updated() must be called at least once");
             return Err(Error::NeedUpdate);
         }
 
+        self.flush_stats = FlushStats::default();
+
+        let queued = self.output_queue.len();
+        self.wnd_advertised = Some(self.wnd_unused());
         let mut segment = KcpSegment {
             conv: self.conv,
             cmd: KCP_CMD_ACK,
-            wnd: self.wnd_unused(),
+            wnd: self.encode_wnd(self.wnd_unused()),
             una: self.rcv_nxt,
             ..Default::default()
         };
 
-        self._flush_ack(&mut segment)?;
-        self.probe_wnd_size();
-        self.flush_probe_commands(&mut segment)?;
+        #[cfg(feature = "partial")]
+        if self.partial {
+            self.forget_expired();
+        }
 
-        // println!("SNDBUF size {}", self.snd_buf.len());
+        self.probe_wnd_size();
+        if !self.defer_ack(self.current) {
+            self.encode_ack(&mut segment);
+        }
+        self.encode_probe_commands(&mut segment);
+        #[cfg(feature = "partial")]
+        self.encode_forward(&mut segment);
 
         // calculate window size
-        let mut cwnd = cmp::min(self.snd_wnd, self.rmt_wnd);
-        if !self.nocwnd {
-            cwnd = cmp::min(self.cwnd, cwnd);
-        }
+        let cwnd = self.send_window();
+
+        self.drop_expired();
+        self.refill_rate_tokens();
 
         // move data from snd_queue to snd_buf
-        while timediff(self.snd_nxt, self.snd_una + cwnd as u32) < 0 {
+        let mut moved = 0;
+        while serial_lt(self.snd_nxt, self.snd_una.wrapping_add(cwnd)) {
+            if self.max_burst > 0 && moved >= self.max_burst {
+                break;
+            }
+
             match self.snd_queue.pop_front() {
                 Some(mut new_segment) => {
                     new_segment.conv = self.conv;
-                    new_segment.cmd = KCP_CMD_PUSH;
+                    if !is_push(new_segment.cmd) {
+                        new_segment.cmd = KCP_CMD_PUSH;
+                    }
                     new_segment.wnd = segment.wnd;
                     new_segment.ts = self.current;
                     new_segment.sn = self.snd_nxt;
-                    self.snd_nxt += 1;
+                    self.snd_nxt = self.snd_nxt.wrapping_add(1);
                     new_segment.una = self.rcv_nxt;
                     new_segment.resendts = self.current;
                     new_segment.rto = self.rx_rto;
                     new_segment.fastack = 0;
                     new_segment.xmit = 0;
+
+                    self.snd_frg = new_segment.frg as usize;
+
                     self.snd_buf.push_back(new_segment);
+                    moved += 1;
                 }
                 None => break,
             }
         }
 
-        // calculate resent
-        let resent = if self.fastresend > 0 {
-            self.fastresend
-        } else {
-            u32::max_value()
+        let was_dead = self.is_dead_link();
+
+        #[cfg(feature = "handshake")]
+        self.encode_handshake(&mut segment);
+
+        let conn = self.connection_state();
+        let detector: &dyn LossDetector = match self.loss_detector {
+            Some(ref detector) => detector.as_ref(),
+            None => &DefaultLossDetector,
         };
 
         let rtomin = if !self.nodelay { self.rx_rto >> 3 } else { 0 };
+        let fastack_rto = self.fastack_rto;
+        let overhead = self.overhead();
 
         let mut lost = false;
         let mut change = 0;
+        let mut burst = 0;
+        let report_losses = self.loss_handler.is_some();
+        let mut losses = Vec::new();
 
         for snd_segment in &mut self.snd_buf {
-            let mut need_send = false;
-
-            if snd_segment.xmit == 0 {
-                need_send = true;
-                snd_segment.xmit += 1;
-                snd_segment.rto = self.rx_rto;
-                snd_segment.resendts = self.current + snd_segment.rto + rtomin;
-            } else if timediff(self.current, snd_segment.resendts) >= 0 {
-                need_send = true;
-                snd_segment.xmit += 1;
-                self.xmit += 1;
-                if !self.nodelay {
-                    snd_segment.rto += cmp::max(snd_segment.rto, self.rx_rto);
-                } else {
-                    let step = snd_segment.rto; // (kcp->nodelay < 2) ? ((IINT32)(segment->rto)) : kcp->rx_rto;
-                    snd_segment.rto += step / 2;
+            let retransmit = if snd_segment.xmit == 0 {
+                if self.max_burst > 0 && burst >= self.max_burst {
+                    continue;
                 }
-                snd_segment.resendts = self.current + snd_segment.rto;
-                lost = true;
-            } else if snd_segment.fastack >= resent {
-                if snd_segment.xmit <= self.fastlimit || self.fastlimit <= 0 {
-                    need_send = true;
+                None
+            } else {
+                match snd_segment.detect_loss(detector, &conn) {
+                    Retransmit::No => continue,
+                    retransmit => Some(retransmit),
+                }
+            };
+
+            // Out of rate, keep it for the next flush
+            if self.rate_limit > 0 && self.rate_tokens <= 0 {
+                continue;
+            }
+
+            // Over the cap, kept with its timers as they are, so it is due again next time
+            if max_segments > 0 && self.flush_stats.segments_sent >= max_segments {
+                continue;
+            }
+
+            match retransmit {
+                None => {
+                    burst += 1;
                     snd_segment.xmit += 1;
-                    snd_segment.fastack = 0;
+                    snd_segment.rto = self.rx_rto;
+                    snd_segment.resendts = self.current + snd_segment.rto + rtomin;
+                }
+                Some(Retransmit::Timeout) => {
+                    snd_segment.xmit += 1;
+                    self.xmit += 1;
+                    if self.rto_backoff > 0.0 {
+                        snd_segment.rto = (snd_segment.rto as f32 * self.rto_backoff) as u32;
+                    } else if !self.nodelay {
+                        snd_segment.rto += cmp::max(snd_segment.rto, self.rx_rto);
+                    } else {
+                        let step = snd_segment.rto; // (kcp->nodelay < 2) ? ((IINT32)(segment->rto)) : kcp->rx_rto;
+                        snd_segment.rto += step / 2;
+                    }
                     snd_segment.resendts = self.current + snd_segment.rto;
+                    self.stats.timeout_retransmits += 1;
+                    lost = true;
+                    if report_losses {
+                        losses.push((Retransmit::Timeout, snd_segment.sn));
+                    }
+                }
+                Some(_) => {
+                    snd_segment.xmit += 1;
+                    snd_segment.fastack = 0;
+                    snd_segment.nack = false;
+                    snd_segment.resendts = if fastack_rto > 0 {
+                        self.current + fastack_rto
+                    } else {
+                        self.current + snd_segment.rto
+                    };
+                    self.stats.fast_retransmits += 1;
                     change += 1;
+                    if report_losses {
+                        losses.push((Retransmit::Fast, snd_segment.sn));
+                    }
                 }
             }
 
-            if need_send {
-                snd_segment.ts = self.current;
-                snd_segment.wnd = segment.wnd;
-                snd_segment.una = self.rcv_nxt;
+            snd_segment.ts = self.current;
+            snd_segment.wnd = segment.wnd;
+            snd_segment.una = self.rcv_nxt;
 
-                let need = KCP_OVERHEAD as usize + snd_segment.data.len();
+            let need = overhead + snd_segment.data.len();
 
-                if self.buf.len() + need > self.mtu as usize {
-                    self.output.write_all(&self.buf)?;
-                    self.buf.clear();
-                }
+            if !self.buf.is_empty() && self.buf.len() + need > self.mtu - self.reserved {
+                let datagram = self.buf.split();
+                self.output_queue.push_back(datagram);
+            }
 
-                snd_segment.encode(&mut self.buf);
+            snd_segment.encode(
+                &mut self.buf,
+                self.compact,
+                self.checksum,
+                self.big_endian,
+                self.session_token,
+            );
+            self.flush_stats.segments_sent += 1;
+            self.stats.segments_sent += 1;
+            if retransmit.is_some() {
+                self.flush_stats.retransmits += 1;
+            }
+            if self.rate_limit > 0 {
+                self.rate_tokens -= need as i64;
+            }
 
-                if snd_segment.xmit >= self.dead_link {
-                    self.state = -1; // (IUINT32)-1
-                }
+            if snd_segment.xmit >= self.dead_link {
+                self.state = LinkState::DeadLink;
             }
         }
 
-        // Flush all data in buffer
-        if !self.buf.is_empty() {
-            self.output.write_all(&self.buf)?;
-            self.buf.clear();
-        }
+        self.last_burst = burst;
 
-        // update ssthresh
-        if change > 0 {
-            let inflight = self.snd_nxt - self.snd_una;
-            self.ssthresh = inflight as u16 / 2;
-            if self.ssthresh < KCP_THRESH_MIN {
-                self.ssthresh = KCP_THRESH_MIN;
-            }
-            self.cwnd = self.ssthresh + resent as u16;
-            self.incr = self.cwnd as usize * self.mss;
+        if self.keepalive > 0 {
+            self.encode_keepalive(queued, &mut segment);
         }
 
-        if lost {
-            self.ssthresh = cwnd / 2;
-            if self.ssthresh < KCP_THRESH_MIN {
-                self.ssthresh = KCP_THRESH_MIN;
-            }
-            self.cwnd = 1;
-            self.incr = self.mss;
+        if self.idle_timeout > 0
+            && !self.is_dead_link()
+            && self.idle_time(self.current) >= self.idle_timeout
+        {
+            debug!("conv={} timed out", self.conv);
+            self.state = LinkState::TimedOut;
         }
 
-        if self.cwnd < 1 {
-            self.cwnd = 1;
-            self.incr = self.mss;
+        if !was_dead && self.is_dead_link() {
+            debug!("conv={} dead link", self.conv);
+            if let Some(ref mut handler) = self.dead_link_handler {
+                handler(self.conv);
+            }
         }
 
-        Ok(())
-    }
-
-    /// Update state every 10ms ~ 100ms.
-    ///
-    /// Or you can ask `check` when to call this again.
-    pub fn update(&mut self, current: u32) -> KcpResult<()> {
-        self.current = current;
-
-        if !self.updated {
-            self.updated = true;
-            self.ts_flush = self.current;
+        // Announced at the end of anything sent, which is read by any remote
+        #[cfg(feature = "wscale")]
+        if self.should_send_wscale() && (!self.buf.is_empty() || self.output_queue.len() > queued) {
+            self.encode_wscale(&mut segment);
         }
 
-        let mut slap = timediff(self.current, self.ts_flush);
+        // Flush all data in buffer
+        self.finish_datagram();
+        self.encode_mtu_probe(segment.wnd);
 
-        if slap >= 10000 || slap < -10000 {
-            self.ts_flush = self.current;
-            slap = 0;
+        // update ssthresh
+        if change > 0 || lost {
+            let state = self.congestion_state(cwnd);
+            let algo = self.cc_algo_mut();
+            if change > 0 {
+                algo.on_loss(Retransmit::Fast, &state);
+            }
+            if lost {
+                algo.on_loss(Retransmit::Timeout, &state);
+            }
         }
 
-        if slap >= 0 {
-            self.ts_flush += self.interval;
-            if timediff(self.current, self.ts_flush) >= 0 {
-                self.ts_flush = self.current + self.interval;
+        self.classic.ensure_cwnd(self.mss);
+
+        if !losses.is_empty() {
+            let cwnd = self.cwnd();
+            let ssthresh = self.ssthresh();
+            if let Some(ref mut handler) = self.loss_handler {
+                for (retransmit, sn) in losses {
+                    handler(match retransmit {
+                        Retransmit::Timeout => LossEvent::Timeout { sn, cwnd, ssthresh },
+                        _ => LossEvent::FastResend { sn, cwnd, ssthresh },
+                    });
+                }
             }
-            self.flush()?;
         }
 
+        self.encrypt_datagrams(queued);
         Ok(())
     }
 }
 
-#[cfg(feature = "tokio")]
-impl<Output: AsyncWrite + Unpin> Kcp<Output> {
-    async fn _async_flush_ack(&mut self, segment: &mut KcpSegment) -> KcpResult<()> {
-        // flush acknowledges
-        // while let Some((sn, ts)) = self.acklist.pop_front() {
-        for &(sn, ts) in &self.acklist {
-            if self.buf.len() + KCP_OVERHEAD as usize > self.mtu as usize {
-                self.output.write_all(&self.buf).await?;
-                self.buf.clear();
-            }
-            segment.sn = sn;
-            segment.ts = ts;
-            segment.encode(&mut self.buf);
+impl<Output: Write> Kcp<Output> {
+    // Write all the encoded datagrams into output, then flush it, which ends a batch for an
+    // output grouping datagrams like `FecEncoder`
+    fn write_output(&mut self) -> KcpResult<()> {
+        if self.output_queue.is_empty() {
+            return Ok(());
         }
-        self.acklist.clear();
 
-        Ok(())
-    }
+        #[cfg(feature = "std")]
+        if self.vectored && self.output_queue.len() > 1 {
+            self.write_output_vectored()?;
+            self.output.flush()?;
+            return Ok(());
+        }
 
-    async fn _async_flush_probe_commands(
-        &mut self,
-        cmd: u8,
-        segment: &mut KcpSegment,
-    ) -> KcpResult<()> {
-        segment.cmd = cmd;
-        if self.buf.len() + KCP_OVERHEAD as usize > self.mtu as usize {
-            self.output.write_all(&self.buf).await?;
-            self.buf.clear();
+        while let Some(datagram) = self.output_queue.front() {
+            if let Some(ref mut hook) = self.packet_hook {
+                hook(datagram, self.current);
+            }
+            self.output.write_all(datagram)?;
+            self.flush_stats.bytes_written += datagram.len();
+            self.output_queue.pop_front();
         }
-        segment.encode(&mut self.buf);
+        self.output.flush()?;
         Ok(())
     }
 
-    async fn async_flush_probe_commands(&mut self, segment: &mut KcpSegment) -> KcpResult<()> {
-        // flush window probing commands
-        if (self.probe & KCP_ASK_SEND) != 0 {
-            self._async_flush_probe_commands(KCP_CMD_WASK, segment)
-                .await?;
+    // Write all queued datagrams with as few `write_vectored` calls as the output takes, a
+    // datagram written partly is resumed from where it stopped
+    #[cfg(feature = "std")]
+    fn write_output_vectored(&mut self) -> KcpResult<()> {
+        if let Some(ref mut hook) = self.packet_hook {
+            for datagram in &self.output_queue {
+                hook(datagram, self.current);
+            }
         }
 
-        // flush window probing commands
-        if (self.probe & KCP_ASK_TELL) != 0 {
-            self._async_flush_probe_commands(KCP_CMD_WINS, segment)
-                .await?;
+        while !self.output_queue.is_empty() {
+            let slices: Vec<std::io::IoSlice<'_>> = self
+                .output_queue
+                .iter()
+                .map(|datagram| std::io::IoSlice::new(datagram))
+                .collect();
+            let mut n = match self.output.write_vectored(&slices) {
+                Ok(0) => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::WriteZero,
+                        "failed to write whole buffer",
+                    )
+                    .into())
+                }
+                Ok(n) => n,
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                Err(err) => return Err(err.into()),
+            };
+
+            self.flush_stats.bytes_written += n;
+            while n > 0 {
+                let datagram = self.output_queue.front_mut().unwrap();
+                if n < datagram.len() {
+                    datagram.advance(n);
+                    break;
+                }
+                n -= datagram.len();
+                self.output_queue.pop_front();
+            }
         }
-        self.probe = 0;
         Ok(())
     }
 
     /// Flush pending ACKs
-    pub async fn async_flush_ack(&mut self) -> KcpResult<()> {
-        if !self.updated {
-            debug!("flush updated() must be called at least once");
-            return Err(Error::NeedUpdate);
-        }
+    pub fn flush_ack(&mut self) -> KcpResult<()> {
+        self.encode_flush_ack(false)?;
+        self.write_output()
+    }
 
-        let mut segment = KcpSegment {
-            conv: self.conv,
-            cmd: KCP_CMD_ACK,
-            wnd: self.wnd_unused(),
-            una: self.rcv_nxt,
-            ..Default::default()
-        };
+    /// Flush what the receiving side owes the remote: pending ACKs and window probes
+    ///
+    /// Unlike `flush`, nothing is moved from the send queue and the send buffer isn't scanned for
+    /// retransmissions, so its timers are left alone. The window is advertised as `flush` does.
+    pub fn flush_recv_side(&mut self) -> KcpResult<()> {
+        self.encode_flush_ack(true)?;
+        self.write_output()
+    }
 
-        self._async_flush_ack(&mut segment).await
+    /// Flush pending window probes, `probe_now` and `tell_window`, with pending ACKs
+    ///
+    /// The same as `flush_recv_side`, the send buffer isn't scanned.
+    #[inline]
+    pub fn flush_probe_only(&mut self) -> KcpResult<()> {
+        self.flush_recv_side()
     }
 
-    /// Flush pending data in buffer.
-    pub async fn async_flush(&mut self) -> KcpResult<()> {
-        if !self.updated {
-            debug!("flush updated() must be called at least once");
-            return Err(Error::NeedUpdate);
+    /// Flush pending ACKs if there are at least `min` of them, returns whether it flushed
+    pub fn flush_ack_if(&mut self, min: usize) -> KcpResult<bool> {
+        if !self.acks_reached(min) {
+            return Ok(false);
         }
+        self.flush_ack()?;
+        Ok(true)
+    }
 
-        let mut segment = KcpSegment {
-            conv: self.conv,
-            cmd: KCP_CMD_ACK,
-            wnd: self.wnd_unused(),
-            una: self.rcv_nxt,
-            ..Default::default()
-        };
+    /// Replace the output, returns the previous one
+    ///
+    /// Datagrams already encoded are written to the previous output first, if that fails the
+    /// output is not replaced. The state of the connection is kept.
+    pub fn set_output(&mut self, output: Output) -> KcpResult<Output> {
+        self.finish_datagram();
+        self.write_output()?;
+        Ok(mem::replace(&mut self.output.0, output))
+    }
 
-        self._async_flush_ack(&mut segment).await?;
-        self.probe_wnd_size();
-        self.async_flush_probe_commands(&mut segment).await?;
+    /// Send bytes and flush them right away, for the first message of a client
+    ///
+    /// The first datagram carries the data along with the conv, a server waiting for it with
+    /// `input_conv` takes the conv and delivers the data from that single datagram, without
+    /// a round trip before. Nothing changes on the wire, these are ordinary PUSH segments, and
+    /// a retransmission of them reaching the server again is dropped as a duplicate.
+    pub fn send_with_conv_negotiation(&mut self, buf: &[u8]) -> KcpResult<usize> {
+        let n = self.send(buf)?;
+        self.flush()?;
+        Ok(n)
+    }
 
-        // println!("SNDBUF size {}", self.snd_buf.len());
+    /// Flush pending data in buffer.
+    ///
+    /// All the datagrams are encoded before any is written, and segments are counted as sent
+    /// when encoded. If the output fails, like `WouldBlock` of a non-blocking socket, the
+    /// datagrams not written yet are kept and written first by the next `flush` or `update`, so
+    /// nothing is lost or encoded twice. The output is flushed after the datagrams are written.
+    pub fn flush(&mut self) -> KcpResult<()> {
+        self.flush_and_report().map(|_| ())
+    }
 
-        // calculate window size
-        let mut cwnd = cmp::min(self.snd_wnd, self.rmt_wnd);
-        if !self.nocwnd {
-            cwnd = cmp::min(self.cwnd, cwnd);
-        }
+    /// Flush pending data in buffer, sending at most `max_segments` PUSH segments, 0 means
+    /// unlimited
+    ///
+    /// Retransmissions come first, then the new segments in order. The rest is left for the next
+    /// `flush` without being counted as sent, their retransmission timers untouched, so a pacing
+    /// sender can spread a window over several intervals instead of a burst. `cwnd` and
+    /// `effective_send_window` tell how much a window is. ACKs and window probes are not capped.
+    pub fn flush_limited(&mut self, max_segments: usize) -> KcpResult<()> {
+        self.encode_flush_limited(max_segments)?;
+        self.write_output()
+    }
 
-        // move data from snd_queue to snd_buf
-        while timediff(self.snd_nxt, self.snd_una + cwnd as u32) < 0 {
-            match self.snd_queue.pop_front() {
-                Some(mut new_segment) => {
-                    new_segment.conv = self.conv;
-                    new_segment.cmd = KCP_CMD_PUSH;
-                    new_segment.wnd = segment.wnd;
-                    new_segment.ts = self.current;
-                    new_segment.sn = self.snd_nxt;
-                    self.snd_nxt += 1;
-                    new_segment.una = self.rcv_nxt;
-                    new_segment.resendts = self.current;
-                    new_segment.rto = self.rx_rto;
-                    new_segment.fastack = 0;
-                    new_segment.xmit = 0;
-                    self.snd_buf.push_back(new_segment);
-                }
-                None => break,
+    /// Flush pending data in buffer, for a non-blocking output
+    ///
+    /// Datagrams left by a previous call are written first, nothing new is encoded until all of
+    /// them are. `WouldBlock` of the output is returned as `FlushOutcome::WouldBlock` instead of
+    /// an error, the next call resumes from the datagram which failed without encoding anything
+    /// again, so no segment is counted as sent twice.
+    pub fn try_flush(&mut self) -> KcpResult<FlushOutcome> {
+        if !self.output_queue.is_empty() {
+            match self.try_write_output()? {
+                FlushOutcome::Complete => {}
+                blocked => return Ok(blocked),
             }
         }
 
-        // calculate resent
-        let resent = if self.fastresend > 0 {
-            self.fastresend
-        } else {
-            u32::max_value()
-        };
-
-        let rtomin = if !self.nodelay { self.rx_rto >> 3 } else { 0 };
-
-        let mut lost = false;
-        let mut change = 0;
+        self.encode_flush()?;
+        self.try_write_output()
+    }
 
-        for snd_segment in &mut self.snd_buf {
-            let mut need_send = false;
-
-            if snd_segment.xmit == 0 {
-                need_send = true;
-                snd_segment.xmit += 1;
-                snd_segment.rto = self.rx_rto;
-                snd_segment.resendts = self.current + snd_segment.rto + rtomin;
-            } else if timediff(self.current, snd_segment.resendts) >= 0 {
-                need_send = true;
-                snd_segment.xmit += 1;
-                self.xmit += 1;
-                if !self.nodelay {
-                    snd_segment.rto += cmp::max(snd_segment.rto, self.rx_rto);
-                } else {
-                    let step = snd_segment.rto; // (kcp->nodelay < 2) ? ((IINT32)(segment->rto)) : kcp->rx_rto;
-                    snd_segment.rto += step / 2;
-                }
-                snd_segment.resendts = self.current + snd_segment.rto;
-                lost = true;
-            } else if snd_segment.fastack >= resent {
-                if snd_segment.xmit <= self.fastlimit || self.fastlimit <= 0 {
-                    need_send = true;
-                    snd_segment.xmit += 1;
-                    snd_segment.fastack = 0;
-                    snd_segment.resendts = self.current + snd_segment.rto;
-                    change += 1;
-                }
+    // `write_output` with `WouldBlock` as an outcome
+    fn try_write_output(&mut self) -> KcpResult<FlushOutcome> {
+        match self.write_output() {
+            Ok(()) => Ok(FlushOutcome::Complete),
+            Err(Error::IoError(ref err)) if err.kind() == io::ErrorKind::WouldBlock => {
+                Ok(FlushOutcome::WouldBlock {
+                    pending_bytes: self.output_queue.iter().map(|d| d.len()).sum(),
+                })
             }
+            Err(err) => Err(err),
+        }
+    }
 
-            if need_send {
-                snd_segment.ts = self.current;
-                snd_segment.wnd = segment.wnd;
-                snd_segment.una = self.rcv_nxt;
-
-                let need = KCP_OVERHEAD as usize + snd_segment.data.len();
-
-                if self.buf.len() + need > self.mtu as usize {
-                    self.output.write_all(&self.buf).await?;
-                    self.buf.clear();
-                }
+    /// Flush pending data in buffer, returns what has been written
+    pub fn flush_and_report(&mut self) -> KcpResult<FlushStats> {
+        self.encode_flush()?;
+        self.write_output()?;
+        Ok(self.flush_stats)
+    }
 
-                snd_segment.encode(&mut self.buf);
+    /// Update state every 10ms ~ 100ms.
+    ///
+    /// Or you can ask `check` when to call this again.
+    ///
+    /// `current` is a monotonic millisecond clock (or ticks, see `set_tick_resolution`),
+    /// a `current` earlier than the previous one is ignored. Datagrams waiting to be written,
+    /// like the ACKs of `set_ack_nodelay`, are written even if it is not time to flush.
+    pub fn update(&mut self, current: u32) -> KcpResult<()> {
+        self.flush_if_due(current).map(|_| ())
+    }
 
-                if snd_segment.xmit >= self.dead_link {
-                    self.state = -1; // (IUINT32)-1
-                }
+    /// `update`, returning whether it was time to flush
+    ///
+    /// For an event loop with its own timers, many connections may share one wakeup, and only
+    /// the ones due are flushed. `check` tells when the next one is due.
+    pub fn flush_if_due(&mut self, current: u32) -> KcpResult<bool> {
+        if self.update_clock(current) {
+            self.flush()?;
+            Ok(true)
+        } else {
+            if !self.output_queue.is_empty() {
+                self.write_output()?;
             }
+            Ok(false)
         }
+    }
 
-        // Flush all data in buffer
-        if !self.buf.is_empty() {
-            self.output.write_all(&self.buf).await?;
-            self.buf.clear();
-        }
+    /// `update` with the time of the clock
+    ///
+    /// Return `Error::NoClock` without `with_clock` or `set_clock`.
+    pub fn update_now(&mut self) -> KcpResult<()> {
+        let current = self.now()?;
+        self.update(current)
+    }
+}
 
-        // update ssthresh
-        if change > 0 {
-            let inflight = self.snd_nxt - self.snd_una;
-            self.ssthresh = inflight as u16 / 2;
-            if self.ssthresh < KCP_THRESH_MIN {
-                self.ssthresh = KCP_THRESH_MIN;
+#[cfg(feature = "tokio")]
+impl<Output: AsyncWrite + Unpin> Kcp<Output> {
+    // Write all the encoded datagrams into output
+    async fn async_write_output(&mut self) -> KcpResult<()> {
+        while let Some(datagram) = self.output_queue.front() {
+            if let Some(ref mut hook) = self.packet_hook {
+                hook(datagram, self.current);
             }
-            self.cwnd = self.ssthresh + resent as u16;
-            self.incr = self.cwnd as usize * self.mss;
+            self.output.write_all(datagram).await?;
+            self.flush_stats.bytes_written += datagram.len();
+            self.output_queue.pop_front();
         }
+        Ok(())
+    }
 
-        if lost {
-            self.ssthresh = cwnd / 2;
-            if self.ssthresh < KCP_THRESH_MIN {
-                self.ssthresh = KCP_THRESH_MIN;
-            }
-            self.cwnd = 1;
-            self.incr = self.mss;
-        }
+    /// Flush pending ACKs
+    pub async fn async_flush_ack(&mut self) -> KcpResult<()> {
+        self.encode_flush_ack(false)?;
+        self.async_write_output().await
+    }
 
-        if self.cwnd < 1 {
-            self.cwnd = 1;
-            self.incr = self.mss;
-        }
+    /// Flush pending ACKs and window probes, see `flush_recv_side`
+    pub async fn async_flush_recv_side(&mut self) -> KcpResult<()> {
+        self.encode_flush_ack(true)?;
+        self.async_write_output().await
+    }
 
-        Ok(())
+    /// Flush pending ACKs if there are at least `min` of them, returns whether it flushed
+    pub async fn async_flush_ack_if(&mut self, min: usize) -> KcpResult<bool> {
+        if !self.acks_reached(min) {
+            return Ok(false);
+        }
+        self.async_flush_ack().await?;
+        Ok(true)
     }
 
-    /// Update state every 10ms ~ 100ms.
+    /// Replace the output, returns the previous one
     ///
-    /// Or you can ask `check` when to call this again.
-    pub async fn async_update(&mut self, current: u32) -> KcpResult<()> {
-        self.current = current;
+    /// Datagrams already encoded are written to the previous output first, if that fails the
+    /// output is not replaced. The state of the connection is kept.
+    pub async fn async_set_output(&mut self, output: Output) -> KcpResult<Output> {
+        self.finish_datagram();
+        self.async_write_output().await?;
+        Ok(mem::replace(&mut self.output.0, output))
+    }
 
-        if !self.updated {
-            self.updated = true;
-            self.ts_flush = self.current;
-        }
+    /// Send bytes and flush them right away, for the first message of a client
+    ///
+    /// See `send_with_conv_negotiation`.
+    pub async fn async_send_with_conv_negotiation(&mut self, buf: &[u8]) -> KcpResult<usize> {
+        let n = self.send(buf)?;
+        self.async_flush().await?;
+        Ok(n)
+    }
 
-        let mut slap = timediff(self.current, self.ts_flush);
+    /// Flush pending data in buffer.
+    pub async fn async_flush(&mut self) -> KcpResult<()> {
+        self.async_flush_and_report().await.map(|_| ())
+    }
 
-        if slap >= 10000 || slap < -10000 {
-            self.ts_flush = self.current;
-            slap = 0;
-        }
+    /// Flush pending data in buffer, returns what has been written
+    pub async fn async_flush_and_report(&mut self) -> KcpResult<FlushStats> {
+        self.encode_flush()?;
+        self.async_write_output().await?;
+        Ok(self.flush_stats)
+    }
 
-        if slap >= 0 {
-            self.ts_flush += self.interval;
-            if timediff(self.current, self.ts_flush) >= 0 {
-                self.ts_flush = self.current + self.interval;
-            }
+    /// Update state every 10ms ~ 100ms.
+    ///
+    /// Or you can ask `check` when to call this again.
+    ///
+    /// `current` is a monotonic millisecond clock (or ticks, see `set_tick_resolution`),
+    /// a `current` earlier than the previous one is ignored. Datagrams waiting to be written,
+    /// like the ACKs of `set_ack_nodelay`, are written even if it is not time to flush.
+    pub async fn async_update(&mut self, current: u32) -> KcpResult<()> {
+        if self.update_clock(current) {
             self.async_flush().await?;
+        } else if !self.output_queue.is_empty() {
+            self.async_write_output().await?;
         }
 
         Ok(())
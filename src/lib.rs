@@ -1,21 +1,65 @@
 //! [KCP](https://github.com/skywind3000/kcp) implementation in Rust.
 //!
 //! A Fast and Reliable ARQ Protocol
+//!
+//! Works without `std` if the default `std` feature is disabled, `alloc` is still required.
+//! Outputs implement `io::Write` then, a minimal replacement of `std::io::Write`.
+
+#![cfg_attr(not(feature = "std"), no_std)]
 
+extern crate alloc;
 extern crate bytes;
 #[macro_use]
 extern crate log;
 
 mod error;
+#[cfg(feature = "fec")]
+mod fec;
+pub mod io;
 mod kcp;
+#[cfg(feature = "socket")]
+mod socket;
+#[cfg(feature = "std")]
+mod stream;
+#[cfg(feature = "testing")]
+pub mod testing;
 
 /// The `KCP` prelude
 pub mod prelude {
-    pub use super::{get_conv, Kcp, KCP_OVERHEAD};
+    #[allow(deprecated)]
+    pub use super::get_conv;
+    pub use super::{try_get_conv, Kcp, KCP_OVERHEAD};
 }
 
 pub use error::Error;
-pub use kcp::{get_conv, get_sn, set_conv, Kcp, KCP_OVERHEAD};
+#[cfg(feature = "fec")]
+pub use fec::{FecDecoder, FecEncoder, FEC_OVERHEAD};
+#[cfg(feature = "handshake")]
+pub use kcp::is_syn;
+#[cfg(all(feature = "isn", feature = "std"))]
+pub use kcp::random_isn;
+#[cfg(feature = "codec")]
+pub use kcp::PayloadCodec;
+#[cfg(feature = "compact")]
+pub use kcp::KCP_COMPACT_OVERHEAD;
+pub use kcp::{
+    demux, get_cmd, get_frg, get_ts, get_una, get_wnd, parse_header, serial_diff, serial_le,
+    serial_lt, try_get_conv, try_get_sn, try_set_conv, AckSample, BoxedKcp, CcAlgo, ClassicCc,
+    Clock, CollectingKcp, Collector, CongestionControl, CongestionState, ConnectionState,
+    DefaultLossDetector, Demux, FlushOutcome, FlushStats, Kcp, KcpBuilder, KcpSnapshot, KcpSummary,
+    LinkState, LocalBoxedKcp, LossDetector, LossEvent, ManualClock, NoDelayConfig, PathMetrics,
+    Retransmit, RtoEstimator, RttState, SegmentCrypt, SegmentHeader, SegmentState, Stats,
+    KCP_INTERVAL, KCP_MTU_DEF, KCP_OVERHEAD, KCP_RTO_DEF, KCP_RTO_MAX, KCP_RTO_MIN, KCP_WND_RCV,
+    KCP_WND_SND,
+};
+#[allow(deprecated)]
+pub use kcp::{get_conv, get_sn, set_conv};
+#[cfg(feature = "endian")]
+pub use kcp::{get_conv_with, get_sn_with, parse_header_with, try_set_conv_with, Endian};
+#[cfg(feature = "socket")]
+pub use socket::{KcpSocket, UdpOutput};
+#[cfg(feature = "std")]
+pub use stream::KcpStream;
 
 /// KCP result
 pub type KcpResult<T> = Result<T, Error>;
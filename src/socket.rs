@@ -0,0 +1,169 @@
+//! Reliable datagrams over a `UdpSocket`
+
+use std::io::{self, ErrorKind, Write};
+use std::net::{SocketAddr, UdpSocket};
+use std::time::{Duration, Instant};
+
+use crate::error::Error;
+use crate::kcp::Kcp;
+use crate::KcpResult;
+
+/// Output of `KcpSocket`, sends every datagram to the peer
+#[derive(Debug)]
+pub struct UdpOutput {
+    socket: UdpSocket,
+    peer: SocketAddr,
+}
+
+impl Write for UdpOutput {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.socket.send_to(buf, self.peer)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// A reliable datagram socket
+///
+/// Owns a `UdpSocket` and drives `Kcp` on the calling thread. Messages are sent with `send`
+/// and received with `recv`, which blocks until a complete message arrives. Call `poll`
+/// regularly (at least every `Kcp::check` millis) if you are not blocking in `recv`,
+/// otherwise retransmissions and ACKs won't be sent.
+#[derive(Debug)]
+pub struct KcpSocket {
+    kcp: Kcp<UdpOutput>,
+    socket: UdpSocket,
+    peer: SocketAddr,
+    epoch: Instant,
+    buf: Vec<u8>,
+}
+
+impl KcpSocket {
+    /// Creates a `KcpSocket` talking with `peer` through `socket`
+    ///
+    /// `conv` must be equal in both endpoints in one connection.
+    pub fn new(conv: u32, socket: UdpSocket, peer: SocketAddr) -> KcpResult<KcpSocket> {
+        let output = UdpOutput {
+            socket: socket.try_clone()?,
+            peer,
+        };
+
+        let mut s = KcpSocket {
+            kcp: Kcp::new(conv, output),
+            socket,
+            peer,
+            epoch: Instant::now(),
+            buf: vec![0u8; 65536],
+        };
+        s.kcp.update(s.current())?;
+
+        Ok(s)
+    }
+
+    /// Get the underlying `Kcp`
+    #[inline]
+    pub fn kcp(&self) -> &Kcp<UdpOutput> {
+        &self.kcp
+    }
+
+    /// Get the underlying `Kcp` for configuration
+    #[inline]
+    pub fn kcp_mut(&mut self) -> &mut Kcp<UdpOutput> {
+        &mut self.kcp
+    }
+
+    /// Get the underlying `UdpSocket`
+    #[inline]
+    pub fn socket(&self) -> &UdpSocket {
+        &self.socket
+    }
+
+    /// Address of the remote peer
+    #[inline]
+    pub fn peer_addr(&self) -> SocketAddr {
+        self.peer
+    }
+
+    /// Send a message, it is transmitted immediately if the window permits
+    pub fn send(&mut self, buf: &[u8]) -> KcpResult<usize> {
+        let n = self.kcp.send(buf)?;
+        self.kcp.update(self.current())?;
+        self.kcp.flush()?;
+        Ok(n)
+    }
+
+    /// Receive a message, blocks until a complete message has been received
+    pub fn recv(&mut self) -> KcpResult<Vec<u8>> {
+        loop {
+            if let Some(msg) = self.try_recv()? {
+                return Ok(msg);
+            }
+
+            let wait = self.kcp.check(self.current());
+            self.input(Some(Duration::from_millis(u64::from(wait.max(1)))))?;
+            self.kcp.update(self.current())?;
+        }
+    }
+
+    /// Receive a message without blocking, returns `None` if there is no complete message yet
+    pub fn try_recv(&mut self) -> KcpResult<Option<Vec<u8>>> {
+        let size = match self.kcp.peeksize() {
+            Ok(size) => size,
+            Err(Error::RecvQueueEmpty) | Err(Error::ExpectingFragment) => return Ok(None),
+            Err(err) => return Err(err),
+        };
+
+        let mut msg = vec![0u8; size];
+        let n = self.kcp.recv(&mut msg)?;
+        msg.truncate(n);
+        Ok(Some(msg))
+    }
+
+    /// Read all the pending datagrams from the socket without blocking and update `Kcp`
+    pub fn poll(&mut self) -> KcpResult<()> {
+        self.input(None)?;
+        self.kcp.update(self.current())
+    }
+
+    /// Milliseconds since this socket has been created, the clock of `Kcp`
+    #[inline]
+    pub fn current(&self) -> u32 {
+        self.epoch.elapsed().as_millis() as u32
+    }
+
+    // Read datagrams from socket into kcp, waits at most `timeout` for the first one
+    fn input(&mut self, timeout: Option<Duration>) -> KcpResult<()> {
+        match timeout {
+            Some(timeout) => {
+                self.socket.set_nonblocking(false)?;
+                self.socket.set_read_timeout(Some(timeout))?;
+            }
+            None => self.socket.set_nonblocking(true)?,
+        }
+
+        loop {
+            let (n, addr) = match self.socket.recv_from(&mut self.buf) {
+                Ok(r) => r,
+                Err(ref err)
+                    if err.kind() == ErrorKind::WouldBlock || err.kind() == ErrorKind::TimedOut =>
+                {
+                    break;
+                }
+                Err(err) => return Err(err.into()),
+            };
+
+            if addr != self.peer {
+                trace!("socket ignored {} bytes from {}", n, addr);
+            } else if let Err(err) = self.kcp.input(&self.buf[..n]) {
+                debug!("socket input {} bytes from {} failed: {}", n, addr, err);
+            }
+
+            // Don't block again once there is something to process
+            self.socket.set_nonblocking(true)?;
+        }
+
+        Ok(())
+    }
+}
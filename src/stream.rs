@@ -0,0 +1,103 @@
+//! `std::io::Read` and `std::io::Write` over `Kcp`
+
+use std::io::{self, Read, Write};
+
+use bytes::{Buf, BytesMut};
+
+use crate::kcp::Kcp;
+use crate::{Error, KcpResult};
+
+/// A byte stream over `Kcp`
+///
+/// `write` sends the data and flushes it at once, `read` returns the data received so far, or
+/// `ErrorKind::WouldBlock` if there is none. Once the data is queued `write` succeeds, failing to
+/// flush it is reported by `flush` or `drive`, so `write_all` never sends it twice. Datagrams
+/// from the remote are passed in with `input`, and `drive` has to be called regularly to update
+/// `Kcp`.
+///
+/// Message boundaries are not kept. If the buffer of `read` is smaller than the next message,
+/// the rest of it is kept and returned by the following reads, `Error::UserBufTooSmall` is
+/// never returned. Empty messages are skipped, so `read` never returns 0 as the end of stream.
+#[derive(Debug)]
+pub struct KcpStream<O: Write> {
+    kcp: Kcp<O>,
+    pending: BytesMut,
+}
+
+impl<O: Write> KcpStream<O> {
+    /// Creates a stream over `kcp`
+    pub fn new(kcp: Kcp<O>) -> KcpStream<O> {
+        KcpStream {
+            kcp,
+            pending: BytesMut::new(),
+        }
+    }
+
+    /// Get the underlying `Kcp`
+    #[inline]
+    pub fn get_ref(&self) -> &Kcp<O> {
+        &self.kcp
+    }
+
+    /// Get the underlying `Kcp` for configuration
+    #[inline]
+    pub fn get_mut(&mut self) -> &mut Kcp<O> {
+        &mut self.kcp
+    }
+
+    /// Unwrap the underlying `Kcp`, data received but not read yet is dropped
+    pub fn into_inner(self) -> Kcp<O> {
+        self.kcp
+    }
+
+    /// Input a datagram received from the remote
+    ///
+    /// ACKs are written at once with `Kcp::set_ack_nodelay`.
+    pub fn input(&mut self, buf: &[u8]) -> KcpResult<usize> {
+        let n = self.kcp.input(buf)?;
+        if self.kcp.ack_nodelay() {
+            // Nothing is encoded before the first `drive`
+            match self.kcp.flush_ack() {
+                Ok(()) | Err(Error::NeedUpdate) => {}
+                Err(err) => return Err(err),
+            }
+        }
+        Ok(n)
+    }
+
+    /// Update `Kcp` with the clock `current`, see `Kcp::update`
+    pub fn drive(&mut self, current: u32) -> KcpResult<()> {
+        self.kcp.update(current)
+    }
+}
+
+impl<O: Write> Read for KcpStream<O> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+
+        while self.pending.is_empty() {
+            self.pending = self.kcp.recv_bytes()?;
+        }
+
+        let n = buf.len().min(self.pending.len());
+        buf[..n].copy_from_slice(&self.pending[..n]);
+        self.pending.advance(n);
+        Ok(n)
+    }
+}
+
+impl<O: Write> Write for KcpStream<O> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.kcp.send(buf)?;
+        // Sent by `flush` or `drive` anyway, which report the error
+        let _ = self.kcp.flush();
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.kcp.flush()?;
+        Ok(())
+    }
+}
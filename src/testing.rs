@@ -0,0 +1,114 @@
+//! Helpers for testing protocols built on KCP without a socket
+
+use std::collections::VecDeque;
+use std::fmt::{self, Debug};
+use std::io::{self, Write};
+use std::sync::{Arc, Mutex};
+
+use crate::kcp::Kcp;
+use crate::KcpResult;
+
+/// In-memory output of a `Kcp` in `Loopback`, keeps every datagram written
+#[derive(Clone, Debug, Default)]
+pub struct Channel(Arc<Mutex<VecDeque<Vec<u8>>>>);
+
+impl Channel {
+    /// Take all the datagrams written so far
+    pub fn take(&self) -> Vec<Vec<u8>> {
+        self.0.lock().unwrap().drain(..).collect()
+    }
+}
+
+impl Write for Channel {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.lock().unwrap().push_back(buf.to_vec());
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+type LossFn = Box<dyn FnMut(&[u8]) -> bool + Send>;
+
+/// A pair of connected `Kcp`, driven by `pump`
+pub struct Loopback {
+    a: Kcp<Channel>,
+    b: Kcp<Channel>,
+    a_out: Channel,
+    b_out: Channel,
+    loss: Option<LossFn>,
+}
+
+/// Creates a pair of `Kcp` with `conv`, the output of one is the input of the other
+pub fn loopback(conv: u32) -> Loopback {
+    let a_out = Channel::default();
+    let b_out = Channel::default();
+
+    Loopback {
+        a: Kcp::new(conv, a_out.clone()),
+        b: Kcp::new(conv, b_out.clone()),
+        a_out,
+        b_out,
+        loss: None,
+    }
+}
+
+impl Loopback {
+    /// One side of the connection
+    #[inline]
+    pub fn a(&mut self) -> &mut Kcp<Channel> {
+        &mut self.a
+    }
+
+    /// The other side of the connection
+    #[inline]
+    pub fn b(&mut self) -> &mut Kcp<Channel> {
+        &mut self.b
+    }
+
+    /// Drop datagrams for which `loss` returns `true`, in both directions
+    pub fn set_loss<F>(&mut self, loss: F)
+    where
+        F: FnMut(&[u8]) -> bool + Send + 'static,
+    {
+        self.loss = Some(Box::new(loss));
+    }
+
+    /// Update both sides with `current`, then deliver the datagrams written to each other
+    pub fn pump(&mut self, current: u32) -> KcpResult<()> {
+        self.a.update(current)?;
+        self.b.update(current)?;
+
+        for datagram in self.a_out.take() {
+            if !self.lost(&datagram) {
+                self.b.input(&datagram)?;
+            }
+        }
+        for datagram in self.b_out.take() {
+            if !self.lost(&datagram) {
+                self.a.input(&datagram)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn lost(&mut self, datagram: &[u8]) -> bool {
+        match self.loss {
+            Some(ref mut loss) => loss(datagram),
+            None => false,
+        }
+    }
+}
+
+impl Debug for Loopback {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Loopback")
+            .field("a", &self.a)
+            .field("b", &self.b)
+            .field("loss", &self.loss.is_some())
+            .finish()
+    }
+}
@@ -0,0 +1,78 @@
+extern crate kcp;
+
+use kcp::{CongestionControl, Error, KcpBuilder};
+
+#[test]
+fn builder_defaults() {
+    let kcp = KcpBuilder::new().build(1, Vec::<u8>::new()).unwrap();
+    assert_eq!(kcp.conv(), 1);
+    assert_eq!(kcp.mtu(), 1400);
+    assert_eq!(kcp.snd_wnd(), 32);
+    assert_eq!(kcp.rcv_wnd(), 128);
+    assert!(!kcp.is_stream());
+}
+
+#[test]
+fn builder_options() {
+    let kcp = KcpBuilder::new()
+        .stream(true)
+        .mtu(500)
+        .reserved(20)
+        .nodelay(true)
+        .interval(20)
+        .fast_resend(2)
+        .congestion(CongestionControl::LossBased)
+        .wndsize(64, 16)
+        .initial_rto(50)
+        .max_fragments(8)
+        .snd_queue_limit(100)
+        .build(7, Vec::<u8>::new())
+        .unwrap();
+    assert_eq!(kcp.conv(), 7);
+    assert!(kcp.is_stream());
+    assert_eq!(kcp.mtu(), 500);
+    assert_eq!(kcp.reserved(), 20);
+    assert_eq!(kcp.mss(), 500 - 20 - kcp::KCP_OVERHEAD);
+    assert_eq!(kcp.snd_wnd(), 64);
+    assert_eq!(kcp.rcv_wnd(), 16);
+    assert_eq!(kcp.rx_rto(), 50);
+    assert_eq!(kcp.max_fragments(), 8);
+    assert_eq!(kcp.snd_queue_limit(), 100);
+    assert_eq!(kcp.congestion(), CongestionControl::LossBased);
+}
+
+#[test]
+fn builder_invalid() {
+    let builder = KcpBuilder::new().mtu(10);
+    assert!(matches!(
+        builder.build(1, Vec::<u8>::new()),
+        Err(Error::InvalidMtu(10))
+    ));
+
+    let builder = KcpBuilder::new().mtu(100).reserved(100);
+    assert!(matches!(
+        builder.build(1, Vec::<u8>::new()),
+        Err(Error::InvalidReserved(100))
+    ));
+
+    for builder in [
+        KcpBuilder::new().tick_resolution(0),
+        KcpBuilder::new().interval(5),
+        KcpBuilder::new().wndsize(0, 128),
+        KcpBuilder::new().initial_rto(50),
+        KcpBuilder::new()
+            .rx_minrto(200)
+            .nodelay(true)
+            .initial_rto(150),
+        KcpBuilder::new().wndsize(32, 16).max_fragments(32),
+    ] {
+        assert!(
+            matches!(
+                builder.build(1, Vec::<u8>::new()),
+                Err(Error::InvalidConfig(..))
+            ),
+            "{:?}",
+            builder
+        );
+    }
+}
@@ -0,0 +1,126 @@
+#![cfg(all(feature = "std", feature = "checksum"))]
+
+extern crate bytes;
+extern crate kcp;
+
+mod common;
+
+use bytes::Buf;
+
+use common::pair;
+
+/// IEEE CRC32, bit by bit
+fn crc32(buf: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &b in buf {
+        crc ^= b as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xEDB8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+#[test]
+fn checksum_roundtrip() {
+    let (mut kcp1, sink1, mut kcp2, sink2) = pair(1, 100, |kcp| kcp.set_checksum(true).unwrap());
+    assert!(kcp1.checksum());
+    assert_eq!(kcp1.mss(), 100 - kcp::KCP_OVERHEAD - 4);
+
+    let msg: Vec<u8> = (0..200).map(|i| i as u8).collect();
+    kcp1.send(&msg).unwrap();
+    kcp1.flush().unwrap();
+    let datagrams = sink1.take();
+    let sizes: Vec<usize> = datagrams.iter().map(|d| d.len()).collect();
+    assert_eq!(sizes, vec![100, 100, kcp::KCP_OVERHEAD + 4 + 56]);
+
+    // A CRC32 of the header and the payload after each segment
+    let (segment, crc) = datagrams[0].split_at(96);
+    assert_eq!(crc, crc32(segment).to_le_bytes());
+
+    for datagram in &datagrams {
+        kcp2.input(datagram).unwrap();
+    }
+    assert_eq!(&kcp2.recv_bytes().unwrap()[..], &msg[..]);
+
+    kcp2.flush().unwrap();
+    for datagram in sink2.take() {
+        assert_eq!(datagram.len(), 3 * (kcp::KCP_OVERHEAD + 4));
+        kcp1.input(&datagram).unwrap();
+    }
+    assert_eq!(kcp1.wait_snd(), 0);
+}
+
+#[test]
+fn checksum_mismatch() {
+    let (mut kcp1, sink1, mut kcp2, _) = pair(1, 100, |kcp| kcp.set_checksum(true).unwrap());
+    kcp1.send(b"corrupted").unwrap();
+    kcp1.flush().unwrap();
+    let mut datagram = sink1.take().remove(0);
+
+    datagram[kcp::KCP_OVERHEAD + 2] ^= 0x10;
+    assert!(matches!(
+        kcp2.input(&datagram),
+        Err(kcp::Error::ChecksumMismatch)
+    ));
+    assert!(kcp2.recv_bytes().is_err());
+
+    // Retransmitted as a lost one
+    for current in (10..500).step_by(10) {
+        kcp1.update(current).unwrap();
+        for datagram in sink1.take() {
+            kcp2.input(&datagram).unwrap();
+        }
+    }
+    assert_eq!(&kcp2.recv_bytes().unwrap()[..], b"corrupted");
+}
+
+#[test]
+fn checksum_framed() {
+    let (mut kcp1, sink1, mut kcp2, _) = pair(1, 100, |kcp| kcp.set_checksum(true).unwrap());
+    kcp1.send(b"first").unwrap();
+    kcp1.send(b"second").unwrap();
+    kcp1.flush().unwrap();
+
+    let bytes = sink1.take().concat();
+    let mut processed = 0;
+    for chunk in bytes.chunks(5) {
+        processed += kcp2.input_framed(chunk).unwrap();
+    }
+    assert_eq!(processed, bytes.len());
+    assert_eq!(&kcp2.recv_bytes().unwrap()[..], b"first");
+    assert_eq!(&kcp2.recv_bytes().unwrap()[..], b"second");
+}
+
+#[test]
+fn checksum_input_buf() {
+    let (mut kcp1, sink1, mut kcp2, _) = pair(1, 100, |kcp| kcp.set_checksum(true).unwrap());
+    kcp1.send(b"chained").unwrap();
+    kcp1.flush().unwrap();
+    let mut datagram = sink1.take().remove(0);
+
+    let (head, tail) = datagram.split_at(10);
+    assert_eq!(kcp2.input_buf(head.chain(tail)).unwrap(), datagram.len());
+    assert_eq!(&kcp2.recv_bytes().unwrap()[..], b"chained");
+
+    let last = datagram.len() - 1;
+    datagram[last] ^= 1;
+    let (head, tail) = datagram.split_at(10);
+    assert!(matches!(
+        kcp2.input_buf(head.chain(tail)),
+        Err(kcp::Error::ChecksumMismatch)
+    ));
+}
+
+#[test]
+fn checksum_reset() {
+    let (mut kcp1, _, _, _) = pair(1, 100, |kcp| kcp.set_checksum(true).unwrap());
+    kcp1.reset(2);
+    assert!(!kcp1.checksum());
+    assert_eq!(kcp1.mtu(), 1400);
+    assert_eq!(kcp1.mss(), 1400 - kcp::KCP_OVERHEAD);
+}
@@ -0,0 +1,296 @@
+#![cfg(feature = "std")]
+
+extern crate kcp;
+
+use std::cell::RefCell;
+use std::io::{self, Write};
+use std::rc::Rc;
+
+use kcp::{Kcp, ManualClock, RttState};
+
+#[derive(Clone, Default)]
+struct Sink(Rc<RefCell<Vec<u8>>>);
+
+impl Sink {
+    fn len(&self) -> usize {
+        self.0.borrow().len()
+    }
+}
+
+impl Write for Sink {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.borrow_mut().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[test]
+fn clock_went_backwards() {
+    let mut kcp = Kcp::new(1, Sink::default());
+
+    kcp.update(1000).unwrap();
+    kcp.update(1100).unwrap();
+    assert_eq!(kcp.clock_went_backwards_count(), 0);
+
+    kcp.update(900).unwrap();
+    kcp.update(1050).unwrap();
+    assert_eq!(kcp.clock_went_backwards_count(), 2);
+
+    kcp.update(1200).unwrap();
+    assert_eq!(kcp.clock_went_backwards_count(), 2);
+}
+
+#[test]
+fn clock_went_backwards_keeps_resend_timer() {
+    let sink = Sink::default();
+    let mut kcp = Kcp::new(1, sink.clone());
+    kcp.set_nodelay(true, 10, 0, true);
+
+    kcp.update(1000).unwrap();
+    kcp.send(b"hello").unwrap();
+    kcp.flush().unwrap();
+
+    let sent = sink.len();
+    assert!(sent > 0);
+
+    // Going back in time must not postpone the pending retransmission
+    kcp.update(10).unwrap();
+    kcp.update(1500).unwrap();
+    assert!(sink.len() > sent);
+}
+
+#[test]
+fn clock_tick_resolution() {
+    let sink = Sink::default();
+    let mut kcp = Kcp::new(1, sink.clone());
+    kcp.set_tick_resolution(1000);
+    kcp.set_nodelay(true, 10, 0, true);
+    assert_eq!(kcp.tick_resolution(), 1000);
+
+    kcp.update(0).unwrap();
+    assert_eq!(kcp.check(0), 10_000);
+    assert_eq!(kcp.check(2_500), 7_500);
+
+    kcp.send(b"hello").unwrap();
+    kcp.update(10_000).unwrap();
+    let sent = sink.len();
+    assert!(sent > 0);
+
+    // Minimal RTO is 30ms in nodelay mode, nothing to resend in 20ms
+    kcp.update(30_000).unwrap();
+    assert_eq!(sink.len(), sent);
+    kcp.update(300_000).unwrap();
+    assert!(sink.len() > sent);
+}
+
+#[test]
+fn clock_rtt_estimator_state() {
+    let sink1 = Sink::default();
+    let sink2 = Sink::default();
+    let mut kcp1 = Kcp::new(1, sink1.clone());
+    let mut kcp2 = Kcp::new(1, sink2.clone());
+    kcp1.set_nodelay(true, 10, 0, true);
+    kcp1.update(0).unwrap();
+    kcp2.update(0).unwrap();
+
+    kcp1.send(b"rtt").unwrap();
+    kcp1.flush().unwrap();
+    kcp2.input(&sink1.0.borrow_mut().split_off(0)).unwrap();
+    kcp2.flush().unwrap();
+
+    kcp1.update(40).unwrap();
+    kcp1.input(&sink2.0.borrow_mut().split_off(0)).unwrap();
+
+    let state = kcp1.rtt_estimator_state();
+    assert_eq!(
+        state,
+        RttState {
+            srtt: 40,
+            rttval: 20,
+            rto: 120,
+            min_rtt: 40,
+        }
+    );
+
+    let mut kcp3 = Kcp::new(2, Sink::default());
+    kcp3.set_nodelay(true, 10, 0, true);
+    kcp3.apply_rtt_estimator_state(state);
+    assert_eq!(kcp3.rtt_estimator_state(), state);
+}
+
+#[test]
+fn clock_idle() {
+    let mut kcp = Kcp::new(1, Sink::default());
+    kcp.set_nodelay(true, 10, 0, true);
+    kcp.update(0).unwrap();
+    kcp.set_idle(true);
+
+    let mut current = 0;
+    let mut waits = Vec::new();
+    for _ in 0..10 {
+        let wait = kcp.check(current);
+        waits.push(wait);
+        current += wait;
+        kcp.update(current).unwrap();
+    }
+    assert_eq!(waits, vec![10, 10, 20, 40, 80, 160, 320, 640, 1000, 1000]);
+
+    // Something to send, back to normal
+    kcp.send(b"wake").unwrap();
+    assert_eq!(kcp.check(current + 1), 0);
+    kcp.update(current + 1).unwrap();
+    assert!(kcp.check(current + 1) <= 10);
+
+    kcp.set_idle(false);
+    assert_eq!(kcp.check(current + 1), 0);
+}
+
+#[test]
+fn clock_idle_input_many() {
+    let sink1 = Sink::default();
+    let sink2 = Sink::default();
+    let mut kcp1 = Kcp::new(1, sink1.clone());
+    let mut kcp2 = Kcp::new(1, sink2.clone());
+    kcp1.set_nodelay(true, 10, 0, true);
+    kcp1.update(0).unwrap();
+    kcp1.set_idle(true);
+    kcp2.update(0).unwrap();
+
+    kcp1.send(b"acked").unwrap();
+    kcp1.flush().unwrap();
+    kcp2.input(&sink1.0.borrow_mut().split_off(0)).unwrap();
+    kcp2.flush().unwrap();
+    let ack = sink2.0.borrow_mut().split_off(0);
+    kcp1.input(&ack).unwrap();
+
+    let mut current = 0;
+    for _ in 0..10 {
+        current += kcp1.check(current);
+        kcp1.update(current).unwrap();
+    }
+    assert_eq!(kcp1.check(current), 1000);
+
+    // A batch from the remote wakes it as `input` does, even with nothing to reply
+    assert_eq!(kcp1.input_many(&[&ack]).unwrap(), 1);
+    assert!(kcp1.check(current) <= 10);
+}
+
+#[test]
+fn clock_adaptive_interval() {
+    fn idle_waits(kcp: &mut Kcp<Sink>, current: &mut u32, rounds: usize) -> Vec<u32> {
+        let mut waits = Vec::new();
+        for _ in 0..rounds {
+            let wait = kcp.check(*current);
+            waits.push(wait);
+            *current += wait;
+            kcp.update(*current).unwrap();
+        }
+        waits
+    }
+
+    let mut kcp1 = Kcp::new(1, Sink::default());
+    let sink2 = Sink::default();
+    let mut kcp2 = Kcp::new(1, sink2.clone());
+    kcp1.set_nodelay(true, 10, 0, true);
+    kcp1.update(0).unwrap();
+    kcp1.set_adaptive_interval(20, 500);
+    assert!(kcp1.is_idle());
+
+    let mut current = 0;
+    assert_eq!(
+        idle_waits(&mut kcp1, &mut current, 7),
+        vec![10, 20, 40, 80, 160, 320, 500]
+    );
+
+    // Sending collapses the interval at once
+    kcp1.send(b"wake").unwrap();
+    assert_eq!(kcp1.check(current + 1), 0);
+    kcp1.update(current + 1).unwrap();
+    assert!(kcp1.check(current + 1) <= 20);
+
+    // Acked, quiet again
+    kcp2.update(current).unwrap();
+    kcp2.input(&kcp1.output_mut().0.borrow_mut().split_off(0))
+        .unwrap();
+    kcp2.flush().unwrap();
+    kcp1.input(&sink2.0.borrow_mut().split_off(0)).unwrap();
+    current += 1;
+    let waits = idle_waits(&mut kcp1, &mut current, 7);
+    assert_eq!(waits[1..], [20, 40, 80, 160, 320, 500]);
+
+    // So does any input
+    kcp2.send(b"ping").unwrap();
+    kcp2.flush().unwrap();
+    kcp1.input(&sink2.0.borrow_mut().split_off(0)).unwrap();
+    assert_eq!(kcp1.check(current + 1), 0);
+    kcp1.update(current + 1).unwrap();
+    assert!(kcp1.check(current + 1) <= 20);
+}
+
+#[test]
+fn clock_flush_clamp() {
+    let mut kcp = Kcp::new(1, Sink::default());
+    assert_eq!(kcp.flush_clamp(), Some(10000));
+    kcp.update(100000).unwrap();
+
+    // Far behind the flush timer, it is reset to `current`
+    assert_eq!(kcp.check(50000), 0);
+
+    kcp.set_flush_clamp(Some(60000));
+    assert_eq!(kcp.check(50000), 100);
+    assert_eq!(kcp.check(30000), 0);
+
+    kcp.set_flush_clamp(None);
+    assert_eq!(kcp.check(30000), 100);
+    assert_eq!(kcp.check(100050), 50);
+}
+
+#[test]
+fn clock_next_flush_time() {
+    let mut kcp = Kcp::new(1, Sink::default());
+    assert_eq!(kcp.next_flush_time(500), 500);
+
+    // Across the wraparound of the clock
+    let current = u32::MAX - 50;
+    kcp.update(current).unwrap();
+    assert_eq!(kcp.next_flush_time(current), 49);
+    assert_eq!(kcp.next_flush_time(current + 20), 49);
+    assert_eq!(kcp.check(current + 20), 80);
+
+    // Due, call `update` now
+    assert_eq!(kcp.next_flush_time(60), 60);
+    assert_eq!(kcp.check(60), 0);
+}
+
+#[test]
+fn clock_manual() {
+    let mut kcp = Kcp::new(1, Sink::default());
+    assert!(matches!(kcp.update_now(), Err(kcp::Error::NoClock)));
+    assert!(matches!(kcp.check_now(), Err(kcp::Error::NoClock)));
+
+    let clock = ManualClock::new(1000);
+    let sink = Sink::default();
+    let mut kcp = Kcp::with_clock(1, sink.clone(), clock.clone());
+    kcp.update_now().unwrap();
+    assert_eq!(kcp.check_now().unwrap(), 100);
+
+    kcp.send(b"hello").unwrap();
+    clock.advance(50);
+    kcp.update_now().unwrap();
+    assert_eq!(sink.len(), 0);
+    assert_eq!(kcp.check_now().unwrap(), 50);
+
+    clock.advance(50);
+    kcp.update_now().unwrap();
+    assert!(sink.len() > 0);
+
+    // Kept by reset
+    kcp.reset(2);
+    clock.set(5000);
+    kcp.update_now().unwrap();
+    assert_eq!(kcp.next_flush_time(5000), 5100);
+}
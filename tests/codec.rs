@@ -0,0 +1,118 @@
+#![cfg(all(feature = "std", feature = "codec"))]
+
+extern crate kcp;
+
+mod common;
+
+use bytes::{BufMut, BytesMut};
+use kcp::{Kcp, KcpResult, PayloadCodec};
+
+use common::{pair, Sink};
+
+/// Run-length encoding, pairs of a count and a byte
+struct Rle;
+
+impl PayloadCodec for Rle {
+    fn compress(&self, data: &[u8]) -> BytesMut {
+        let mut out = BytesMut::new();
+        for run in data.chunk_by(|a, b| a == b) {
+            for chunk in run.chunks(255) {
+                out.put_u8(chunk.len() as u8);
+                out.put_u8(chunk[0]);
+            }
+        }
+        out
+    }
+
+    fn decompress(&self, data: &[u8]) -> KcpResult<BytesMut> {
+        if !data.len().is_multiple_of(2) {
+            return Err(kcp::Error::DecompressFailed);
+        }
+        let mut out = BytesMut::new();
+        for pair in data.chunks(2) {
+            out.put_bytes(pair[1], pair[0] as usize);
+        }
+        Ok(out)
+    }
+}
+
+#[test]
+fn codec_roundtrip() {
+    let (mut kcp1, sink, mut kcp2, _) = pair(1, 100, |kcp| kcp.set_payload_codec(Rle).unwrap());
+
+    // 1000 bytes compressed into one segment
+    let mut msg = vec![1u8; 600];
+    msg.extend_from_slice(&[2u8; 400]);
+    assert_eq!(kcp1.send(&msg).unwrap(), 1000);
+    assert_eq!(kcp1.wait_snd(), 1);
+
+    // Fragmented after compression
+    let varied: Vec<u8> = (0..100).map(|i| i as u8).collect();
+    kcp1.send(&varied).unwrap();
+    assert_eq!(kcp1.wait_snd(), 4);
+
+    kcp1.flush().unwrap();
+    for datagram in sink.take() {
+        kcp2.input(&datagram).unwrap();
+    }
+    assert_eq!(kcp2.peeksize().unwrap(), 10);
+
+    let mut buf = [0u8; 100];
+    assert!(matches!(
+        kcp2.recv(&mut buf),
+        Err(kcp::Error::UserBufTooSmall)
+    ));
+    let mut buf = vec![0u8; 1000];
+    assert_eq!(kcp2.recv(&mut buf).unwrap(), 1000);
+    assert_eq!(buf, msg);
+    assert_eq!(&kcp2.recv_bytes().unwrap()[..], &varied[..]);
+}
+
+#[test]
+fn codec_recv_variants() {
+    let (mut kcp1, sink, mut kcp2, _) = pair(1, 100, |kcp| kcp.set_payload_codec(Rle).unwrap());
+    for i in 0..3u8 {
+        kcp1.send(&[i; 50]).unwrap();
+    }
+    kcp1.flush().unwrap();
+    for datagram in sink.take() {
+        kcp2.input(&datagram).unwrap();
+    }
+
+    let mut out = Vec::new();
+    assert_eq!(kcp2.recv_into_vec(&mut out).unwrap(), 50);
+    assert_eq!(out, [0u8; 50]);
+    let messages = kcp2.recv_all();
+    assert_eq!(messages.len(), 2);
+    assert_eq!(&messages[1][..], &[2u8; 50][..]);
+}
+
+#[test]
+fn codec_malformed() {
+    let (_, _, mut kcp2, _) = pair(1, 100, |kcp| kcp.set_payload_codec(Rle).unwrap());
+    let sink = Sink::default();
+    let mut sender = Kcp::new(1, sink.clone());
+    sender.set_nodelay(true, 10, 0, true);
+    sender.update(0).unwrap();
+
+    // Sent without the codec, a malformed message is dropped
+    sender.send(b"odd").unwrap();
+    sender.send(&[3, 7]).unwrap();
+    sender.flush().unwrap();
+    for datagram in sink.take() {
+        kcp2.input(&datagram).unwrap();
+    }
+    let mut buf = [0u8; 16];
+    assert!(matches!(
+        kcp2.recv(&mut buf),
+        Err(kcp::Error::DecompressFailed)
+    ));
+    assert_eq!(kcp2.recv(&mut buf).unwrap(), 3);
+    assert_eq!(&buf[..3], &[7, 7, 7]);
+
+    let mut stream = Kcp::new_stream(1, Sink::default());
+    assert!(matches!(
+        stream.set_payload_codec(Rle),
+        Err(kcp::Error::InvalidConfig(..))
+    ));
+}
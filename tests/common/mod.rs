@@ -0,0 +1,68 @@
+//! Fixtures shared by the integration tests
+
+#![allow(dead_code)]
+
+use std::cell::RefCell;
+use std::io::{self, Write};
+use std::rc::Rc;
+
+use kcp::Kcp;
+
+/// Collects every datagram written by `Kcp`
+#[derive(Clone, Default)]
+pub struct Sink(pub Rc<RefCell<Vec<Vec<u8>>>>);
+
+impl Sink {
+    /// Take all the datagrams written so far
+    pub fn take(&self) -> Vec<Vec<u8>> {
+        self.0.borrow_mut().drain(..).collect()
+    }
+}
+
+impl Write for Sink {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.borrow_mut().push(buf.to_vec());
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Input every datagram collected in `sink` into `kcp`
+pub fn deliver<W: Write>(sink: &Sink, kcp: &mut Kcp<W>) {
+    for datagram in sink.take() {
+        kcp.input(&datagram).unwrap();
+    }
+}
+
+/// Two connected ends with `mtu` and the fast nodelay mode, `configure` is applied to both
+/// before the first `update`
+pub fn pair(
+    conv: u32,
+    mtu: usize,
+    configure: impl Fn(&mut Kcp<Sink>),
+) -> (Kcp<Sink>, Sink, Kcp<Sink>, Sink) {
+    pair_with(Kcp::new, conv, mtu, configure)
+}
+
+/// Same as `pair`, creating both ends with `new`
+pub fn pair_with(
+    new: impl Fn(u32, Sink) -> Kcp<Sink>,
+    conv: u32,
+    mtu: usize,
+    configure: impl Fn(&mut Kcp<Sink>),
+) -> (Kcp<Sink>, Sink, Kcp<Sink>, Sink) {
+    let sink1 = Sink::default();
+    let sink2 = Sink::default();
+    let mut kcp1 = new(conv, sink1.clone());
+    let mut kcp2 = new(conv, sink2.clone());
+    for kcp in [&mut kcp1, &mut kcp2] {
+        kcp.set_mtu(mtu).unwrap();
+        kcp.set_nodelay(true, 10, 0, true);
+        configure(kcp);
+        kcp.update(0).unwrap();
+    }
+    (kcp1, sink1, kcp2, sink2)
+}
@@ -0,0 +1,111 @@
+#![cfg(all(feature = "std", feature = "compact"))]
+
+extern crate kcp;
+
+mod common;
+
+use kcp::{Kcp, KCP_COMPACT_OVERHEAD};
+
+use common::{pair_with, Sink};
+
+const KCP_CMD_PUSH: u8 = 81;
+
+#[test]
+fn compact_roundtrip() {
+    let (mut kcp1, sink1, mut kcp2, sink2) = pair_with(Kcp::new_compact, 7, 100, |_| {});
+    assert!(kcp1.is_compact());
+    assert_eq!(kcp1.mss(), 100 - KCP_COMPACT_OVERHEAD);
+
+    let msg: Vec<u8> = (0..250).map(|i| i as u8).collect();
+    kcp1.send(&msg).unwrap();
+    kcp1.flush().unwrap();
+    let datagrams = sink1.take();
+    let sizes: Vec<usize> = datagrams.iter().map(|d| d.len()).collect();
+    assert_eq!(sizes, vec![100, 100, 100, KCP_COMPACT_OVERHEAD + 10]);
+    assert_eq!(datagrams[0][0], KCP_CMD_PUSH);
+
+    for datagram in &datagrams {
+        kcp2.input(datagram).unwrap();
+    }
+    assert_eq!(&kcp2.recv_bytes().unwrap()[..], &msg[..]);
+
+    kcp2.flush().unwrap();
+    let acks = sink2.take();
+    assert_eq!(acks.concat().len(), 4 * KCP_COMPACT_OVERHEAD);
+    for ack in &acks {
+        kcp1.input(ack).unwrap();
+    }
+    assert_eq!(kcp1.wait_snd(), 0);
+
+    // Not understood by a standard one
+    let mut standard = Kcp::new(7, Sink::default());
+    standard.update(0).unwrap();
+    assert!(standard.input(&datagrams[0]).is_err());
+}
+
+#[test]
+fn compact_framed() {
+    let (mut kcp1, sink1, mut kcp2, _) = pair_with(Kcp::new_compact, 7, 100, |_| {});
+    kcp1.send(b"first").unwrap();
+    kcp1.send(b"second").unwrap();
+    kcp1.flush().unwrap();
+
+    let bytes = sink1.take().concat();
+    let mut processed = 0;
+    for chunk in bytes.chunks(3) {
+        processed += kcp2.input_framed(chunk).unwrap();
+    }
+    assert_eq!(processed, bytes.len());
+    assert_eq!(&kcp2.recv_bytes().unwrap()[..], b"first");
+    assert_eq!(&kcp2.recv_bytes().unwrap()[..], b"second");
+}
+
+#[test]
+fn compact_restored() {
+    let (kcp1, _, _, _) = pair_with(Kcp::new_compact, 7, 100, |_| {});
+    let restored = Kcp::restore(kcp1.snapshot(), Sink::default());
+    assert!(restored.is_compact());
+    assert_eq!(restored.mss(), 100 - KCP_COMPACT_OVERHEAD);
+}
+
+#[cfg(feature = "handshake")]
+#[test]
+fn compact_handshake() {
+    let sink1 = Sink::default();
+    let sink2 = Sink::default();
+    let mut client = Kcp::new_compact(7, sink1.clone());
+    let mut server = Kcp::new_compact(7, sink2.clone());
+    client.set_nodelay(true, 10, 0, true);
+    client.connect();
+    client.send(b"hello").unwrap();
+    client.update(0).unwrap();
+    let syn = sink1.take().remove(0);
+    assert_eq!(syn.len(), KCP_COMPACT_OVERHEAD);
+
+    server.accept(&syn).unwrap();
+    assert_eq!(server.conv(), 7);
+    server.update(0).unwrap();
+    client.input(&sink2.take()[0]).unwrap();
+    assert_eq!(client.state(), kcp::LinkState::Active);
+
+    client.update(10).unwrap();
+    for datagram in sink1.take() {
+        server.input(&datagram).unwrap();
+    }
+    assert_eq!(&server.recv_bytes().unwrap()[..], b"hello");
+
+    // Not a request
+    assert!(matches!(
+        server.accept(&[KCP_CMD_PUSH; KCP_COMPACT_OVERHEAD]),
+        Err(kcp::Error::NotSyn)
+    ));
+}
+
+#[test]
+fn compact_reset() {
+    let (mut kcp1, _, _, _) = pair_with(Kcp::new_compact, 7, 100, |_| {});
+    kcp1.reset(2);
+    assert!(kcp1.is_compact());
+    assert_eq!(kcp1.conv(), 2);
+    assert_eq!(kcp1.mss(), 1400 - KCP_COMPACT_OVERHEAD);
+}
@@ -0,0 +1,96 @@
+#![cfg(feature = "std")]
+
+extern crate bytes;
+extern crate kcp;
+
+mod common;
+
+use bytes::{BufMut, BytesMut};
+use kcp::{Kcp, KcpResult, SegmentCrypt};
+
+use common::Sink;
+
+/// XOR with a key, followed by a checksum byte
+struct Xor(u8);
+
+impl SegmentCrypt for Xor {
+    fn encrypt(&self, buf: &mut BytesMut) {
+        let sum = buf.iter().fold(0u8, |sum, b| sum.wrapping_add(*b));
+        for b in buf.iter_mut() {
+            *b ^= self.0;
+        }
+        buf.put_u8(sum);
+    }
+
+    fn decrypt(&self, buf: &mut [u8]) -> KcpResult<usize> {
+        let (sum, data) = buf.split_last_mut().ok_or(kcp::Error::DecryptFailed)?;
+        for b in data.iter_mut() {
+            *b ^= self.0;
+        }
+        if data.iter().fold(0u8, |sum, b| sum.wrapping_add(*b)) != *sum {
+            return Err(kcp::Error::DecryptFailed);
+        }
+        Ok(data.len())
+    }
+}
+
+#[test]
+fn crypt_round_trip() {
+    let sink1 = Sink::default();
+    let sink2 = Sink::default();
+    let mut kcp1 = Kcp::new(7, sink1.clone());
+    let mut kcp2 = Kcp::new(7, sink2.clone());
+    kcp1.set_crypt(Xor(0x5a));
+    kcp2.set_crypt(Xor(0x5a));
+    kcp1.set_nodelay(true, 10, 2, true);
+    kcp1.update(0).unwrap();
+    kcp2.update(0).unwrap();
+
+    kcp1.send(b"secret").unwrap();
+    kcp1.flush().unwrap();
+
+    let datagram = sink1.0.borrow_mut().pop().unwrap();
+    assert_eq!(datagram.len(), kcp::KCP_OVERHEAD + 6 + 1);
+    assert_eq!(kcp::try_get_conv(&datagram).unwrap(), 7);
+    assert!(!datagram.windows(6).any(|w| w == b"secret"));
+
+    // Not readable without the key
+    let mut plain = Kcp::new(7, Sink::default());
+    plain.update(0).unwrap();
+    assert!(plain.input(&datagram).is_err());
+
+    let mut tampered = datagram.clone();
+    tampered[10] ^= 1;
+    assert!(matches!(
+        kcp2.input(&tampered),
+        Err(kcp::Error::DecryptFailed)
+    ));
+
+    kcp2.input(&datagram).unwrap();
+    let mut buf = [0u8; 16];
+    let n = kcp2.recv(&mut buf).unwrap();
+    assert_eq!(&buf[..n], b"secret");
+
+    // ACK goes back encrypted as well
+    kcp2.flush().unwrap();
+    for datagram in sink2.0.borrow_mut().drain(..) {
+        kcp1.input(&datagram).unwrap();
+    }
+    assert_eq!(kcp1.wait_snd(), 0);
+}
+
+#[test]
+fn crypt_flush_into() {
+    let mut kcp = Kcp::new(7, Sink::default());
+    kcp.set_crypt(Xor(0x5a));
+    kcp.update(0).unwrap();
+    kcp.send(b"secret").unwrap();
+
+    let mut out = BytesMut::new();
+    assert!(matches!(
+        kcp.flush_into(&mut out, &mut Vec::new()),
+        Err(kcp::Error::InvalidConfig(_))
+    ));
+    assert!(out.is_empty());
+    assert_eq!(kcp.wait_snd(), 1);
+}
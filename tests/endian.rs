@@ -0,0 +1,73 @@
+#![cfg(all(feature = "std", feature = "endian"))]
+
+extern crate kcp;
+
+mod common;
+
+use kcp::{Endian, Kcp};
+
+use common::{pair, Sink};
+
+#[test]
+fn endian_roundtrip() {
+    let (mut kcp1, sink1, mut kcp2, sink2) =
+        pair(0x0102_0304, 100, |kcp| kcp.set_endianness(Endian::Big));
+    assert_eq!(kcp1.endianness(), Endian::Big);
+
+    let msg: Vec<u8> = (0..200).map(|i| i as u8).collect();
+    kcp1.send(&msg).unwrap();
+    kcp1.flush().unwrap();
+    for datagram in sink1.take() {
+        kcp2.input(&datagram).unwrap();
+    }
+    assert_eq!(&kcp2.recv_bytes().unwrap()[..], &msg[..]);
+
+    kcp2.flush().unwrap();
+    for datagram in sink2.take() {
+        kcp1.input(&datagram).unwrap();
+    }
+    assert_eq!(kcp1.wait_snd(), 0);
+}
+
+#[test]
+fn endian_header() {
+    let (mut kcp1, sink1, ..) = pair(0x0102_0304, 100, |kcp| kcp.set_endianness(Endian::Big));
+    kcp1.send(b"big").unwrap();
+    kcp1.flush().unwrap();
+    let datagram = sink1.take().remove(0);
+
+    assert_eq!(&datagram[..4], &[1, 2, 3, 4]);
+    assert_eq!(&datagram[20..24], &[0, 0, 0, 3]);
+    assert_eq!(
+        kcp::get_conv_with(&datagram, Endian::Big).unwrap(),
+        0x0102_0304
+    );
+    assert_eq!(kcp::try_get_conv(&datagram).unwrap(), 0x0403_0201);
+    assert_eq!(kcp::get_sn_with(&datagram, Endian::Big).unwrap(), 0);
+
+    let header = kcp::parse_header_with(&datagram, Endian::Big).unwrap();
+    assert_eq!(header.conv, 0x0102_0304);
+    assert_eq!(header.wnd, kcp::KCP_WND_RCV);
+    assert_eq!(header.len, 3);
+
+    let mut datagram = datagram;
+    kcp::try_set_conv_with(&mut datagram, 7, Endian::Big).unwrap();
+    assert_eq!(&datagram[..4], &[0, 0, 0, 7]);
+    assert!(kcp::get_conv_with(&datagram[..10], Endian::Big).is_err());
+}
+
+#[test]
+fn endian_mismatch() {
+    let (mut kcp1, sink1, ..) = pair(0x0102_0304, 100, |kcp| kcp.set_endianness(Endian::Big));
+    let sink = Sink::default();
+    let mut little = Kcp::new(0x0102_0304, sink);
+    little.set_strict_conv(true);
+    little.update(0).unwrap();
+    assert_eq!(little.endianness(), Endian::Little);
+
+    kcp1.send(b"big").unwrap();
+    kcp1.flush().unwrap();
+    let datagram = sink1.take().remove(0);
+    assert!(little.input(&datagram).is_err());
+    assert!(little.recv_bytes().is_err());
+}
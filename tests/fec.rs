@@ -0,0 +1,193 @@
+#![cfg(all(feature = "std", feature = "fec"))]
+
+extern crate kcp;
+
+mod common;
+
+use std::io::{self, Write};
+
+use kcp::{FecDecoder, FecEncoder, Kcp};
+
+use common::Sink;
+
+#[test]
+fn fec_recover() {
+    let sink = Sink::default();
+    let mut encoder = FecEncoder::new(sink.clone(), 4, 2);
+    let datagrams: Vec<Vec<u8>> = (0..4u8).map(|i| vec![i; 10 + i as usize * 7]).collect();
+    for datagram in &datagrams {
+        encoder.write_all(datagram).unwrap();
+    }
+
+    let packets = sink.take();
+    assert_eq!(packets.len(), 6);
+
+    // Any 2 lost packets can be recovered
+    for a in 0..6 {
+        for b in a + 1..6 {
+            let mut decoder = FecDecoder::new(4, 2);
+            let mut received = Vec::new();
+            for (i, packet) in packets.iter().enumerate() {
+                if i != a && i != b {
+                    received.extend(decoder.decode(packet, 0).unwrap());
+                }
+            }
+
+            let mut received: Vec<Vec<u8>> = received.iter().map(|d| d.to_vec()).collect();
+            received.sort();
+            assert_eq!(received, datagrams, "lost {} and {}", a, b);
+        }
+    }
+
+    // 3 lost packets are not recoverable
+    let mut decoder = FecDecoder::new(4, 2);
+    let mut received = 0;
+    for packet in &packets[3..] {
+        received += decoder.decode(packet, 0).unwrap().len();
+    }
+    assert_eq!(received, 1);
+}
+
+#[test]
+fn fec_partial_group() {
+    let sink = Sink::default();
+    let mut encoder = FecEncoder::new(sink.clone(), 4, 2);
+    let datagrams: Vec<Vec<u8>> = (0..3u8).map(|i| vec![i; 10 + i as usize]).collect();
+    for datagram in &datagrams {
+        encoder.write_all(datagram).unwrap();
+    }
+    assert_eq!(sink.0.borrow().len(), 3);
+
+    // Flushed before the group is complete, its parity is sent anyway
+    encoder.flush().unwrap();
+    let packets = sink.take();
+    assert_eq!(packets.len(), 5);
+    encoder.flush().unwrap();
+    assert!(sink.take().is_empty());
+
+    // Any 2 lost packets can be recovered
+    for a in 0..5 {
+        for b in a + 1..5 {
+            let mut decoder = FecDecoder::new(4, 2);
+            let mut received = Vec::new();
+            for (i, packet) in packets.iter().enumerate() {
+                if i != a && i != b {
+                    received.extend(decoder.decode(packet, 0).unwrap());
+                }
+            }
+
+            let mut received: Vec<Vec<u8>> = received.iter().map(|d| d.to_vec()).collect();
+            received.sort();
+            assert_eq!(received, datagrams, "lost {} and {}", a, b);
+        }
+    }
+
+    // The next group starts after the parity
+    encoder.write_all(b"next").unwrap();
+    assert_eq!(sink.take()[0][..4], 6u32.to_le_bytes());
+}
+
+#[test]
+fn fec_kcp_tail() {
+    let sink = Sink::default();
+    let mut kcp1 = Kcp::with_fec(1, sink.clone(), 4, 2);
+    let mut kcp2 = Kcp::new(1, io::sink());
+    kcp1.set_nodelay(true, 10, 0, true);
+    kcp1.update(0).unwrap();
+    kcp2.update(0).unwrap();
+
+    // The tail of a burst, less than a group, is protected as well
+    for i in 0..2u8 {
+        kcp1.send(&[i; 1000]).unwrap();
+    }
+    kcp1.flush().unwrap();
+    let packets = sink.take();
+    assert_eq!(packets.len(), 4);
+    assert!(packets.iter().all(|packet| packet.len() <= 1400));
+
+    let mut decoder = FecDecoder::new(4, 2);
+    for packet in &packets[1..] {
+        for datagram in decoder.decode(packet, 0).unwrap() {
+            kcp2.input(&datagram).unwrap();
+        }
+    }
+    let mut buf = [0u8; 1000];
+    for i in 0..2u8 {
+        assert_eq!(kcp2.recv(&mut buf).unwrap(), 1000);
+        assert_eq!(buf, [i; 1000]);
+    }
+}
+
+#[test]
+fn fec_stale_group() {
+    let sink = Sink::default();
+    let mut encoder = FecEncoder::new(sink.clone(), 4, 2);
+    encoder.write_all(b"incomplete").unwrap();
+
+    let mut decoder = FecDecoder::new(4, 2);
+    decoder.set_timeout(100);
+    for packet in sink.take() {
+        assert_eq!(decoder.decode(&packet, 0).unwrap().len(), 1);
+    }
+    assert_eq!(decoder.pending_groups(), 1);
+
+    encoder.write_all(b"next").unwrap();
+    for packet in sink.take() {
+        decoder.decode(&packet, 100).unwrap();
+    }
+    assert_eq!(decoder.pending_groups(), 1);
+
+    assert!(decoder.decode(b"bad", 100).is_err());
+}
+
+#[test]
+fn fec_malformed() {
+    let mut decoder = FecDecoder::new(4, 2);
+
+    // A data shard claiming 16 bytes, carrying 2
+    let packet = [0, 0, 0, 0, 0xf1, 0, 16, 0, 1, 2];
+    assert!(matches!(
+        decoder.decode(&packet, 0),
+        Err(kcp::Error::InvalidSegmentSize(10))
+    ));
+
+    let packet = [0, 0, 0, 0, 0x34, 0x12, 0, 0];
+    assert!(matches!(
+        decoder.decode(&packet, 0),
+        Err(kcp::Error::UnsupportedFecType(0x1234))
+    ));
+}
+
+#[test]
+fn fec_kcp() {
+    let sink = Sink::default();
+    let mut kcp1 = Kcp::with_fec(1, sink.clone(), 4, 2);
+    let mut kcp2 = Kcp::new(1, io::sink());
+    kcp1.set_nodelay(true, 10, 0, true);
+    kcp1.update(0).unwrap();
+    kcp2.update(0).unwrap();
+    assert_eq!(kcp1.mtu(), 1400 - kcp::FEC_OVERHEAD);
+
+    for i in 0..8u8 {
+        kcp1.send(&[i; 1000]).unwrap();
+    }
+    kcp1.flush().unwrap();
+
+    // Every third packet is lost, recovered without retransmission
+    let mut decoder = FecDecoder::new(4, 2);
+    for (i, packet) in sink.take().iter().enumerate() {
+        assert!(packet.len() <= 1400);
+        if i % 3 == 0 {
+            continue;
+        }
+        for datagram in decoder.decode(packet, 0).unwrap() {
+            kcp2.input(&datagram).unwrap();
+        }
+    }
+
+    let mut buf = [0u8; 1000];
+    for i in 0..8u8 {
+        assert_eq!(kcp2.recv(&mut buf).unwrap(), 1000);
+        assert_eq!(buf, [i; 1000]);
+    }
+}
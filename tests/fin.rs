@@ -0,0 +1,75 @@
+#![cfg(all(feature = "std", feature = "fin"))]
+
+extern crate kcp;
+
+mod common;
+
+use kcp::Kcp;
+
+use common::{deliver, Sink};
+
+const KCP_CMD_FIN: u8 = 86;
+
+#[test]
+fn fin_after_data() {
+    let sink1 = Sink::default();
+    let sink2 = Sink::default();
+    let mut kcp1 = Kcp::new(1, sink1.clone());
+    let mut kcp2 = Kcp::new(1, sink2.clone());
+    kcp1.set_nodelay(false, 10, 0, true);
+    kcp1.update(0).unwrap();
+    kcp2.update(0).unwrap();
+
+    kcp1.send(b"hello").unwrap();
+    kcp1.send(b"world").unwrap();
+    kcp1.close();
+    assert!(kcp1.is_closed());
+    kcp1.close();
+    assert_eq!(kcp1.snd_queue_len(), 3);
+
+    kcp1.flush().unwrap();
+    deliver(&sink1, &mut kcp2);
+    assert!(kcp2.is_peer_closed());
+    assert_eq!(&kcp2.recv_bytes().unwrap()[..], b"hello");
+    assert_eq!(&kcp2.recv_bytes().unwrap()[..], b"world");
+    assert!(kcp2.recv_bytes().is_err());
+
+    // The FIN is acknowledged like any PUSH
+    kcp2.flush().unwrap();
+    deliver(&sink2, &mut kcp1);
+    assert_eq!(kcp1.wait_snd(), 0);
+}
+
+#[test]
+fn fin_before_lost_data() {
+    let sink1 = Sink::default();
+    let sink2 = Sink::default();
+    let mut kcp1 = Kcp::new(1, sink1.clone());
+    let mut kcp2 = Kcp::new(1, sink2.clone());
+    kcp1.set_mtu(50).unwrap();
+    kcp1.set_nodelay(false, 10, 0, true);
+    kcp1.update(0).unwrap();
+    kcp2.update(0).unwrap();
+
+    kcp1.send(b"data lost").unwrap();
+    kcp1.close();
+    kcp1.flush().unwrap();
+
+    // Drop the data, only the FIN arrives
+    let datagrams: Vec<Vec<u8>> = sink1.0.borrow_mut().drain(..).collect();
+    assert_eq!(datagrams.len(), 2);
+    assert_eq!(kcp::get_cmd(&datagrams[1]).unwrap(), KCP_CMD_FIN);
+    kcp2.input(&datagrams[1]).unwrap();
+    assert!(!kcp2.is_peer_closed());
+    kcp2.flush().unwrap();
+    deliver(&sink2, &mut kcp1);
+    assert_eq!(kcp1.wait_snd(), 1);
+
+    // The data is retransmitted after the RTO
+    kcp1.update(10000).unwrap();
+    let datagrams: Vec<Vec<u8>> = sink1.0.borrow_mut().drain(..).collect();
+    assert_eq!(datagrams.len(), 1);
+    kcp2.input(&datagrams[0]).unwrap();
+    assert!(kcp2.is_peer_closed());
+    assert_eq!(&kcp2.recv_bytes().unwrap()[..], b"data lost");
+}
@@ -0,0 +1,1741 @@
+#![cfg(feature = "std")]
+
+extern crate bytes;
+extern crate kcp;
+
+mod common;
+
+use std::sync::{Arc, Mutex};
+
+use bytes::Buf;
+use kcp::{
+    CcAlgo, CongestionControl, CongestionState, ConnectionState, FlushStats, Kcp, LinkState,
+    LossDetector, LossEvent, NoDelayConfig, Retransmit, RtoEstimator, SegmentState, Stats,
+};
+
+use common::Sink;
+
+const KCP_CMD_PUSH: u8 = 81;
+const KCP_CMD_ACK: u8 = 82;
+const KCP_CMD_WASK: u8 = 83;
+const KCP_CMD_WINS: u8 = 84;
+
+/// Take all the datagrams written to `sink` so far, returns `(cmd, sn, len)` for every segment
+fn take_segments(sink: &Sink) -> Vec<(u8, u32, usize)> {
+    let mut segments = Vec::new();
+    for datagram in sink.take() {
+        let mut buf = &datagram[..];
+        while buf.len() >= kcp::KCP_OVERHEAD {
+            let _conv = buf.get_u32_le();
+            let cmd = buf.get_u8();
+            let _frg = buf.get_u8();
+            let _wnd = buf.get_u16_le();
+            let _ts = buf.get_u32_le();
+            let sn = buf.get_u32_le();
+            let _una = buf.get_u32_le();
+            let len = buf.get_u32_le() as usize;
+            buf.advance(len);
+            segments.push((cmd, sn, len));
+        }
+    }
+    segments
+}
+
+fn pushes(segments: &[(u8, u32, usize)]) -> Vec<u32> {
+    segments
+        .iter()
+        .filter(|s| s.0 == KCP_CMD_PUSH)
+        .map(|s| s.1)
+        .collect()
+}
+
+fn acks(segments: &[(u8, u32, usize)]) -> Vec<u32> {
+    segments
+        .iter()
+        .filter(|s| s.0 == KCP_CMD_ACK)
+        .map(|s| s.1)
+        .collect()
+}
+
+#[test]
+fn flush_max_burst() {
+    let sink = Sink::default();
+    let mut kcp = Kcp::new(1, sink.clone());
+    kcp.set_nodelay(true, 10, 0, true);
+    kcp.set_max_burst(3);
+
+    for _ in 0..10 {
+        kcp.send(b"burst").unwrap();
+    }
+
+    kcp.update(0).unwrap();
+    assert_eq!(pushes(&take_segments(&sink)), vec![0, 1, 2]);
+    assert_eq!(kcp.last_burst(), 3);
+
+    kcp.flush().unwrap();
+    assert_eq!(pushes(&take_segments(&sink)), vec![3, 4, 5]);
+
+    kcp.set_max_burst(0);
+    kcp.flush().unwrap();
+    assert_eq!(pushes(&take_segments(&sink)), vec![6, 7, 8, 9]);
+    assert_eq!(kcp.last_burst(), 4);
+}
+
+#[test]
+fn flush_ack_coalesce() {
+    let sink1 = Sink::default();
+    let sink2 = Sink::default();
+    let mut kcp1 = Kcp::new(1, sink1.clone());
+    let mut kcp2 = Kcp::new(1, sink2.clone());
+    kcp1.set_nodelay(true, 10, 0, true);
+    kcp2.set_nodelay(true, 10, 0, true);
+    kcp2.set_ack_coalesce_window(50);
+
+    kcp1.update(0).unwrap();
+    kcp2.update(0).unwrap();
+
+    kcp1.send(b"ping").unwrap();
+    kcp1.flush().unwrap();
+    for datagram in sink1.0.borrow_mut().drain(..) {
+        kcp2.input(&datagram).unwrap();
+    }
+
+    // ACK only, deferred
+    for current in (10..50).step_by(10) {
+        kcp2.update(current).unwrap();
+        assert!(take_segments(&sink2).is_empty());
+    }
+
+    kcp2.update(50).unwrap();
+    assert_eq!(acks(&take_segments(&sink2)), vec![0]);
+
+    // ACK goes along with data immediately
+    kcp1.send(b"ping").unwrap();
+    kcp1.flush().unwrap();
+    for datagram in sink1.0.borrow_mut().drain(..) {
+        kcp2.input(&datagram).unwrap();
+    }
+    kcp2.send(b"pong").unwrap();
+    kcp2.update(60).unwrap();
+    let segments = take_segments(&sink2);
+    assert_eq!(acks(&segments), vec![1]);
+    assert_eq!(pushes(&segments), vec![0]);
+}
+
+#[test]
+fn flush_ack_delay() {
+    // Datagrams written by the receiver while a message is sent every 10ms for a second
+    let run = |ack_delay: u32| {
+        let sink1 = Sink::default();
+        let sink2 = Sink::default();
+        let mut kcp1 = Kcp::new(1, sink1.clone());
+        let mut kcp2 = Kcp::new(1, sink2.clone());
+        kcp1.set_nodelay(true, 10, 0, true);
+        kcp2.set_nodelay(true, 10, 0, true);
+        kcp2.set_ack_delay(ack_delay);
+        kcp1.update(0).unwrap();
+        kcp2.update(0).unwrap();
+
+        let mut datagrams = 0;
+        for current in (10..=1000).step_by(10) {
+            kcp1.send(b"ping").unwrap();
+            kcp1.update(current).unwrap();
+            for datagram in sink1.0.borrow_mut().drain(..) {
+                kcp2.input(&datagram).unwrap();
+            }
+            kcp2.update(current).unwrap();
+            kcp2.recv_all();
+            for datagram in sink2.0.borrow_mut().drain(..) {
+                datagrams += 1;
+                kcp1.input(&datagram).unwrap();
+            }
+        }
+        assert_eq!(kcp1.stats().timeout_retransmits, 0);
+        datagrams
+    };
+
+    let immediate = run(0);
+    let delayed = run(40);
+    assert_eq!(immediate, 100);
+    assert!(delayed <= 30, "{} datagrams", delayed);
+
+    // Answering a window probe isn't delayed
+    let sink1 = Sink::default();
+    let sink2 = Sink::default();
+    let mut kcp1 = Kcp::new(1, sink1.clone());
+    let mut kcp2 = Kcp::new(1, sink2.clone());
+    kcp2.set_ack_delay(40);
+    kcp1.update(0).unwrap();
+    kcp2.update(0).unwrap();
+    kcp1.send(b"ping").unwrap();
+    kcp1.probe_now();
+    kcp1.flush().unwrap();
+    for datagram in sink1.0.borrow_mut().drain(..) {
+        kcp2.input(&datagram).unwrap();
+    }
+    kcp2.flush().unwrap();
+    let segments = take_segments(&sink2);
+    assert_eq!(acks(&segments), vec![0]);
+    assert!(segments.iter().any(|s| s.0 == KCP_CMD_WINS));
+}
+
+/// Retransmits every segment once, on the next flush after it was sent
+struct Eager;
+
+impl LossDetector for Eager {
+    fn detect(&self, segment: &SegmentState, _conn: &ConnectionState) -> Retransmit {
+        if segment.xmit < 2 {
+            Retransmit::Fast
+        } else {
+            Retransmit::No
+        }
+    }
+}
+
+#[test]
+fn flush_loss_detector() {
+    let sink = Sink::default();
+    let mut kcp = Kcp::new(1, sink.clone());
+    kcp.set_nodelay(true, 10, 0, true);
+    kcp.set_loss_detector(Eager);
+
+    kcp.send(b"lost").unwrap();
+    kcp.update(0).unwrap();
+    assert_eq!(pushes(&take_segments(&sink)), vec![0]);
+
+    kcp.flush().unwrap();
+    assert_eq!(pushes(&take_segments(&sink)), vec![0]);
+
+    // Never times out, even long after RTO
+    kcp.update(10000).unwrap();
+    assert!(take_segments(&sink).is_empty());
+}
+
+#[test]
+fn flush_dupack_threshold() {
+    let sink1 = Sink::default();
+    let sink2 = Sink::default();
+    let mut kcp1 = Kcp::new(1, sink1.clone());
+    let mut kcp2 = Kcp::new(1, sink2.clone());
+    kcp1.set_nodelay(true, 10, 2, true);
+    kcp1.set_dupack_threshold(4);
+    assert_eq!(kcp1.dupack_threshold(), 4);
+
+    kcp1.update(0).unwrap();
+    kcp2.update(0).unwrap();
+
+    for _ in 0..6 {
+        kcp1.send(b"dup").unwrap();
+    }
+    kcp1.flush().unwrap();
+
+    // Lose the first segment, the rest skip it once per ACK
+    let datagrams: Vec<Vec<u8>> = sink1.0.borrow_mut().drain(..).collect();
+    let mut segments = Vec::new();
+    for datagram in &datagrams {
+        for chunk in datagram.chunks(kcp::KCP_OVERHEAD + 3) {
+            segments.push(chunk.to_vec());
+        }
+    }
+    let mut resent_at = None;
+    for (i, segment) in segments.iter().enumerate().skip(1) {
+        kcp2.input(segment).unwrap();
+        kcp2.flush().unwrap();
+        for ack in sink2.0.borrow_mut().drain(..) {
+            kcp1.input(&ack).unwrap();
+        }
+
+        kcp1.flush().unwrap();
+        if pushes(&take_segments(&sink1)) == vec![0] {
+            resent_at = Some(i);
+            break;
+        }
+    }
+    assert_eq!(resent_at, Some(4));
+}
+
+#[test]
+fn flush_fastack_limit() {
+    // Fast resends of a lost segment while 11 segments after it are acknowledged one by one
+    let run = |limit: u32| {
+        let sink1 = Sink::default();
+        let sink2 = Sink::default();
+        let mut kcp1 = Kcp::new(1, sink1.clone());
+        let mut kcp2 = Kcp::new(1, sink2.clone());
+        kcp1.set_nodelay(true, 10, 1, true);
+        kcp1.set_fastack_limit(limit);
+        assert_eq!(kcp1.fastack_limit(), limit);
+        kcp1.update(0).unwrap();
+        kcp2.update(0).unwrap();
+
+        for _ in 0..12 {
+            kcp1.send(b"dup").unwrap();
+        }
+        kcp1.flush().unwrap();
+
+        let datagrams: Vec<Vec<u8>> = sink1.0.borrow_mut().drain(..).collect();
+        let mut segments = Vec::new();
+        for datagram in &datagrams {
+            for chunk in datagram.chunks(kcp::KCP_OVERHEAD + 3) {
+                segments.push(chunk.to_vec());
+            }
+        }
+        for segment in segments.iter().skip(1) {
+            kcp2.input(segment).unwrap();
+            kcp2.flush().unwrap();
+            for ack in sink2.0.borrow_mut().drain(..) {
+                kcp1.input(&ack).unwrap();
+            }
+            kcp1.flush().unwrap();
+            take_segments(&sink1);
+        }
+        kcp1.stats().fast_retransmits
+    };
+
+    assert_eq!(Kcp::new_collecting(1).fastack_limit(), 5);
+    assert_eq!(run(2), 2);
+    assert_eq!(run(5), 5);
+    assert_eq!(run(0), 11);
+}
+
+#[test]
+fn flush_fastack_conserve() {
+    for conserve in [false, true] {
+        let sink1 = Sink::default();
+        let sink2 = Sink::default();
+        let mut kcp1 = Kcp::new(1, sink1.clone());
+        let mut kcp2 = Kcp::new(1, sink2.clone());
+        kcp1.set_nodelay(true, 10, 1, true);
+        kcp1.set_fastack_conserve(conserve);
+        assert_eq!(kcp1.fastack_conserve(), conserve);
+        kcp1.update(0).unwrap();
+        kcp2.update(0).unwrap();
+
+        kcp1.send(b"old").unwrap();
+        kcp1.send(b"new").unwrap();
+        kcp1.flush().unwrap();
+        let datagram = sink1.0.borrow_mut().drain(..).next().unwrap();
+        let second = datagram.chunks(kcp::KCP_OVERHEAD + 3).nth(1).unwrap();
+
+        // Both are retransmitted, then the ACK of the first transmission of sn 1 arrives
+        kcp1.update(1000).unwrap();
+        assert_eq!(pushes(&take_segments(&sink1)), vec![0, 1]);
+        kcp2.input(second).unwrap();
+        kcp2.flush().unwrap();
+        for ack in sink2.0.borrow_mut().drain(..) {
+            kcp1.input(&ack).unwrap();
+        }
+
+        // Only the conserve mode counts it for sn 0, retransmitted after it
+        kcp1.flush().unwrap();
+        let resent = if conserve { vec![0] } else { vec![] };
+        assert_eq!(pushes(&take_segments(&sink1)), resent);
+    }
+}
+
+#[test]
+fn flush_nocwnd_toggled() {
+    let sink1 = Sink::default();
+    let sink2 = Sink::default();
+    let mut kcp1 = Kcp::new(1, sink1.clone());
+    let mut kcp2 = Kcp::new(1, sink2.clone());
+    kcp1.set_nodelay(true, 10, 0, true);
+    kcp1.update(0).unwrap();
+    kcp2.update(0).unwrap();
+
+    let mut current = 0;
+    let mut run = |kcp1: &mut Kcp<Sink>, lossy: bool| {
+        let mut windows = Vec::new();
+        while kcp1.wait_snd() > 0 {
+            current += 10;
+            kcp1.update(current).unwrap();
+            for datagram in sink1.0.borrow_mut().drain(..) {
+                if !lossy {
+                    kcp2.input(&datagram).unwrap();
+                }
+            }
+            kcp2.update(current).unwrap();
+            kcp2.recv_all();
+            for datagram in sink2.0.borrow_mut().drain(..) {
+                kcp1.input(&datagram).unwrap();
+            }
+            windows.push(kcp1.cwnd());
+            if lossy && kcp1.stats().timeout_retransmits > 0 {
+                break;
+            }
+        }
+        windows
+    };
+
+    // Losses while disabled still bring the window down
+    for _ in 0..16 {
+        kcp1.send(&[0u8; 100]).unwrap();
+    }
+    run(&mut kcp1, true);
+    run(&mut kcp1, false);
+    assert!(kcp1.cwnd() <= 2);
+
+    kcp1.set_nodelay(true, 10, 0, false);
+    assert_eq!(kcp1.cwnd(), 1);
+    assert_eq!(kcp1.ssthresh(), 32);
+    assert_eq!(kcp1.incr(), kcp1.mss());
+
+    // Growing in slow start every round trip
+    for _ in 0..64 {
+        kcp1.send(&[0u8; 100]).unwrap();
+    }
+    let windows = run(&mut kcp1, false);
+    assert!(windows.windows(2).all(|w| w[0] <= w[1]));
+    let cwnd = kcp1.cwnd();
+    assert!(cwnd >= 8);
+    assert!(cwnd < kcp1.ssthresh());
+
+    // Not restarted unless it was disabled
+    kcp1.set_nodelay(true, 10, 0, false);
+    assert_eq!(kcp1.cwnd(), cwnd);
+}
+
+/// Rounds of 10ms of a lossless link until `cwnd` reaches the send window
+fn rounds_to_fill(factor: f32) -> u32 {
+    let sink1 = Sink::default();
+    let sink2 = Sink::default();
+    let mut kcp1 = Kcp::new(1, sink1.clone());
+    let mut kcp2 = Kcp::new(1, sink2.clone());
+    kcp1.set_nodelay(true, 10, 0, false);
+    kcp2.set_nodelay(true, 10, 0, false);
+    kcp1.set_wndsize(64, 256);
+    kcp2.set_wndsize(64, 256);
+    kcp1.set_cwnd_increase_factor(factor);
+    kcp1.update(0).unwrap();
+    kcp2.update(0).unwrap();
+
+    for round in 1..1000 {
+        while kcp1.wait_snd() < 128 {
+            kcp1.send(&[0u8; 1000]).unwrap();
+        }
+        let current = round * 10;
+        kcp1.update(current).unwrap();
+        for datagram in sink1.0.borrow_mut().drain(..) {
+            kcp2.input(&datagram).unwrap();
+        }
+        kcp2.update(current).unwrap();
+        kcp2.recv_all();
+        for datagram in sink2.0.borrow_mut().drain(..) {
+            kcp1.input(&datagram).unwrap();
+        }
+        if kcp1.cwnd() >= 64 {
+            return round;
+        }
+    }
+    panic!("cwnd stuck at {}", kcp1.cwnd());
+}
+
+#[test]
+fn flush_cwnd_increase_factor() {
+    let mut kcp = Kcp::new(1, Sink::default());
+    assert_eq!(kcp.cwnd_increase_factor(), 1.0);
+    kcp.set_cwnd_increase_factor(100.0);
+    assert_eq!(kcp.cwnd_increase_factor(), 8.0);
+    kcp.set_cwnd_increase_factor(0.0);
+    assert_eq!(kcp.cwnd_increase_factor(), 0.25);
+
+    let classic = rounds_to_fill(1.0);
+    let double = rounds_to_fill(2.0);
+    let aggressive = rounds_to_fill(4.0);
+    assert!(double < classic, "{} {}", double, classic);
+    assert!(aggressive < double, "{} {}", aggressive, double);
+}
+
+#[test]
+fn flush_low_minrto() {
+    // Over loopback, flushed every millisecond, returns the RTO and when the lost one is resent
+    let run = |estimator: RtoEstimator| {
+        let sink1 = Sink::default();
+        let sink2 = Sink::default();
+        let mut kcp1 = Kcp::new(1, sink1.clone());
+        let mut kcp2 = Kcp::new(1, sink2.clone());
+        kcp1.set_nodelay(true, 10, 0, true);
+        kcp1.set_rto_estimator(estimator);
+        kcp1.set_rx_minrto(1);
+        kcp2.set_ack_nodelay(true);
+        kcp1.update(0).unwrap();
+        kcp2.update(0).unwrap();
+
+        let mut lost = None;
+        let mut resent = None;
+        for current in 1..200 {
+            if current % 5 == 0 {
+                kcp1.send(&[0u8; 16]).unwrap();
+            }
+            kcp1.update(current).unwrap();
+            kcp1.flush().unwrap();
+            for datagram in sink1.0.borrow_mut().drain(..) {
+                // Lost once
+                if current == 150 {
+                    lost = Some(kcp::try_get_sn(&datagram).unwrap());
+                    continue;
+                }
+                let has_lost = datagram
+                    .chunks(kcp::KCP_OVERHEAD + 16)
+                    .any(|seg| Some(kcp::try_get_sn(seg).unwrap()) == lost);
+                if has_lost && resent.is_none() {
+                    resent = Some(current - 150);
+                }
+                kcp2.input(&datagram).unwrap();
+            }
+            kcp2.update(current).unwrap();
+            kcp2.recv_all();
+            for datagram in sink2.0.borrow_mut().drain(..) {
+                kcp1.input(&datagram).unwrap();
+            }
+        }
+        (kcp1.rx_rto(), resent.unwrap())
+    };
+
+    // Bounded by RTT and the flush interval
+    let (rto, resent) = run(RtoEstimator::Ikcp);
+    assert!(rto <= 10, "rto {}", rto);
+    assert!(resent <= 11, "resent after {}", resent);
+
+    // Bounded by RTT only
+    let (rto, resent) = run(RtoEstimator::Rfc6298);
+    assert!(rto <= 2, "rto {}", rto);
+    assert!(resent <= 3, "resent after {}", resent);
+}
+
+#[test]
+fn flush_configure() {
+    let mut kcp1 = Kcp::new(1, Sink::default());
+    let mut kcp2 = Kcp::new(1, Sink::default());
+    kcp1.set_nodelay(true, 20, 2, true);
+    kcp2.configure(NoDelayConfig::fast());
+    assert_eq!(format!("{:?}", kcp1), format!("{:?}", kcp2));
+
+    // A negative resend keeps the current one
+    kcp1.set_nodelay(false, 100, -1, false);
+    kcp2.configure(NoDelayConfig {
+        resend: 2,
+        ..NoDelayConfig::default()
+    });
+    assert_eq!(format!("{:?}", kcp1), format!("{:?}", kcp2));
+
+    kcp1.set_nodelay(false, 100, 0, false);
+    kcp2.configure(NoDelayConfig::default());
+    assert_eq!(format!("{:?}", kcp1), format!("{:?}", kcp2));
+}
+
+#[test]
+fn flush_rto_backoff() {
+    let sink = Sink::default();
+    let mut kcp = Kcp::new(1, sink.clone());
+    kcp.set_nodelay(false, 100, 0, true);
+
+    kcp.send(b"stuck").unwrap();
+    kcp.update(0).unwrap();
+    assert_eq!(kcp.current_rto_for(0), Some(200));
+    assert_eq!(kcp.current_rto_for(1), None);
+
+    kcp.update(300).unwrap();
+    assert_eq!(pushes(&take_segments(&sink)), vec![0, 0]);
+    assert_eq!(kcp.current_rto_for(0), Some(400));
+}
+
+#[test]
+fn flush_rate_limit() {
+    let sink = Sink::default();
+    let mut kcp = Kcp::new(1, sink.clone());
+    kcp.set_nodelay(true, 10, 0, true);
+    kcp.set_wndsize(128, 128);
+    kcp.update(0).unwrap();
+    kcp.set_rate_limit(10000);
+
+    for _ in 0..40 {
+        kcp.send(&[0u8; 476]).unwrap();
+    }
+
+    // 1400 bytes in bucket, segments are 500 bytes
+    kcp.flush().unwrap();
+    assert_eq!(pushes(&take_segments(&sink)).len(), 3);
+
+    let mut sent = 0;
+    for current in (10..=1000).step_by(10) {
+        kcp.update(current).unwrap();
+        sent += pushes(&take_segments(&sink)).len();
+    }
+    assert_eq!(sent, 20);
+    assert!(kcp.check(1000) > 0);
+}
+
+#[test]
+fn flush_would_flush() {
+    let sink1 = Sink::default();
+    let sink2 = Sink::default();
+    let mut kcp1 = Kcp::new(1, sink1.clone());
+    let mut kcp2 = Kcp::new(1, sink2.clone());
+    kcp1.set_nodelay(true, 10, 0, true);
+    assert!(!kcp1.would_flush(0));
+
+    kcp1.update(0).unwrap();
+    kcp2.update(0).unwrap();
+    assert!(!kcp1.would_flush(0));
+
+    kcp1.send(b"data").unwrap();
+    assert!(kcp1.would_flush(0));
+    kcp1.flush().unwrap();
+    assert!(!kcp1.would_flush(10));
+    assert!(kcp1.would_flush(300));
+
+    for datagram in sink1.0.borrow_mut().drain(..) {
+        kcp2.input(&datagram).unwrap();
+    }
+    assert!(kcp2.would_flush(0));
+    kcp2.flush().unwrap();
+    assert!(!kcp2.would_flush(0));
+    assert_eq!(acks(&take_segments(&sink2)), vec![0]);
+}
+
+#[test]
+fn flush_shrink_output_buf() {
+    let sink = Sink::default();
+    let mut kcp = Kcp::new(1, sink.clone());
+    kcp.set_nodelay(true, 10, 0, true);
+    kcp.set_wndsize(128, 128);
+    kcp.update(0).unwrap();
+
+    let baseline = kcp.output_buf_capacity();
+    for _ in 0..100 {
+        kcp.send(&[0u8; 1000]).unwrap();
+    }
+    kcp.flush().unwrap();
+    assert_eq!(pushes(&take_segments(&sink)).len(), 100);
+
+    kcp.shrink_output_buf();
+    assert!(kcp.output_buf_capacity() <= baseline);
+}
+
+#[test]
+fn flush_reserve() {
+    let sink = Sink::default();
+    let mut kcp = Kcp::new(1, sink.clone());
+    kcp.set_nodelay(true, 10, 0, true);
+    kcp.set_wndsize(128, 128);
+    kcp.update(0).unwrap();
+
+    kcp.shrink_output_buf();
+    kcp.reserve(128, 128);
+    assert!(kcp.output_buf_capacity() >= (kcp.mtu() + kcp::KCP_OVERHEAD) * 3);
+
+    // Only advisory, more than reserved is still taken
+    for _ in 0..200 {
+        kcp.send(&[0u8; 1000]).unwrap();
+    }
+    assert_eq!(kcp.wait_snd(), 200);
+    kcp.flush().unwrap();
+    assert_eq!(pushes(&take_segments(&sink)).len(), 128);
+}
+
+#[test]
+fn flush_advertised_wnd_override() {
+    let sink1 = Sink::default();
+    let sink2 = Sink::default();
+    let mut kcp1 = Kcp::new(1, sink1.clone());
+    let mut kcp2 = Kcp::new(1, sink2.clone());
+    kcp1.set_nodelay(true, 10, 0, true);
+    kcp2.set_advertised_wnd_override(Some(0));
+    kcp1.update(0).unwrap();
+    kcp2.update(0).unwrap();
+
+    kcp1.send(b"first").unwrap();
+    kcp1.flush().unwrap();
+    for datagram in sink1.0.borrow_mut().drain(..) {
+        kcp2.input(&datagram).unwrap();
+    }
+    kcp2.flush().unwrap();
+    for datagram in sink2.0.borrow_mut().drain(..) {
+        kcp1.input(&datagram).unwrap();
+    }
+    assert_eq!(kcp1.rmt_wnd(), 0);
+
+    // Remote window is closed
+    kcp1.send(b"second").unwrap();
+    kcp1.flush().unwrap();
+    assert!(pushes(&take_segments(&sink1)).is_empty());
+}
+
+#[test]
+fn flush_probe_now() {
+    let sink1 = Sink::default();
+    let sink2 = Sink::default();
+    let mut kcp1 = Kcp::new(1, sink1.clone());
+    let mut kcp2 = Kcp::new(1, sink2.clone());
+    kcp1.set_nodelay(true, 10, 0, true);
+    kcp2.set_advertised_wnd_override(Some(0));
+    kcp1.update(0).unwrap();
+    kcp2.update(0).unwrap();
+
+    kcp1.send(b"first").unwrap();
+    kcp1.flush().unwrap();
+    for datagram in sink1.0.borrow_mut().drain(..) {
+        kcp2.input(&datagram).unwrap();
+    }
+    kcp2.flush().unwrap();
+    for datagram in sink2.0.borrow_mut().drain(..) {
+        kcp1.input(&datagram).unwrap();
+    }
+    assert_eq!(kcp1.rmt_wnd(), 0);
+    kcp1.send(b"second").unwrap();
+    kcp1.update(100).unwrap();
+    take_segments(&sink1);
+
+    // Reopened, the remote is told right away
+    kcp2.set_advertised_wnd_override(None);
+    assert!(!kcp1.is_probing());
+    kcp1.probe_now();
+    assert!(kcp1.is_probing());
+    kcp1.update(200).unwrap();
+    assert!(!kcp1.is_probing());
+    let datagrams: Vec<Vec<u8>> = sink1.0.borrow_mut().drain(..).collect();
+    assert_eq!(datagrams.len(), 1);
+    assert_eq!(kcp::get_cmd(&datagrams[0]).unwrap(), KCP_CMD_WASK);
+
+    kcp2.input(&datagrams[0]).unwrap();
+    assert!(kcp2.is_probing());
+    kcp2.flush().unwrap();
+    for datagram in sink2.0.borrow_mut().drain(..) {
+        kcp1.input(&datagram).unwrap();
+    }
+    assert!(kcp1.rmt_wnd() > 0);
+    kcp1.update(300).unwrap();
+    assert_eq!(pushes(&take_segments(&sink1)), vec![1]);
+}
+
+#[test]
+fn flush_ack_nodelay() {
+    for nodelay in [false, true] {
+        let sink1 = Sink::default();
+        let sink2 = Sink::default();
+        let mut kcp1 = Kcp::new(1, sink1.clone());
+        let mut kcp2 = Kcp::new(1, sink2.clone());
+        kcp1.set_nodelay(false, 100, 0, true);
+        kcp2.set_nodelay(false, 100, 0, true);
+        kcp2.set_ack_nodelay(nodelay);
+        kcp1.update(0).unwrap();
+        kcp2.update(0).unwrap();
+
+        kcp1.send(b"ping").unwrap();
+        kcp1.flush().unwrap();
+        for datagram in sink1.0.borrow_mut().drain(..) {
+            kcp2.input(&datagram).unwrap();
+        }
+        assert_eq!(kcp2.pending_acks(), if nodelay { 0 } else { 1 });
+        assert_eq!(kcp2.check(5), if nodelay { 0 } else { 95 });
+
+        // Written by the next update, or the next flush
+        kcp2.update(5).unwrap();
+        kcp2.update(100).unwrap();
+        let current = if nodelay { 5 } else { 100 };
+        assert_eq!(sink2.0.borrow().len(), 1);
+        kcp1.update(current).unwrap();
+        for datagram in sink2.0.borrow_mut().drain(..) {
+            kcp1.input(&datagram).unwrap();
+        }
+
+        assert_eq!(kcp1.wait_snd(), 0);
+        assert_eq!(kcp1.stats().srtt, current);
+    }
+}
+
+#[test]
+fn flush_pending_output_size() {
+    let sink1 = Sink::default();
+    let sink2 = Sink::default();
+    let mut kcp1 = Kcp::new(1, sink1.clone());
+    let mut kcp2 = Kcp::new(1, sink2.clone());
+    kcp1.set_nodelay(true, 10, 0, true);
+    kcp1.set_max_burst(2);
+    kcp1.update(0).unwrap();
+    kcp2.update(0).unwrap();
+
+    for _ in 0..3 {
+        kcp1.send(&[0u8; 10]).unwrap();
+    }
+    assert_eq!(kcp1.pending_output_size(0), 2 * 34);
+    kcp1.flush().unwrap();
+    let written: usize = sink1.0.borrow().iter().map(|d| d.len()).sum();
+    assert_eq!(written, 2 * 34);
+    assert_eq!(kcp1.pending_output_size(0), 34);
+
+    for datagram in sink1.0.borrow_mut().drain(..) {
+        kcp2.input(&datagram).unwrap();
+    }
+    assert_eq!(kcp2.pending_output_size(0), 2 * kcp::KCP_OVERHEAD);
+
+    // Both resent after RTO, along with the one never sent
+    assert_eq!(kcp1.pending_output_size(1000), 3 * 34);
+}
+
+#[test]
+fn flush_and_report() {
+    let sink1 = Sink::default();
+    let sink2 = Sink::default();
+    let mut kcp1 = Kcp::new(1, sink1.clone());
+    let mut kcp2 = Kcp::new(1, sink2.clone());
+    kcp1.set_nodelay(true, 5000, 0, true);
+    kcp1.update(0).unwrap();
+    kcp2.update(0).unwrap();
+
+    kcp1.send(&[0u8; 10]).unwrap();
+    kcp1.send(&[0u8; 10]).unwrap();
+    assert_eq!(
+        kcp1.flush_and_report().unwrap(),
+        FlushStats {
+            bytes_written: 68,
+            segments_sent: 2,
+            acks_sent: 0,
+            retransmits: 0,
+        }
+    );
+    assert_eq!(kcp1.flush_and_report().unwrap(), FlushStats::default());
+
+    for datagram in sink1.0.borrow_mut().drain(..) {
+        kcp2.input(&datagram).unwrap();
+    }
+    let stats = kcp2.flush_and_report().unwrap();
+    assert_eq!(stats.acks_sent, 2);
+    assert_eq!(stats.bytes_written, 48);
+
+    // Not acknowledged, no flush in update until 5000
+    kcp1.update(1000).unwrap();
+    kcp1.send(&[0u8; 10]).unwrap();
+    let stats = kcp1.flush_and_report().unwrap();
+    assert_eq!(stats.segments_sent, 3);
+    assert_eq!(stats.retransmits, 2);
+}
+
+#[test]
+fn flush_congestion_state() {
+    let sink1 = Sink::default();
+    let sink2 = Sink::default();
+    let mut kcp1 = Kcp::new(1, sink1.clone());
+    let mut kcp2 = Kcp::new(1, sink2.clone());
+    kcp1.update(0).unwrap();
+    kcp2.update(0).unwrap();
+    assert_eq!((kcp1.cwnd(), kcp1.ssthresh(), kcp1.incr()), (1, 2, 1376));
+    assert_eq!(
+        (kcp1.rx_srtt(), kcp1.rx_rttval(), kcp1.rx_rto()),
+        (0, 0, 200)
+    );
+
+    kcp1.send(&[0u8; 10]).unwrap();
+    kcp1.send(&[0u8; 10]).unwrap();
+    kcp1.flush().unwrap();
+
+    // Window limited to a single segment in slow start
+    assert_eq!(kcp1.cwnd(), 1);
+    assert_eq!(sink1.0.borrow().len(), 1);
+    assert_eq!(kcp1.wait_snd(), 2);
+
+    for datagram in sink1.0.borrow_mut().drain(..) {
+        kcp2.input(&datagram).unwrap();
+    }
+    kcp2.flush().unwrap();
+    kcp1.update(40).unwrap();
+    for datagram in sink2.0.borrow_mut().drain(..) {
+        kcp1.input(&datagram).unwrap();
+    }
+    assert_eq!((kcp1.cwnd(), kcp1.ssthresh(), kcp1.incr()), (2, 2, 2752));
+    assert_eq!(
+        (kcp1.rx_srtt(), kcp1.rx_rttval(), kcp1.rx_rto()),
+        (40, 20, 140)
+    );
+}
+
+#[test]
+fn flush_ack_if() {
+    let sink1 = Sink::default();
+    let sink2 = Sink::default();
+    let mut kcp1 = Kcp::new(1, sink1.clone());
+    let mut kcp2 = Kcp::new(1, sink2.clone());
+    kcp1.set_nodelay(true, 10, 0, true);
+    kcp1.update(0).unwrap();
+    kcp2.update(0).unwrap();
+    assert!(!kcp2.flush_ack_if(0).unwrap());
+
+    for _ in 0..3 {
+        kcp1.send(b"ping").unwrap();
+    }
+    kcp1.flush().unwrap();
+    for datagram in sink1.0.borrow_mut().drain(..) {
+        kcp2.input(&datagram).unwrap();
+    }
+    assert_eq!(kcp2.pending_acks(), 3);
+
+    assert!(!kcp2.flush_ack_if(4).unwrap());
+    assert!(sink2.0.borrow().is_empty());
+
+    assert!(kcp2.flush_ack_if(3).unwrap());
+    assert_eq!(kcp2.pending_acks(), 0);
+
+    // Window left after the 3 messages received
+    let wnd = u16::from_le_bytes([sink2.0.borrow()[0][6], sink2.0.borrow()[0][7]]);
+    assert_eq!(wnd, 128 - 3);
+    assert_eq!(acks(&take_segments(&sink2)), vec![0, 1, 2]);
+}
+
+#[test]
+fn flush_dead_link_handler() {
+    let mut kcp = Kcp::new(7, Sink::default());
+    kcp.set_nodelay(true, 10, 0, true);
+    kcp.set_maximum_resend_times(3);
+
+    let dead = Arc::new(Mutex::new(Vec::new()));
+    let handler_dead = dead.clone();
+    kcp.set_dead_link_handler(move |conv| handler_dead.lock().unwrap().push(conv));
+
+    kcp.update(0).unwrap();
+    kcp.send(b"lost").unwrap();
+    assert_eq!(kcp.state(), LinkState::Active);
+
+    // Never acknowledged, dead by the third transmission
+    let mut current = 0;
+    while !kcp.is_dead_link() {
+        current += 10;
+        kcp.update(current).unwrap();
+    }
+    assert_eq!(kcp.state(), LinkState::DeadLink);
+    assert_eq!(*dead.lock().unwrap(), vec![7]);
+
+    for _ in 0..100 {
+        current += 10;
+        kcp.update(current).unwrap();
+    }
+    assert_eq!(*dead.lock().unwrap(), vec![7]);
+
+    kcp.clear_dead_link();
+    assert!(!kcp.is_dead_link());
+    assert_eq!(kcp.state(), LinkState::Active);
+    while !kcp.is_dead_link() {
+        current += 10;
+        kcp.update(current).unwrap();
+    }
+    assert_eq!(*dead.lock().unwrap(), vec![7, 7]);
+}
+
+#[test]
+fn flush_revive() {
+    let sink1 = Sink::default();
+    let sink2 = Sink::default();
+    let mut kcp1 = Kcp::new(1, sink1.clone());
+    let mut kcp2 = Kcp::new(1, sink2.clone());
+    kcp1.set_nodelay(true, 10, 0, true);
+    kcp1.set_maximum_resend_times(3);
+    kcp1.update(0).unwrap();
+    kcp2.update(0).unwrap();
+    kcp1.send(b"hiccup").unwrap();
+
+    // Everything lost in the outage
+    let mut current = 0;
+    while !kcp1.is_dead_link() {
+        current += 10;
+        kcp1.update(current).unwrap();
+    }
+    take_segments(&sink1);
+    assert_eq!(kcp1.stats().timeout_retransmits, 2);
+
+    // Resent at once, with as many resends left as a new segment
+    kcp1.revive();
+    assert_eq!(kcp1.state(), LinkState::Active);
+    assert_eq!(kcp1.wait_snd(), 1);
+    kcp1.flush().unwrap();
+    assert!(!kcp1.is_dead_link());
+    let resent = sink1.0.borrow_mut().split_off(0);
+    assert_eq!(resent.len(), 1);
+    assert_eq!(kcp1.stats().timeout_retransmits, 3);
+
+    // The link is back
+    kcp2.input(&resent[0]).unwrap();
+    assert_eq!(&kcp2.recv_bytes().unwrap()[..], b"hiccup");
+    kcp2.flush().unwrap();
+    for datagram in sink2.0.borrow_mut().drain(..) {
+        kcp1.input(&datagram).unwrap();
+    }
+    assert_eq!(kcp1.wait_snd(), 0);
+    assert!(!kcp1.is_dead_link());
+}
+
+#[test]
+fn flush_inflight_segments() {
+    let sink1 = Sink::default();
+    let sink2 = Sink::default();
+    let mut kcp1 = Kcp::new(1, sink1.clone());
+    let mut kcp2 = Kcp::new(1, sink2.clone());
+    kcp1.set_nodelay(true, 10, 0, true);
+    kcp1.update(0).unwrap();
+    kcp2.update(0).unwrap();
+    assert_eq!(kcp1.inflight_segments().count(), 0);
+
+    for _ in 0..3 {
+        kcp1.send(b"ping").unwrap();
+    }
+    kcp1.flush().unwrap();
+
+    // Only the first one arrives
+    let datagram = sink1.0.borrow_mut().remove(0);
+    kcp2.input(&datagram[..kcp::KCP_OVERHEAD + 4]).unwrap();
+    kcp2.flush().unwrap();
+    for datagram in sink2.0.borrow_mut().drain(..) {
+        kcp1.input(&datagram).unwrap();
+    }
+
+    kcp1.update(300).unwrap();
+    let inflight: Vec<SegmentState> = kcp1.inflight_segments().collect();
+    assert_eq!(
+        inflight.iter().map(|s| s.sn).collect::<Vec<_>>(),
+        vec![1, 2]
+    );
+    assert!(inflight.iter().all(|s| s.xmit == 2 && s.resendts > 300));
+}
+
+#[test]
+fn flush_effective_send_window() {
+    let sink = Sink::default();
+    let mut kcp = Kcp::new(1, sink.clone());
+    kcp.update(0).unwrap();
+    assert_eq!(kcp.effective_send_window(), kcp.cwnd());
+
+    for _ in 0..10 {
+        kcp.send(b"ping").unwrap();
+    }
+    kcp.flush().unwrap();
+    assert_eq!(
+        pushes(&take_segments(&sink)).len() as u16,
+        kcp.effective_send_window()
+    );
+
+    // Limited by the windows alone
+    kcp.set_nodelay(false, 100, 0, true);
+    assert_eq!(kcp.effective_send_window(), kcp.snd_wnd());
+    kcp.set_wndsize(4, 128);
+    assert_eq!(kcp.effective_send_window(), 4);
+    kcp.set_wndsize(256, 128);
+    assert_eq!(kcp.effective_send_window(), kcp.rmt_wnd());
+}
+
+#[test]
+fn flush_retransmit() {
+    let sink1 = Sink::default();
+    let sink2 = Sink::default();
+    let mut kcp1 = Kcp::new(1, sink1.clone());
+    let mut kcp2 = Kcp::new(1, sink2.clone());
+    kcp1.set_nodelay(true, 10, 0, true);
+    kcp1.set_maximum_resend_times(4);
+    assert!(matches!(kcp1.retransmit(0), Err(kcp::Error::NeedUpdate)));
+    kcp1.update(0).unwrap();
+    kcp2.update(0).unwrap();
+
+    for _ in 0..3 {
+        kcp1.send(b"ping").unwrap();
+    }
+    assert!(!kcp1.retransmit(0).unwrap());
+    kcp1.flush().unwrap();
+    let sent = sink1.0.borrow().clone();
+    assert_eq!(pushes(&take_segments(&sink1)), vec![0, 1, 2]);
+
+    // Resent at once as a fast resend, the RTO left alone, again and again until the link is dead
+    let rto = kcp1.inflight_segments().nth(1).unwrap().rto;
+    for xmit in 2..=4 {
+        assert!(kcp1.retransmit(1).unwrap());
+        assert_eq!(kcp1.check(0), 0);
+        kcp1.flush().unwrap();
+        assert_eq!(pushes(&take_segments(&sink1)), vec![1]);
+        assert_eq!(kcp1.stats().fast_retransmits, xmit - 1);
+        assert_eq!(kcp1.stats().timeout_retransmits, 0);
+        assert_eq!(kcp1.inflight_segments().nth(1).unwrap().rto, rto);
+        assert_eq!(kcp1.is_dead_link(), xmit == 4);
+    }
+    kcp1.flush().unwrap();
+    assert!(take_segments(&sink1).is_empty());
+    assert!(!kcp1.retransmit(3).unwrap());
+
+    for datagram in &sent {
+        kcp2.input(datagram).unwrap();
+    }
+    kcp2.flush().unwrap();
+    for datagram in sink2.0.borrow_mut().drain(..) {
+        kcp1.input(&datagram).unwrap();
+    }
+    assert!(!kcp1.retransmit(0).unwrap());
+    assert!(!kcp1.retransmit(1).unwrap());
+}
+
+#[test]
+fn flush_stats() {
+    let sink1 = Sink::default();
+    let sink2 = Sink::default();
+    let mut kcp1 = Kcp::new(1, sink1.clone());
+    let mut kcp2 = Kcp::new(1, sink2.clone());
+    kcp1.set_mtu(50).unwrap();
+    kcp1.set_nodelay(true, 10, 1, true);
+    kcp1.update(0).unwrap();
+    kcp2.update(0).unwrap();
+
+    for _ in 0..3 {
+        kcp1.send(b"ping").unwrap();
+    }
+    kcp1.flush().unwrap();
+
+    // The first one is lost
+    let datagrams: Vec<Vec<u8>> = sink1.0.borrow_mut().drain(..).collect();
+    assert_eq!(datagrams.len(), 3);
+    for datagram in &datagrams[1..] {
+        kcp2.input(datagram).unwrap();
+    }
+    kcp2.flush().unwrap();
+    for datagram in sink2.0.borrow_mut().drain(..) {
+        kcp1.input(&datagram).unwrap();
+    }
+
+    // Fast resend, then timeout as it is lost again
+    kcp1.flush().unwrap();
+    kcp1.update(1000).unwrap();
+    assert_eq!(
+        kcp1.stats(),
+        Stats {
+            segments_sent: 5,
+            timeout_retransmits: 1,
+            fast_retransmits: 1,
+            acks_received: 2,
+            pushes_received: 0,
+            duplicate_pushes_received: 0,
+            stale_pushes_received: 0,
+            srtt: kcp1.rx_srtt(),
+        }
+    );
+    assert_eq!(kcp2.stats().pushes_received, 2);
+    assert_eq!(kcp2.stats().acks_received, 0);
+
+    kcp1.reset_stats();
+    assert_eq!(
+        kcp1.stats(),
+        Stats {
+            srtt: kcp1.rx_srtt(),
+            ..Stats::default()
+        }
+    );
+}
+
+/// Fixed congestion window, records the losses
+struct FixedCc(u16, Arc<Mutex<Vec<Retransmit>>>);
+
+impl CcAlgo for FixedCc {
+    fn on_ack(&mut self, _acked: u32, _state: &CongestionState) {}
+
+    fn on_loss(&mut self, loss: Retransmit, _state: &CongestionState) {
+        self.1.lock().unwrap().push(loss);
+    }
+
+    fn cwnd(&self) -> u16 {
+        self.0
+    }
+}
+
+#[test]
+fn flush_loss_handler() {
+    let sink1 = Sink::default();
+    let sink2 = Sink::default();
+    let mut kcp1 = Kcp::new(1, sink1.clone());
+    let mut kcp2 = Kcp::new(1, sink2.clone());
+    kcp1.set_mtu(50).unwrap();
+    kcp1.set_nodelay(true, 10, 1, true);
+    let events = Arc::new(Mutex::new(Vec::new()));
+    let handler_events = events.clone();
+    kcp1.set_loss_handler(move |event| handler_events.lock().unwrap().push(event));
+    kcp1.update(0).unwrap();
+    kcp2.update(0).unwrap();
+
+    for _ in 0..3 {
+        kcp1.send(b"ping").unwrap();
+    }
+    kcp1.flush().unwrap();
+    assert!(events.lock().unwrap().is_empty());
+
+    // The first one is lost
+    let datagrams: Vec<Vec<u8>> = sink1.0.borrow_mut().drain(..).collect();
+    for datagram in &datagrams[1..] {
+        kcp2.input(datagram).unwrap();
+    }
+    kcp2.flush().unwrap();
+    for datagram in sink2.0.borrow_mut().drain(..) {
+        kcp1.input(&datagram).unwrap();
+    }
+
+    kcp1.flush().unwrap();
+    assert_eq!(
+        events.lock().unwrap().drain(..).collect::<Vec<_>>(),
+        vec![LossEvent::FastResend {
+            sn: 0,
+            cwnd: kcp1.cwnd(),
+            ssthresh: kcp1.ssthresh(),
+        }]
+    );
+
+    kcp1.update(1000).unwrap();
+    assert_eq!(
+        events.lock().unwrap().drain(..).collect::<Vec<_>>(),
+        vec![LossEvent::Timeout {
+            sn: 0,
+            cwnd: 1,
+            ssthresh: kcp1.ssthresh(),
+        }]
+    );
+    assert_eq!(kcp1.cwnd(), 1);
+}
+
+#[test]
+fn flush_congestion_control() {
+    // Window after the only segment sent timed out
+    for (cc, cwnd) in [
+        (CongestionControl::Classic, 1),
+        (CongestionControl::LossBased, 2),
+    ] {
+        let mut kcp = Kcp::new(1, Sink::default());
+        kcp.set_congestion(cc);
+        assert_eq!(kcp.congestion(), cc);
+        kcp.update(0).unwrap();
+        kcp.send(b"ping").unwrap();
+        kcp.flush().unwrap();
+        kcp.update(1000).unwrap();
+        assert_eq!((kcp.cwnd(), kcp.ssthresh()), (cwnd, 2));
+    }
+
+    let sink = Sink::default();
+    let losses = Arc::new(Mutex::new(Vec::new()));
+    let mut kcp = Kcp::new(1, sink.clone());
+    kcp.set_mtu(50).unwrap();
+    kcp.set_congestion_algo(FixedCc(3, losses.clone()));
+    kcp.update(0).unwrap();
+    for _ in 0..5 {
+        kcp.send(b"ping").unwrap();
+    }
+    kcp.flush().unwrap();
+    assert_eq!(kcp.cwnd(), 3);
+    assert_eq!(take_segments(&sink).len(), 3);
+    kcp.update(1000).unwrap();
+    assert_eq!(*losses.lock().unwrap(), vec![Retransmit::Timeout]);
+
+    // Only limited by the windows
+    kcp.set_congestion(CongestionControl::None);
+    assert_eq!(kcp.congestion(), CongestionControl::None);
+    kcp.flush().unwrap();
+    assert_eq!(
+        take_segments(&sink)
+            .iter()
+            .filter(|&&(cmd, sn, _)| cmd == KCP_CMD_PUSH && sn >= 3)
+            .count(),
+        2
+    );
+}
+
+#[test]
+fn flush_fastack_rto() {
+    let sink1 = Sink::default();
+    let sink2 = Sink::default();
+    let mut kcp1 = Kcp::new(1, sink1.clone());
+    let mut kcp2 = Kcp::new(1, sink2.clone());
+    kcp1.set_mtu(50).unwrap();
+    kcp1.set_nodelay(true, 10, 1, true);
+    kcp1.set_fastack_rto(15);
+    assert_eq!(kcp1.fastack_rto(), 15);
+    kcp1.update(0).unwrap();
+    kcp2.update(0).unwrap();
+
+    for _ in 0..3 {
+        kcp1.send(b"ping").unwrap();
+    }
+    kcp1.flush().unwrap();
+
+    // sn 0 is lost, fast resent with the shorter timer
+    let datagrams: Vec<Vec<u8>> = sink1.0.borrow_mut().drain(..).collect();
+    kcp2.input(&datagrams[1]).unwrap();
+    kcp2.flush().unwrap();
+    for datagram in sink2.0.borrow_mut().drain(..) {
+        kcp1.input(&datagram).unwrap();
+    }
+    kcp1.flush().unwrap();
+
+    let resent = kcp1.inflight_segments().next().unwrap();
+    assert_eq!((resent.sn, resent.xmit), (0, 2));
+    assert_eq!(resent.resendts, 15);
+    assert!(resent.rto > 15);
+}
+
+#[test]
+fn flush_keepalive() {
+    let sink1 = Sink::default();
+    let sink2 = Sink::default();
+    let mut kcp1 = Kcp::new(1, sink1.clone());
+    let mut kcp2 = Kcp::new(1, sink2.clone());
+    kcp1.set_keepalive(1000, 0);
+    kcp1.update(0).unwrap();
+    kcp2.update(0).unwrap();
+
+    kcp1.update(500).unwrap();
+    assert!(take_segments(&sink1).is_empty());
+
+    // Idle for an interval, the remote without keepalive answers the probe
+    kcp1.update(1000).unwrap();
+    let datagrams: Vec<Vec<u8>> = sink1.0.borrow_mut().drain(..).collect();
+    assert_eq!(datagrams.len(), 1);
+    assert_eq!(kcp::get_cmd(&datagrams[0]).unwrap(), KCP_CMD_WASK);
+    kcp2.input(&datagrams[0]).unwrap();
+    kcp2.update(1000).unwrap();
+    let datagrams: Vec<Vec<u8>> = sink2.0.borrow_mut().drain(..).collect();
+    assert_eq!(datagrams.len(), 1);
+    assert_eq!(kcp::get_cmd(&datagrams[0]).unwrap(), KCP_CMD_WINS);
+
+    kcp1.update(1100).unwrap();
+    kcp1.input(&datagrams[0]).unwrap();
+    assert_eq!(kcp1.last_recv_time(), 1100);
+    assert_eq!(kcp1.stats().segments_sent, 0);
+
+    // Data being sent counts as traffic
+    kcp1.send(b"data").unwrap();
+    kcp1.update(1800).unwrap();
+    assert_eq!(pushes(&take_segments(&sink1)), vec![0]);
+    kcp1.update(2000).unwrap();
+    assert!(take_segments(&sink1).is_empty());
+}
+
+#[test]
+fn flush_keepalive_dead() {
+    let sink = Sink::default();
+    let mut kcp = Kcp::new(1, sink.clone());
+    let dead = Arc::new(Mutex::new(0));
+    let counter = dead.clone();
+    kcp.set_dead_link_handler(move |_| *counter.lock().unwrap() += 1);
+    kcp.set_keepalive(100, 3);
+    kcp.update(0).unwrap();
+
+    kcp.update(200).unwrap();
+    assert!(!kcp.is_dead_link());
+    kcp.update(300).unwrap();
+    assert!(kcp.is_dead_link());
+    kcp.update(400).unwrap();
+    assert_eq!(*dead.lock().unwrap(), 1);
+    assert_eq!(kcp.last_recv_time(), 0);
+}
+
+#[test]
+fn flush_reserved() {
+    let sink = Sink::default();
+    let mut kcp = Kcp::new(1, sink.clone());
+    kcp.set_mtu(100).unwrap();
+    kcp.set_nodelay(true, 10, 0, true);
+    kcp.set_reserved(20).unwrap();
+    assert_eq!(kcp.reserved(), 20);
+    assert_eq!(kcp.mss(), 100 - kcp::KCP_OVERHEAD - 20);
+    assert!(matches!(
+        kcp.set_reserved(100 - kcp::KCP_OVERHEAD),
+        Err(kcp::Error::InvalidReserved(_))
+    ));
+    kcp.set_reserved(30).unwrap();
+    assert!(matches!(
+        kcp.set_mtu(kcp::KCP_OVERHEAD + 30),
+        Err(kcp::Error::InvalidMtu(_))
+    ));
+    assert_eq!(kcp.mtu(), 100);
+    kcp.set_reserved(20).unwrap();
+    kcp.update(0).unwrap();
+
+    // Two segments would fit the MTU, but not what is left of it
+    for _ in 0..3 {
+        kcp.send(&[0; 20]).unwrap();
+    }
+    kcp.flush().unwrap();
+    let sizes: Vec<usize> = sink.0.borrow().iter().map(|d| d.len()).collect();
+    assert_eq!(sizes, vec![kcp::KCP_OVERHEAD + 20; 3]);
+}
+
+#[test]
+fn flush_recv_side() {
+    let sink1 = Sink::default();
+    let sink2 = Sink::default();
+    let mut kcp1 = Kcp::new(1, sink1.clone());
+    let mut kcp2 = Kcp::new(1, sink2.clone());
+    kcp1.set_nodelay(true, 10, 0, true);
+    kcp1.update(0).unwrap();
+    kcp2.update(0).unwrap();
+
+    kcp1.send(b"data").unwrap();
+    kcp1.probe_now();
+    kcp1.flush().unwrap();
+    for datagram in sink1.0.borrow_mut().drain(..) {
+        kcp2.input(&datagram).unwrap();
+    }
+
+    // The data of the receiver stays queued
+    kcp2.send(b"reply").unwrap();
+    kcp2.flush_recv_side().unwrap();
+    let segments = take_segments(&sink2);
+    assert_eq!(segments, vec![(KCP_CMD_ACK, 0, 0), (KCP_CMD_WINS, 0, 0)]);
+    assert!(!kcp2.is_probing());
+    assert_eq!(kcp2.wait_snd(), 1);
+    assert_eq!(kcp2.stats().segments_sent, 0);
+
+    kcp2.flush().unwrap();
+    assert_eq!(pushes(&take_segments(&sink2)), vec![0]);
+}
+
+#[test]
+fn flush_tell_window() {
+    let sink1 = Sink::default();
+    let sink2 = Sink::default();
+    let mut kcp1 = Kcp::new(1, sink1.clone());
+    let mut kcp2 = Kcp::new(1, sink2.clone());
+    kcp1.set_nodelay(true, 10, 0, true);
+    kcp1.update(0).unwrap();
+    kcp2.update(0).unwrap();
+
+    for i in 0..4u8 {
+        kcp1.send(&[i]).unwrap();
+    }
+    kcp1.flush().unwrap();
+    for datagram in sink1.0.borrow_mut().drain(..) {
+        kcp2.input(&datagram).unwrap();
+    }
+    kcp2.flush_ack().unwrap();
+    let acks = sink2.0.borrow_mut().split_off(0);
+    assert_eq!(kcp::get_wnd(&acks[0]).unwrap(), 124);
+
+    // Advertised after the application has read, with the window of that time
+    kcp2.recv_all();
+    kcp2.tell_window();
+    assert!(kcp2.is_probing());
+    kcp2.flush_probe_only().unwrap();
+    let datagrams = sink2.0.borrow_mut().split_off(0);
+    assert_eq!(datagrams.len(), 1);
+    assert_eq!(kcp::get_cmd(&datagrams[0]).unwrap(), KCP_CMD_WINS);
+    assert_eq!(kcp::get_wnd(&datagrams[0]).unwrap(), 128);
+    assert!(!kcp2.is_probing());
+
+    kcp1.input(&datagrams[0]).unwrap();
+    assert_eq!(kcp1.rmt_wnd(), 128);
+}
+
+#[test]
+fn flush_notify_window_update() {
+    let sink1 = Sink::default();
+    let sink2 = Sink::default();
+    let mut kcp1 = Kcp::new(1, sink1.clone());
+    let mut kcp2 = Kcp::new(1, sink2.clone());
+    kcp1.set_nodelay(true, 10, 0, true);
+    kcp1.update(0).unwrap();
+    kcp2.update(0).unwrap();
+    assert!(!kcp2.notify_window_update());
+
+    for i in 0..4u8 {
+        kcp1.send(&[i]).unwrap();
+    }
+    kcp1.flush().unwrap();
+    for datagram in sink1.0.borrow_mut().drain(..) {
+        kcp2.input(&datagram).unwrap();
+    }
+    kcp2.flush_ack().unwrap();
+    take_segments(&sink2);
+    assert!(!kcp2.notify_window_update());
+
+    // A read below the threshold isn't told by itself
+    kcp2.recv_bytes().unwrap();
+    assert!(!kcp2.is_probing());
+    assert!(kcp2.notify_window_update());
+    assert!(kcp2.is_probing());
+    kcp2.flush().unwrap();
+    assert_eq!(take_segments(&sink2), vec![(KCP_CMD_WINS, 0, 0)]);
+    assert!(!kcp2.notify_window_update());
+}
+
+#[test]
+fn flush_initial_rto() {
+    // One way delay of 300ms, the ACK of the first segment comes back at 600
+    let run = |initial_rto: Option<u32>| {
+        let sink1 = Sink::default();
+        let sink2 = Sink::default();
+        let mut kcp1 = Kcp::new(1, sink1.clone());
+        let mut kcp2 = Kcp::new(1, sink2.clone());
+        if let Some(rto) = initial_rto {
+            kcp1.set_initial_rto(rto);
+        }
+        kcp1.update(0).unwrap();
+        kcp2.update(0).unwrap();
+
+        kcp1.send(b"ping").unwrap();
+        kcp1.flush().unwrap();
+        let first = sink1.0.borrow_mut().remove(0);
+        for current in (10..600).step_by(10) {
+            kcp1.update(current).unwrap();
+        }
+        kcp2.update(300).unwrap();
+        kcp2.input(&first).unwrap();
+        kcp2.flush().unwrap();
+        kcp1.update(600).unwrap();
+        for datagram in sink2.0.borrow_mut().drain(..) {
+            kcp1.input(&datagram).unwrap();
+        }
+        assert_eq!(kcp1.wait_snd(), 0);
+        kcp1.stats().timeout_retransmits
+    };
+
+    assert!(run(None) > 0);
+    assert_eq!(run(Some(1000)), 0);
+}
+
+#[test]
+fn flush_rto_backoff_factor() {
+    let sink = Sink::default();
+    let mut kcp = Kcp::new(1, sink.clone());
+    assert_eq!(kcp.rto_backoff(), 0.0);
+    kcp.set_rto_backoff(10.0);
+    assert_eq!(kcp.rto_backoff(), 4.0);
+    kcp.set_rto_backoff(1.5);
+    kcp.set_nodelay(false, 100, 0, true);
+
+    kcp.send(b"stuck").unwrap();
+    kcp.update(0).unwrap();
+    assert_eq!(kcp.current_rto_for(0), Some(200));
+
+    kcp.update(300).unwrap();
+    assert_eq!(pushes(&take_segments(&sink)), vec![0, 0]);
+    assert_eq!(kcp.current_rto_for(0), Some(300));
+
+    // No backoff at all
+    kcp.set_rto_backoff(0.5);
+    assert_eq!(kcp.rto_backoff(), 1.0);
+    kcp.update(700).unwrap();
+    assert_eq!(kcp.current_rto_for(0), Some(300));
+}
+
+#[test]
+fn flush_has_pending_output() {
+    let sink1 = Sink::default();
+    let sink2 = Sink::default();
+    let mut kcp1 = Kcp::new(1, sink1.clone());
+    let mut kcp2 = Kcp::new(1, sink2.clone());
+    kcp1.set_nodelay(true, 10, 0, true);
+    kcp1.set_keepalive(1000, 0);
+    assert!(!kcp1.has_pending_output());
+
+    kcp1.update(0).unwrap();
+    kcp2.update(0).unwrap();
+    kcp1.send(b"data").unwrap();
+    assert!(kcp1.has_pending_output());
+    kcp1.flush().unwrap();
+    assert!(!kcp1.has_pending_output());
+    for datagram in sink1.0.borrow_mut().drain(..) {
+        kcp2.input(&datagram).unwrap();
+    }
+    kcp2.flush().unwrap();
+    for datagram in sink2.0.borrow_mut().drain(..) {
+        kcp1.input(&datagram).unwrap();
+    }
+
+    // A keepalive is due
+    kcp1.update(990).unwrap();
+    assert!(!kcp1.has_pending_output());
+    assert!(kcp1.would_flush(1000));
+    assert_eq!(kcp1.pending_output_size(1000), kcp::KCP_OVERHEAD);
+    kcp1.update(1000).unwrap();
+    assert_eq!(take_segments(&sink1), vec![(KCP_CMD_WASK, 0, 0)]);
+    assert!(!kcp1.has_pending_output());
+}
+
+#[test]
+fn flush_bytes_in_flight() {
+    let sink1 = Sink::default();
+    let sink2 = Sink::default();
+    let mut kcp1 = Kcp::new(1, sink1.clone());
+    let mut kcp2 = Kcp::new(1, sink2.clone());
+    kcp1.update(0).unwrap();
+    kcp2.update(0).unwrap();
+
+    for len in 1..=10 {
+        kcp1.send(&vec![0u8; len * 10]).unwrap();
+    }
+    assert_eq!(kcp1.bytes_in_flight(), 0);
+    assert_eq!(kcp1.bytes_queued(), 550);
+
+    // Only as many as the congestion window allows are sent
+    kcp1.flush().unwrap();
+    let sent = kcp1.snd_buf_len();
+    assert!(sent > 0 && sent < 10);
+    let in_flight: usize = (1..=sent).map(|len| len * 10).sum();
+    assert_eq!(kcp1.bytes_in_flight(), in_flight);
+    assert_eq!(kcp1.bytes_queued(), 550 - in_flight);
+
+    for datagram in sink1.0.borrow_mut().drain(..) {
+        kcp2.input(&datagram).unwrap();
+    }
+    kcp2.flush().unwrap();
+    for datagram in sink2.0.borrow_mut().drain(..) {
+        kcp1.input(&datagram).unwrap();
+    }
+    assert_eq!(kcp1.bytes_in_flight(), 0);
+}
+
+#[test]
+fn flush_not_updated() {
+    let sink1 = Sink::default();
+    let sink2 = Sink::default();
+    let mut kcp1 = Kcp::new(1, sink1.clone());
+    let mut kcp2 = Kcp::new(1, sink2.clone());
+    kcp2.set_nodelay(false, 100, 0, true);
+    kcp1.update(0).unwrap();
+    kcp1.send(b"ping").unwrap();
+    kcp1.flush().unwrap();
+
+    // Queued and received, but nothing written before the first update
+    assert!(!kcp2.is_updated());
+    kcp2.send(b"pong").unwrap();
+    for datagram in sink1.0.borrow_mut().drain(..) {
+        kcp2.input(&datagram).unwrap();
+    }
+    assert!(matches!(kcp2.flush(), Err(kcp::Error::NeedUpdate)));
+    assert!(take_segments(&sink2).is_empty());
+
+    kcp2.update(0).unwrap();
+    assert!(kcp2.is_updated());
+    assert_eq!(
+        take_segments(&sink2),
+        vec![(KCP_CMD_ACK, 0, 0), (KCP_CMD_PUSH, 0, 4)]
+    );
+}
+
+#[test]
+fn flush_summary() {
+    let sink = Sink::default();
+    let mut kcp = Kcp::new(1, sink.clone());
+    kcp.set_nodelay(false, 100, 0, true);
+    kcp.update(0).unwrap();
+    kcp.send(b"secret payload").unwrap();
+    kcp.send(b"another one").unwrap();
+    kcp.set_max_burst(1);
+    kcp.flush().unwrap();
+
+    let summary = format!("{:?}", kcp.summary());
+    assert_eq!(
+        summary,
+        "Kcp { conv: 1, state: Active, snd_wnd: 32, rcv_wnd: 128, rmt_wnd: 128, cwnd: 1, \
+         snd_una: 0, snd_nxt: 1, rcv_nxt: 0, snd_queue: 1, snd_buf: 1, rcv_queue: 0, \
+         rcv_buf: 0, srtt: 0, rttval: 0, rto: 200, acks: 0, .. }"
+    );
+    assert!(!summary.contains("secret"));
+}
+
+#[test]
+fn flush_limited() {
+    let sink = Sink::default();
+    let mut kcp = Kcp::new(1, sink.clone());
+    kcp.set_nodelay(true, 5000, 0, true);
+    kcp.update(0).unwrap();
+    for i in 0..10u8 {
+        kcp.send(&[i]).unwrap();
+    }
+
+    kcp.flush_limited(3).unwrap();
+    assert_eq!(pushes(&take_segments(&sink)), vec![0, 1, 2]);
+    kcp.flush_limited(3).unwrap();
+    assert_eq!(pushes(&take_segments(&sink)), vec![3, 4, 5]);
+    assert_eq!(kcp.stats().segments_sent, 6);
+
+    // Timed out segments deferred by the cap are still due, retransmissions before new ones
+    kcp.update(1000).unwrap();
+    kcp.flush_limited(4).unwrap();
+    assert_eq!(pushes(&take_segments(&sink)), vec![0, 1, 2, 3]);
+    assert_eq!(kcp.stats().timeout_retransmits, 4);
+    kcp.flush_limited(4).unwrap();
+    assert_eq!(pushes(&take_segments(&sink)), vec![4, 5, 6, 7]);
+    assert_eq!(kcp.stats().timeout_retransmits, 6);
+
+    kcp.flush_limited(0).unwrap();
+    assert_eq!(pushes(&take_segments(&sink)), vec![8, 9]);
+    assert_eq!(kcp.stats().segments_sent, 16);
+}
+
+#[test]
+fn flush_idle_timeout() {
+    let sink1 = Sink::default();
+    let sink2 = Sink::default();
+    let mut kcp1 = Kcp::new(7, sink1.clone());
+    let mut kcp2 = Kcp::new(7, sink2.clone());
+    kcp1.set_idle_timeout(1000);
+    assert_eq!(kcp1.idle_timeout(), 1000);
+
+    let dead = Arc::new(Mutex::new(Vec::new()));
+    let handler_dead = dead.clone();
+    kcp1.set_dead_link_handler(move |conv| handler_dead.lock().unwrap().push(conv));
+
+    kcp1.update(0).unwrap();
+    kcp2.update(0).unwrap();
+
+    // Kept alive by input, with nothing to send
+    kcp2.send(b"alive").unwrap();
+    kcp2.update(500).unwrap();
+    kcp1.update(800).unwrap();
+    for datagram in sink2.0.borrow_mut().drain(..) {
+        kcp1.input(&datagram).unwrap();
+    }
+    assert_eq!(kcp1.idle_time(800), 0);
+    kcp1.update(1500).unwrap();
+    assert_eq!(kcp1.state(), LinkState::Active);
+    assert_eq!(kcp1.idle_time(1500), 700);
+
+    // The remote is gone
+    kcp1.update(1800).unwrap();
+    assert_eq!(kcp1.state(), LinkState::TimedOut);
+    assert!(kcp1.is_dead_link());
+    assert_eq!(*dead.lock().unwrap(), vec![7]);
+    kcp1.update(3000).unwrap();
+    assert_eq!(*dead.lock().unwrap(), vec![7]);
+    assert!(take_segments(&sink1)
+        .iter()
+        .all(|&(cmd, ..)| cmd == KCP_CMD_ACK));
+}
+
+#[test]
+fn flush_if_due() {
+    let sink = Sink::default();
+    let mut kcp = Kcp::new(1, sink.clone());
+    kcp.set_nodelay(true, 100, 0, true);
+    assert!(kcp.flush_if_due(0).unwrap());
+    kcp.send(b"due").unwrap();
+
+    assert!(!kcp.flush_if_due(50).unwrap());
+    assert!(take_segments(&sink).is_empty());
+    assert_eq!(kcp.check(50), 50);
+
+    assert!(kcp.flush_if_due(100).unwrap());
+    assert_eq!(pushes(&take_segments(&sink)), vec![0]);
+    assert!(!kcp.flush_if_due(150).unwrap());
+    assert!(kcp.flush_if_due(200).unwrap());
+}
+
+#[test]
+fn flush_time_until() {
+    let sink = Sink::default();
+    let mut kcp = Kcp::new(1, sink.clone());
+    kcp.set_nodelay(true, 100, 0, true);
+    assert_eq!(kcp.time_until_flush(0), 0);
+    kcp.update(0).unwrap();
+    assert_eq!(kcp.time_until_flush(30), 70);
+    assert_eq!(kcp.time_until_retransmit(30), None);
+
+    kcp.send(b"timed").unwrap();
+    kcp.flush().unwrap();
+    let rto = kcp.rx_rto();
+    assert_eq!(kcp.time_until_retransmit(30), Some(rto - 30));
+    assert_eq!(kcp.check(30), 70.min(rto - 30));
+
+    kcp.update(100).unwrap();
+    assert_eq!(kcp.time_until_flush(100), 100);
+    assert_eq!(kcp.time_until_retransmit(rto + 10), Some(0));
+    assert_eq!(kcp.check(rto + 10), 0);
+}
@@ -0,0 +1,160 @@
+#![cfg(feature = "std")]
+
+extern crate kcp;
+extern crate rand;
+
+mod common;
+
+use kcp::Kcp;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+
+use common::Sink;
+
+/// Segments with plausible headers, lengths and payloads which may not agree with each other
+fn arbitrary_segments(rng: &mut StdRng) -> Vec<u8> {
+    let mut buf = Vec::new();
+    for _ in 0..rng.gen_range(1..5) {
+        let conv: u32 = if rng.gen_bool(0.9) { 1 } else { rng.gen() };
+        let cmd: u8 = if rng.gen_bool(0.9) {
+            rng.gen_range(81..91)
+        } else {
+            rng.gen()
+        };
+        let frg: u8 = if rng.gen_bool(0.5) { 0 } else { rng.gen() };
+        let wnd: u16 = rng.gen();
+        let ts: u32 = rng.gen();
+        let sn: u32 = if rng.gen_bool(0.8) {
+            rng.gen_range(0..300)
+        } else {
+            rng.gen()
+        };
+        let una: u32 = if rng.gen_bool(0.8) {
+            rng.gen_range(0..300)
+        } else {
+            rng.gen()
+        };
+        let payload: Vec<u8> = (0..rng.gen_range(0..64)).map(|_| rng.gen()).collect();
+        let len = if rng.gen_bool(0.8) {
+            payload.len() as u32
+        } else {
+            rng.gen()
+        };
+
+        buf.extend_from_slice(&conv.to_le_bytes());
+        buf.push(cmd);
+        buf.push(frg);
+        buf.extend_from_slice(&wnd.to_le_bytes());
+        buf.extend_from_slice(&ts.to_le_bytes());
+        buf.extend_from_slice(&sn.to_le_bytes());
+        buf.extend_from_slice(&una.to_le_bytes());
+        buf.extend_from_slice(&len.to_le_bytes());
+        buf.extend_from_slice(&payload);
+    }
+
+    if rng.gen_bool(0.2) {
+        let at = rng.gen_range(0..=buf.len());
+        buf.truncate(at);
+    }
+    buf
+}
+
+/// Random bytes, random segments, or a datagram of a real sender with some bits flipped
+fn arbitrary_datagram(rng: &mut StdRng, sent: &[Vec<u8>]) -> Vec<u8> {
+    match rng.gen_range(0..3) {
+        0 => (0..rng.gen_range(0..200)).map(|_| rng.gen()).collect(),
+        1 => arbitrary_segments(rng),
+        _ if sent.is_empty() => arbitrary_segments(rng),
+        _ => {
+            let mut buf = sent[rng.gen_range(0..sent.len())].clone();
+            for _ in 0..rng.gen_range(0..4) {
+                if !buf.is_empty() {
+                    let i = rng.gen_range(0..buf.len());
+                    buf[i] ^= 1 << rng.gen_range(0..8);
+                }
+            }
+            buf
+        }
+    }
+}
+
+fn fuzz(seed: u64, stream: bool) {
+    let mut rng = StdRng::seed_from_u64(seed);
+
+    let sink = Sink::default();
+    let mut sender = Kcp::new(1, sink.clone());
+    sender.set_mtu(200).unwrap();
+    sender.update(0).unwrap();
+    for i in 0..20 {
+        sender.send(&vec![i as u8; rng.gen_range(1..600)]).unwrap();
+    }
+    sender.flush().unwrap();
+    let sent = sink.0.borrow().clone();
+
+    let mut kcp = if stream {
+        Kcp::new_stream(1, Sink::default())
+    } else {
+        Kcp::new(1, Sink::default())
+    };
+    kcp.set_strict_conv(rng.gen());
+    #[cfg(feature = "sack")]
+    kcp.set_sack(true);
+    #[cfg(feature = "wscale")]
+    kcp.set_window_scale(2);
+    #[cfg(feature = "partial")]
+    kcp.set_partial_reliability(true);
+    let mut current = 0;
+    kcp.update(current).unwrap();
+
+    for _ in 0..2000 {
+        let datagram = arbitrary_datagram(&mut rng, &sent);
+        match rng.gen_range(0..4) {
+            0 | 1 => {
+                let consumed = if rng.gen() {
+                    kcp.input(&datagram)
+                } else {
+                    kcp.input_lenient(&datagram)
+                };
+                if let Ok(n) = consumed {
+                    assert!(n <= datagram.len(), "consumed {} of {}", n, datagram.len());
+                }
+            }
+            // Buffered across calls, a bogus length drops what is buffered
+            2 => {
+                let _ = kcp.input_framed(&datagram);
+            }
+            _ => {
+                let (head, tail) = datagram.split_at(datagram.len() / 2);
+                assert!(kcp.input_many(&[head, tail]).unwrap() <= 2);
+            }
+        }
+
+        // Something in flight for ACKs to hit
+        if kcp.wait_snd() < 64 {
+            if stream {
+                let _ = kcp.send(&datagram);
+            } else {
+                let _ = kcp.send_with_deadline(&datagram, current + rng.gen_range(0..500));
+            }
+        }
+
+        current += rng.gen_range(0..50);
+        kcp.update(current).unwrap();
+        let _ = kcp.peeksize();
+        kcp.recv_all();
+    }
+}
+
+#[test]
+fn fuzz_input() {
+    for seed in 0..8 {
+        fuzz(seed, false);
+    }
+}
+
+#[test]
+fn fuzz_input_stream() {
+    for seed in 0..8 {
+        fuzz(seed, true);
+    }
+}
@@ -0,0 +1,135 @@
+#![cfg(all(feature = "std", feature = "handshake"))]
+
+extern crate kcp;
+
+mod common;
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+use kcp::{Kcp, LinkState};
+
+use common::Sink;
+
+const KCP_CMD_PUSH: u8 = 81;
+const KCP_CMD_SYN: u8 = 89;
+const KCP_CMD_SYNACK: u8 = 90;
+
+fn client() -> (Kcp<Sink>, Sink) {
+    let sink = Sink::default();
+    let mut kcp = Kcp::new(7, sink.clone());
+    kcp.set_nodelay(true, 10, 0, true);
+    kcp.connect();
+    (kcp, sink)
+}
+
+#[test]
+fn handshake_connect() {
+    let (mut client, client_sink) = client();
+    assert_eq!(client.state(), LinkState::Connecting);
+
+    // Data is held back until accepted
+    client.send(b"hello").unwrap();
+    client.update(0).unwrap();
+    let syn = client_sink.take();
+    assert_eq!(syn.len(), 1);
+    assert_eq!(syn[0].len(), kcp::KCP_OVERHEAD);
+    assert_eq!(kcp::get_cmd(&syn[0]).unwrap(), KCP_CMD_SYN);
+    assert!(kcp::is_syn(&syn[0]));
+
+    let server_sink = Sink::default();
+    let mut server = Kcp::new(0, server_sink.clone());
+    server.accept(&syn[0]).unwrap();
+    assert_eq!(server.conv(), 7);
+    assert_eq!(server.state(), LinkState::Active);
+    server.update(0).unwrap();
+    let synack = server_sink.take();
+    assert_eq!(kcp::get_cmd(&synack[0]).unwrap(), KCP_CMD_SYNACK);
+
+    client.input(&synack[0]).unwrap();
+    assert_eq!(client.state(), LinkState::Active);
+    client.update(10).unwrap();
+    let data = client_sink.take();
+    assert_eq!(kcp::get_cmd(&data[0]).unwrap(), KCP_CMD_PUSH);
+    server.input(&data[0]).unwrap();
+    assert_eq!(&server.recv_bytes().unwrap()[..], b"hello");
+}
+
+#[cfg(feature = "endian")]
+#[test]
+fn handshake_big_endian() {
+    let (mut client, client_sink) = client();
+    client.set_endianness(kcp::Endian::Big);
+    client.update(0).unwrap();
+    let syn = client_sink.take().remove(0);
+    assert!(kcp::is_syn(&syn));
+
+    let server_sink = Sink::default();
+    let mut server = Kcp::new(0, server_sink.clone());
+    server.set_endianness(kcp::Endian::Big);
+    server.accept(&syn).unwrap();
+    assert_eq!(server.conv(), 7);
+    server.update(0).unwrap();
+
+    client.input(&server_sink.take()[0]).unwrap();
+    assert_eq!(client.state(), LinkState::Active);
+}
+
+#[test]
+fn handshake_stray() {
+    let sink = Sink::default();
+    let mut kcp = Kcp::new(7, sink.clone());
+    kcp.update(0).unwrap();
+    kcp.send(b"stray").unwrap();
+    kcp.flush().unwrap();
+    let push = sink.take();
+    assert!(!kcp::is_syn(&push[0]));
+    assert!(!kcp::is_syn(&[0u8; 4]));
+
+    let mut server = Kcp::new(0, Sink::default());
+    assert!(matches!(server.accept(&push[0]), Err(kcp::Error::NotSyn)));
+    assert_eq!(server.conv(), 0);
+    assert!(server.recv_bytes().is_err());
+}
+
+#[test]
+fn handshake_retry() {
+    let (mut client, client_sink) = client();
+    client.update(0).unwrap();
+    let syn = client_sink.take();
+
+    // The answer is lost, the request is repeated and answered again
+    let server_sink = Sink::default();
+    let mut server = Kcp::new(0, server_sink.clone());
+    server.accept(&syn[0]).unwrap();
+    server.update(0).unwrap();
+    server_sink.take();
+
+    client.update(100).unwrap();
+    assert!(client_sink.take().is_empty());
+    client.update(1000).unwrap();
+    let syn = client_sink.take();
+    assert_eq!(kcp::get_cmd(&syn[0]).unwrap(), KCP_CMD_SYN);
+    server.input(&syn[0]).unwrap();
+    server.update(1000).unwrap();
+    client.input(&server_sink.take()[0]).unwrap();
+    assert_eq!(client.state(), LinkState::Active);
+}
+
+#[test]
+fn handshake_not_accepted() {
+    let (mut client, client_sink) = client();
+    client.set_maximum_resend_times(3);
+    let dead = Arc::new(AtomicBool::new(false));
+    let flag = dead.clone();
+    client.set_dead_link_handler(move |_| flag.store(true, Ordering::SeqCst));
+
+    let mut requests = 0;
+    for current in (0..10000).step_by(10) {
+        client.update(current).unwrap();
+        requests += client_sink.take().len();
+    }
+    assert_eq!(requests, 3);
+    assert_eq!(client.state(), LinkState::DeadLink);
+    assert!(dead.load(Ordering::SeqCst));
+}
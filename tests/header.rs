@@ -0,0 +1,97 @@
+#![cfg(feature = "std")]
+
+extern crate kcp;
+
+use std::cell::RefCell;
+use std::io::{self, Write};
+use std::rc::Rc;
+
+use kcp::{Error, Kcp, SegmentHeader, KCP_OVERHEAD};
+
+const KCP_CMD_PUSH: u8 = 81;
+
+#[derive(Clone, Default)]
+struct Sink(Rc<RefCell<Vec<u8>>>);
+
+impl Write for Sink {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.borrow_mut().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[test]
+fn header_parse() {
+    let sink = Sink::default();
+    let mut kcp = Kcp::new(9, sink.clone());
+    kcp.set_mtu(100).unwrap();
+    kcp.update(30).unwrap();
+    kcp.send(&[7u8; 100]).unwrap();
+    kcp.flush().unwrap();
+
+    let datagram = sink.0.borrow().clone();
+    let header = kcp::parse_header(&datagram).unwrap();
+    assert_eq!(
+        header,
+        SegmentHeader {
+            conv: 9,
+            cmd: KCP_CMD_PUSH,
+            frg: 1,
+            wnd: 128,
+            ts: 30,
+            sn: 0,
+            una: 0,
+            len: 76,
+        }
+    );
+    assert_eq!(kcp::try_get_conv(&datagram).unwrap(), header.conv);
+    assert_eq!(kcp::get_cmd(&datagram).unwrap(), header.cmd);
+    assert_eq!(kcp::get_frg(&datagram).unwrap(), header.frg);
+    assert_eq!(kcp::get_wnd(&datagram).unwrap(), header.wnd);
+    assert_eq!(kcp::get_ts(&datagram).unwrap(), header.ts);
+    assert_eq!(kcp::try_get_sn(&datagram).unwrap(), header.sn);
+    assert_eq!(kcp::get_una(&datagram).unwrap(), header.una);
+}
+
+#[test]
+fn header_too_short() {
+    let buf = [0u8; KCP_OVERHEAD - 1];
+    assert!(matches!(
+        kcp::parse_header(&buf),
+        Err(Error::InvalidSegmentSize(23))
+    ));
+    assert!(matches!(
+        kcp::try_get_conv(&buf),
+        Err(Error::InvalidSegmentSize(23))
+    ));
+    assert!(kcp::try_get_sn(&[]).is_err());
+    assert!(kcp::get_cmd(&[]).is_err());
+}
+
+#[test]
+fn header_set_conv() {
+    let mut datagram = [0u8; KCP_OVERHEAD];
+    kcp::try_set_conv(&mut datagram, 5).unwrap();
+    assert_eq!(kcp::try_get_conv(&datagram).unwrap(), 5);
+    assert_eq!(kcp::try_get_conv(&datagram).unwrap(), 5);
+
+    // Short packets are errors, not panics
+    let mut short = [0u8; 3];
+    assert!(matches!(
+        kcp::try_set_conv(&mut short, 5),
+        Err(Error::InvalidSegmentSize(3))
+    ));
+    assert!(matches!(
+        kcp::try_get_conv(&short),
+        Err(Error::InvalidSegmentSize(3))
+    ));
+    assert!(matches!(
+        kcp::try_get_sn(&short),
+        Err(Error::InvalidSegmentSize(3))
+    ));
+    assert_eq!(short, [0u8; 3]);
+}
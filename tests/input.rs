@@ -0,0 +1,1099 @@
+#![cfg(feature = "std")]
+
+extern crate bytes;
+extern crate kcp;
+
+use std::cell::RefCell;
+use std::io::{self, Write};
+use std::rc::Rc;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use bytes::Buf;
+use kcp::Kcp;
+
+const KCP_CMD_PUSH: u8 = 81;
+const KCP_CMD_WINS: u8 = 84;
+
+/// Writes everything into one byte stream
+#[derive(Clone, Default)]
+struct Stream(Rc<RefCell<Vec<u8>>>);
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.borrow_mut().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// A datagram of one PUSH segment of conv 1 and a single fragment
+fn push(sn: u32, data: &[u8]) -> Vec<u8> {
+    let mut datagram = Vec::new();
+    datagram.extend_from_slice(&1u32.to_le_bytes());
+    datagram.extend_from_slice(&[KCP_CMD_PUSH, 0]);
+    datagram.extend_from_slice(&128u16.to_le_bytes());
+    datagram.extend_from_slice(&0u32.to_le_bytes()); // ts
+    datagram.extend_from_slice(&sn.to_le_bytes());
+    datagram.extend_from_slice(&0u32.to_le_bytes()); // una
+    datagram.extend_from_slice(&(data.len() as u32).to_le_bytes());
+    datagram.extend_from_slice(data);
+    datagram
+}
+
+#[test]
+fn input_framed_oversized() {
+    let mut kcp = Kcp::new(1, Stream::default());
+    kcp.update(0).unwrap();
+
+    // A bogus length isn't waited for, the segment before it is still taken
+    let mut bytes = push(0, b"valid");
+    let mut bogus = push(1, b"");
+    bogus[20..24].copy_from_slice(&u32::MAX.to_le_bytes());
+    bytes.extend_from_slice(&bogus);
+    assert!(matches!(
+        kcp.input_framed(&bytes),
+        Err(kcp::Error::InvalidSegmentDataSize(1400, 0xffff_ffff))
+    ));
+    assert_eq!(&kcp.recv_bytes().unwrap()[..], b"valid");
+
+    // Nothing is left buffered
+    assert_eq!(kcp.input_framed(&push(1, b"next")).unwrap(), 28);
+    assert_eq!(&kcp.recv_bytes().unwrap()[..], b"next");
+}
+
+#[test]
+fn input_framed() {
+    let stream = Stream::default();
+    let mut kcp1 = Kcp::new(1, stream.clone());
+    let mut kcp2 = Kcp::new(1, Stream::default());
+    kcp1.set_mtu(100).unwrap();
+    kcp1.set_nodelay(true, 10, 0, true);
+    kcp1.update(0).unwrap();
+    kcp2.update(0).unwrap();
+
+    let msg: Vec<u8> = (0..250).map(|i| i as u8).collect();
+    kcp1.send(&msg).unwrap();
+    kcp1.send(b"tail").unwrap();
+    kcp1.flush().unwrap();
+
+    // Deliver in chunks not aligned with segments
+    let bytes = stream.0.borrow_mut().split_off(0);
+    let mut processed = 0;
+    for chunk in bytes.chunks(7) {
+        processed += kcp2.input_framed(chunk).unwrap();
+    }
+    assert_eq!(processed, bytes.len());
+
+    let mut buf = [0u8; 512];
+    let n = kcp2.recv(&mut buf).unwrap();
+    assert_eq!(&buf[..n], &msg[..]);
+    let n = kcp2.recv(&mut buf).unwrap();
+    assert_eq!(&buf[..n], b"tail");
+}
+
+#[test]
+fn input_buf_chained() {
+    let stream = Stream::default();
+    let mut kcp1 = Kcp::new(1, stream.clone());
+    let mut kcp2 = Kcp::new(1, Stream::default());
+    kcp1.set_mtu(100).unwrap();
+    kcp1.set_nodelay(true, 10, 0, true);
+    kcp1.update(0).unwrap();
+    kcp2.update(0).unwrap();
+
+    let msg: Vec<u8> = (0..150).map(|i| i as u8).collect();
+    kcp1.send(&msg).unwrap();
+    kcp1.flush().unwrap();
+
+    // Both datagrams in pieces splitting headers and payloads
+    let bytes = stream.0.borrow_mut().split_off(0);
+    let (first, second) = bytes.split_at(100);
+    let chained = (&first[..7]).chain(&first[7..50]).chain(&first[50..]);
+    assert_eq!(kcp2.input_buf(chained).unwrap(), 100);
+    let chained = (&second[..30]).chain(&second[30..]);
+    assert_eq!(kcp2.input_buf(chained).unwrap(), second.len());
+    assert_eq!(&kcp2.recv_bytes().unwrap()[..], &msg[..]);
+
+    assert!(matches!(
+        kcp2.input_buf(&first[..10]),
+        Err(kcp::Error::InvalidSegmentSize(10))
+    ));
+}
+
+#[test]
+fn input_duplicate_is_acked() {
+    let stream1 = Stream::default();
+    let stream2 = Stream::default();
+    let mut kcp1 = Kcp::new(1, stream1.clone());
+    let mut kcp2 = Kcp::new(1, stream2.clone());
+    kcp1.set_nodelay(true, 10, 0, true);
+    kcp1.update(0).unwrap();
+    kcp2.update(0).unwrap();
+
+    kcp1.send(b"once").unwrap();
+    kcp1.flush().unwrap();
+    let segment = stream1.0.borrow_mut().split_off(0);
+
+    kcp2.input(&segment).unwrap();
+    kcp2.flush().unwrap();
+    let mut buf = [0u8; 16];
+    assert_eq!(kcp2.recv(&mut buf).unwrap(), 4);
+    stream2.0.borrow_mut().clear();
+
+    // The ACK was lost, the retransmission is below `rcv_nxt` but still acknowledged
+    kcp2.input(&segment).unwrap();
+    kcp2.flush().unwrap();
+    let ack = stream2.0.borrow_mut().split_off(0);
+    assert_eq!(ack.len(), kcp::KCP_OVERHEAD);
+
+    kcp1.input(&ack).unwrap();
+    assert_eq!(kcp1.wait_snd(), 0);
+    assert!(kcp2.recv(&mut buf).is_err());
+}
+
+#[test]
+fn input_duplicate_stats() {
+    let mut kcp = Kcp::new(1, Stream::default());
+    kcp.update(0).unwrap();
+
+    kcp.input(&push(0, b"a")).unwrap();
+    kcp.input(&push(2, b"c")).unwrap();
+    kcp.input(&push(2, b"c")).unwrap();
+    kcp.input(&push(0, b"a")).unwrap();
+    let stats = kcp.stats();
+    assert_eq!(stats.duplicate_pushes_received, 1);
+    assert_eq!(stats.stale_pushes_received, 1);
+
+    // Moved on to the receive queue, no longer in the buffer
+    kcp.input(&push(1, b"b")).unwrap();
+    kcp.input(&push(2, b"c")).unwrap();
+    let stats = kcp.stats();
+    assert_eq!(stats.pushes_received, 6);
+    assert_eq!(stats.duplicate_pushes_received, 1);
+    assert_eq!(stats.stale_pushes_received, 2);
+    assert_eq!(kcp.recv_all().len(), 3);
+}
+
+#[test]
+fn input_reordering_degree() {
+    let stream = Stream::default();
+    let mut kcp1 = Kcp::new(1, stream.clone());
+    let mut kcp2 = Kcp::new(1, Stream::default());
+    kcp1.set_nodelay(true, 10, 2, true);
+    kcp2.set_nodelay(true, 10, 2, true);
+    kcp2.set_adaptive_dupack(true);
+    kcp1.update(0).unwrap();
+    kcp2.update(0).unwrap();
+
+    for i in 0..6u8 {
+        kcp1.send(&[i, i]).unwrap();
+    }
+    kcp1.flush().unwrap();
+
+    let bytes = stream.0.borrow_mut().split_off(0);
+    let segments: Vec<&[u8]> = bytes.chunks(kcp::KCP_OVERHEAD + 2).collect();
+    assert_eq!(segments.len(), 6);
+
+    // Segment 0 is overtaken by 3 segments
+    for &i in &[1, 2, 3, 0, 4, 5] {
+        kcp2.input(segments[i]).unwrap();
+    }
+    assert_eq!(kcp2.reordering_degree(), 3);
+    assert_eq!(kcp2.dupack_threshold(), 4);
+
+    kcp2.set_adaptive_dupack(false);
+    assert_eq!(kcp2.dupack_threshold(), 2);
+}
+
+#[test]
+fn input_full_window_reversed() {
+    let stream1 = Stream::default();
+    let stream2 = Stream::default();
+    let mut kcp1 = Kcp::new(1, stream1.clone());
+    let mut kcp2 = Kcp::new(1, stream2.clone());
+    kcp1.set_nodelay(true, 10, 0, true);
+    kcp1.set_wndsize(8192, 128);
+    kcp2.set_wndsize(128, 8192);
+    kcp1.update(0).unwrap();
+    kcp2.update(0).unwrap();
+
+    // Tell the sender about the receive window
+    kcp2.probe_now();
+    kcp2.flush().unwrap();
+    kcp1.input(&stream2.0.borrow_mut().split_off(0)).unwrap();
+    kcp1.flush().unwrap();
+    stream1.0.borrow_mut().clear();
+
+    for i in 0..8192u32 {
+        kcp1.send(&i.to_le_bytes()).unwrap();
+    }
+    kcp1.flush().unwrap();
+    let bytes = stream1.0.borrow_mut().split_off(0);
+    let segments: Vec<&[u8]> = bytes.chunks(kcp::KCP_OVERHEAD + 4).collect();
+    assert_eq!(segments.len(), 8192);
+
+    let start = Instant::now();
+    for segment in segments.iter().rev() {
+        kcp2.input(segment).unwrap();
+    }
+    assert!(start.elapsed() < Duration::from_secs(5));
+
+    let mut buf = [0u8; 4];
+    for i in 0..8192u32 {
+        assert_eq!(kcp2.recv(&mut buf).unwrap(), 4);
+        assert_eq!(u32::from_le_bytes(buf), i);
+    }
+    assert!(kcp2.recv(&mut buf).is_err());
+}
+
+#[test]
+fn input_rcv_buf_limit() {
+    let stream1 = Stream::default();
+    let stream2 = Stream::default();
+    let mut kcp1 = Kcp::new(1, stream1.clone());
+    let mut kcp2 = Kcp::new(1, stream2.clone());
+    kcp1.set_nodelay(true, 10, 0, true);
+    kcp2.set_rcv_buf_limit(4);
+    assert_eq!(kcp2.rcv_buf_limit(), 4);
+    kcp1.update(0).unwrap();
+    kcp2.update(0).unwrap();
+
+    for i in 0..10u8 {
+        kcp1.send(&[i]).unwrap();
+    }
+    kcp1.flush().unwrap();
+    let bytes = stream1.0.borrow_mut().split_off(0);
+    let segments: Vec<&[u8]> = bytes.chunks(kcp::KCP_OVERHEAD + 1).collect();
+
+    // Only 9, 8, 7 and 6 are buffered, 0 is still accepted
+    for segment in segments.iter().rev() {
+        kcp2.input(segment).unwrap();
+    }
+    kcp2.flush().unwrap();
+    assert_eq!(
+        stream2.0.borrow_mut().split_off(0).len(),
+        5 * kcp::KCP_OVERHEAD
+    );
+    let mut buf = [0u8; 1];
+    assert_eq!(kcp2.recv(&mut buf).unwrap(), 1);
+    assert_eq!(buf[0], 0);
+    assert!(kcp2.recv(&mut buf).is_err());
+
+    // The dropped ones are accepted once retransmitted
+    for segment in &segments[1..] {
+        kcp2.input(segment).unwrap();
+    }
+    for i in 1..10u8 {
+        assert_eq!(kcp2.recv(&mut buf).unwrap(), 1);
+        assert_eq!(buf[0], i);
+    }
+}
+
+#[test]
+fn input_gap_filled_acks() {
+    let stream1 = Stream::default();
+    let stream2 = Stream::default();
+    let mut kcp1 = Kcp::new(1, stream1.clone());
+    let mut kcp2 = Kcp::new(1, stream2.clone());
+    kcp1.set_nodelay(true, 10, 0, true);
+    kcp1.update(0).unwrap();
+    kcp2.update(0).unwrap();
+
+    for i in 0..5u8 {
+        kcp1.send(&[i, i]).unwrap();
+    }
+    kcp1.flush().unwrap();
+
+    let bytes = stream1.0.borrow_mut().split_off(0);
+    let segments: Vec<&[u8]> = bytes.chunks(kcp::KCP_OVERHEAD + 2).collect();
+
+    // The first one arrives last, filling the gap
+    for &i in &[1, 2, 3, 4, 0] {
+        kcp2.input(segments[i]).unwrap();
+    }
+    kcp2.flush().unwrap();
+    let ack = stream2.0.borrow_mut().split_off(0);
+    assert_eq!(ack.len(), kcp::KCP_OVERHEAD);
+
+    kcp1.input(&ack).unwrap();
+    assert_eq!(kcp1.wait_snd(), 0);
+}
+
+#[test]
+fn input_peek() {
+    let stream = Stream::default();
+    let mut kcp1 = Kcp::new(1, stream.clone());
+    let mut kcp2 = Kcp::new(1, Stream::default());
+    kcp1.set_mtu(100).unwrap();
+    kcp1.set_nodelay(true, 10, 0, true);
+    kcp1.update(0).unwrap();
+    kcp2.update(0).unwrap();
+
+    let msg: Vec<u8> = (0..200).map(|i| i as u8).collect();
+    kcp1.send(&msg).unwrap();
+    kcp1.flush().unwrap();
+
+    let bytes = stream.0.borrow_mut().split_off(0);
+    let mut small = [0u8; 100];
+    let mut buf = [0u8; 256];
+    kcp2.input_framed(&bytes[..100]).unwrap();
+    assert!(matches!(
+        kcp2.peek(&mut buf),
+        Err(kcp::Error::ExpectingFragment)
+    ));
+
+    kcp2.input_framed(&bytes[100..]).unwrap();
+    assert!(matches!(
+        kcp2.peek(&mut small),
+        Err(kcp::Error::UserBufTooSmall)
+    ));
+
+    // Repeated peeks see the same message
+    for _ in 0..2 {
+        let n = kcp2.peek(&mut buf).unwrap();
+        assert_eq!(&buf[..n], &msg[..]);
+    }
+
+    let n = kcp2.recv(&mut buf).unwrap();
+    assert_eq!(&buf[..n], &msg[..]);
+    assert!(matches!(
+        kcp2.peek(&mut buf),
+        Err(kcp::Error::RecvQueueEmpty)
+    ));
+}
+
+#[test]
+fn input_recv_ready() {
+    let stream = Stream::default();
+    let mut kcp1 = Kcp::new(1, stream.clone());
+    let mut kcp2 = Kcp::new(1, Stream::default());
+    kcp1.set_mtu(100).unwrap();
+    kcp1.set_nodelay(true, 10, 0, true);
+    kcp1.update(0).unwrap();
+    kcp2.update(0).unwrap();
+    assert!(!kcp2.recv_ready());
+    assert_eq!(kcp2.ready_message_count(), 0);
+
+    kcp1.send(&[1u8; 200]).unwrap();
+    kcp1.send(b"short").unwrap();
+    kcp1.send(b"tail").unwrap();
+    kcp1.flush().unwrap();
+    let bytes = stream.0.borrow_mut().split_off(0);
+
+    // Only a part of the first message
+    kcp2.input_framed(&bytes[..100]).unwrap();
+    assert_eq!(kcp2.rcv_queue_len(), 1);
+    assert!(!kcp2.recv_ready());
+    assert_eq!(kcp2.ready_message_count(), 0);
+
+    kcp2.input_framed(&bytes[100..]).unwrap();
+    assert!(kcp2.recv_ready());
+    assert_eq!(kcp2.ready_message_count(), 3);
+
+    let mut buf = [0u8; 256];
+    for left in (0..3).rev() {
+        kcp2.recv(&mut buf).unwrap();
+        assert_eq!(kcp2.ready_message_count(), left);
+        assert_eq!(kcp2.recv_ready(), left > 0);
+    }
+}
+
+#[test]
+fn input_try_recv() {
+    let stream = Stream::default();
+    let mut kcp1 = Kcp::new(1, stream.clone());
+    let mut kcp2 = Kcp::new(1, Stream::default());
+    kcp1.set_mtu(100).unwrap();
+    kcp1.set_nodelay(true, 10, 0, true);
+    kcp1.update(0).unwrap();
+    kcp2.update(0).unwrap();
+
+    let mut buf = [0u8; 256];
+    assert_eq!(kcp2.try_recv(&mut buf).unwrap(), None);
+
+    kcp1.send(&[1u8; 200]).unwrap();
+    kcp1.flush().unwrap();
+    let bytes = stream.0.borrow_mut().split_off(0);
+
+    // Waiting for fragments
+    kcp2.input_framed(&bytes[..100]).unwrap();
+    assert_eq!(kcp2.try_recv(&mut buf).unwrap(), None);
+
+    kcp2.input_framed(&bytes[100..]).unwrap();
+    let mut small = [0u8; 10];
+    assert!(matches!(
+        kcp2.try_recv(&mut small),
+        Err(kcp::Error::UserBufTooSmall)
+    ));
+    assert_eq!(kcp2.try_recv(&mut buf).unwrap(), Some(200));
+    assert_eq!(&buf[..200], &[1u8; 200][..]);
+    assert_eq!(kcp2.try_recv(&mut buf).unwrap(), None);
+}
+
+#[test]
+fn input_recv_bytes() {
+    let stream = Stream::default();
+    let mut kcp1 = Kcp::new(1, stream.clone());
+    let mut kcp2 = Kcp::new(1, Stream::default());
+    kcp1.set_mtu(100).unwrap();
+    kcp1.set_nodelay(true, 10, 0, true);
+    kcp1.update(0).unwrap();
+    kcp2.update(0).unwrap();
+    assert!(matches!(kcp2.recv_bytes(), Err(kcp::Error::RecvQueueEmpty)));
+
+    let msg: Vec<u8> = (0..200).map(|i| i as u8).collect();
+    kcp1.send(b"single").unwrap();
+    kcp1.send(&msg).unwrap();
+    kcp1.flush().unwrap();
+
+    let bytes = stream.0.borrow_mut().split_off(0);
+    kcp2.input_framed(&bytes[..130]).unwrap();
+    assert_eq!(&kcp2.recv_bytes().unwrap()[..], b"single");
+    assert!(matches!(
+        kcp2.recv_bytes(),
+        Err(kcp::Error::ExpectingFragment)
+    ));
+
+    kcp2.input_framed(&bytes[130..]).unwrap();
+    assert_eq!(&kcp2.recv_bytes().unwrap()[..], &msg[..]);
+}
+
+#[test]
+fn input_recv_into_vec() {
+    let stream = Stream::default();
+    let mut kcp1 = Kcp::new(1, stream.clone());
+    let mut kcp2 = Kcp::new(1, Stream::default());
+    kcp1.set_mtu(100).unwrap();
+    kcp1.set_nodelay(true, 10, 0, true);
+    kcp1.update(0).unwrap();
+    kcp2.update(0).unwrap();
+
+    let mut out = b"stale".to_vec();
+    assert!(matches!(
+        kcp2.recv_into_vec(&mut out),
+        Err(kcp::Error::RecvQueueEmpty)
+    ));
+    assert_eq!(out, b"stale");
+
+    let msg: Vec<u8> = (0..200).map(|i| i as u8).collect();
+    kcp1.send(&msg).unwrap();
+    kcp1.send(b"short").unwrap();
+    kcp1.flush().unwrap();
+    kcp2.input(&stream.0.borrow_mut().split_off(0)).unwrap();
+
+    // Grown for the fragmented message, then shrunk for the next one
+    assert_eq!(kcp2.recv_into_vec(&mut out).unwrap(), msg.len());
+    assert_eq!(out, msg);
+    assert_eq!(kcp2.recv_into_vec(&mut out).unwrap(), 5);
+    assert_eq!(out, b"short");
+}
+
+#[test]
+fn input_lenient() {
+    let stream = Stream::default();
+    let mut kcp1 = Kcp::new(1, stream.clone());
+    kcp1.set_nodelay(true, 10, 2, true);
+    kcp1.update(0).unwrap();
+    kcp1.send(b"first").unwrap();
+    kcp1.send(b"second").unwrap();
+    kcp1.flush().unwrap();
+
+    // Both segments in one datagram, the second one truncated
+    let mut datagram = stream.0.borrow_mut().split_off(0);
+    assert_eq!(datagram.len(), 2 * kcp::KCP_OVERHEAD + 11);
+    datagram.truncate(datagram.len() - 3);
+
+    let mut kcp2 = Kcp::new(1, Stream::default());
+    kcp2.update(0).unwrap();
+    assert!(matches!(
+        kcp2.input(&datagram),
+        Err(kcp::Error::InvalidSegmentDataSize(6, 3))
+    ));
+
+    let ack = Stream::default();
+    let mut kcp3 = Kcp::new(1, ack.clone());
+    kcp3.update(0).unwrap();
+    assert_eq!(
+        kcp3.input_lenient(&datagram).unwrap(),
+        kcp::KCP_OVERHEAD + 5
+    );
+    let mut buf = [0u8; 16];
+    let n = kcp3.recv(&mut buf).unwrap();
+    assert_eq!(&buf[..n], b"first");
+    kcp3.flush().unwrap();
+    assert_eq!(ack.0.borrow().len(), kcp::KCP_OVERHEAD);
+
+    // Nothing valid at all
+    assert!(kcp3
+        .input_lenient(&datagram[kcp::KCP_OVERHEAD + 5..])
+        .is_err());
+}
+
+#[test]
+fn input_many() {
+    let stream = Stream::default();
+    let mut kcp1 = Kcp::new(1, stream.clone());
+    let mut kcp2 = Kcp::new(1, Stream::default());
+    kcp1.set_nodelay(true, 10, 2, true);
+    kcp1.update(0).unwrap();
+    kcp2.update(0).unwrap();
+
+    let mut packets = Vec::new();
+    for msg in [&b"one"[..], b"two", b"three"] {
+        kcp1.send(msg).unwrap();
+        kcp1.flush().unwrap();
+        packets.push(stream.0.borrow_mut().split_off(0));
+    }
+
+    // From another conversation, skipped without dropping the others
+    let mut other = packets[0].clone();
+    other[0] = 2;
+    packets.insert(1, other);
+
+    let packets: Vec<&[u8]> = packets.iter().map(|p| &p[..]).collect();
+    assert_eq!(kcp2.input_many(&packets).unwrap(), 3);
+
+    let mut buf = [0u8; 16];
+    for msg in [&b"one"[..], b"two", b"three"] {
+        let n = kcp2.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..n], msg);
+    }
+}
+
+#[test]
+fn input_resize_rcv_wnd() {
+    let stream = Stream::default();
+    let out = Stream::default();
+    let mut kcp1 = Kcp::new(1, stream.clone());
+    let mut kcp2 = Kcp::new(1, out.clone());
+    kcp1.set_nodelay(true, 10, 2, true);
+    kcp1.update(0).unwrap();
+    kcp2.update(0).unwrap();
+    kcp2.resize_rcv_wnd(4);
+    assert_eq!(kcp2.rcv_wnd(), 4);
+
+    for i in 0..8u8 {
+        kcp1.send(&[i, i]).unwrap();
+    }
+    kcp1.flush().unwrap();
+
+    let bytes = stream.0.borrow_mut().split_off(0);
+    let segments: Vec<&[u8]> = bytes.chunks(kcp::KCP_OVERHEAD + 2).collect();
+    for segment in &segments {
+        kcp2.input(segment).unwrap();
+    }
+
+    // 4..8 are received, waiting for room in the receive queue
+    assert_eq!(kcp2.peeksize().unwrap(), 2);
+    kcp2.flush().unwrap();
+    out.0.borrow_mut().clear();
+
+    // Growing moves them in, and tells the remote
+    kcp2.resize_rcv_wnd(12);
+    kcp2.flush().unwrap();
+    assert!(out
+        .0
+        .borrow()
+        .chunks(kcp::KCP_OVERHEAD)
+        .any(|segment| segment[4] == KCP_CMD_WINS));
+
+    // Shrinking keeps what has been received
+    kcp2.resize_rcv_wnd(2);
+    let mut buf = [0u8; 2];
+    for i in 0..8u8 {
+        assert_eq!(kcp2.recv(&mut buf).unwrap(), 2);
+        assert_eq!(buf, [i, i]);
+    }
+}
+
+#[test]
+fn input_conv_from_client() {
+    let stream = Stream::default();
+    let mut client = Kcp::new(0, stream.clone());
+    client.update(0).unwrap();
+    client.send(b"hello").unwrap();
+    client.flush().unwrap();
+    let bytes = stream.0.borrow_mut().split_off(0);
+
+    // Same conv as the client, still taken from the first segment
+    let mut server = Kcp::new(0, Stream::default());
+    server.input_conv();
+    server.update(0).unwrap();
+
+    // A truncated segment keeps waiting
+    assert!(server.input(&bytes[..bytes.len() - 1]).is_err());
+    assert!(server.waiting_conv());
+
+    server.input(&bytes).unwrap();
+    assert!(!server.waiting_conv());
+    assert_eq!(server.conv(), 0);
+
+    let mut buf = [0u8; 16];
+    let n = server.recv(&mut buf).unwrap();
+    assert_eq!(&buf[..n], b"hello");
+
+    // A different conv is learned the same way
+    let stream = Stream::default();
+    let mut client = Kcp::new(7, stream.clone());
+    client.update(0).unwrap();
+    client.send(b"hello").unwrap();
+    client.flush().unwrap();
+    let bytes = stream.0.borrow_mut().split_off(0);
+
+    let mut server = Kcp::new(0, Stream::default());
+    server.input_conv();
+    server.update(0).unwrap();
+    server.input(&bytes).unwrap();
+    assert!(!server.waiting_conv());
+    assert_eq!(server.conv(), 7);
+}
+
+#[test]
+fn input_conv_with_first_payload() {
+    let stream1 = Stream::default();
+    let mut client = Kcp::new(42, stream1.clone());
+    let mut server = Kcp::new(0, Stream::default());
+    server.input_conv();
+    client.update(0).unwrap();
+    server.update(0).unwrap();
+
+    client.send_with_conv_negotiation(b"request").unwrap();
+    let datagram = stream1.0.borrow_mut().split_off(0);
+
+    // Conv and payload from one datagram
+    server.input(&datagram).unwrap();
+    assert_eq!(server.conv(), 42);
+    let mut buf = [0u8; 16];
+    let n = server.recv(&mut buf).unwrap();
+    assert_eq!(&buf[..n], b"request");
+
+    // Retransmitted before the server replies
+    server.input(&datagram).unwrap();
+    assert!(server.recv(&mut buf).is_err());
+    assert_eq!(server.conv(), 42);
+}
+
+#[test]
+fn input_recv_all() {
+    let stream = Stream::default();
+    let mut kcp1 = Kcp::new(1, stream.clone());
+    let mut kcp2 = Kcp::new(1, Stream::default());
+    kcp1.set_mtu(50).unwrap();
+    kcp1.set_nodelay(true, 10, 0, true);
+    kcp1.update(0).unwrap();
+    kcp2.update(0).unwrap();
+    assert!(kcp2.recv_all().is_empty());
+
+    let long = [7u8; 60];
+    kcp1.send(b"one").unwrap();
+    kcp1.send(&long).unwrap();
+    kcp1.send(b"two").unwrap();
+    kcp1.send(&long).unwrap();
+    kcp1.flush().unwrap();
+
+    // The last fragment is lost
+    let bytes = stream.0.borrow_mut().split_off(0);
+    kcp2.input(&bytes[..bytes.len() - (kcp::KCP_OVERHEAD + 8)])
+        .unwrap();
+
+    let messages = kcp2.recv_all();
+    assert_eq!(messages.len(), 3);
+    assert_eq!(&messages[0][..], b"one");
+    assert_eq!(&messages[1][..], &long[..]);
+    assert_eq!(&messages[2][..], b"two");
+    assert!(kcp2.recv_all().is_empty());
+    assert!(matches!(
+        kcp2.peeksize(),
+        Err(kcp::Error::ExpectingFragment)
+    ));
+}
+
+#[test]
+fn input_recv_batch() {
+    let stream = Stream::default();
+    let out = Stream::default();
+    let mut kcp1 = Kcp::new(1, stream.clone());
+    let mut kcp2 = Kcp::new(1, out.clone());
+    kcp1.set_mtu(50).unwrap();
+    kcp1.set_nodelay(true, 10, 0, true);
+    kcp2.resize_rcv_wnd(4);
+    kcp1.update(0).unwrap();
+    kcp2.update(0).unwrap();
+
+    let long = [7u8; 60];
+    kcp1.send(b"one").unwrap();
+    kcp1.send(&long).unwrap();
+    kcp1.send(b"two").unwrap();
+    kcp1.send(b"three").unwrap();
+    kcp1.flush().unwrap();
+    let bytes = stream.0.borrow_mut().split_off(0);
+    kcp2.input(&bytes).unwrap();
+    kcp2.flush().unwrap();
+    out.0.borrow_mut().clear();
+
+    // A full queue, the window is told once a batch frees it
+    let mut messages = Vec::new();
+    assert_eq!(kcp2.recv_batch(&mut messages, 2), 2);
+    assert_eq!(&messages[0][..], b"one");
+    assert_eq!(&messages[1][..], &long[..]);
+    kcp2.flush().unwrap();
+    assert!(out
+        .0
+        .borrow()
+        .chunks(kcp::KCP_OVERHEAD)
+        .any(|segment| segment[4] == KCP_CMD_WINS));
+
+    assert_eq!(kcp2.recv_batch(&mut messages, 0), 2);
+    assert_eq!(&messages[2][..], b"two");
+    assert_eq!(&messages[3][..], b"three");
+    assert_eq!(kcp2.recv_batch(&mut messages, 5), 0);
+    assert_eq!(messages.len(), 4);
+}
+
+#[test]
+fn input_and_recv() {
+    let stream = Stream::default();
+    let out = Stream::default();
+    let mut kcp1 = Kcp::new(1, stream.clone());
+    let mut kcp2 = Kcp::new(1, out.clone());
+    kcp1.set_mtu(50).unwrap();
+    kcp1.set_nodelay(true, 10, 0, true);
+    kcp1.update(0).unwrap();
+    kcp2.update(0).unwrap();
+
+    let long = [9u8; 40];
+    kcp1.send(&long).unwrap();
+    kcp1.send(b"after").unwrap();
+    kcp1.flush().unwrap();
+    let bytes = stream.0.borrow_mut().split_off(0);
+    let (first, second) = bytes.split_at(kcp::KCP_OVERHEAD + 26);
+
+    // Half of the message
+    let mut messages = Vec::new();
+    assert_eq!(kcp2.input_and_recv(first, &mut messages).unwrap(), 0);
+    assert!(messages.is_empty());
+
+    assert_eq!(kcp2.input_and_recv(second, &mut messages).unwrap(), 2);
+    assert_eq!(&messages[0][..], &long[..]);
+    assert_eq!(&messages[1][..], b"after");
+
+    // ACKs are left for the next flush
+    assert_eq!(kcp2.pending_acks(), 3);
+    assert!(out.0.borrow().is_empty());
+    kcp2.flush().unwrap();
+    kcp1.input(&out.0.borrow_mut().split_off(0)).unwrap();
+    assert_eq!(kcp1.wait_snd(), 0);
+}
+
+#[test]
+fn input_peek_fragments() {
+    let stream = Stream::default();
+    let mut kcp1 = Kcp::new(1, stream.clone());
+    let mut kcp2 = Kcp::new(1, Stream::default());
+    kcp1.set_mtu(50).unwrap();
+    kcp1.set_nodelay(true, 10, 0, true);
+    kcp1.update(0).unwrap();
+    kcp2.update(0).unwrap();
+    assert!(matches!(
+        kcp2.peek_fragments(),
+        Err(kcp::Error::RecvQueueEmpty)
+    ));
+
+    let long = [7u8; 60];
+    kcp1.send(&long).unwrap();
+    kcp1.send(b"one").unwrap();
+    kcp1.send(&long).unwrap();
+    kcp1.flush().unwrap();
+
+    // The last fragment is lost
+    let bytes = stream.0.borrow_mut().split_off(0);
+    kcp2.input(&bytes[..bytes.len() - (kcp::KCP_OVERHEAD + 8)])
+        .unwrap();
+
+    assert_eq!(kcp2.peek_fragments().unwrap(), 3);
+    assert_eq!(kcp2.peeksize().unwrap(), 60);
+    kcp2.recv_bytes().unwrap();
+    assert_eq!(kcp2.peek_fragments().unwrap(), 1);
+    assert_eq!(kcp2.peeksize().unwrap(), 3);
+    kcp2.recv_bytes().unwrap();
+    assert!(matches!(
+        kcp2.peek_fragments(),
+        Err(kcp::Error::ExpectingFragment)
+    ));
+}
+
+#[test]
+fn input_queue_lengths() {
+    let stream = Stream::default();
+    let mut kcp1 = Kcp::new(1, stream.clone());
+    let mut kcp2 = Kcp::new(1, Stream::default());
+    kcp1.set_mtu(50).unwrap();
+    kcp1.set_nodelay(true, 10, 0, false);
+    kcp1.update(0).unwrap();
+    kcp2.update(0).unwrap();
+
+    for _ in 0..4 {
+        kcp1.send(b"ping").unwrap();
+    }
+    kcp1.flush().unwrap();
+
+    // Limited by the initial cwnd
+    assert_eq!((kcp1.snd_buf_len(), kcp1.snd_queue_len()), (1, 3));
+    assert_eq!(kcp1.wait_snd(), 4);
+
+    kcp1.set_nodelay(true, 10, 0, true);
+    kcp1.flush().unwrap();
+    assert_eq!((kcp1.snd_buf_len(), kcp1.snd_queue_len()), (4, 0));
+
+    // sn 0 is lost
+    let bytes = stream.0.borrow_mut().split_off(0);
+    let segment = kcp::KCP_OVERHEAD + 4;
+    kcp2.input(&bytes[segment..]).unwrap();
+    assert_eq!((kcp2.rcv_buf_len(), kcp2.rcv_queue_len()), (3, 0));
+
+    kcp2.input(&bytes[..segment]).unwrap();
+    assert_eq!((kcp2.rcv_buf_len(), kcp2.rcv_queue_len()), (0, 4));
+}
+
+#[test]
+fn input_strict_conv() {
+    let stray = Stream::default();
+    let valid = Stream::default();
+    let mut kcp1 = Kcp::new(2, stray.clone());
+    let mut kcp2 = Kcp::new(1, valid.clone());
+    kcp1.update(0).unwrap();
+    kcp2.update(0).unwrap();
+    kcp1.send(b"stray").unwrap();
+    kcp1.flush().unwrap();
+    kcp2.send(b"valid").unwrap();
+    kcp2.flush().unwrap();
+
+    let mut datagram = stray.0.borrow().clone();
+    datagram.extend_from_slice(&valid.0.borrow());
+    assert_eq!(kcp::try_get_conv(&datagram).unwrap(), 2);
+
+    let mut kcp = Kcp::new(1, Stream::default());
+    kcp.update(0).unwrap();
+    assert!(kcp.strict_conv());
+    assert!(matches!(
+        kcp.input(&datagram),
+        Err(kcp::Error::ConvMismatch {
+            expected: 1,
+            found: 2,
+            offset: 0
+        })
+    ));
+    assert!(kcp.recv_bytes().is_err());
+
+    kcp.set_legacy_conv_error(true);
+    assert!(matches!(
+        kcp.input(&datagram),
+        Err(kcp::Error::ConvInconsistent(1, 2))
+    ));
+    assert!(kcp.recv_bytes().is_err());
+
+    kcp.set_strict_conv(false);
+    assert_eq!(kcp.input(&datagram).unwrap(), datagram.len());
+    assert_eq!(&kcp.recv_bytes().unwrap()[..], b"valid");
+}
+
+#[test]
+fn input_conv_mismatch_offset() {
+    let stream1 = Stream::default();
+    let stream2 = Stream::default();
+    let mut kcp1 = Kcp::new(1, stream1.clone());
+    let mut kcp2 = Kcp::new(2, stream2.clone());
+    kcp1.update(0).unwrap();
+    kcp2.update(0).unwrap();
+    kcp1.send(b"first").unwrap();
+    kcp1.flush().unwrap();
+    kcp2.send(b"second").unwrap();
+    kcp2.flush().unwrap();
+
+    // Segments of two convs batched by a demultiplexer
+    let mut datagram = stream1.0.borrow().clone();
+    let boundary = datagram.len();
+    datagram.extend_from_slice(&stream2.0.borrow());
+
+    let mut first = Kcp::new(1, Stream::default());
+    let mut second = Kcp::new(2, Stream::default());
+    first.update(0).unwrap();
+    second.update(0).unwrap();
+    let offset = match first.input(&datagram) {
+        Err(kcp::Error::ConvMismatch {
+            expected: 1,
+            found: 2,
+            offset,
+        }) => offset,
+        other => panic!("{:?}", other),
+    };
+    assert_eq!(offset, boundary);
+    assert_eq!(&first.recv_bytes().unwrap()[..], b"first");
+
+    assert_eq!(
+        second.input(&datagram[offset..]).unwrap(),
+        datagram.len() - offset
+    );
+    assert_eq!(&second.recv_bytes().unwrap()[..], b"second");
+}
+
+#[test]
+fn input_empty_message() {
+    let mut kcp = Kcp::new(1, Stream::default());
+    kcp.update(0).unwrap();
+
+    kcp.input(&push(0, b"")).unwrap();
+    kcp.input(&push(1, b"")).unwrap();
+    kcp.input(&push(2, b"data")).unwrap();
+    assert_eq!(kcp.ready_message_count(), 3);
+
+    let mut buf = [0u8; 16];
+    assert_eq!(kcp.peeksize().unwrap(), 0);
+    assert_eq!(kcp.recv(&mut buf).unwrap(), 0);
+    assert!(kcp.recv_bytes().unwrap().is_empty());
+    assert_eq!(kcp.peeksize().unwrap(), 4);
+    assert_eq!(kcp.recv(&mut buf).unwrap(), 4);
+    assert_eq!(&buf[..4], b"data");
+    assert!(matches!(
+        kcp.recv(&mut buf),
+        Err(kcp::Error::RecvQueueEmpty)
+    ));
+
+    // Not holding back the ones after it
+    kcp.input(&push(3, b"")).unwrap();
+    kcp.input(&push(4, b"more")).unwrap();
+    let messages = kcp.recv_all();
+    assert_eq!(messages.len(), 2);
+    assert!(messages[0].is_empty());
+    assert_eq!(&messages[1][..], b"more");
+}
+
+#[test]
+fn input_ack_samples() {
+    let stream1 = Stream::default();
+    let stream2 = Stream::default();
+    let mut kcp1 = Kcp::new(1, stream1.clone());
+    let mut kcp2 = Kcp::new(1, stream2.clone());
+    kcp1.set_nodelay(true, 10, 0, true);
+    kcp2.set_nodelay(true, 10, 0, true);
+    let samples = Arc::new(Mutex::new(Vec::new()));
+    let collected = samples.clone();
+    kcp1.set_ack_sample_handler(move |sample| collected.lock().unwrap().push(sample));
+    kcp1.update(0).unwrap();
+    kcp2.update(0).unwrap();
+
+    kcp1.send(b"first").unwrap();
+    kcp1.send(b"second").unwrap();
+    kcp1.flush().unwrap();
+    kcp2.input(&stream1.0.borrow_mut().split_off(0)).unwrap();
+    kcp2.update(10).unwrap();
+    kcp1.update(40).unwrap();
+    kcp1.input(&stream2.0.borrow_mut().split_off(0)).unwrap();
+
+    let samples = samples.lock().unwrap();
+    let sample = |sn| kcp::AckSample {
+        sn,
+        rtt: 40,
+        send_ts: 0,
+        recv_ts: 40,
+    };
+    assert_eq!(*samples, vec![sample(0), sample(1)]);
+    assert_eq!(kcp1.rx_srtt(), 40);
+}
+
+#[test]
+fn input_path_metrics() {
+    let stream1 = Stream::default();
+    let stream2 = Stream::default();
+    let mut kcp1 = Kcp::new(1, stream1.clone());
+    let mut kcp2 = Kcp::new(1, stream2.clone());
+    kcp1.set_nodelay(true, 10, 0, true);
+    kcp1.update(0).unwrap();
+    kcp2.update(0).unwrap();
+
+    for i in 0..6u8 {
+        kcp1.send(&[i, i]).unwrap();
+        kcp1.update(10 * (i as u32 + 1)).unwrap();
+    }
+    let bytes = stream1.0.borrow_mut().split_off(0);
+    let segments: Vec<&[u8]> = bytes.chunks(kcp::KCP_OVERHEAD + 2).collect();
+
+    // Segment 0 is reordered, segment 4 is lost
+    for &i in &[1, 2, 3, 0, 5] {
+        kcp2.input(segments[i]).unwrap();
+    }
+    kcp2.flush().unwrap();
+    kcp1.input(&stream2.0.borrow_mut().split_off(0)).unwrap();
+    assert_eq!(kcp1.path_metrics().dup_acks_received, 1);
+
+    // The retransmission fills the hole, but it is not reordered
+    kcp1.update(1000).unwrap();
+    kcp2.input(&stream1.0.borrow_mut().split_off(0)).unwrap();
+    assert_eq!(
+        kcp2.path_metrics(),
+        kcp::PathMetrics {
+            dup_acks_received: 0,
+            out_of_order_segments: 2,
+            reordered_on_recv: 1,
+        }
+    );
+
+    kcp2.reset_stats();
+    assert_eq!(kcp2.path_metrics(), kcp::PathMetrics::default());
+}
+
+#[test]
+fn input_demux() {
+    let stream1 = Stream::default();
+    let stream2 = Stream::default();
+    let mut kcp1 = Kcp::new(1, stream1.clone());
+    let mut kcp2 = Kcp::new(2, stream2.clone());
+    kcp1.set_nodelay(true, 10, 0, true);
+    kcp1.update(0).unwrap();
+    kcp2.update(0).unwrap();
+
+    kcp1.send(b"one").unwrap();
+    kcp1.send(b"uno").unwrap();
+    kcp1.flush().unwrap();
+    kcp2.send(b"two").unwrap();
+    kcp2.flush().unwrap();
+
+    // Segments of both conversations batched in one datagram
+    let mut datagram = stream1.0.borrow_mut().split_off(0);
+    datagram.extend(stream2.0.borrow_mut().split_off(0));
+    let runs: Vec<(u32, &[u8])> = kcp::demux(&datagram).map(Result::unwrap).collect();
+    assert_eq!(runs.len(), 2);
+    assert_eq!(runs[0].0, 1);
+    assert_eq!(runs[0].1.len(), 2 * (kcp::KCP_OVERHEAD + 3));
+    assert_eq!(runs[1].0, 2);
+
+    let mut receivers = [
+        Kcp::new(1, Stream::default()),
+        Kcp::new(2, Stream::default()),
+    ];
+    for (conv, segments) in runs {
+        let kcp = &mut receivers[conv as usize - 1];
+        assert_eq!(kcp.input(segments).unwrap(), segments.len());
+    }
+    assert_eq!(&receivers[0].recv_bytes().unwrap()[..], b"one");
+    assert_eq!(&receivers[0].recv_bytes().unwrap()[..], b"uno");
+    assert_eq!(&receivers[1].recv_bytes().unwrap()[..], b"two");
+
+    // The segments before a malformed one are still routed
+    datagram.truncate(datagram.len() - 1);
+    let mut runs = kcp::demux(&datagram);
+    assert_eq!(runs.next().unwrap().unwrap().0, 1);
+    assert!(matches!(
+        runs.next(),
+        Some(Err(kcp::Error::InvalidSegmentDataSize(3, 2)))
+    ));
+    assert!(runs.next().is_none());
+    assert!(matches!(
+        kcp::demux(&[0u8; 10]).next(),
+        Some(Err(kcp::Error::InvalidSegmentSize(10)))
+    ));
+}
@@ -0,0 +1,111 @@
+#![cfg(all(feature = "std", feature = "isn"))]
+
+extern crate kcp;
+
+mod common;
+
+use kcp::{Kcp, LinkState};
+
+use common::Sink;
+
+const KCP_CMD_PUSH: u8 = 81;
+
+fn kcp(conv: u32, isn: u32) -> (Kcp<Sink>, Sink) {
+    let sink = Sink::default();
+    let mut kcp = Kcp::new(conv, sink.clone());
+    kcp.set_nodelay(true, 10, 0, true);
+    kcp.set_isn(isn);
+    (kcp, sink)
+}
+
+/// A client near the wrap around and a server far from 0, connected by the handshake
+fn connect() -> (Kcp<Sink>, Sink, Kcp<Sink>, Sink) {
+    let (mut client, client_sink) = kcp(7, u32::MAX - 2);
+    let (mut server, server_sink) = kcp(0, 0x8000_1234);
+    client.connect();
+    client.update(0).unwrap();
+    let syn = client_sink.take().remove(0);
+    assert_eq!(kcp::try_get_sn(&syn).unwrap(), u32::MAX - 2);
+
+    server.accept(&syn).unwrap();
+    server.update(0).unwrap();
+    let synack = server_sink.take().remove(0);
+    assert_eq!(kcp::try_get_sn(&synack).unwrap(), 0x8000_1234);
+
+    client.input(&synack).unwrap();
+    assert_eq!(client.state(), LinkState::Active);
+    (client, client_sink, server, server_sink)
+}
+
+#[test]
+fn isn_transfer() {
+    let (mut client, client_sink, mut server, server_sink) = connect();
+
+    for i in 0..6u8 {
+        client.send(&[i; 100]).unwrap();
+        server.send(&[i + 10; 100]).unwrap();
+    }
+
+    let mut pushes = Vec::new();
+    for current in (10..500).step_by(10) {
+        client.update(current).unwrap();
+        server.update(current).unwrap();
+        for datagram in client_sink.take() {
+            if kcp::get_cmd(&datagram).unwrap() == KCP_CMD_PUSH {
+                pushes.push(kcp::try_get_sn(&datagram).unwrap());
+            }
+            server.input(&datagram).unwrap();
+        }
+        for datagram in server_sink.take() {
+            client.input(&datagram).unwrap();
+        }
+    }
+
+    assert_eq!(pushes[0], u32::MAX - 2);
+    for i in 0..6u8 {
+        assert_eq!(&server.recv_bytes().unwrap()[..], &[i; 100][..]);
+        assert_eq!(&client.recv_bytes().unwrap()[..], &[i + 10; 100][..]);
+    }
+    assert_eq!(client.wait_snd(), 0);
+    assert_eq!(server.wait_snd(), 0);
+}
+
+#[test]
+fn isn_before_known() {
+    let (mut client, client_sink) = kcp(7, 100);
+    let (mut server, server_sink) = kcp(0, 0x8000_0000);
+    client.connect();
+    client.update(0).unwrap();
+    server.accept(&client_sink.take()[0]).unwrap();
+    server.send(b"early").unwrap();
+    server.update(0).unwrap();
+    let datagram = server_sink.take().remove(0);
+
+    // Data overtaking the answer is dropped, neither acknowledged nor received
+    let (synack, data) = datagram.split_at(kcp::KCP_OVERHEAD);
+    assert_eq!(kcp::get_cmd(data).unwrap(), KCP_CMD_PUSH);
+    client.input(data).unwrap();
+    assert_eq!(client.state(), LinkState::Connecting);
+    assert_eq!(client.pending_acks(), 0);
+
+    // Resent after the answer
+    client.input(synack).unwrap();
+    assert_eq!(client.state(), LinkState::Active);
+    for current in (10..1000).step_by(10) {
+        client.update(current).unwrap();
+        server.update(current).unwrap();
+        for datagram in client_sink.take() {
+            server.input(&datagram).unwrap();
+        }
+        for datagram in server_sink.take() {
+            client.input(&datagram).unwrap();
+        }
+    }
+    assert_eq!(&client.recv_bytes().unwrap()[..], b"early");
+    assert_eq!(server.wait_snd(), 0);
+}
+
+#[test]
+fn isn_random() {
+    assert_ne!(kcp::random_isn(), kcp::random_isn());
+}
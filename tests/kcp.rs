@@ -1,20 +1,21 @@
+#![cfg(feature = "std")]
+
 extern crate bytes;
 extern crate env_logger;
 extern crate kcp;
 extern crate rand;
-extern crate time;
 
 use std::cell::RefCell;
 use std::collections::VecDeque;
 use std::io::{self, Cursor, ErrorKind, Read, Write};
 use std::rc::Rc;
-use std::thread::sleep;
-use std::time::Duration;
 
 use bytes::buf::{Buf, BufMut};
 use bytes::BytesMut;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
 
-use kcp::Kcp;
+use kcp::{Clock, Kcp, ManualClock, RtoEstimator, RttState};
 
 #[derive(Debug)]
 struct DelayPacket {
@@ -47,13 +48,15 @@ impl DelayPacket {
 struct Random {
     seeds: Vec<u32>,
     size: usize,
+    rng: StdRng,
 }
 
 impl Random {
-    fn new(size: usize) -> Random {
+    fn new(size: usize, seed: u64) -> Random {
         Random {
             seeds: vec![0u32; size],
             size: 0,
+            rng: StdRng::seed_from_u64(seed),
         }
     }
 
@@ -69,7 +72,7 @@ impl Random {
             self.size = self.seeds.len();
         }
 
-        let i = rand::random::<usize>() % self.size;
+        let i = self.rng.gen::<usize>() % self.size;
         let x = self.seeds[i];
 
         self.size -= 1;
@@ -79,11 +82,6 @@ impl Random {
     }
 }
 
-#[inline]
-fn current() -> u32 {
-    (time::OffsetDateTime::now_utc().unix_timestamp_nanos() / 1000000) as u32
-}
-
 struct LatencySimulator {
     lostrate: u32,
     rttmin: u32,
@@ -91,15 +89,23 @@ struct LatencySimulator {
     nmax: usize,
     tx1: u32,
     tx2: u32,
-    current: u32,
+    clock: ManualClock,
     p12: VecDeque<DelayPacket>,
     p21: VecDeque<DelayPacket>,
     r12: Random,
     r21: Random,
+    rng: StdRng,
 }
 
 impl LatencySimulator {
-    fn new(lostrate: u32, rttmin: u32, rttmax: u32, nmax: usize) -> LatencySimulator {
+    fn new(
+        clock: ManualClock,
+        lostrate: u32,
+        rttmin: u32,
+        rttmax: u32,
+        nmax: usize,
+        seed: u64,
+    ) -> LatencySimulator {
         LatencySimulator {
             lostrate: lostrate / 2,
             rttmin: rttmin / 2,
@@ -107,11 +113,12 @@ impl LatencySimulator {
             nmax,
             tx1: 0,
             tx2: 0,
-            current: crate::current(),
+            clock,
             p12: VecDeque::new(),
             p21: VecDeque::new(),
-            r12: Random::new(100),
-            r21: Random::new(100),
+            r12: Random::new(100, seed),
+            r21: Random::new(100, seed.wrapping_add(1)),
+            rng: StdRng::seed_from_u64(seed.wrapping_add(2)),
         }
     }
 
@@ -138,14 +145,12 @@ impl LatencySimulator {
         }
 
         let mut pkg = DelayPacket::new(BytesMut::from(data));
-        self.current = crate::current();
-
         let mut delay = self.rttmin;
         if self.rttmax > self.rttmin {
-            delay += rand::random::<u32>() % (self.rttmax - self.rttmin);
+            delay += self.rng.gen::<u32>() % (self.rttmax - self.rttmin);
         }
 
-        pkg.set_ts(self.current + delay);
+        pkg.set_ts(self.clock.now() + delay);
 
         if peer == 0 {
             self.p12.push_back(pkg);
@@ -174,8 +179,7 @@ impl LatencySimulator {
                 }
             };
 
-            self.current = crate::current();
-            if self.current < pkg.ts() {
+            if self.clock.now() < pkg.ts() {
                 return Err(io::Error::new(ErrorKind::WouldBlock, "No packet yet"));
             }
 
@@ -213,34 +217,46 @@ impl Write for KcpOutput {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy)]
 enum TestMode {
     Default,
     Normal,
     Fast,
+    FastAckRto,
+    AckNodelay,
+    Rfc6298,
 }
 
-fn run(mode: TestMode, msgcount: u32, lostrate: u32) {
+fn run(mode: TestMode, msgcount: u32, lostrate: u32) -> (RttState, u32) {
+    run_seeded(mode, msgcount, lostrate, rand::random())
+}
+
+/// Returns the RTT estimation of the sender and the max RTT of the messages
+fn run_seeded(mode: TestMode, msgcount: u32, lostrate: u32, seed: u64) -> (RttState, u32) {
     // Rtt 60ms ~ 125ms
-    let vnet = LatencySimulator::new(lostrate, 60, 125, 1000);
+    // Virtual time, advanced by 1ms a round
+    let clock = ManualClock::new(0);
+    let vnet = LatencySimulator::new(clock.clone(), lostrate, 60, 125, 1000, seed);
     let vnet = Rc::new(RefCell::new(vnet));
 
-    let mut kcp1 = Kcp::new(
+    let mut kcp1 = Kcp::with_clock(
         0x11223344,
         KcpOutput {
             sim: vnet.clone(),
             peer: 0,
         },
+        clock.clone(),
     );
-    let mut kcp2 = Kcp::new(
+    let mut kcp2 = Kcp::with_clock(
         0x11223344,
         KcpOutput {
             sim: vnet.clone(),
             peer: 1,
         },
+        clock.clone(),
     );
 
-    let mut current = crate::current();
+    let mut current = clock.now();
     let mut slap = current + 20;
     let mut index = 0;
     let mut next = 0;
@@ -268,17 +284,39 @@ fn run(mode: TestMode, msgcount: u32, lostrate: u32) {
             kcp1.set_rx_minrto(10);
             kcp2.set_fast_resend(1);
         }
-    }
+        TestMode::FastAckRto => {
+            kcp1.set_nodelay(true, 10, 2, true);
+            kcp2.set_nodelay(true, 10, 2, true);
+
+            kcp1.set_rx_minrto(10);
+            kcp2.set_fast_resend(1);
 
-    // let mut ts1 = ::current();
+            kcp1.set_fastack_rto(30);
+            kcp2.set_fastack_rto(30);
+        }
+        TestMode::AckNodelay => {
+            kcp1.set_nodelay(false, 10, 0, true);
+            kcp2.set_nodelay(false, 10, 0, true);
+
+            kcp1.set_ack_nodelay(true);
+            kcp2.set_ack_nodelay(true);
+        }
+        TestMode::Rfc6298 => {
+            kcp1.set_nodelay(false, 10, 0, true);
+            kcp2.set_nodelay(false, 10, 0, true);
+
+            kcp1.set_rto_estimator(RtoEstimator::Rfc6298);
+            kcp2.set_rto_estimator(RtoEstimator::Rfc6298);
+        }
+    }
 
     let mut buf = [0u8; 2000];
     while next <= msgcount {
-        sleep(Duration::from_millis(1));
+        clock.advance(1);
 
-        current = crate::current();
-        kcp1.update(crate::current()).unwrap();
-        kcp2.update(crate::current()).unwrap();
+        current = clock.now();
+        kcp1.update_now().unwrap();
+        kcp2.update_now().unwrap();
 
         // kcp1 send packet every 20ms
         while current >= slap {
@@ -357,6 +395,15 @@ fn run(mode: TestMode, msgcount: u32, lostrate: u32) {
             }
         }
     }
+
+    (kcp1.rtt_estimator_state(), maxrtt)
+}
+
+/// Sum of the max RTT of runs seeded the same, to compare modes under the same losses
+fn sum_maxrtt(mode: TestMode, lostrate: u32) -> u32 {
+    (0..8)
+        .map(|seed| run_seeded(mode, 1000, lostrate, seed).1)
+        .sum()
 }
 
 #[cfg(test)]
@@ -378,6 +425,16 @@ mod tests {
         run(TestMode::Fast, 1000, 10);
     }
 
+    #[test]
+    fn kcp_fastack_rto() {
+        run(TestMode::FastAckRto, 1000, 10);
+
+        // A lost fast resend is resent sooner
+        let fast = sum_maxrtt(TestMode::Fast, 30);
+        let fastack_rto = sum_maxrtt(TestMode::FastAckRto, 30);
+        assert!(fastack_rto < fast, "{} >= {}", fastack_rto, fast);
+    }
+
     #[test]
     fn kcp_massive_lost_default() {
         run(TestMode::Default, 1000, 50);
@@ -392,4 +449,43 @@ mod tests {
     fn kcp_massive_lost_fast() {
         run(TestMode::Fast, 1000, 50);
     }
+
+    #[test]
+    fn kcp_massive_lost_fastack_rto() {
+        let fast = sum_maxrtt(TestMode::Fast, 50);
+        let fastack_rto = sum_maxrtt(TestMode::FastAckRto, 50);
+        assert!(fastack_rto < fast, "{} >= {}", fastack_rto, fast);
+    }
+
+    #[test]
+    fn kcp_rto_ikcp() {
+        let (state, _) = run(TestMode::Normal, 300, 10);
+        // RTT of the simulator is 60ms ~ 125ms
+        assert!((60..=125).contains(&state.srtt), "{:?}", state);
+        assert!(state.rto > state.srtt && state.rto < 1000, "{:?}", state);
+    }
+
+    #[test]
+    fn kcp_ack_nodelay() {
+        let (state, _) = run(TestMode::AckNodelay, 300, 10);
+        // Not delayed by the flush interval of 10ms
+        assert!((60..=125).contains(&state.srtt), "{:?}", state);
+
+        // Lower than with ACKs sent at the next flush, under the same losses
+        let sum_srtt = |mode| -> u32 {
+            (0..16)
+                .map(|seed| run_seeded(mode, 300, 10, seed).0.srtt)
+                .sum()
+        };
+        let normal = sum_srtt(TestMode::Normal);
+        let ack_nodelay = sum_srtt(TestMode::AckNodelay);
+        assert!(ack_nodelay < normal, "{} >= {}", ack_nodelay, normal);
+    }
+
+    #[test]
+    fn kcp_rto_rfc6298() {
+        let (state, _) = run(TestMode::Rfc6298, 300, 10);
+        assert!((60..=125).contains(&state.srtt), "{:?}", state);
+        assert!(state.rto >= 1000 && state.rto < 2000, "{:?}", state);
+    }
 }
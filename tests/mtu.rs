@@ -0,0 +1,154 @@
+#![cfg(feature = "std")]
+
+extern crate kcp;
+
+mod common;
+
+use std::io::{self};
+
+use kcp::Kcp;
+
+use common::pair;
+
+const KCP_CMD_PUSH: u8 = 81;
+const KCP_CMD_WASK: u8 = 83;
+const KCP_CMD_WINS: u8 = 84;
+
+#[test]
+fn mtu_probe_answered() {
+    let (mut kcp1, sink1, mut kcp2, sink2) = pair(1, 500, |_| {});
+    assert!(matches!(
+        kcp1.probe_mtu(500),
+        Err(kcp::Error::InvalidMtu(500))
+    ));
+
+    kcp1.probe_mtu(1400).unwrap();
+    assert!(kcp1.is_probing_mtu());
+    assert!(kcp1.has_pending_output());
+    kcp1.update(10).unwrap();
+    let datagrams = sink1.take();
+    assert_eq!(datagrams.len(), 1);
+    assert_eq!(datagrams[0].len(), 1400);
+    assert_eq!(kcp::get_cmd(&datagrams[0]).unwrap(), KCP_CMD_WASK);
+    assert!(kcp1.is_probing_mtu());
+
+    // Answered by a remote which doesn't know about it
+    kcp2.input(&datagrams[0]).unwrap();
+    kcp2.update(10).unwrap();
+    let datagrams = sink2.take();
+    assert_eq!(kcp::get_cmd(&datagrams[0]).unwrap(), KCP_CMD_WINS);
+    kcp1.input(&datagrams[0]).unwrap();
+    assert!(!kcp1.is_probing_mtu());
+    assert_eq!(kcp1.mtu(), 1400);
+    assert_eq!(kcp1.mss(), 1400 - kcp::KCP_OVERHEAD);
+}
+
+#[test]
+fn mtu_probe_lost() {
+    let (mut kcp1, sink1, _, _) = pair(1, 500, |_| {});
+    kcp1.probe_mtu(1400).unwrap();
+    kcp1.update(10).unwrap();
+    assert_eq!(sink1.take().len(), 1);
+
+    // Not answered within the RTO
+    kcp1.update(100).unwrap();
+    assert!(kcp1.is_probing_mtu());
+    kcp1.update(300).unwrap();
+    assert!(!kcp1.is_probing_mtu());
+    assert_eq!(kcp1.mtu(), 500);
+    assert!(sink1.take().is_empty());
+
+    // Given up by another window probe
+    kcp1.probe_mtu(1400).unwrap();
+    kcp1.probe_now();
+    kcp1.update(310).unwrap();
+    assert!(!kcp1.is_probing_mtu());
+    let datagrams = sink1.take();
+    assert_eq!(datagrams.len(), 1);
+    assert_eq!(datagrams[0].len(), kcp::KCP_OVERHEAD);
+}
+
+#[test]
+fn mtu_exceeded() {
+    let (mut kcp1, sink1, mut kcp2, _) = pair(1, 500, |_| {});
+    kcp1.set_mtu(1400).unwrap();
+    kcp1.set_wndsize(1, 128);
+    kcp1.send(&[1u8; 1000]).unwrap();
+    kcp1.send(&[2u8; 1000]).unwrap();
+    kcp1.update(10).unwrap();
+    let first = sink1.take();
+    assert_eq!(first.len(), 1);
+    assert_eq!(first[0].len(), kcp::KCP_OVERHEAD + 1000);
+
+    // Stale feedback
+    kcp1.on_mtu_exceeded(1400).unwrap();
+    assert_eq!(kcp1.mtu(), 1400);
+
+    // The segment in flight can't be split, the queued message is fragmented again
+    assert!(matches!(
+        kcp1.on_mtu_exceeded(600),
+        Err(kcp::Error::InflightExceedsMtu(1))
+    ));
+    assert_eq!(kcp1.mtu(), 600);
+    kcp1.update(20).unwrap();
+    assert!(sink1.take().is_empty());
+
+    // Resent as is on timeout, in case the path carries it after all
+    let mut current = 20;
+    let resent = loop {
+        current += 10;
+        kcp1.update(current).unwrap();
+        let resent = sink1.take();
+        if !resent.is_empty() {
+            break resent;
+        }
+    };
+    assert_eq!(resent.len(), 1);
+    assert_eq!(resent[0].len(), kcp::KCP_OVERHEAD + 1000);
+    assert_eq!(kcp::get_cmd(&resent[0]).unwrap(), KCP_CMD_PUSH);
+    assert_eq!(kcp1.stats().timeout_retransmits, 1);
+
+    // Reassembled by the remote
+    kcp1.set_wndsize(128, 128);
+    for current in (current + 10..current + 200).step_by(10) {
+        kcp1.update(current).unwrap();
+    }
+    for datagram in sink1.take().iter().chain(&resent) {
+        assert!(datagram.len() <= 600 || datagram == &resent[0]);
+        kcp2.input(datagram).unwrap();
+    }
+    assert_eq!(&kcp2.recv_bytes().unwrap()[..], &[1u8; 1000][..]);
+    assert_eq!(&kcp2.recv_bytes().unwrap()[..], &[2u8; 1000][..]);
+}
+
+#[test]
+fn mtu_lowered_max_segment() {
+    let (mut kcp1, sink1, mut kcp2, _) = pair(1, 500, |_| {});
+    kcp1.set_mtu(9000).unwrap();
+    let message = vec![7u8; kcp1.mss()];
+    kcp1.send(&message).unwrap();
+    kcp1.update(10).unwrap();
+    assert_eq!(sink1.take()[0].len(), 9000);
+
+    // Lost, then resent whole as the largest segment possible after the MTU is at its minimum
+    kcp1.set_mtu(50).unwrap();
+    kcp1.update(500).unwrap();
+    let resent = sink1.take();
+    assert_eq!(resent.len(), 1);
+    assert_eq!(resent[0].len(), 9000);
+    assert_eq!(kcp1.stats().timeout_retransmits, 1);
+
+    kcp2.input(&resent[0]).unwrap();
+    assert_eq!(kcp2.recv_bytes().unwrap().to_vec(), message);
+}
+
+#[test]
+fn mtu_defaults() {
+    let kcp = Kcp::new(1, io::sink());
+    assert_eq!(kcp.mtu(), kcp::KCP_MTU_DEF);
+    assert_eq!(kcp.mss(), kcp::KCP_MTU_DEF - kcp::KCP_OVERHEAD);
+    assert_eq!(Kcp::<io::Sink>::mss_for_mtu(kcp::KCP_MTU_DEF), kcp.mss());
+    assert_eq!(kcp.snd_wnd(), kcp::KCP_WND_SND);
+    assert_eq!(kcp.rcv_wnd(), kcp::KCP_WND_RCV);
+    assert_eq!(kcp.rx_rto(), kcp::KCP_RTO_DEF);
+}
@@ -0,0 +1,431 @@
+#![cfg(feature = "std")]
+
+extern crate kcp;
+
+use std::collections::HashMap;
+use std::io::{self, Write};
+use std::sync::{Arc, Mutex};
+
+use bytes::BytesMut;
+use kcp::{BoxedKcp, CollectingKcp, FlushOutcome, Kcp};
+
+/// Collects every datagram written by `Kcp`, shareable across threads
+#[derive(Clone, Default)]
+struct Shared(Arc<Mutex<Vec<Vec<u8>>>>);
+
+impl Write for Shared {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.lock().unwrap().push(buf.to_vec());
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[test]
+fn output_boxed() {
+    let shared = Shared::default();
+
+    let mut conns: HashMap<u32, BoxedKcp> = HashMap::new();
+    conns.insert(1, Kcp::new_boxed(1, Box::new(shared.clone())));
+    conns.insert(2, Kcp::new_boxed(2, Box::new(io::sink())));
+
+    for (conv, kcp) in conns.iter_mut() {
+        assert_eq!(kcp.conv(), *conv);
+        kcp.update(0).unwrap();
+        kcp.send(b"hello").unwrap();
+        kcp.flush().unwrap();
+    }
+
+    // Only the shared output keeps what has been written
+    let datagrams = shared.0.lock().unwrap().clone();
+    assert_eq!(datagrams.len(), 1);
+
+    let mut kcp = Kcp::new(1, io::sink());
+    kcp.update(0).unwrap();
+    kcp.input(&datagrams[0]).unwrap();
+    let mut buf = [0u8; 16];
+    let n = kcp.recv(&mut buf).unwrap();
+    assert_eq!(&buf[..n], b"hello");
+
+    // Boxed connections can be moved to another thread
+    let handle = std::thread::spawn(move || conns.len());
+    assert_eq!(handle.join().unwrap(), 2);
+}
+
+/// Fails every write until it is repaired, then counts the datagrams written
+#[derive(Default)]
+struct Broken(Option<usize>);
+
+impl Write for Broken {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self.0 {
+            Some(ref mut n) => {
+                *n += 1;
+                Ok(buf.len())
+            }
+            None => Err(io::Error::other("broken")),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[test]
+fn output_set_output() {
+    let old = Shared::default();
+    let new = Shared::default();
+
+    let mut kcp = Kcp::new(1, old.clone());
+    kcp.set_nodelay(true, 10, 2, true);
+    kcp.update(0).unwrap();
+    kcp.send(b"first").unwrap();
+    kcp.flush().unwrap();
+    assert_eq!(old.0.lock().unwrap().len(), 1);
+
+    // State is kept, the unacknowledged segment is retransmitted to the new output
+    kcp.set_output(new.clone()).unwrap();
+    assert!(kcp.output_ref().0.lock().unwrap().is_empty());
+    kcp.update(1000).unwrap();
+    assert_eq!(old.0.lock().unwrap().len(), 1);
+    assert_eq!(new.0.lock().unwrap().len(), 1);
+    assert_eq!(kcp.wait_snd(), 1);
+
+    // Datagrams which could not be written are kept until the output accepts them
+    let mut kcp = Kcp::new(1, Broken::default());
+    kcp.set_nodelay(true, 10, 2, true);
+    kcp.update(0).unwrap();
+    kcp.send(b"second").unwrap();
+    assert!(kcp.flush().is_err());
+    assert!(kcp.set_output(Broken(Some(0))).is_err());
+    assert_eq!(kcp.output_ref().0, None);
+
+    kcp.output_mut().0 = Some(0);
+    let old = kcp.set_output(Broken::default()).unwrap();
+    assert_eq!(old.0, Some(1));
+}
+
+#[test]
+fn output_packet_hook() {
+    let shared = Shared::default();
+    let seen = Arc::new(Mutex::new(Vec::new()));
+
+    let mut kcp = Kcp::new(1, shared.clone());
+    kcp.set_mtu(50).unwrap();
+    kcp.set_nodelay(true, 10, 2, true);
+    let hooked = seen.clone();
+    kcp.set_packet_hook(move |datagram, current| {
+        hooked.lock().unwrap().push((datagram.to_vec(), current));
+    });
+    kcp.update(100).unwrap();
+    kcp.send(&[0u8; 60]).unwrap();
+    kcp.update(110).unwrap();
+
+    // Every datagram, right before it is written
+    let seen = seen.lock().unwrap();
+    let datagrams = shared.0.lock().unwrap();
+    assert_eq!(seen.len(), 3);
+    for ((datagram, current), written) in seen.iter().zip(datagrams.iter()) {
+        assert_eq!(datagram, written);
+        assert_eq!(*current, 110);
+    }
+}
+
+/// Accepts a number of datagrams, then blocks like a full non-blocking socket
+#[derive(Default)]
+struct Limited {
+    budget: usize,
+    written: Vec<Vec<u8>>,
+}
+
+impl Write for Limited {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.budget == 0 {
+            return Err(io::ErrorKind::WouldBlock.into());
+        }
+        self.budget -= 1;
+        self.written.push(buf.to_vec());
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[test]
+fn output_would_block() {
+    let mut kcp = Kcp::new(1, Limited::default());
+    kcp.set_mtu(50).unwrap();
+    kcp.set_nodelay(true, 10, 2, true);
+    kcp.update(0).unwrap();
+    kcp.output_mut().budget = 1;
+    kcp.send(&[7u8; 60]).unwrap();
+
+    // Encoded as sent, the rest is kept instead of being encoded again
+    match kcp.flush() {
+        Err(kcp::Error::IoError(err)) => assert_eq!(err.kind(), io::ErrorKind::WouldBlock),
+        other => panic!("unexpected {:?}", other),
+    }
+    assert_eq!(kcp.output_ref().written.len(), 1);
+    assert!(kcp.pending_output_size(0) > 0);
+    assert!(kcp.update(5).is_err());
+
+    kcp.output_mut().budget = usize::MAX;
+    kcp.update(6).unwrap();
+    assert_eq!(kcp.pending_output_size(6), 0);
+    assert_eq!(kcp.stats().segments_sent, 3);
+    let datagrams = kcp.output_mut().written.split_off(0);
+    assert_eq!(datagrams.len(), 3);
+
+    let mut peer = Kcp::new(1, io::sink());
+    peer.update(0).unwrap();
+    for datagram in &datagrams {
+        peer.input(datagram).unwrap();
+    }
+    assert_eq!(&peer.recv_bytes().unwrap()[..], &[7u8; 60][..]);
+}
+
+#[test]
+fn output_try_flush() {
+    let mut kcp = Kcp::new(1, Limited::default());
+    kcp.set_mtu(50).unwrap();
+    kcp.set_nodelay(true, 10, 2, true);
+    kcp.update(0).unwrap();
+    kcp.output_mut().budget = 1;
+    kcp.send(&[7u8; 60]).unwrap();
+
+    let pending = match kcp.try_flush().unwrap() {
+        FlushOutcome::WouldBlock { pending_bytes } => pending_bytes,
+        FlushOutcome::Complete => panic!("expected to block"),
+    };
+    assert_eq!(pending, kcp.pending_output_size(0));
+    assert_eq!(kcp.output_ref().written.len(), 1);
+
+    // Still blocked, nothing is encoded again
+    kcp.send(b"more").unwrap();
+    assert_eq!(
+        kcp.try_flush().unwrap(),
+        FlushOutcome::WouldBlock {
+            pending_bytes: pending
+        }
+    );
+
+    // Resumed, the queued datagrams go first
+    kcp.output_mut().budget = 2;
+    assert!(matches!(
+        kcp.try_flush().unwrap(),
+        FlushOutcome::WouldBlock { .. }
+    ));
+    assert_eq!(kcp.output_ref().written.len(), 3);
+    assert_eq!(kcp.stats().segments_sent, 4);
+
+    kcp.output_mut().budget = usize::MAX;
+    assert_eq!(kcp.try_flush().unwrap(), FlushOutcome::Complete);
+    assert_eq!(kcp.stats().segments_sent, 4);
+
+    let mut peer = Kcp::new(1, io::sink());
+    peer.update(0).unwrap();
+    for datagram in &kcp.output_ref().written {
+        peer.input(datagram).unwrap();
+    }
+    assert_eq!(&peer.recv_bytes().unwrap()[..], &[7u8; 60][..]);
+    assert_eq!(&peer.recv_bytes().unwrap()[..], b"more");
+}
+
+/// Fails the second write once, with an error a socket may return after an ICMP
+#[derive(Default)]
+struct FailsSecond {
+    writes: usize,
+    written: Vec<Vec<u8>>,
+}
+
+impl Write for FailsSecond {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.writes += 1;
+        if self.writes == 2 {
+            return Err(io::ErrorKind::ConnectionRefused.into());
+        }
+        self.written.push(buf.to_vec());
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[test]
+fn output_write_error_resumed() {
+    let mut kcp = Kcp::new(1, FailsSecond::default());
+    kcp.set_mtu(50).unwrap();
+    kcp.set_nodelay(true, 10, 2, true);
+    kcp.update(0).unwrap();
+    kcp.send(&[3u8; 60]).unwrap();
+
+    match kcp.flush() {
+        Err(kcp::Error::IoError(err)) => {
+            assert_eq!(err.kind(), io::ErrorKind::ConnectionRefused)
+        }
+        other => panic!("unexpected {:?}", other),
+    }
+    assert_eq!(kcp.output_ref().written.len(), 1);
+    assert_eq!(kcp.stats().segments_sent, 3);
+
+    // The kept datagrams go first, in order, without being encoded again
+    kcp.send(b"next").unwrap();
+    kcp.flush().unwrap();
+    assert_eq!(kcp.stats().segments_sent, 4);
+    let sns: Vec<u32> = kcp
+        .output_ref()
+        .written
+        .iter()
+        .map(|datagram| kcp::try_get_sn(datagram).unwrap())
+        .collect();
+    assert_eq!(sns, vec![0, 1, 2, 3]);
+
+    let mut peer = Kcp::new(1, io::sink());
+    peer.update(0).unwrap();
+    for datagram in &kcp.output_ref().written {
+        peer.input(datagram).unwrap();
+    }
+    assert_eq!(&peer.recv_bytes().unwrap()[..], &[3u8; 60][..]);
+    assert_eq!(&peer.recv_bytes().unwrap()[..], b"next");
+}
+
+/// Sends every slice as a datagram, at most `batch` of them per call as `sendmmsg` does
+#[derive(Default)]
+struct Batched {
+    batch: usize,
+    calls: usize,
+    datagrams: Vec<Vec<u8>>,
+}
+
+impl Write for Batched {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.write_vectored(&[io::IoSlice::new(buf)])
+    }
+
+    fn write_vectored(&mut self, bufs: &[io::IoSlice<'_>]) -> io::Result<usize> {
+        self.calls += 1;
+        let mut n = 0;
+        for buf in bufs.iter().take(self.batch) {
+            self.datagrams.push(buf.to_vec());
+            n += buf.len();
+        }
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[test]
+fn output_vectored() {
+    let mut kcps: Vec<Kcp<Batched>> = (0..2)
+        .map(|_| {
+            let mut kcp = Kcp::new(
+                1,
+                Batched {
+                    batch: 4,
+                    ..Batched::default()
+                },
+            );
+            kcp.set_mtu(100).unwrap();
+            kcp.set_nodelay(true, 10, 0, true);
+            kcp.update(0).unwrap();
+            kcp.send(&[7u8; 1000]).unwrap();
+            kcp
+        })
+        .collect();
+    kcps[1].set_vectored(true);
+    assert!(kcps[1].vectored());
+
+    let written: Vec<usize> = kcps
+        .iter_mut()
+        .map(|kcp| kcp.flush_and_report().unwrap().bytes_written)
+        .collect();
+    assert_eq!(written[0], written[1]);
+
+    // The same datagrams with a call for every 4 of them
+    let (plain, vectored) = (kcps[0].output_ref(), kcps[1].output_ref());
+    assert_eq!(plain.datagrams.len(), 14);
+    assert_eq!(plain.calls, 14);
+    assert_eq!(vectored.datagrams, plain.datagrams);
+    assert_eq!(vectored.calls, 4);
+}
+
+#[test]
+fn output_collecting() {
+    let mut kcp1 = CollectingKcp::new_collecting(1);
+    let mut kcp2 = Kcp::new_collecting(1);
+    kcp1.set_mtu(100).unwrap();
+    kcp1.set_nodelay(true, 10, 0, true);
+    kcp1.update(0).unwrap();
+    kcp2.update(0).unwrap();
+    assert!(kcp1.take_output().is_empty());
+
+    kcp1.send(&[7u8; 300]).unwrap();
+    kcp1.flush().unwrap();
+    let datagrams = kcp1.take_output();
+    assert_eq!(datagrams.len(), 4);
+    assert!(datagrams.iter().all(|d| d.len() <= 100));
+    assert!(kcp1.take_output().is_empty());
+
+    for datagram in &datagrams {
+        kcp2.input(datagram).unwrap();
+    }
+    assert_eq!(&kcp2.recv_bytes().unwrap()[..], &[7u8; 300][..]);
+
+    // ACKs come back the same way
+    kcp2.flush().unwrap();
+    for datagram in kcp2.take_output() {
+        kcp1.input(&datagram).unwrap();
+    }
+    assert_eq!(kcp1.wait_snd(), 0);
+}
+
+#[test]
+fn output_flush_into() {
+    let mut kcp = Kcp::new(1, io::sink());
+    kcp.set_mtu(50).unwrap();
+    kcp.set_nodelay(true, 10, 2, true);
+    kcp.update(0).unwrap();
+    let mut peer = Kcp::new(1, io::sink());
+    peer.update(0).unwrap();
+
+    let mut out = BytesMut::from(&b"kept"[..]);
+    let mut lens = Vec::new();
+    kcp.send(&[5u8; 60]).unwrap();
+    kcp.send(b"short").unwrap();
+    let n = kcp.flush_into(&mut out, &mut lens).unwrap();
+    assert_eq!(n, 3 * kcp::KCP_OVERHEAD + 60 + kcp::KCP_OVERHEAD + 5);
+    assert_eq!(out.len(), 4 + n);
+    assert_eq!(kcp.stats().segments_sent, 4);
+    assert_eq!(lens.iter().sum::<usize>(), n);
+    assert!(lens.iter().all(|&len| len <= 50));
+    assert_eq!(kcp.flush_into(&mut BytesMut::new(), &mut lens).unwrap(), 0);
+
+    // Each one is a datagram
+    let mut datagrams = &out[4..];
+    let mut other = Kcp::new(1, io::sink());
+    other.update(0).unwrap();
+    for &len in &lens {
+        let (datagram, rest) = datagrams.split_at(len);
+        other.input(datagram).unwrap();
+        datagrams = rest;
+    }
+    assert_eq!(&other.recv_bytes().unwrap()[..], &[5u8; 60][..]);
+
+    // Segments are self-delimiting, split anywhere over a stream
+    let (first, second) = out[4..].split_at(30);
+    assert_eq!(peer.input_framed(first).unwrap(), 0);
+    peer.input_framed(second).unwrap();
+    assert_eq!(&peer.recv_bytes().unwrap()[..], &[5u8; 60][..]);
+    assert_eq!(&peer.recv_bytes().unwrap()[..], b"short");
+}
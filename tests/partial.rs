@@ -0,0 +1,141 @@
+#![cfg(all(feature = "std", feature = "partial"))]
+
+extern crate kcp;
+
+mod common;
+
+use common::{pair, Sink};
+
+const KCP_CMD_PUSH: u8 = 81;
+const KCP_CMD_FORWARD: u8 = 88;
+
+/// Take all the segments written to `sink` so far
+fn take_segments(sink: &Sink) -> Vec<Vec<u8>> {
+    let mut segments = Vec::new();
+    for datagram in sink.take() {
+        let mut buf = &datagram[..];
+        while !buf.is_empty() {
+            let len = kcp::KCP_OVERHEAD + kcp::parse_header(buf).unwrap().len as usize;
+            segments.push(buf[..len].to_vec());
+            buf = &buf[len..];
+        }
+    }
+    segments
+}
+
+/// `(cmd, sn)` of the segments
+fn commands(segments: &[Vec<u8>]) -> Vec<(u8, u32)> {
+    segments
+        .iter()
+        .map(|seg| {
+            let header = kcp::parse_header(seg).unwrap();
+            (header.cmd, header.sn)
+        })
+        .collect()
+}
+
+#[test]
+fn partial_forget_expired() {
+    let (mut kcp1, sink1, mut kcp2, sink2) = pair(1, 100, |kcp| kcp.set_partial_reliability(true));
+    assert!(kcp1.partial_reliability());
+
+    // 3 fragments, only the first one arrives
+    kcp1.send_with_deadline(&[1u8; 200], 100).unwrap();
+    kcp1.send(b"fresh").unwrap();
+    kcp1.flush().unwrap();
+    let segments = take_segments(&sink1);
+    assert_eq!(
+        commands(&segments),
+        [
+            (KCP_CMD_PUSH, 0),
+            (KCP_CMD_PUSH, 1),
+            (KCP_CMD_PUSH, 2),
+            (KCP_CMD_PUSH, 3)
+        ]
+    );
+    kcp2.input(&segments[0]).unwrap();
+    kcp2.input(&segments[3]).unwrap();
+    kcp2.flush().unwrap();
+    for ack in take_segments(&sink2) {
+        kcp1.input(&ack).unwrap();
+    }
+    assert!(kcp2.recv_bytes().is_err());
+
+    // Forgotten instead of resent, and skipped again until the remote answers
+    kcp1.update(100).unwrap();
+    assert_eq!(commands(&take_segments(&sink1)), [(KCP_CMD_FORWARD, 3)]);
+    kcp1.update(110).unwrap();
+    assert!(take_segments(&sink1).is_empty());
+    kcp1.update(1000).unwrap();
+    let forward = take_segments(&sink1);
+    assert_eq!(commands(&forward), [(KCP_CMD_FORWARD, 3)]);
+
+    // The head of the message received is dropped as well
+    kcp2.input(&forward[0]).unwrap();
+    assert_eq!(&kcp2.recv_bytes().unwrap()[..], b"fresh");
+    assert!(kcp2.recv_bytes().is_err());
+    kcp2.flush().unwrap();
+    for wins in take_segments(&sink2) {
+        kcp1.input(&wins).unwrap();
+    }
+    kcp1.update(2000).unwrap();
+    assert!(take_segments(&sink1).is_empty());
+    assert_eq!(kcp1.wait_snd(), 0);
+
+    // Not delivered
+    assert!(!kcp1.is_acked(&(0..3)));
+    assert!(!kcp1.is_acked(&(2..4)));
+    assert!(kcp1.is_acked(&(3..4)));
+}
+
+#[test]
+fn partial_behind_reliable() {
+    let (mut kcp1, sink1, _, _) = pair(1, 100, |kcp| kcp.set_partial_reliability(true));
+
+    kcp1.send(b"reliable").unwrap();
+    kcp1.send_with_deadline(b"stale", 100).unwrap();
+    kcp1.flush().unwrap();
+    assert_eq!(take_segments(&sink1).len(), 2);
+
+    // Not forgotten while a reliable one before it isn't delivered
+    kcp1.update(1000).unwrap();
+    assert_eq!(
+        commands(&take_segments(&sink1)),
+        [(KCP_CMD_PUSH, 0), (KCP_CMD_PUSH, 1)]
+    );
+    assert_eq!(kcp1.wait_snd(), 2);
+}
+
+#[test]
+fn partial_rest_queued() {
+    let (mut kcp1, sink1, mut kcp2, sink2) = pair(1, 100, |kcp| kcp.set_partial_reliability(true));
+    kcp1.set_wndsize(2, 128);
+
+    // Only 2 of the 3 fragments fit in the window
+    kcp1.send_with_deadline(&[1u8; 200], 100).unwrap();
+    kcp1.send(b"next").unwrap();
+    kcp1.flush().unwrap();
+    assert_eq!(
+        commands(&take_segments(&sink1)),
+        [(KCP_CMD_PUSH, 0), (KCP_CMD_PUSH, 1)]
+    );
+
+    // The queued fragment is dropped along, the next message takes its sn
+    kcp1.update(100).unwrap();
+    let segments = take_segments(&sink1);
+    assert_eq!(
+        commands(&segments),
+        [(KCP_CMD_FORWARD, 2), (KCP_CMD_PUSH, 2)]
+    );
+    for segment in &segments {
+        kcp2.input(segment).unwrap();
+    }
+    assert_eq!(&kcp2.recv_bytes().unwrap()[..], b"next");
+    kcp2.flush().unwrap();
+    for ack in take_segments(&sink2) {
+        kcp1.input(&ack).unwrap();
+    }
+    assert_eq!(kcp1.wait_snd(), 0);
+    assert!(!kcp1.is_acked(&(0..3)));
+    assert!(kcp1.is_acked(&(2..3)));
+}
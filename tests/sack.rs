@@ -0,0 +1,88 @@
+#![cfg(all(feature = "std", feature = "sack"))]
+
+extern crate bytes;
+extern crate kcp;
+
+mod common;
+
+use bytes::Buf;
+use kcp::Kcp;
+
+use common::Sink;
+
+const KCP_CMD_PUSH: u8 = 81;
+const KCP_CMD_ACK: u8 = 82;
+const KCP_CMD_SACK: u8 = 85;
+
+/// `(cmd, payload)` of every segment in `datagram`
+fn segments(datagram: &[u8]) -> Vec<(u8, Vec<u8>)> {
+    let mut buf = datagram;
+    let mut segments = Vec::new();
+    while buf.len() >= kcp::KCP_OVERHEAD {
+        let header = kcp::parse_header(buf).unwrap();
+        buf.advance(kcp::KCP_OVERHEAD);
+        segments.push((header.cmd, buf[..header.len as usize].to_vec()));
+        buf.advance(header.len as usize);
+    }
+    segments
+}
+
+#[test]
+fn sack_ranges() {
+    let sink1 = Sink::default();
+    let sink2 = Sink::default();
+    let mut kcp1 = Kcp::new(1, sink1.clone());
+    let mut kcp2 = Kcp::new(1, sink2.clone());
+    kcp1.set_mtu(50).unwrap();
+    kcp1.set_nodelay(true, 10, 0, true);
+    kcp1.set_sack(true);
+    kcp2.set_sack(true);
+    kcp1.update(0).unwrap();
+    kcp2.update(0).unwrap();
+
+    kcp2.send(b"hi").unwrap();
+    kcp2.flush().unwrap();
+    for datagram in sink2.0.borrow_mut().drain(..) {
+        kcp1.input(&datagram).unwrap();
+    }
+    assert!(!kcp1.sack_peer());
+
+    // The usual ACK, then an empty SACK ending the datagram
+    for _ in 0..10 {
+        kcp1.send(b"ping").unwrap();
+    }
+    kcp1.flush().unwrap();
+    let datagrams: Vec<Vec<u8>> = sink1.0.borrow_mut().drain(..).collect();
+    assert_eq!(
+        segments(&datagrams[0]),
+        vec![(KCP_CMD_ACK, vec![]), (KCP_CMD_SACK, vec![])]
+    );
+    assert_eq!(datagrams.len(), 11);
+    assert!(datagrams[1..]
+        .iter()
+        .all(|d| segments(d)[0].0 == KCP_CMD_PUSH));
+
+    // sn 3 and 6 are lost
+    for (i, datagram) in datagrams.iter().enumerate() {
+        if i != 4 && i != 7 {
+            kcp2.input(datagram).unwrap();
+        }
+    }
+    assert!(kcp2.sack_peer());
+
+    kcp2.flush().unwrap();
+    let datagrams: Vec<Vec<u8>> = sink2.0.borrow_mut().drain(..).collect();
+    assert_eq!(datagrams.len(), 1);
+    let mut payload = &segments(&datagrams[0])[0].1[..];
+    assert_eq!(segments(&datagrams[0])[0].0, KCP_CMD_SACK);
+    let mut decoded = Vec::new();
+    while payload.has_remaining() {
+        decoded.push(payload.get_u32_le());
+    }
+    assert_eq!(decoded, vec![0, 3, 4, 6, 7, 10]);
+
+    kcp1.input(&datagrams[0]).unwrap();
+    assert!(kcp1.sack_peer());
+    let inflight: Vec<u32> = kcp1.inflight_segments().map(|s| s.sn).collect();
+    assert_eq!(inflight, vec![3, 6]);
+}
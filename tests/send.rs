@@ -0,0 +1,677 @@
+#![cfg(feature = "std")]
+
+extern crate bytes;
+extern crate kcp;
+
+mod common;
+
+use bytes::Bytes;
+use kcp::{Kcp, KcpBuilder};
+
+use common::{deliver, Sink};
+
+#[test]
+fn send_tracked_is_acked() {
+    let sink1 = Sink::default();
+    let sink2 = Sink::default();
+    let mut kcp1 = Kcp::new(1, sink1.clone());
+    let mut kcp2 = Kcp::new(1, sink2.clone());
+    kcp1.set_mtu(100).unwrap();
+    kcp1.set_nodelay(true, 10, 2, true);
+    kcp1.update(0).unwrap();
+    kcp2.update(0).unwrap();
+
+    let first = kcp1.send_tracked(&[1u8; 10]).unwrap();
+    let second = kcp1.send_tracked(&[2u8; 200]).unwrap();
+    assert_eq!(first, 0..1);
+    assert_eq!(second, 1..4);
+    assert!(!kcp1.is_acked(&first));
+
+    kcp1.flush().unwrap();
+    deliver(&sink1, &mut kcp2);
+    kcp2.flush().unwrap();
+    deliver(&sink2, &mut kcp1);
+
+    assert!(kcp1.is_acked(&first));
+    assert!(kcp1.is_acked(&second));
+
+    let third = kcp1.send_tracked(b"third").unwrap();
+    assert_eq!(third, 4..5);
+    assert!(!kcp1.is_acked(&third));
+}
+
+#[test]
+fn send_tracked_queued() {
+    let sink1 = Sink::default();
+    let sink2 = Sink::default();
+    let mut kcp1 = Kcp::new(1, sink1.clone());
+    let mut kcp2 = Kcp::new(1, sink2.clone());
+    kcp1.set_mtu(100).unwrap();
+    kcp1.set_nodelay(true, 10, 0, true);
+    kcp1.set_wndsize(3, 128);
+    kcp2.set_nodelay(true, 10, 0, true);
+    kcp1.update(0).unwrap();
+    kcp2.update(0).unwrap();
+
+    // Only 3 segments fit in the window, the rest waits in the queue
+    let tickets: Vec<_> = (0..4u8)
+        .map(|i| kcp1.send_tracked(&[i; 100]).unwrap())
+        .collect();
+    assert_eq!(tickets, vec![0..2, 2..4, 4..6, 6..8]);
+    kcp1.flush().unwrap();
+    assert_eq!(kcp1.snd_buf_len(), 3);
+
+    deliver(&sink1, &mut kcp2);
+    kcp2.flush().unwrap();
+    deliver(&sink2, &mut kcp1);
+    assert!(kcp1.is_acked(&tickets[0]));
+    assert!(!kcp1.is_acked(&tickets[1]));
+
+    // Still right once sent later
+    for current in (10..100).step_by(10) {
+        kcp1.update(current).unwrap();
+        deliver(&sink1, &mut kcp2);
+        kcp2.update(current).unwrap();
+        deliver(&sink2, &mut kcp1);
+    }
+    assert!(tickets.iter().all(|ticket| kcp1.is_acked(ticket)));
+    for i in 0..4u8 {
+        assert_eq!(&kcp2.recv_bytes().unwrap()[..], &[i; 100][..]);
+    }
+}
+
+#[test]
+fn send_with_deadline() {
+    let sink = Sink::default();
+    let mut kcp = Kcp::new(1, sink.clone());
+    kcp.set_mtu(100).unwrap();
+    kcp.set_nodelay(true, 10, 0, true);
+    kcp.set_wndsize(2, 128);
+    kcp.update(0).unwrap();
+
+    // Only the first 2 segments fit in the window
+    kcp.send_with_deadline(&[1u8; 200], 50).unwrap();
+    kcp.send_with_deadline(b"stale", 50).unwrap();
+    kcp.send(b"fresh").unwrap();
+    kcp.flush().unwrap();
+    assert_eq!(kcp.wait_snd(), 5);
+
+    // The rest of the first message is kept, the second one is dropped
+    kcp.update(50).unwrap();
+    assert_eq!(kcp.wait_snd(), 4);
+
+    // A stream write could be cut off in the middle
+    let mut kcp = Kcp::new_stream(1, Sink::default());
+    kcp.update(0).unwrap();
+    assert!(matches!(
+        kcp.send_with_deadline(&[1u8; 300], 50),
+        Err(kcp::Error::InvalidConfig(_))
+    ));
+    assert_eq!(kcp.wait_snd(), 0);
+}
+
+#[test]
+fn send_cancel_unsent() {
+    let sink1 = Sink::default();
+    let sink2 = Sink::default();
+    let mut kcp1 = Kcp::new(1, sink1.clone());
+    let mut kcp2 = Kcp::new(1, sink2.clone());
+    kcp1.set_mtu(100).unwrap();
+    kcp1.set_nodelay(true, 10, 0, true);
+    kcp1.set_wndsize(2, 128);
+    kcp1.update(0).unwrap();
+    kcp2.update(0).unwrap();
+
+    // The first 2 segments fit in the window
+    let msg = [7u8; 200];
+    kcp1.send(&msg).unwrap();
+    kcp1.send(b"superseded").unwrap();
+    kcp1.flush().unwrap();
+
+    // The last fragment of the first message is kept
+    assert_eq!(kcp1.cancel_unsent(), 10);
+    assert_eq!(kcp1.wait_snd(), 3);
+    assert_eq!(kcp1.cancel_unsent(), 0);
+
+    for current in (10..200).step_by(10) {
+        kcp1.update(current).unwrap();
+        deliver(&sink1, &mut kcp2);
+        kcp2.update(current).unwrap();
+        deliver(&sink2, &mut kcp1);
+    }
+
+    let mut buf = [0u8; 256];
+    let n = kcp2.recv(&mut buf).unwrap();
+    assert_eq!(&buf[..n], &msg[..]);
+    assert!(kcp2.recv(&mut buf).is_err());
+}
+
+#[test]
+fn send_cancel_unsent_if() {
+    let sink1 = Sink::default();
+    let sink2 = Sink::default();
+    let mut kcp1 = Kcp::new(1, sink1.clone());
+    let mut kcp2 = Kcp::new(1, sink2.clone());
+    kcp1.set_mtu(100).unwrap();
+    kcp1.set_nodelay(true, 10, 0, true);
+    kcp1.set_wndsize(2, 128);
+    kcp1.update(0).unwrap();
+    kcp2.update(0).unwrap();
+
+    // The first 2 segments fit in the window, the last fragment of the first message is kept
+    kcp1.send(&[1u8; 200]).unwrap();
+    kcp1.flush().unwrap();
+    kcp1.send(b"state 1").unwrap();
+    kcp1.send(&[2u8; 200]).unwrap();
+    kcp1.send(b"state 2").unwrap();
+
+    let mut seen = Vec::new();
+    let cancelled = kcp1.cancel_unsent_if(|msg| {
+        seen.push(msg.len());
+        msg == b"state 1" || msg[0] == 2
+    });
+    assert_eq!(cancelled, 2);
+    assert_eq!(seen, vec![7, 200, 7]);
+    assert_eq!(kcp1.wait_snd(), 4);
+
+    for current in (10..200).step_by(10) {
+        kcp1.update(current).unwrap();
+        deliver(&sink1, &mut kcp2);
+        kcp2.update(current).unwrap();
+        deliver(&sink2, &mut kcp1);
+    }
+
+    assert_eq!(&kcp2.recv_bytes().unwrap()[..], &[1u8; 200][..]);
+    assert_eq!(&kcp2.recv_bytes().unwrap()[..], b"state 2");
+    assert!(kcp2.recv_bytes().is_err());
+}
+
+#[test]
+fn send_shutdown() {
+    let sink1 = Sink::default();
+    let sink2 = Sink::default();
+    let mut kcp1 = Kcp::new(1, sink1.clone());
+    let mut kcp2 = Kcp::new(1, sink2.clone());
+    kcp1.set_nodelay(true, 10, 0, true);
+    kcp2.set_nodelay(true, 10, 0, true);
+    kcp1.update(0).unwrap();
+    kcp2.update(0).unwrap();
+
+    kcp1.send(b"sent").unwrap();
+    kcp1.flush().unwrap();
+    kcp1.send(b"queued").unwrap();
+    kcp1.shutdown_send();
+    assert!(kcp1.is_send_shutdown());
+    assert!(matches!(
+        kcp1.send(b"refused"),
+        Err(kcp::Error::SendShutdown)
+    ));
+    assert!(matches!(kcp1.send(b""), Err(kcp::Error::SendShutdown)));
+
+    // The first one is lost, resent with the queued one
+    sink1.0.borrow_mut().clear();
+    kcp1.update(1000).unwrap();
+    deliver(&sink1, &mut kcp2);
+    assert_eq!(&kcp2.recv_bytes().unwrap()[..], b"sent");
+    assert_eq!(&kcp2.recv_bytes().unwrap()[..], b"queued");
+    assert!(kcp2.recv_bytes().is_err());
+
+    // Still receiving the response
+    kcp2.send(b"response").unwrap();
+    kcp2.update(1010).unwrap();
+    deliver(&sink2, &mut kcp1);
+    assert_eq!(kcp1.wait_snd(), 0);
+    assert_eq!(&kcp1.recv_bytes().unwrap()[..], b"response");
+
+    kcp1.reset(2);
+    assert!(!kcp1.is_send_shutdown());
+}
+
+#[test]
+#[cfg(feature = "testing")]
+fn send_sequence_wrap() {
+    let sink1 = Sink::default();
+    let sink2 = Sink::default();
+    let mut kcp1 = Kcp::new(1, sink1.clone());
+    let mut kcp2 = Kcp::new(1, sink2.clone());
+    kcp1.set_mtu(50).unwrap();
+    kcp1.set_nodelay(true, 10, 0, true);
+    kcp2.set_nodelay(true, 10, 0, true);
+    kcp1.force_initial_sn(u32::MAX - 2, 0);
+    kcp2.force_initial_sn(0, u32::MAX - 2);
+    assert!(kcp1.sequence_near_wrap());
+    kcp1.update(0).unwrap();
+    kcp2.update(0).unwrap();
+
+    let first = kcp1.send_tracked(&[0, 0]).unwrap();
+    assert_eq!(first, u32::MAX - 2..u32::MAX - 1);
+    for i in 1..6u8 {
+        kcp1.send(&[i, i]).unwrap();
+    }
+    kcp1.flush().unwrap();
+
+    // One segment per datagram, lose the first one, the others are released by ACK across the boundary
+    let mut datagrams = sink1.0.borrow_mut().split_off(0);
+    assert_eq!(datagrams.len(), 6);
+    datagrams.remove(0);
+    for datagram in datagrams {
+        kcp2.input(&datagram).unwrap();
+    }
+    kcp2.flush().unwrap();
+    deliver(&sink2, &mut kcp1);
+    assert_eq!(kcp1.wait_snd(), 1);
+    assert!(!kcp1.is_acked(&first));
+
+    // Retransmitted, released by UNA
+    for current in (10..1000).step_by(10) {
+        kcp1.update(current).unwrap();
+        deliver(&sink1, &mut kcp2);
+        kcp2.update(current).unwrap();
+        deliver(&sink2, &mut kcp1);
+        if kcp1.wait_snd() == 0 {
+            break;
+        }
+    }
+    assert_eq!(kcp1.wait_snd(), 0);
+    assert!(kcp1.is_acked(&first));
+    assert!(!kcp1.sequence_near_wrap());
+    assert!(!kcp2.sequence_near_wrap());
+
+    let mut buf = [0u8; 8];
+    for i in 0..6u8 {
+        assert_eq!(kcp2.recv(&mut buf).unwrap(), 2);
+        assert_eq!(buf[..2], [i, i]);
+    }
+}
+
+#[test]
+fn send_bytes() {
+    let sink1 = Sink::default();
+    let mut kcp1 = Kcp::new(1, sink1.clone());
+    let mut kcp2 = Kcp::new(1, Sink::default());
+    kcp1.set_mtu(50).unwrap();
+    kcp1.set_nodelay(true, 10, 0, true);
+    kcp1.update(0).unwrap();
+    kcp2.update(0).unwrap();
+
+    let message: Vec<u8> = (0..100).collect();
+    assert_eq!(kcp1.send_bytes(Bytes::from(message.clone())).unwrap(), 100);
+    assert_eq!(kcp1.wait_snd(), 4);
+
+    // Shared with another `Bytes`
+    let shared = Bytes::from_static(b"shared");
+    assert_eq!(kcp1.send_bytes(shared.clone()).unwrap(), 6);
+    assert_eq!(kcp1.send_bytes(Bytes::new()).unwrap(), 0);
+
+    kcp1.flush().unwrap();
+    deliver(&sink1, &mut kcp2);
+    assert_eq!(&kcp2.recv_bytes().unwrap()[..], &message[..]);
+    assert_eq!(kcp2.recv_bytes().unwrap(), shared);
+}
+
+#[test]
+fn send_bytes_stream() {
+    let mut kcp = Kcp::new_stream(1, Sink::default());
+    kcp.set_mtu(100).unwrap();
+
+    kcp.send(&[0u8; 10]).unwrap();
+    kcp.send_bytes(Bytes::from(vec![1u8; 150])).unwrap();
+    assert_eq!(kcp.stream_new_segments(), 3);
+    assert_eq!(kcp.stream_coalesced_bytes(), 66);
+    assert_eq!(kcp.wait_snd(), 3);
+}
+
+#[test]
+fn send_max_fragments() {
+    let sink1 = Sink::default();
+    let sink2 = Sink::default();
+    let mut kcp1 = Kcp::new(1, sink1.clone());
+    let mut kcp2 = Kcp::new(1, sink2.clone());
+    kcp1.set_mtu(50).unwrap();
+    kcp1.set_nodelay(true, 10, 0, true);
+    kcp2.set_nodelay(true, 10, 0, true);
+    kcp1.update(0).unwrap();
+    kcp2.update(0).unwrap();
+    let mss = 50 - kcp::KCP_OVERHEAD;
+    assert_eq!(kcp1.max_fragments(), 127);
+    assert!(kcp1.send(&vec![0u8; mss * 128]).is_err());
+
+    // Legal under a 256-segment window
+    kcp1.set_wndsize(256, 256);
+    kcp2.set_wndsize(256, 256);
+    assert_eq!(kcp1.max_fragments(), 255);
+    let message: Vec<u8> = (0..mss * 255).map(|i| i as u8).collect();
+    kcp1.send(&message).unwrap();
+    assert!(kcp1.send(&vec![0u8; mss * 256]).is_err());
+    for current in (10..100).step_by(10) {
+        kcp1.update(current).unwrap();
+        deliver(&sink1, &mut kcp2);
+        kcp2.update(current).unwrap();
+        deliver(&sink2, &mut kcp1);
+    }
+    assert_eq!(&kcp2.recv_bytes().unwrap()[..], &message[..]);
+
+    kcp1.set_max_fragments(2);
+    assert!(matches!(
+        kcp1.send(&vec![0u8; mss * 2 + 1]),
+        Err(kcp::Error::UserBufTooBig)
+    ));
+    kcp1.send(&vec![0u8; mss * 2]).unwrap();
+    kcp1.set_max_fragments(1000);
+    assert_eq!(kcp1.max_fragments(), 256);
+}
+
+#[test]
+fn send_max_fragments_remote_wnd() {
+    let sink1 = Sink::default();
+    let sink2 = Sink::default();
+    let mut kcp1 = Kcp::new(1, sink1.clone());
+    let mut kcp2 = KcpBuilder::new()
+        .wndsize(32, 16)
+        .build(1, sink2.clone())
+        .unwrap();
+    kcp1.set_mtu(50).unwrap();
+    kcp1.set_nodelay(true, 10, 0, true);
+    kcp1.update(0).unwrap();
+    kcp2.update(0).unwrap();
+    let mss = 50 - kcp::KCP_OVERHEAD;
+
+    // Not known before the remote is heard from
+    assert_eq!(kcp1.max_fragments(), 127);
+    kcp2.send(b"hello").unwrap();
+    kcp2.flush().unwrap();
+    deliver(&sink2, &mut kcp1);
+    assert_eq!(kcp1.max_fragments(), 15);
+
+    // Never complete in the window of the remote
+    assert!(matches!(
+        kcp1.send(&vec![0u8; mss * 16]),
+        Err(kcp::Error::UserBufTooBig)
+    ));
+    let message: Vec<u8> = (0..mss * 15).map(|i| i as u8).collect();
+    kcp1.send(&message).unwrap();
+    for current in (10..200).step_by(10) {
+        kcp1.update(current).unwrap();
+        deliver(&sink1, &mut kcp2);
+        kcp2.update(current).unwrap();
+        deliver(&sink2, &mut kcp1);
+    }
+    assert_eq!(&kcp2.recv_bytes().unwrap()[..], &message[..]);
+}
+
+#[test]
+fn send_no_coalesce() {
+    let sink = Sink::default();
+    let mut kcp = Kcp::new_stream(1, sink.clone());
+
+    kcp.send(b"hello").unwrap();
+    kcp.send(b" world").unwrap();
+    assert_eq!(kcp.wait_snd(), 1);
+
+    kcp.send_no_coalesce(b"boundary").unwrap();
+    assert_eq!(kcp.wait_snd(), 2);
+
+    kcp.send(b" appended").unwrap();
+    assert_eq!(kcp.wait_snd(), 2);
+}
+
+#[test]
+fn send_empty() {
+    let sink = Sink::default();
+    let mut kcp = Kcp::new(1, sink.clone());
+    assert_eq!(kcp.send(&[]).unwrap(), 0);
+    assert_eq!(kcp.wait_snd(), 0);
+
+    let mut kcp = Kcp::new_stream(1, sink.clone());
+    assert_eq!(kcp.send(&[]).unwrap(), 0);
+    assert_eq!(kcp.wait_snd(), 0);
+
+    let range = kcp.send_tracked(&[]).unwrap();
+    assert!(range.is_empty());
+}
+
+#[test]
+fn send_stream_coalescing() {
+    let mut kcp = Kcp::new_stream(1, Sink::default());
+    kcp.set_mtu(100).unwrap();
+
+    kcp.send(&[0u8; 10]).unwrap();
+    kcp.send(&[0u8; 20]).unwrap();
+    assert_eq!(kcp.stream_new_segments(), 1);
+    assert_eq!(kcp.stream_coalesced_bytes(), 20);
+
+    // Fills up the segment, the rest goes into 2 new segments
+    kcp.send(&[0u8; 150]).unwrap();
+    assert_eq!(kcp.stream_new_segments(), 3);
+    assert_eq!(kcp.stream_coalesced_bytes(), 66);
+}
+
+#[test]
+fn send_queue_limit() {
+    let mut kcp = Kcp::new(1, Sink::default());
+    kcp.set_mtu(100).unwrap();
+    kcp.set_snd_queue_limit(3);
+
+    kcp.send(&[0u8; 100]).unwrap();
+    assert_eq!(kcp.snd_queue_len(), 2);
+
+    // Nothing is enqueued if the whole message doesn't fit
+    assert!(matches!(
+        kcp.send(&[0u8; 100]),
+        Err(kcp::Error::SendQueueFull)
+    ));
+    assert_eq!(kcp.snd_queue_len(), 2);
+    kcp.send(&[0u8; 10]).unwrap();
+    assert_eq!(kcp.snd_queue_len(), 3);
+
+    // Appending to the last segment in stream mode doesn't need room
+    let mut kcp = Kcp::new_stream(1, Sink::default());
+    kcp.set_mtu(100).unwrap();
+    kcp.set_snd_queue_limit(1);
+
+    kcp.send(&[0u8; 10]).unwrap();
+    kcp.send(&[0u8; 20]).unwrap();
+    assert!(matches!(
+        kcp.send(&[0u8; 50]),
+        Err(kcp::Error::SendQueueFull)
+    ));
+    assert_eq!(kcp.stream_coalesced_bytes(), 20);
+    assert_eq!(kcp.wait_snd(), 1);
+}
+
+#[test]
+fn send_total_buffer_limit() {
+    let sink1 = Sink::default();
+    let mut kcp1 = Kcp::new(1, sink1.clone());
+    let mut kcp2 = Kcp::new(1, Sink::default());
+    kcp1.set_mtu(100).unwrap();
+    kcp1.set_nodelay(true, 10, 0, true);
+    kcp1.set_total_buffer_limit(300);
+    kcp1.update(0).unwrap();
+    kcp2.update(0).unwrap();
+
+    // 100 bytes in 2 segments with their headers
+    kcp1.send(&[0u8; 100]).unwrap();
+    assert_eq!(kcp1.buffered_bytes(), 100 + 2 * kcp::KCP_OVERHEAD);
+    kcp1.send(&[1u8; 100]).unwrap();
+    assert!(matches!(
+        kcp1.send(&[2u8; 1]),
+        Err(kcp::Error::SendQueueFull)
+    ));
+
+    // Still counted while in flight
+    kcp1.flush().unwrap();
+    assert_eq!(kcp1.snd_queue_len(), 0);
+    assert_eq!(kcp1.buffered_bytes(), 200 + 4 * kcp::KCP_OVERHEAD);
+    assert!(kcp1.send(&[2u8; 1]).is_err());
+
+    // Received but not read yet
+    deliver(&sink1, &mut kcp2);
+    assert_eq!(kcp2.buffered_bytes(), 200 + 4 * kcp::KCP_OVERHEAD);
+    kcp2.recv_all();
+    assert_eq!(kcp2.buffered_bytes(), 0);
+}
+
+#[test]
+fn send_reset() {
+    let sink1 = Sink::default();
+    let sink2 = Sink::default();
+    let mut kcp1 = Kcp::new(1, sink1.clone());
+    let mut kcp2 = Kcp::new(1, sink2.clone());
+    kcp1.set_nodelay(true, 10, 2, true);
+    kcp1.set_mtu(100).unwrap();
+    kcp1.update(0).unwrap();
+    kcp2.update(0).unwrap();
+
+    kcp1.send(&[1u8; 300]).unwrap();
+    kcp1.flush().unwrap();
+    deliver(&sink1, &mut kcp2);
+    kcp2.flush().unwrap();
+    kcp1.update(30).unwrap();
+    deliver(&sink2, &mut kcp1);
+    kcp1.send(&[2u8; 300]).unwrap();
+
+    kcp1.reset(2);
+    assert_eq!(
+        format!("{:?}", kcp1),
+        format!("{:?}", Kcp::new(2, Sink::default()))
+    );
+
+    // Works as a new conversation
+    let mut kcp3 = Kcp::new(2, Sink::default());
+    sink1.0.borrow_mut().clear();
+    kcp1.update(0).unwrap();
+    kcp3.update(0).unwrap();
+    kcp1.send(b"again").unwrap();
+    kcp1.flush().unwrap();
+    deliver(&sink1, &mut kcp3);
+    let mut buf = [0u8; 16];
+    let n = kcp3.recv(&mut buf).unwrap();
+    assert_eq!(&buf[..n], b"again");
+}
+
+#[test]
+fn send_set_mtu_refragments() {
+    let sink1 = Sink::default();
+    let sink2 = Sink::default();
+    let mut kcp1 = Kcp::new(1, sink1.clone());
+    let mut kcp2 = Kcp::new(1, sink2.clone());
+    kcp1.set_nodelay(true, 10, 0, true);
+    kcp1.update(0).unwrap();
+    kcp2.update(0).unwrap();
+
+    let msg: Vec<u8> = (0..3400).map(|i| i as u8).collect();
+    kcp1.send(&msg).unwrap();
+    kcp1.send(b"tail").unwrap();
+
+    // Too many fragments, nothing changed
+    assert!(kcp1.set_mtu(50).is_err());
+    assert_eq!(kcp1.mtu(), 1400);
+
+    kcp1.set_mtu(200).unwrap();
+    kcp1.flush().unwrap();
+    assert!(sink1.0.borrow().iter().all(|d| d.len() <= 200));
+
+    deliver(&sink1, &mut kcp2);
+    let mut buf = [0u8; 4096];
+    let n = kcp2.recv(&mut buf).unwrap();
+    assert_eq!(&buf[..n], &msg[..]);
+    let n = kcp2.recv(&mut buf).unwrap();
+    assert_eq!(&buf[..n], b"tail");
+}
+
+#[test]
+#[cfg(feature = "testing")]
+fn send_sequence_wrap_reordered() {
+    let sink1 = Sink::default();
+    let sink2 = Sink::default();
+    let mut kcp1 = Kcp::new(1, sink1.clone());
+    let mut kcp2 = Kcp::new(1, sink2.clone());
+    kcp1.set_mtu(50).unwrap();
+    kcp1.set_nodelay(true, 10, 1, true);
+    kcp2.set_nodelay(true, 10, 0, true);
+    kcp1.force_initial_sn(u32::MAX - 1, 0);
+    kcp2.force_initial_sn(0, u32::MAX - 1);
+    kcp1.update(0).unwrap();
+    kcp2.update(0).unwrap();
+
+    for i in 0..4u8 {
+        kcp1.send(&[i, i]).unwrap();
+    }
+    kcp1.flush().unwrap();
+
+    // Lose the first one, the others arrive in reverse across the boundary
+    let mut datagrams = sink1.0.borrow_mut().split_off(0);
+    assert_eq!(datagrams.len(), 4);
+    datagrams.remove(0);
+    for datagram in datagrams.iter().rev() {
+        kcp2.input(datagram).unwrap();
+    }
+    assert_eq!(kcp2.rcv_buf_len(), 3);
+    kcp2.flush().unwrap();
+    deliver(&sink2, &mut kcp1);
+    assert_eq!(kcp1.wait_snd(), 1);
+
+    // Fast resent before the RTO
+    kcp1.update(10).unwrap();
+    let datagrams = sink1.0.borrow_mut().split_off(0);
+    assert_eq!(datagrams.len(), 1);
+    assert_eq!(kcp::try_get_sn(&datagrams[0]).unwrap(), u32::MAX - 1);
+
+    kcp2.input(&datagrams[0]).unwrap();
+    let mut buf = [0u8; 8];
+    for i in 0..4u8 {
+        assert_eq!(kcp2.recv(&mut buf).unwrap(), 2);
+        assert_eq!(buf[..2], [i, i]);
+    }
+}
+
+#[test]
+#[cfg(feature = "testing")]
+fn send_sequence_numbers() {
+    let sink1 = Sink::default();
+    let sink2 = Sink::default();
+    let mut kcp1 = Kcp::new(1, sink1.clone());
+    let mut kcp2 = Kcp::new(1, sink2.clone());
+    kcp1.set_nodelay(true, 10, 0, true);
+    kcp1.force_initial_sn(u32::MAX - 1, 0);
+    kcp2.force_initial_sn(0, u32::MAX - 1);
+    kcp1.update(0).unwrap();
+    kcp2.update(0).unwrap();
+    assert_eq!(kcp1.snd_una(), u32::MAX - 1);
+    assert_eq!(kcp1.snd_nxt(), u32::MAX - 1);
+    assert_eq!(kcp2.rcv_nxt(), u32::MAX - 1);
+
+    for i in 0..4u8 {
+        kcp1.send(&[i]).unwrap();
+    }
+    kcp1.flush().unwrap();
+    assert_eq!(kcp1.snd_una(), u32::MAX - 1);
+    assert_eq!(kcp1.snd_nxt(), 2);
+    assert_eq!(kcp::serial_diff(kcp1.snd_nxt(), kcp1.snd_una()), 4);
+
+    deliver(&sink1, &mut kcp2);
+    assert_eq!(kcp2.rcv_nxt(), 2);
+    kcp2.flush().unwrap();
+    deliver(&sink2, &mut kcp1);
+    assert_eq!(kcp1.snd_una(), 2);
+}
+
+#[test]
+fn send_max_message_size() {
+    assert_eq!(Kcp::<Sink>::OVERHEAD, kcp::KCP_OVERHEAD);
+    assert_eq!(Kcp::<Sink>::mss_for_mtu(500), 500 - kcp::KCP_OVERHEAD);
+    assert_eq!(Kcp::<Sink>::mss_for_mtu(10), 0);
+
+    let mut kcp = Kcp::new(1, Sink::default());
+    kcp.set_mtu(500).unwrap();
+    assert_eq!(Kcp::<Sink>::mss_for_mtu(500), kcp.mss());
+
+    let max = Kcp::<Sink>::max_message_size(500, kcp.rcv_wnd());
+    assert_eq!(max, kcp.mss() * kcp.max_fragments());
+    assert!(matches!(
+        kcp.send(&vec![0u8; max + 1]),
+        Err(kcp::Error::UserBufTooBig)
+    ));
+    assert_eq!(kcp.send(&vec![0u8; max]).unwrap(), max);
+}
@@ -0,0 +1,38 @@
+extern crate kcp;
+
+use kcp::{serial_diff, serial_le, serial_lt};
+
+#[test]
+fn serial_ordering() {
+    assert_eq!(serial_diff(5, 3), 2);
+    assert_eq!(serial_diff(3, 5), -2);
+    assert!(serial_lt(3, 5));
+    assert!(!serial_lt(5, 5));
+    assert!(serial_le(5, 5));
+    assert!(!serial_le(6, 5));
+}
+
+#[test]
+fn serial_wraparound() {
+    assert_eq!(serial_diff(0, u32::MAX), 1);
+    assert_eq!(serial_diff(u32::MAX, 0), -1);
+    assert!(serial_lt(u32::MAX, 0));
+    assert!(serial_lt(u32::MAX - 10, 10));
+    assert!(!serial_lt(10, u32::MAX - 10));
+    assert!(serial_le(u32::MAX, u32::MAX.wrapping_add(1)));
+
+    // Beyond i32 of the plain difference
+    assert_eq!(serial_diff(0x8000_0010, 0x10), i32::MIN);
+    assert_eq!(serial_diff(0x7fff_fff0, 0x8000_0010), -0x20);
+    assert!(serial_lt(0x7fff_fff0, 0x8000_0010));
+    assert!(serial_lt(0x8000_0010, 0x0000_0100 + 0x8000_0010));
+}
+
+#[test]
+fn serial_half_range() {
+    // The latest half of the numbers before `b` is before it, the rest after it
+    let b: u32 = 0xffff_fff0;
+    assert!(serial_lt(b.wrapping_sub(0x7fff_ffff), b));
+    assert!(!serial_lt(b.wrapping_add(0x7fff_ffff), b));
+    assert!(serial_lt(b, b.wrapping_add(0x7fff_ffff)));
+}
@@ -0,0 +1,95 @@
+#![cfg(all(feature = "std", feature = "session"))]
+
+extern crate kcp;
+
+mod common;
+
+use kcp::Kcp;
+
+use common::Sink;
+
+fn session(token: u32) -> (Kcp<Sink>, Sink) {
+    let sink = Sink::default();
+    let mut kcp = Kcp::new(1, sink.clone());
+    kcp.set_mtu(100).unwrap();
+    kcp.set_session_token(Some(token)).unwrap();
+    kcp.set_nodelay(true, 10, 0, true);
+    kcp.update(0).unwrap();
+    (kcp, sink)
+}
+
+#[test]
+fn session_roundtrip() {
+    let (mut kcp1, sink1) = session(0xdead_beef);
+    let (mut kcp2, sink2) = session(0xdead_beef);
+    assert_eq!(kcp1.session_token(), Some(0xdead_beef));
+    assert_eq!(kcp1.mss(), 100 - kcp::KCP_OVERHEAD - 4);
+
+    let msg: Vec<u8> = (0..200).map(|i| i as u8).collect();
+    kcp1.send(&msg).unwrap();
+    kcp1.flush().unwrap();
+    let datagrams = sink1.take();
+    assert_eq!(datagrams[0].len(), 100);
+    assert_eq!(&datagrams[0][96..], &0xdead_beef_u32.to_le_bytes());
+
+    for datagram in &datagrams {
+        kcp2.input(datagram).unwrap();
+    }
+    assert_eq!(&kcp2.recv_bytes().unwrap()[..], &msg[..]);
+
+    kcp2.flush().unwrap();
+    for datagram in sink2.take() {
+        kcp1.input(&datagram).unwrap();
+    }
+    assert_eq!(kcp1.wait_snd(), 0);
+}
+
+#[test]
+fn session_cross_talk() {
+    // Two sessions given the same conv
+    let (mut kcp1, sink1) = session(1);
+    let (mut other, _) = session(2);
+    kcp1.send(b"misrouted").unwrap();
+    kcp1.flush().unwrap();
+    let datagram = sink1.take().remove(0);
+
+    assert!(matches!(
+        other.input(&datagram),
+        Err(kcp::Error::SessionMismatch)
+    ));
+    assert!(other.recv_bytes().is_err());
+    assert_eq!(other.pending_acks(), 0);
+}
+
+#[cfg(feature = "checksum")]
+#[test]
+fn session_checksum() {
+    let (mut kcp1, sink1) = session(7);
+    let (mut kcp2, _) = session(7);
+    kcp1.set_checksum(true).unwrap();
+    kcp2.set_checksum(true).unwrap();
+    assert_eq!(kcp1.mss(), 100 - kcp::KCP_OVERHEAD - 8);
+
+    kcp1.send(b"both").unwrap();
+    kcp1.flush().unwrap();
+    let datagram = sink1.take().remove(0);
+    assert_eq!(datagram.len(), kcp::KCP_OVERHEAD + 4 + 8);
+    kcp2.input(&datagram).unwrap();
+    assert_eq!(&kcp2.recv_bytes().unwrap()[..], b"both");
+}
+
+#[test]
+fn session_reset() {
+    let (mut kcp1, sink1) = session(0xdead_beef);
+    kcp1.reset(2);
+    assert_eq!(kcp1.session_token(), None);
+    assert_eq!(kcp1.mss(), 1400 - kcp::KCP_OVERHEAD);
+
+    // The new conversation doesn't carry the secret of the old one
+    kcp1.update(0).unwrap();
+    kcp1.send(b"plain").unwrap();
+    kcp1.flush().unwrap();
+    let datagram = sink1.take().remove(0);
+    assert_eq!(datagram.len(), kcp::KCP_OVERHEAD + 5);
+    assert!(!datagram.ends_with(&0xdead_beef_u32.to_le_bytes()));
+}
@@ -0,0 +1,94 @@
+#![cfg(feature = "std")]
+
+extern crate bytes;
+extern crate kcp;
+
+mod common;
+
+use std::io::Write;
+
+use bytes::Buf;
+use kcp::{Kcp, KcpSnapshot};
+
+use common::Sink;
+
+const KCP_CMD_PUSH: u8 = 81;
+
+/// Feed all the datagrams written to `sink` so far into `kcp`, returns `sn` of the data segments
+fn deliver_pushes<W: Write>(sink: &Sink, kcp: &mut Kcp<W>) -> Vec<u32> {
+    let mut pushes = Vec::new();
+    for datagram in sink.take() {
+        let mut buf = &datagram[..];
+        while buf.len() >= kcp::KCP_OVERHEAD {
+            let cmd = buf[4];
+            let sn = (&buf[12..]).get_u32_le();
+            let len = (&buf[20..]).get_u32_le() as usize;
+            if cmd == KCP_CMD_PUSH {
+                pushes.push(sn);
+            }
+            buf.advance(kcp::KCP_OVERHEAD + len);
+        }
+        kcp.input(&datagram).unwrap();
+    }
+    pushes
+}
+
+#[test]
+fn snapshot_restore() {
+    let sink1 = Sink::default();
+    let sink2 = Sink::default();
+    let mut kcp1 = Kcp::new(1, sink1.clone());
+    let mut kcp2 = Kcp::new(1, sink2.clone());
+    kcp1.set_nodelay(true, 10, 2, true);
+    kcp1.set_wndsize(4, 128);
+    kcp1.update(0).unwrap();
+    kcp2.update(0).unwrap();
+
+    for i in 0..10u8 {
+        kcp1.send(&[i; 100]).unwrap();
+    }
+    kcp1.flush().unwrap();
+    assert_eq!(deliver_pushes(&sink1, &mut kcp2), vec![0, 1, 2, 3]);
+    kcp2.flush().unwrap();
+    kcp1.update(20).unwrap();
+    deliver_pushes(&sink2, &mut kcp1);
+
+    // 0..4 are acknowledged, 4..8 in flight
+    kcp1.flush().unwrap();
+    sink1.0.borrow_mut().clear();
+    let snapshot: KcpSnapshot = kcp1.snapshot();
+    drop(kcp1);
+
+    // Resumes on another clock, only the in-flight segments are retransmitted
+    let sink3 = Sink::default();
+    let mut kcp3 = Kcp::restore(snapshot, sink3.clone());
+    kcp3.update(100_000).unwrap();
+    assert!(sink3.0.borrow().is_empty());
+
+    kcp3.update(100_200).unwrap();
+    assert_eq!(deliver_pushes(&sink3, &mut kcp2), vec![4, 5, 6, 7]);
+
+    let mut current = 100_200;
+    while kcp3.wait_snd() > 0 {
+        current += 20;
+        kcp2.update(current).unwrap();
+        deliver_pushes(&sink2, &mut kcp3);
+        kcp3.update(current).unwrap();
+        assert!(deliver_pushes(&sink3, &mut kcp2).iter().all(|&sn| sn >= 4));
+    }
+
+    let mut buf = [0u8; 100];
+    for i in 0..10u8 {
+        assert_eq!(kcp2.recv(&mut buf).unwrap(), 100);
+        assert_eq!(buf, [i; 100]);
+    }
+}
+
+#[cfg(feature = "serde")]
+#[test]
+fn snapshot_serde() {
+    fn serializable<T: serde::Serialize + for<'de> serde::Deserialize<'de>>(_: &T) {}
+
+    let kcp = Kcp::new(1, std::io::sink());
+    serializable(&kcp.snapshot());
+}
@@ -0,0 +1,49 @@
+#![cfg(feature = "socket")]
+
+extern crate kcp;
+
+use std::net::UdpSocket;
+use std::thread;
+use std::time::Duration;
+
+use kcp::KcpSocket;
+
+#[test]
+fn socket_echo() {
+    let s1 = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let s2 = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let a1 = s1.local_addr().unwrap();
+    let a2 = s2.local_addr().unwrap();
+
+    let mut k1 = KcpSocket::new(0x11223344, s1, a2).unwrap();
+    let mut k2 = KcpSocket::new(0x11223344, s2, a1).unwrap();
+    k1.kcp_mut().set_nodelay(true, 10, 2, true);
+    k2.kcp_mut().set_nodelay(true, 10, 2, true);
+
+    let echo = thread::spawn(move || {
+        for _ in 0..10 {
+            let msg = k2.recv().unwrap();
+            k2.send(&msg).unwrap();
+        }
+
+        // Keep serving retransmissions until the peer has everything
+        while k2.kcp().wait_snd() > 0 {
+            k2.poll().unwrap();
+            thread::sleep(Duration::from_millis(1));
+        }
+    });
+
+    for i in 0..10u32 {
+        let msg = vec![i as u8; 100 + i as usize * 1000];
+        k1.send(&msg).unwrap();
+        assert_eq!(k1.recv().unwrap(), msg);
+    }
+
+    // ACK the last echo
+    while !echo.is_finished() {
+        k1.poll().unwrap();
+        thread::sleep(Duration::from_millis(1));
+    }
+
+    echo.join().unwrap();
+}
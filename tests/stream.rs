@@ -0,0 +1,95 @@
+#![cfg(feature = "std")]
+
+extern crate kcp;
+
+mod common;
+
+use std::io::{ErrorKind, Read, Write};
+
+use kcp::{Kcp, KcpStream};
+
+use common::Sink;
+
+#[test]
+fn stream_read_write() {
+    let sink1 = Sink::default();
+    let mut s1 = KcpStream::new(Kcp::new(1, sink1.clone()));
+    let mut s2 = KcpStream::new(Kcp::new(1, Sink::default()));
+    s1.get_mut().set_nodelay(true, 10, 0, true);
+    s1.drive(0).unwrap();
+    s2.drive(0).unwrap();
+
+    let mut buf = [0u8; 4];
+    assert_eq!(s2.read(&mut buf).unwrap_err().kind(), ErrorKind::WouldBlock);
+
+    s1.write_all(b"hello ").unwrap();
+    s1.write_all(b"world").unwrap();
+    for datagram in sink1.0.borrow_mut().drain(..) {
+        s2.input(&datagram).unwrap();
+    }
+
+    // Messages larger than the buffer are read in pieces
+    let mut received = Vec::new();
+    loop {
+        match s2.read(&mut buf) {
+            Ok(n) => received.extend_from_slice(&buf[..n]),
+            Err(err) if err.kind() == ErrorKind::WouldBlock => break,
+            Err(err) => panic!("{}", err),
+        }
+    }
+    assert_eq!(received, b"hello world");
+}
+
+#[test]
+fn stream_write_before_drive() {
+    let sink1 = Sink::default();
+    let mut s1 = KcpStream::new(Kcp::new(1, sink1.clone()));
+    let mut s2 = KcpStream::new(Kcp::new(1, Sink::default()));
+    s2.drive(0).unwrap();
+
+    // Queued even though it can't be flushed yet
+    s1.write_all(b"early").unwrap();
+    assert!(s1.flush().is_err());
+    assert!(sink1.0.borrow().is_empty());
+
+    s1.drive(0).unwrap();
+    s1.flush().unwrap();
+    let datagrams: Vec<Vec<u8>> = sink1.0.borrow_mut().drain(..).collect();
+    assert_eq!(datagrams.len(), 1);
+    s2.input(&datagrams[0]).unwrap();
+    let mut buf = [0u8; 16];
+    assert_eq!(s2.read(&mut buf).unwrap(), 5);
+    assert_eq!(&buf[..5], b"early");
+}
+
+#[test]
+fn stream_empty_message() {
+    let sink1 = Sink::default();
+    let mut kcp1 = Kcp::new(1, sink1.clone());
+    let mut s2 = KcpStream::new(Kcp::new(1, Sink::default()));
+    kcp1.update(0).unwrap();
+    s2.drive(0).unwrap();
+
+    // An empty PUSH, as a keepalive of another implementation, before the data
+    let mut empty = vec![1, 0, 0, 0, 81, 0, 128, 0];
+    empty.extend_from_slice(&[0u8; 16]);
+    s2.input(&empty).unwrap();
+    assert_eq!(s2.get_ref().ready_message_count(), 1);
+
+    // Its sn 0 is taken by the empty one
+    kcp1.set_nodelay(false, 10, 0, true);
+    kcp1.send(b"lost").unwrap();
+    kcp1.flush().unwrap();
+    sink1.0.borrow_mut().clear();
+    kcp1.send(b"data").unwrap();
+    kcp1.flush().unwrap();
+    for datagram in sink1.0.borrow_mut().drain(..) {
+        s2.input(&datagram).unwrap();
+    }
+
+    // Not taken as the end of stream
+    let mut buf = [0u8; 16];
+    assert_eq!(s2.read(&mut buf).unwrap(), 4);
+    assert_eq!(&buf[..4], b"data");
+    assert_eq!(s2.read(&mut buf).unwrap_err().kind(), ErrorKind::WouldBlock);
+}
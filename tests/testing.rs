@@ -0,0 +1,203 @@
+#![cfg(feature = "testing")]
+
+extern crate kcp;
+
+use kcp::testing;
+
+#[test]
+fn testing_loopback() {
+    let mut pair = testing::loopback(1);
+    pair.a().set_nodelay(true, 10, 2, true);
+    pair.b().set_nodelay(true, 10, 2, true);
+
+    // Lose every third datagram
+    let mut n = 0;
+    pair.set_loss(move |_| {
+        n += 1;
+        n % 3 == 0
+    });
+
+    for i in 0..10u8 {
+        pair.a().send(&[i; 100]).unwrap();
+    }
+
+    let mut received = Vec::new();
+    let mut buf = [0u8; 128];
+    for current in (0..5000).step_by(10) {
+        pair.pump(current).unwrap();
+        while let Ok(n) = pair.b().recv(&mut buf) {
+            received.push(buf[..n].to_vec());
+        }
+        if received.len() == 10 {
+            break;
+        }
+    }
+
+    let expected: Vec<Vec<u8>> = (0..10u8).map(|i| vec![i; 100]).collect();
+    assert_eq!(received, expected);
+}
+
+#[test]
+fn testing_force_ack_upto() {
+    let mut kcp = kcp::Kcp::new(1, testing::Channel::default());
+    kcp.set_nodelay(true, 10, 0, true);
+    kcp.update(0).unwrap();
+    for i in 0..5u8 {
+        kcp.send(&[i; 10]).unwrap();
+    }
+    kcp.flush().unwrap();
+    assert_eq!(kcp.snd_buf_len(), 5);
+
+    kcp.force_ack_upto(3);
+    assert_eq!(kcp.snd_buf_len(), 2);
+
+    // Stale ones do nothing
+    kcp.force_ack_upto(1);
+    assert_eq!(kcp.snd_buf_len(), 2);
+    kcp.force_ack_upto(5);
+    assert_eq!(kcp.wait_snd(), 0);
+}
+
+#[test]
+fn testing_inject_segment() {
+    let mut kcp = kcp::Kcp::new(1, testing::Channel::default());
+    kcp.update(0).unwrap();
+    let header = |sn: u32, frg: u8| kcp::SegmentHeader {
+        conv: 1,
+        cmd: 81,
+        frg,
+        wnd: 128,
+        ts: 0,
+        sn,
+        una: 0,
+        len: 0,
+    };
+
+    // A message of two fragments, the second one first
+    kcp.inject_segment(&header(1, 0), b"world");
+    assert_eq!(kcp.rcv_buf_len(), 1);
+    assert!(!kcp.recv_ready());
+    kcp.inject_segment(&header(0, 1), b"hello ");
+    kcp.inject_segment(&header(0, 1), b"hello ");
+    assert_eq!(kcp.rcv_buf_len(), 0);
+    assert_eq!(&kcp.recv_bytes().unwrap()[..], b"hello world");
+    assert_eq!(kcp.pending_acks(), 0);
+}
+
+#[test]
+fn testing_corrupt_fragment_chain() {
+    let mut kcp = kcp::Kcp::new(1, testing::Channel::default());
+    kcp.update(0).unwrap();
+    let header = |sn: u32, frg: u8| kcp::SegmentHeader {
+        conv: 1,
+        cmd: 81,
+        frg,
+        wnd: 128,
+        ts: 0,
+        sn,
+        una: 0,
+        len: 0,
+    };
+
+    // The head claims 3 fragments, the second one ends a message
+    kcp.inject_segment(&header(0, 2), b"broken");
+    kcp.inject_segment(&header(1, 0), b"whole");
+    kcp.inject_segment(&header(2, 0), b"next");
+    assert!(!kcp.recv_ready());
+    assert!(matches!(
+        kcp.peeksize(),
+        Err(kcp::Error::CorruptFragmentChain)
+    ));
+
+    // Dropped up to the break, the rest is received
+    let mut buf = [0u8; 16];
+    assert!(matches!(
+        kcp.recv(&mut buf),
+        Err(kcp::Error::CorruptFragmentChain)
+    ));
+    assert_eq!(kcp.recv(&mut buf).unwrap(), 5);
+    assert_eq!(&buf[..5], b"whole");
+
+    // Skipped by a batch
+    kcp.inject_segment(&header(3, 1), b"bad");
+    kcp.inject_segment(&header(4, 1), b"one ");
+    kcp.inject_segment(&header(5, 0), b"two");
+    let messages = kcp.recv_all();
+    assert_eq!(messages.len(), 2);
+    assert_eq!(&messages[0][..], b"next");
+    assert_eq!(&messages[1][..], b"one two");
+}
+
+#[test]
+fn testing_window_tell_threshold() {
+    // Time to deliver everything to a slow reader, with the first window answer lost
+    let run = |threshold: f32| {
+        let mut pair = testing::loopback(1);
+        pair.a().set_nodelay(true, 10, 0, true);
+        pair.a().set_wndsize(256, 128);
+        pair.b().set_nodelay(true, 10, 0, true);
+        pair.b().set_window_tell_threshold(threshold);
+
+        let mut lost = false;
+        pair.set_loss(move |mut datagram| {
+            let mut wins = false;
+            while let Ok(header) = kcp::parse_header(datagram) {
+                wins |= header.cmd == 84;
+                datagram = &datagram[kcp::KCP_OVERHEAD + header.len as usize..];
+            }
+            if wins && !lost {
+                lost = true;
+                return true;
+            }
+            false
+        });
+
+        for i in 0..300u32 {
+            pair.a().send(&i.to_le_bytes()).unwrap();
+        }
+
+        let mut received = 0u32;
+        let mut current = 0;
+        while received < 300 && current < 20000 {
+            pair.pump(current).unwrap();
+            if current >= 1000 {
+                if let Ok(msg) = pair.b().recv_bytes() {
+                    assert_eq!(&msg[..], &received.to_le_bytes());
+                    received += 1;
+                }
+            }
+            current += 10;
+        }
+        assert_eq!(received, 300);
+        current
+    };
+
+    // Stalled until the window probe
+    assert!(run(1.0) > 7000);
+    assert!(run(0.5) < 5000);
+}
+
+#[test]
+fn testing_delivery_rate() {
+    let mut pair = testing::loopback(1);
+    pair.a().set_nodelay(true, 10, 0, true);
+    pair.b().set_nodelay(true, 10, 0, true);
+    pair.a().set_rate_limit(50_000);
+    assert_eq!(pair.a().delivery_rate(), 0.0);
+
+    for current in (0..3000).step_by(10) {
+        while pair.a().wait_snd() < 64 {
+            pair.a().send(&[0u8; 1000]).unwrap();
+        }
+        pair.pump(current).unwrap();
+        pair.b().recv_all();
+    }
+
+    let rate = pair.a().delivery_rate();
+    assert!((45_000.0..55_000.0).contains(&rate), "{}", rate);
+    assert_eq!(pair.b().delivery_rate(), 0.0);
+
+    // Nothing acknowledged for a second
+    pair.a().update(4000).unwrap();
+    assert_eq!(pair.a().delivery_rate(), 0.0);
+}
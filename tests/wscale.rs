@@ -0,0 +1,78 @@
+#![cfg(all(feature = "std", feature = "wscale"))]
+
+extern crate kcp;
+
+mod common;
+
+use kcp::Kcp;
+
+use common::{deliver, Sink};
+
+// Exchange a message both ways `rounds` times
+fn ping_pong(kcp1: &mut Kcp<Sink>, sink1: &Sink, kcp2: &mut Kcp<Sink>, sink2: &Sink, rounds: u32) {
+    for round in 0..rounds {
+        let current = (round + 1) * 100;
+        kcp1.send(b"ping").unwrap();
+        kcp1.update(current).unwrap();
+        deliver(sink1, kcp2);
+        kcp2.recv_bytes().unwrap();
+
+        kcp2.send(b"pong").unwrap();
+        kcp2.update(current).unwrap();
+        deliver(sink2, kcp1);
+        kcp1.recv_bytes().unwrap();
+    }
+}
+
+#[test]
+fn wscale_negotiated() {
+    let sink1 = Sink::default();
+    let sink2 = Sink::default();
+    let mut kcp1 = Kcp::new(1, sink1.clone());
+    let mut kcp2 = Kcp::new(1, sink2.clone());
+    kcp1.set_window_scale(4);
+    kcp2.set_window_scale(4);
+    kcp2.set_wndsize_scaled(128, 200000);
+    kcp1.update(0).unwrap();
+    kcp2.update(0).unwrap();
+
+    // Capped until the remote is known to scale it
+    kcp2.send(b"hello").unwrap();
+    kcp2.flush().unwrap();
+    deliver(&sink2, &mut kcp1);
+    kcp1.recv_bytes().unwrap();
+    assert_eq!(kcp1.rmt_wnd(), 65535);
+    assert!(!kcp1.window_scale_negotiated());
+
+    ping_pong(&mut kcp1, &sink1, &mut kcp2, &sink2, 3);
+    assert!(kcp1.window_scale_negotiated());
+    assert!(kcp2.window_scale_negotiated());
+    assert_eq!(kcp1.rmt_wnd_scaled(), 200000 / 16 * 16);
+    assert_eq!(kcp1.rmt_wnd(), u16::MAX);
+    assert_eq!(kcp2.rcv_wnd_scaled(), 200000);
+    assert_eq!(kcp2.rmt_wnd(), 128);
+
+    // Nothing more to negotiate
+    kcp1.send(b"ping").unwrap();
+    kcp1.update(1000).unwrap();
+    assert_eq!(sink1.0.borrow().len(), 1);
+    assert_eq!(sink1.0.borrow()[0].len(), 2 * kcp::KCP_OVERHEAD + 4);
+}
+
+#[test]
+fn wscale_unsupported_remote() {
+    let sink1 = Sink::default();
+    let sink2 = Sink::default();
+    let mut kcp1 = Kcp::new(1, sink1.clone());
+    let mut kcp2 = Kcp::new(1, sink2.clone());
+    kcp1.set_window_scale(4);
+    kcp1.set_wndsize_scaled(128, 200000);
+    kcp2.set_wndsize_scaled(128, 200000);
+    kcp1.update(0).unwrap();
+    kcp2.update(0).unwrap();
+
+    ping_pong(&mut kcp1, &sink1, &mut kcp2, &sink2, 4);
+    assert!(!kcp1.window_scale_negotiated());
+    assert_eq!(kcp1.rmt_wnd(), 65535);
+    assert_eq!(kcp2.rmt_wnd(), 65535);
+}